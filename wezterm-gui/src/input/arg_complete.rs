@@ -0,0 +1,878 @@
+//! Per-command argument completion
+//!
+//! Completer knows commands, paths and variables; anything specific to one
+//! CLI lives in an ArgCompleter registered for that command.  When the word
+//! being completed is an argument, the provider for the command is asked
+//! first and path completion runs only if it has no opinion.
+
+use crate::input::complete::{CompletionInfo, CompletionKind};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest a provider may wait for a helper command such as git
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long a process listing is reused, so repeated Tabs don't rescan
+const PROCESS_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// Files whose parse a FileCache keeps unless created with another limit
+const FILE_CACHE_CAPACITY: usize = 64;
+
+/// What a provider knows about the argument being completed
+#[derive(Debug, Clone)]
+pub struct CompletionContext<'a> {
+    /// The command the argument belongs to
+    pub command: &'a str,
+    /// Arguments before the one being completed
+    pub args: Vec<&'a str>,
+    /// The argument as typed so far
+    pub word: &'a str,
+    /// Working directory of the shell
+    pub cwd: PathBuf,
+    /// Environment of the shell
+    pub env: HashMap<String, String>,
+    /// Whether the command runs under sudo
+    pub sudo: bool,
+}
+
+impl CompletionContext<'_> {
+    /// The argument right before the one being completed
+    pub fn previous_arg(&self) -> Option<&str> {
+        self.args.last().copied()
+    }
+}
+
+/// Completes the arguments of one command
+pub trait ArgCompleter: Send + Sync {
+    /// Completions for `ctx.word`, each replacing the whole word
+    ///
+    /// None means no opinion, so path completion runs instead; an empty
+    /// list means nothing fits.
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>>;
+
+    /// Whether paths are offered after the completions for `ctx`
+    fn with_paths(&self, _ctx: &CompletionContext) -> bool {
+        false
+    }
+}
+
+/// Candidates an embedder adds to every completion, such as agent or
+/// workflow names
+///
+/// In command position `ctx.command` is empty.
+pub trait CompletionSource: Send + Sync {
+    /// Candidates for `ctx.word`; Completer keeps those matching it
+    fn provide(&self, ctx: &CompletionContext) -> Vec<CompletionInfo>;
+
+    /// Where the candidates go: higher first, with the built-in ones at 0
+    /// ahead of sources that tie with them
+    fn priority(&self) -> i32;
+}
+
+/// Argument completion with `description`, matching the start of the text
+pub(crate) fn argument(text: &str, description: &str, typed: &str) -> CompletionInfo {
+    CompletionInfo {
+        text: text.to_string(),
+        description: Some(description.to_string()),
+        is_directory: false,
+        kind: CompletionKind::Argument,
+        match_indices: (0..typed.chars().count()).collect(),
+    }
+}
+
+/// Run `command`, returning its stdout if it succeeds within `timeout`
+///
+/// A command that takes longer is killed.
+pub(crate) fn output_with_timeout(command: &mut Command, timeout: Duration) -> Option<String> {
+    output_capped(command, timeout, usize::MAX)
+}
+
+/// Run `command` like output_with_timeout, keeping at most `max_output`
+/// bytes of its stdout
+///
+/// Output past the cap is read and dropped, along with the line it cuts.
+/// The deadline covers reading too, since whatever the command started
+/// may keep its stdout open after it exits; on unix the command runs in a
+/// process group of its own so all of it is killed.
+pub(crate) fn output_capped(
+    command: &mut Command,
+    timeout: Duration,
+    max_output: usize,
+) -> Option<String> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // Read on another thread so a full pipe can't stall the child
+    let mut stdout = child.stdout.take()?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(read_capped(&mut stdout, max_output));
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(2)),
+            _ => {
+                log::debug!("Killing {:?}, which took too long", command);
+                kill_process_group(&mut child);
+                return None;
+            }
+        }
+    };
+    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(out) => status.success().then_some(out?),
+        Err(_) => {
+            // Killing what holds the pipe open lets the reader finish
+            log::debug!("Killing what {:?} left holding its output", command);
+            kill_process_group(&mut child);
+            None
+        }
+    }
+}
+
+/// Read `stdout` to the end, keeping at most `max_output` bytes
+fn read_capped(stdout: &mut impl Read, max_output: usize) -> Option<String> {
+    let mut out = Vec::new();
+    stdout.take(max_output as u64).read_to_end(&mut out).ok()?;
+    let dropped = std::io::copy(stdout, &mut std::io::sink()).ok()?;
+    if dropped > 0 {
+        let end = out.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        out.truncate(end);
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Kill `child` and, on unix, everything else in its process group
+fn kill_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        // The group was created with the child's pid as its id
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// A parse kept with the modification time it was made for
+#[derive(Debug)]
+struct Parsed<T> {
+    modified: SystemTime,
+    value: Arc<T>,
+    /// Tick of the last use, to evict the least recently used
+    used: u64,
+}
+
+#[derive(Debug)]
+struct ParsedFiles<T> {
+    by_path: HashMap<PathBuf, Parsed<T>>,
+    tick: u64,
+}
+
+/// What providers parsed from files such as Makefiles or package.json,
+/// reused while a file's modification time stays the same
+///
+/// Holds at most `capacity` files, dropping the least recently used.
+#[derive(Debug)]
+pub(crate) struct FileCache<T> {
+    capacity: usize,
+    parsed: Mutex<ParsedFiles<T>>,
+}
+
+impl<T> Default for FileCache<T> {
+    fn default() -> Self {
+        Self::new(FILE_CACHE_CAPACITY)
+    }
+}
+
+impl<T> FileCache<T> {
+    /// Create a cache of up to `capacity` files
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            parsed: Mutex::new(ParsedFiles {
+                by_path: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// What `parse` makes of `path`, parsed again only if the file
+    /// changed; None if it is missing or `parse` fails
+    pub(crate) fn get(
+        &self,
+        path: &Path,
+        parse: impl FnOnce(&Path) -> Option<T>,
+    ) -> Option<Arc<T>> {
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+        let mut parsed = self.parsed.lock().unwrap();
+        parsed.tick += 1;
+        let tick = parsed.tick;
+        if let Some(known) = parsed.by_path.get_mut(path) {
+            if known.modified == modified {
+                known.used = tick;
+                return Some(Arc::clone(&known.value));
+            }
+        }
+        // Parsing reads the file, so others can use the cache meanwhile
+        drop(parsed);
+        let value = Arc::new(parse(path)?);
+
+        let mut parsed = self.parsed.lock().unwrap();
+        if !parsed.by_path.contains_key(path) && parsed.by_path.len() >= self.capacity {
+            let oldest = parsed
+                .by_path
+                .iter()
+                .min_by_key(|(_, known)| known.used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                parsed.by_path.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            parsed.by_path.insert(
+                path.to_path_buf(),
+                Parsed {
+                    modified,
+                    value: Arc::clone(&value),
+                    used: tick,
+                },
+            );
+        }
+        Some(value)
+    }
+}
+
+/// Git subcommands offered after `git`
+const GIT_SUBCOMMANDS: &[&str] = &[
+    "add",
+    "bisect",
+    "blame",
+    "branch",
+    "checkout",
+    "cherry-pick",
+    "clean",
+    "clone",
+    "commit",
+    "diff",
+    "fetch",
+    "grep",
+    "init",
+    "log",
+    "merge",
+    "mv",
+    "pull",
+    "push",
+    "rebase",
+    "remote",
+    "reset",
+    "restore",
+    "revert",
+    "rm",
+    "show",
+    "stash",
+    "status",
+    "switch",
+    "tag",
+    "worktree",
+];
+
+/// Git subcommands that take a branch
+const GIT_BRANCH_SUBCOMMANDS: &[&str] = &[
+    "branch",
+    "checkout",
+    "cherry-pick",
+    "merge",
+    "rebase",
+    "switch",
+];
+
+/// Completes git subcommands, branches, remotes and changed files
+///
+/// Branches and remotes are read from the repository's files; changed
+/// files come from git itself, which is killed if it is slow.  Outside a
+/// repository only subcommands are offered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GitCompleter;
+
+impl ArgCompleter for GitCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        if ctx.word.starts_with('-') {
+            return None;
+        }
+        let mut positional = ctx.args.iter().filter(|arg| !arg.starts_with('-'));
+        let subcommand = match positional.next() {
+            Some(subcommand) => *subcommand,
+            None => {
+                let items = GIT_SUBCOMMANDS
+                    .iter()
+                    .filter(|name| name.starts_with(ctx.word))
+                    .map(|name| argument(name, "subcommand", ctx.word))
+                    .collect();
+                return Some(items);
+            }
+        };
+        // Past "--" come paths
+        if ctx.args.contains(&"--") {
+            return None;
+        }
+        let git_dir = find_git_dir(&ctx.cwd);
+
+        let (names, description) = match subcommand {
+            "push" | "pull" | "fetch" if positional.next().is_none() => {
+                (git_remotes(git_dir.as_deref()?), "remote")
+            }
+            "push" | "pull" | "fetch" => (git_branches(git_dir.as_deref()?), "branch"),
+            name if GIT_BRANCH_SUBCOMMANDS.contains(&name) => {
+                (git_branches(git_dir.as_deref()?), "branch")
+            }
+            "add" if git_dir.is_some() => return Some(git_changed_files(&ctx.cwd, ctx.word)),
+            _ => return None,
+        };
+        let items = names
+            .iter()
+            .filter(|name| name.starts_with(ctx.word))
+            .map(|name| argument(name, description, ctx.word))
+            .collect();
+        Some(items)
+    }
+}
+
+/// Find the git directory of the repository containing `dir`
+///
+/// For a linked worktree this is the common directory, which holds the
+/// refs and config.
+fn find_git_dir(dir: &Path) -> Option<PathBuf> {
+    let mut git_dir = dir.ancestors().find_map(|dir| {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        // Worktrees and submodules have a file pointing at the git dir
+        let contents = fs::read_to_string(&dot_git).ok()?;
+        let target = contents.strip_prefix("gitdir:")?.trim();
+        Some(dir.join(target))
+    })?;
+    if let Ok(common) = fs::read_to_string(git_dir.join("commondir")) {
+        git_dir = git_dir.join(common.trim());
+    }
+    Some(git_dir)
+}
+
+/// Local branch names, loose and packed
+fn git_branches(git_dir: &Path) -> Vec<String> {
+    let mut branches = BTreeSet::new();
+    let heads = git_dir.join("refs").join("heads");
+    for entry in walkdir::WalkDir::new(&heads)
+        .into_iter()
+        .filter_map(Result::ok)
+    {
+        if entry.file_type().is_file() {
+            if let Ok(name) = entry.path().strip_prefix(&heads) {
+                branches.insert(name.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+    if let Ok(packed) = fs::read_to_string(git_dir.join("packed-refs")) {
+        for line in packed.lines() {
+            let name = line.split_once(' ').map(|(_, name)| name);
+            if let Some(branch) = name.and_then(|name| name.strip_prefix("refs/heads/")) {
+                branches.insert(branch.to_string());
+            }
+        }
+    }
+    branches.into_iter().collect()
+}
+
+/// Remote names from the repository's config
+fn git_remotes(git_dir: &Path) -> Vec<String> {
+    let config = fs::read_to_string(git_dir.join("config")).unwrap_or_default();
+    let remotes: BTreeSet<String> = config
+        .lines()
+        .filter_map(|line| {
+            let section = line.trim().strip_prefix("[remote \"")?;
+            Some(section.strip_suffix("\"]")?.to_string())
+        })
+        .collect();
+    remotes.into_iter().collect()
+}
+
+/// Modified and untracked files under `cwd` that start with `word`
+fn git_changed_files(cwd: &Path, word: &str) -> Vec<CompletionInfo> {
+    let mut command = Command::new("git");
+    command
+        .args([
+            "ls-files",
+            "--modified",
+            "--others",
+            "--exclude-standard",
+            "-t",
+        ])
+        .current_dir(cwd);
+    let out = output_with_timeout(&mut command, COMMAND_TIMEOUT).unwrap_or_default();
+    let mut seen = BTreeSet::new();
+    out.lines()
+        .filter_map(|line| {
+            let (tag, path) = line.split_once(' ')?;
+            let description = if tag == "?" { "untracked" } else { "modified" };
+            (path.starts_with(word) && seen.insert(path)).then(|| argument(path, description, word))
+        })
+        .collect()
+}
+
+/// Signal names, for kill
+const SIGNALS: &[&str] = &[
+    "ABRT", "ALRM", "BUS", "CHLD", "CONT", "FPE", "HUP", "ILL", "INT", "KILL", "PIPE", "PROF",
+    "QUIT", "SEGV", "STOP", "SYS", "TERM", "TRAP", "TSTP", "TTIN", "TTOU", "URG", "USR1", "USR2",
+    "VTALRM", "WINCH", "XCPU", "XFSZ",
+];
+
+/// Signal names starting with `typed`, each put after `lead`
+///
+/// "term" and "SIGTERM" both find TERM.
+fn signal_names(typed: &str, lead: &str) -> Vec<CompletionInfo> {
+    let typed = typed.to_ascii_uppercase();
+    let typed = typed.strip_prefix("SIG").unwrap_or(&typed);
+    SIGNALS
+        .iter()
+        .filter(|name| name.starts_with(typed))
+        .map(|name| argument(&format!("{}{}", lead, name), "signal", lead))
+        .collect()
+}
+
+/// A running process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessEntry {
+    pub pid: u32,
+    /// Command name, without its directory
+    pub name: String,
+    /// Owner, if known
+    pub uid: Option<u32>,
+}
+
+/// Lists running processes
+pub trait ProcessSource: Send + Sync {
+    fn processes(&self) -> Vec<ProcessEntry>;
+}
+
+/// Processes from /proc on Linux, or from ps elsewhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemProcesses;
+
+impl ProcessSource for SystemProcesses {
+    fn processes(&self) -> Vec<ProcessEntry> {
+        proc_processes().unwrap_or_else(ps_processes)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn proc_processes() -> Option<Vec<ProcessEntry>> {
+    use std::os::unix::fs::MetadataExt;
+    let entries = fs::read_dir("/proc").ok()?;
+    let processes = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            // The process may have exited since the listing
+            let name = fs::read_to_string(entry.path().join("comm")).ok()?;
+            Some(ProcessEntry {
+                pid,
+                name: name.trim_end().to_string(),
+                uid: entry.metadata().ok().map(|meta| meta.uid()),
+            })
+        })
+        .collect();
+    Some(processes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn proc_processes() -> Option<Vec<ProcessEntry>> {
+    None
+}
+
+fn ps_processes() -> Vec<ProcessEntry> {
+    let mut command = Command::new("ps");
+    command.args(["-eo", "pid=,uid=,comm="]);
+    let out = output_with_timeout(&mut command, COMMAND_TIMEOUT * 3).unwrap_or_default();
+    out.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let uid = fields.next()?.parse().ok();
+            let comm = fields.collect::<Vec<_>>().join(" ");
+            // macOS gives the full path
+            let name = comm.rsplit('/').next().unwrap_or(&comm).to_string();
+            Some(ProcessEntry { pid, name, uid })
+        })
+        .collect()
+}
+
+/// The user the terminal runs as, if the platform has such a thing
+fn current_uid() -> Option<u32> {
+    #[cfg(unix)]
+    {
+        // SAFETY: getuid can't fail and touches no memory
+        Some(unsafe { libc::getuid() })
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+/// Completes PIDs and signals for kill and renice, and process names for
+/// pkill and killall
+///
+/// Only the user's own processes are offered unless the command runs under
+/// sudo.  PIDs come newest first.
+pub struct ProcessCompleter {
+    source: Box<dyn ProcessSource>,
+    /// Last listing and when it was taken
+    cache: Mutex<Option<(Instant, Arc<Vec<ProcessEntry>>)>>,
+}
+
+impl Default for ProcessCompleter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProcessCompleter {
+    /// Create a completer listing the system's processes
+    pub fn new() -> Self {
+        Self::with_source(Box::new(SystemProcesses))
+    }
+
+    /// Create a completer listing processes from `source`
+    pub fn with_source(source: Box<dyn ProcessSource>) -> Self {
+        Self {
+            source,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Processes visible to the command, newest first
+    fn processes(&self, sudo: bool) -> Vec<ProcessEntry> {
+        let all = {
+            let mut cache = self.cache.lock().unwrap();
+            match &*cache {
+                Some((taken, all)) if taken.elapsed() < PROCESS_CACHE_TTL => Arc::clone(all),
+                _ => {
+                    let all = Arc::new(self.source.processes());
+                    *cache = Some((Instant::now(), Arc::clone(&all)));
+                    all
+                }
+            }
+        };
+        let uid = current_uid().filter(|_| !sudo);
+        let mut visible: Vec<ProcessEntry> = all
+            .iter()
+            .filter(|entry| uid.is_none() || entry.uid.is_none() || entry.uid == uid)
+            .cloned()
+            .collect();
+        visible.sort_by_key(|entry| std::cmp::Reverse(entry.pid));
+        visible
+    }
+
+    /// PIDs whose number or process name starts with `word`
+    fn pids(&self, word: &str, sudo: bool) -> Vec<CompletionInfo> {
+        self.processes(sudo)
+            .into_iter()
+            .filter_map(|entry| {
+                let pid = entry.pid.to_string();
+                let typed = if pid.starts_with(word) {
+                    word
+                } else if entry.name.starts_with(word) {
+                    ""
+                } else {
+                    return None;
+                };
+                Some(argument(&pid, &entry.name, typed))
+            })
+            .collect()
+    }
+
+    /// Distinct process names starting with `word`
+    fn names(&self, word: &str, sudo: bool) -> Vec<CompletionInfo> {
+        let names: BTreeSet<String> = self
+            .processes(sudo)
+            .into_iter()
+            .map(|entry| entry.name)
+            .filter(|name| name.starts_with(word))
+            .collect();
+        names
+            .iter()
+            .map(|name| argument(name, "process", word))
+            .collect()
+    }
+}
+
+impl ArgCompleter for ProcessCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        let previous = ctx.previous_arg();
+        match ctx.command {
+            "kill" if matches!(previous, Some("-s") | Some("-n")) => {
+                Some(signal_names(ctx.word, ""))
+            }
+            "kill" => match ctx.word.strip_prefix('-') {
+                Some(signal) => Some(signal_names(signal, "-")),
+                None => Some(self.pids(ctx.word, ctx.sudo)),
+            },
+            "renice" if previous != Some("-n") && !ctx.word.starts_with('-') => {
+                Some(self.pids(ctx.word, ctx.sudo))
+            }
+            "pkill" | "killall" if !ctx.word.starts_with('-') => {
+                Some(self.names(ctx.word, ctx.sudo))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>(args: &[&'a str], word: &'a str) -> CompletionContext<'a> {
+        CompletionContext {
+            command: "kill",
+            args: args.to_vec(),
+            word,
+            cwd: PathBuf::from("/"),
+            env: HashMap::new(),
+            sudo: false,
+        }
+    }
+
+    #[test]
+    fn test_file_cache_reparses_changes_and_evicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a", "b", "c"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &paths {
+            fs::write(path, "one").unwrap();
+        }
+        let parses = std::cell::Cell::new(0);
+        let cache = FileCache::new(2);
+        let read = |path: &Path| {
+            parses.set(parses.get() + 1);
+            fs::read_to_string(path).ok()
+        };
+
+        assert_eq!(cache.get(&paths[0], read).as_deref().unwrap(), "one");
+        cache.get(&paths[1], read);
+        cache.get(&paths[0], read);
+        assert_eq!(parses.get(), 2);
+
+        // b is the least recently used, so c takes its place
+        cache.get(&paths[2], read);
+        cache.get(&paths[0], read);
+        assert_eq!(parses.get(), 3);
+        cache.get(&paths[1], read);
+        assert_eq!(parses.get(), 4);
+
+        let file = fs::File::options().write(true).open(&paths[0]).unwrap();
+        file.set_len(0).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(cache.get(&paths[0], read).as_deref().unwrap(), "");
+        assert!(cache.get(&dir.path().join("missing"), read).is_none());
+    }
+
+    /// A fixed process table, counting how often it is read
+    struct FakeProcesses(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl ProcessSource for FakeProcesses {
+        fn processes(&self) -> Vec<ProcessEntry> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let uid = current_uid();
+            let other = uid.map(|uid| uid + 1);
+            [
+                (1, "init", other),
+                (411, "bash", uid),
+                (4096, "firefox", uid),
+            ]
+            .iter()
+            .chain(&[(977, "sshd", other), (2001, "firefox", uid)])
+            .map(|&(pid, name, uid)| ProcessEntry {
+                pid,
+                name: name.to_string(),
+                uid,
+            })
+            .collect()
+        }
+    }
+
+    fn texts(items: Vec<CompletionInfo>) -> Vec<String> {
+        items.into_iter().map(|item| item.text).collect()
+    }
+
+    /// Run git in `dir`, returning false if it isn't installed
+    fn git(dir: &Path, args: &[&str]) -> bool {
+        let status = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        match status {
+            Ok(status) => {
+                assert!(status.success(), "git {:?} failed", args);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_git_completion() {
+        let repo = tempfile::tempdir().unwrap();
+        if !git(repo.path(), &["init", "-q", "-b", "main"]) {
+            return;
+        }
+        fs::write(repo.path().join("tracked.txt"), "one").unwrap();
+        git(repo.path(), &["add", "tracked.txt"]);
+        git(repo.path(), &["commit", "-q", "-m", "init"]);
+        git(repo.path(), &["branch", "feature/login"]);
+        git(repo.path(), &["pack-refs", "--all"]);
+        git(repo.path(), &["branch", "fix"]);
+        git(
+            repo.path(),
+            &["remote", "add", "origin", "https://example.com/r.git"],
+        );
+        fs::write(repo.path().join("tracked.txt"), "two").unwrap();
+        fs::write(repo.path().join("new.txt"), "").unwrap();
+
+        let complete = |args: &[&str], word: &str| {
+            let mut ctx = context(args, word);
+            ctx.command = "git";
+            ctx.cwd = repo.path().to_path_buf();
+            GitCompleter.complete(&ctx)
+        };
+        assert_eq!(
+            texts(complete(&[], "ch").unwrap()),
+            ["checkout", "cherry-pick"]
+        );
+        assert_eq!(
+            texts(complete(&["checkout"], "").unwrap()),
+            ["feature/login", "fix", "main"]
+        );
+        assert_eq!(
+            texts(complete(&["switch", "-c"], "f").unwrap()),
+            ["feature/login", "fix"]
+        );
+        let remotes = complete(&["push"], "").unwrap();
+        assert_eq!(remotes[0].description.as_deref(), Some("remote"));
+        assert_eq!(texts(remotes), ["origin"]);
+        assert_eq!(
+            texts(complete(&["push", "origin"], "ma").unwrap()),
+            ["main"]
+        );
+
+        let files = complete(&["add"], "").unwrap();
+        let labelled: Vec<(&str, &str)> = files
+            .iter()
+            .map(|i| (i.text.as_str(), i.description.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            labelled,
+            [("new.txt", "untracked"), ("tracked.txt", "modified")]
+        );
+        assert!(complete(&["checkout", "--"], "").is_none());
+        assert!(complete(&["log"], "").is_none());
+    }
+
+    #[test]
+    fn test_git_outside_a_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(&["checkout"], "");
+        ctx.command = "git";
+        ctx.cwd = dir.path().to_path_buf();
+        assert!(GitCompleter.complete(&ctx).is_none());
+        ctx.args.clear();
+        assert!(!GitCompleter.complete(&ctx).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_slow_command_is_killed() {
+        let start = Instant::now();
+        let mut sleep = Command::new("sleep");
+        sleep.arg("5");
+        assert_eq!(output_with_timeout(&mut sleep, COMMAND_TIMEOUT), None);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_held_open_by_a_child_times_out() {
+        let start = Instant::now();
+        let mut shell = Command::new("sh");
+        shell.args(["-c", "sleep 5 & echo started"]);
+        assert_eq!(output_with_timeout(&mut shell, COMMAND_TIMEOUT), None);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_kill_signals_and_pids() {
+        let reads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let completer = ProcessCompleter::with_source(Box::new(FakeProcesses(Arc::clone(&reads))));
+        let complete =
+            |args: &[&str], word: &str| texts(completer.complete(&context(args, word)).unwrap());
+
+        assert_eq!(complete(&["-s"], "US"), ["USR1", "USR2"]);
+        assert_eq!(complete(&["-n"], "sigte"), ["TERM"]);
+        assert_eq!(complete(&[], "-KI"), ["-KILL"]);
+        assert!(complete(&["-s"], "XYZ").is_empty());
+
+        // Newest first, matching the PID or the name
+        assert_eq!(complete(&[], "4"), ["4096", "411"]);
+        let items = completer.complete(&context(&["-9"], "fire")).unwrap();
+        assert_eq!(items[0].description.as_deref(), Some("firefox"));
+        assert_eq!(texts(items), ["4096", "2001"]);
+        assert_eq!(reads.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_other_users_processes_need_sudo() {
+        let reads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let completer = ProcessCompleter::with_source(Box::new(FakeProcesses(reads)));
+        let mut ctx = context(&[], "");
+        ctx.command = "pkill";
+        assert_eq!(
+            texts(completer.complete(&ctx).unwrap()),
+            ["bash", "firefox"]
+        );
+        ctx.sudo = true;
+        assert_eq!(
+            texts(completer.complete(&ctx).unwrap()),
+            ["bash", "firefox", "init", "sshd"]
+        );
+        ctx.command = "renice";
+        ctx.args = vec!["-n"];
+        assert!(completer.complete(&ctx).is_none());
+    }
+
+    #[test]
+    fn test_own_process_is_listed() {
+        let pid = std::process::id().to_string();
+        let completer = ProcessCompleter::new();
+        let items = completer.complete(&context(&[], &pid)).unwrap();
+        assert!(items.iter().any(|item| item.text == pid));
+    }
+}
@@ -0,0 +1,292 @@
+//! Argument completion from bash-completion, for commands without a
+//! provider of their own
+//!
+//! BashCompleterBridge runs bash in a subprocess, loads the command's
+//! completion function from bash-completion and calls it with COMP_WORDS
+//! and COMP_CWORD set, as bash does on Tab.  The words reach bash as
+//! arguments and are never spliced into the script.  A missing bash, a
+//! command without a completion function or a timeout all mean no
+//! opinion, so path completion runs instead.
+
+use crate::input::arg_complete::{argument, output_capped, ArgCompleter, CompletionContext};
+use crate::input::complete::CompletionInfo;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where bash-completion is usually installed
+const BASH_COMPLETION: &str = "/usr/share/bash-completion/bash_completion";
+/// Longest bash may take to answer
+const BRIDGE_TIMEOUT: Duration = Duration::from_millis(300);
+/// Most output read from bash
+const MAX_OUTPUT: usize = 64 * 1024;
+/// How long an answer is reused, so repeated Tabs don't rerun bash
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Run as `bash -c SCRIPT bash BASH_COMPLETION WORD...`, where the words
+/// are the command, its arguments and the word being completed
+const SCRIPT: &str = r#"source "$1" >/dev/null 2>&1 || exit 1
+shift
+COMP_WORDS=("$@")
+COMP_CWORD=$((${#COMP_WORDS[@]} - 1))
+COMP_LINE="${COMP_WORDS[*]}"
+COMP_POINT=${#COMP_LINE}
+COMP_TYPE=9
+COMP_KEY=9
+cmd=${COMP_WORDS[0]}
+{ _comp_load -- "$cmd" || _completion_loader "$cmd"; } >/dev/null 2>&1
+spec=$(complete -p "$cmd" 2>/dev/null) || exit 1
+[[ $spec =~ -F\ ([^ ]+) ]] || exit 1
+func=${BASH_REMATCH[1]}
+"$func" "$cmd" "${COMP_WORDS[COMP_CWORD]}" "${COMP_WORDS[COMP_CWORD-1]}" >/dev/null 2>&1
+printf '%s\n' "${COMPREPLY[@]}"
+"#;
+
+/// Answers by command line, with when they were given
+type AnswerCache = HashMap<Vec<String>, (Instant, Option<Vec<String>>)>;
+
+/// Runs bash for the bridge
+pub trait BashRunner: Send + Sync {
+    /// Run bash with `args`, returning at most `max_output` bytes of its
+    /// stdout, or None if it fails or outlasts `timeout`
+    fn run(&self, args: &[String], timeout: Duration, max_output: usize) -> Option<String>;
+}
+
+/// Runs the real bash, or another program in its place
+#[derive(Debug, Clone)]
+pub struct SystemBash {
+    program: PathBuf,
+}
+
+impl Default for SystemBash {
+    fn default() -> Self {
+        Self::with_program("bash")
+    }
+}
+
+impl SystemBash {
+    /// Run `program` instead of bash from PATH
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl BashRunner for SystemBash {
+    fn run(&self, args: &[String], timeout: Duration, max_output: usize) -> Option<String> {
+        let mut command = Command::new(&self.program);
+        command.args(args);
+        output_capped(&mut command, timeout, max_output)
+    }
+}
+
+/// Completes arguments with the command's bash-completion function
+pub struct BashCompleterBridge {
+    runner: Box<dyn BashRunner>,
+    /// The bash_completion script to source
+    script: PathBuf,
+    enabled: bool,
+    cache: Mutex<AnswerCache>,
+}
+
+impl Default for BashCompleterBridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BashCompleterBridge {
+    /// Create a bridge to the system's bash and bash-completion
+    pub fn new() -> Self {
+        Self::with_runner(Box::new(SystemBash::default()))
+    }
+
+    /// Create a bridge that runs bash through `runner`
+    pub fn with_runner(runner: Box<dyn BashRunner>) -> Self {
+        Self {
+            runner,
+            script: PathBuf::from(BASH_COMPLETION),
+            enabled: true,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Source `script` instead of the usual bash_completion
+    pub fn set_script(&mut self, script: impl Into<PathBuf>) {
+        self.script = script.into();
+    }
+
+    /// Turn the bridge on or off; while off it has no opinion
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Candidates for the last of `words`, from the cache if fresh
+    fn candidates(&self, words: Vec<String>) -> Option<Vec<String>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((at, candidates)) = cache.get(&words) {
+            if at.elapsed() < CACHE_TTL {
+                return candidates.clone();
+            }
+        }
+        let mut args = vec![
+            "-c".to_string(),
+            SCRIPT.to_string(),
+            "bash".to_string(),
+            self.script.to_string_lossy().into_owned(),
+        ];
+        args.extend(words.iter().cloned());
+        let candidates = self
+            .runner
+            .run(&args, BRIDGE_TIMEOUT, MAX_OUTPUT)
+            .map(|out| {
+                let mut seen = HashSet::new();
+                out.lines()
+                    .map(|line| line.trim_end_matches('\r'))
+                    .filter(|line| !line.is_empty() && seen.insert(*line))
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|candidates| !candidates.is_empty());
+        cache.retain(|_, (at, _)| at.elapsed() < CACHE_TTL);
+        cache.insert(words, (Instant::now(), candidates.clone()));
+        candidates
+    }
+}
+
+impl ArgCompleter for BashCompleterBridge {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        if !self.enabled {
+            return None;
+        }
+        let words = std::iter::once(ctx.command)
+            .chain(ctx.args.iter().copied())
+            .chain(std::iter::once(ctx.word))
+            .map(String::from)
+            .collect();
+        let candidates = self.candidates(words)?;
+        let items = candidates
+            .iter()
+            .map(|text| {
+                let typed = if text.starts_with(ctx.word) {
+                    ctx.word
+                } else {
+                    ""
+                };
+                argument(text, "bash-completion", typed)
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+impl std::fmt::Debug for BashCompleterBridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BashCompleterBridge")
+            .field("script", &self.script)
+            .field("enabled", &self.enabled)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Answers with canned output, remembering what it was run with
+    struct FakeBash {
+        output: Option<String>,
+        calls: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl BashRunner for FakeBash {
+        fn run(&self, args: &[String], timeout: Duration, max_output: usize) -> Option<String> {
+            assert_eq!((timeout, max_output), (BRIDGE_TIMEOUT, MAX_OUTPUT));
+            self.calls.lock().unwrap().push(args.to_vec());
+            self.output.clone()
+        }
+    }
+
+    fn fake_bridge(output: Option<&str>) -> (BashCompleterBridge, Arc<Mutex<Vec<Vec<String>>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let runner = FakeBash {
+            output: output.map(String::from),
+            calls: Arc::clone(&calls),
+        };
+        (BashCompleterBridge::with_runner(Box::new(runner)), calls)
+    }
+
+    fn context<'a>(command: &'a str, args: &[&'a str], word: &'a str) -> CompletionContext<'a> {
+        CompletionContext {
+            command,
+            args: args.to_vec(),
+            word,
+            cwd: PathBuf::from("/"),
+            env: HashMap::new(),
+            sudo: false,
+        }
+    }
+
+    #[test]
+    fn test_words_are_passed_as_arguments() {
+        let (bridge, calls) = fake_bridge(Some("--all\n--all-namespaces\n--all\n"));
+        let ctx = context("kubectl", &["get", "pods; rm -rf ~"], "--al");
+        let items = bridge.complete(&ctx).unwrap();
+        let texts: Vec<&str> = items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["--all", "--all-namespaces"]);
+        assert_eq!(items[0].match_indices, [0, 1, 2, 3]);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0][..3], ["-c", SCRIPT, "bash"]);
+        assert_eq!(
+            calls[0][3..],
+            [BASH_COMPLETION, "kubectl", "get", "pods; rm -rf ~", "--al"]
+        );
+    }
+
+    #[test]
+    fn test_answers_are_cached() {
+        let (bridge, calls) = fake_bridge(Some("start\nstop\n"));
+        let ctx = context("systemctl", &[], "st");
+        assert!(bridge.complete(&ctx).is_some());
+        assert!(bridge.complete(&ctx).is_some());
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        bridge.complete(&context("systemctl", &[], "sta"));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_failures_have_no_opinion() {
+        // Timed out, or nothing to offer
+        let (bridge, _) = fake_bridge(None);
+        assert!(bridge.complete(&context("foo", &[], "")).is_none());
+        let (bridge, _) = fake_bridge(Some("\n"));
+        assert!(bridge.complete(&context("foo", &[], "")).is_none());
+
+        let (mut bridge, calls) = fake_bridge(Some("x\n"));
+        bridge.set_enabled(false);
+        assert!(bridge.complete(&context("foo", &[], "")).is_none());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_slow_bash_is_killed() {
+        let start = Instant::now();
+        let sleep = SystemBash::with_program("sleep");
+        let out = sleep.run(&["5".to_string()], BRIDGE_TIMEOUT, MAX_OUTPUT);
+        assert_eq!(out, None);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Output past the cap is dropped with the line it cuts
+        let seq = SystemBash::with_program("seq");
+        let args = ["1".to_string(), "100000".to_string()];
+        let out = seq.run(&args, Duration::from_secs(2), 11);
+        assert_eq!(out.as_deref(), Some("1\n2\n3\n4\n5\n"));
+    }
+}
@@ -0,0 +1,723 @@
+//! Rope-based text storage for the input editor
+//!
+//! The text lives in chunks of at most MAX_CHUNK bytes at the leaves of a
+//! height-balanced tree.  Every node caches the bytes, chars and line
+//! breaks below it, so inserting, deleting and converting between lines,
+//! columns and byte offsets take O(log n) whatever the text looks like,
+//! including long one-liners of multi-byte text.  Nodes are shared, so
+//! cloning a buffer is cheap and edits copy only the path they change.
+
+use crate::input::editor::CursorPosition;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Largest chunk a leaf holds, in bytes
+const MAX_CHUNK: usize = 1024;
+
+/// Text buffer split into lines
+#[derive(Debug, Clone)]
+pub struct TextBuffer {
+    root: Arc<Node>,
+}
+
+/// Size of some text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Metrics {
+    bytes: usize,
+    chars: usize,
+    line_breaks: usize,
+}
+
+impl Metrics {
+    fn of(text: &str) -> Self {
+        Self {
+            bytes: text.len(),
+            chars: text.chars().count(),
+            line_breaks: text.bytes().filter(|&b| b == b'\n').count(),
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            bytes: self.bytes + other.bytes,
+            chars: self.chars + other.chars,
+            line_breaks: self.line_breaks + other.line_breaks,
+        }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            bytes: self.bytes - other.bytes,
+            chars: self.chars - other.chars,
+            line_breaks: self.line_breaks - other.line_breaks,
+        }
+    }
+}
+
+/// A node of the rope
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf {
+        text: String,
+        metrics: Metrics,
+    },
+    Branch {
+        left: Arc<Node>,
+        right: Arc<Node>,
+        metrics: Metrics,
+        height: usize,
+    },
+}
+
+impl Node {
+    fn leaf(text: String) -> Arc<Self> {
+        let metrics = Metrics::of(&text);
+        Arc::new(Self::Leaf { text, metrics })
+    }
+
+    /// Join two subtrees as they are; see join for keeping balance
+    fn branch(left: Arc<Self>, right: Arc<Self>) -> Arc<Self> {
+        Arc::new(Self::Branch {
+            metrics: left.metrics().add(right.metrics()),
+            height: left.height().max(right.height()) + 1,
+            left,
+            right,
+        })
+    }
+
+    /// Tree over `text`, as balanced as it can be
+    fn from_text(text: &str) -> Arc<Self> {
+        let mut chunks = Vec::new();
+        let mut rest = text;
+        while rest.len() > MAX_CHUNK {
+            let mut end = MAX_CHUNK;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            chunks.push(rest[..end].to_string());
+            rest = &rest[end..];
+        }
+        chunks.push(rest.to_string());
+        Self::from_chunks(&mut chunks)
+    }
+
+    fn from_chunks(chunks: &mut [String]) -> Arc<Self> {
+        if let [chunk] = chunks {
+            return Self::leaf(std::mem::take(chunk));
+        }
+        let (left, right) = chunks.split_at_mut(chunks.len() / 2);
+        Self::branch(Self::from_chunks(left), Self::from_chunks(right))
+    }
+
+    fn metrics(&self) -> Metrics {
+        match self {
+            Self::Leaf { metrics, .. } | Self::Branch { metrics, .. } => *metrics,
+        }
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            Self::Leaf { .. } => 0,
+            Self::Branch { height, .. } => *height,
+        }
+    }
+
+    fn children(&self) -> (Arc<Self>, Arc<Self>) {
+        match self {
+            Self::Branch { left, right, .. } => (Arc::clone(left), Arc::clone(right)),
+            Self::Leaf { .. } => unreachable!("a leaf has no children"),
+        }
+    }
+
+    /// Metrics of the text before the line break `count` ends at, and
+    /// after it; the start of line `count`
+    fn line_start(&self, count: usize) -> Metrics {
+        match self {
+            Self::Leaf { text, .. } => {
+                let end = text
+                    .match_indices('\n')
+                    .nth(count - 1)
+                    .map_or(text.len(), |(idx, _)| idx + 1);
+                Metrics::of(&text[..end])
+            }
+            Self::Branch { left, right, .. } => {
+                let before = left.metrics();
+                if count <= before.line_breaks {
+                    left.line_start(count)
+                } else {
+                    before.add(right.line_start(count - before.line_breaks))
+                }
+            }
+        }
+    }
+
+    /// Byte offset of char `offset`, or the end
+    fn byte_of_char(&self, offset: usize) -> usize {
+        match self {
+            Self::Leaf { text, metrics } if metrics.chars == metrics.bytes => {
+                offset.min(text.len())
+            }
+            Self::Leaf { text, .. } => text
+                .char_indices()
+                .nth(offset)
+                .map_or(text.len(), |(idx, _)| idx),
+            Self::Branch { left, right, .. } => {
+                let before = left.metrics();
+                if offset < before.chars {
+                    left.byte_of_char(offset)
+                } else {
+                    before.bytes + right.byte_of_char(offset - before.chars)
+                }
+            }
+        }
+    }
+
+    /// Number of chars starting before byte `offset`
+    fn chars_before_byte(&self, offset: usize) -> usize {
+        match self {
+            Self::Leaf { text, metrics } if metrics.chars == metrics.bytes => {
+                offset.min(text.len())
+            }
+            Self::Leaf { text, .. } => text.char_indices().take_while(|&(i, _)| i < offset).count(),
+            Self::Branch { left, right, .. } => {
+                let before = left.metrics();
+                if offset <= before.bytes {
+                    left.chars_before_byte(offset)
+                } else {
+                    before.chars + right.chars_before_byte(offset - before.bytes)
+                }
+            }
+        }
+    }
+
+    /// Number of line breaks before byte `offset`
+    fn breaks_before_byte(&self, offset: usize) -> usize {
+        match self {
+            Self::Leaf { text, .. } => {
+                let offset = offset.min(text.len());
+                text.as_bytes()[..offset]
+                    .iter()
+                    .filter(|&&b| b == b'\n')
+                    .count()
+            }
+            Self::Branch { left, right, .. } => {
+                let before = left.metrics();
+                if offset <= before.bytes {
+                    left.breaks_before_byte(offset)
+                } else {
+                    before.line_breaks + right.breaks_before_byte(offset - before.bytes)
+                }
+            }
+        }
+    }
+
+    /// Number of line breaks before char `offset`
+    fn breaks_before_char(&self, offset: usize) -> usize {
+        match self {
+            Self::Leaf { text, .. } => text.chars().take(offset).filter(|&c| c == '\n').count(),
+            Self::Branch { left, right, .. } => {
+                let before = left.metrics();
+                if offset <= before.chars {
+                    left.breaks_before_char(offset)
+                } else {
+                    before.line_breaks + right.breaks_before_char(offset - before.chars)
+                }
+            }
+        }
+    }
+
+    /// Text in a byte range, borrowed when one chunk holds all of it
+    fn slice(&self, range: Range<usize>) -> Cow<'_, str> {
+        match self {
+            Self::Leaf { text, .. } => Cow::Borrowed(&text[range]),
+            Self::Branch { left, right, .. } => {
+                let split = left.metrics().bytes;
+                if range.end <= split {
+                    left.slice(range)
+                } else if range.start >= split {
+                    right.slice(range.start - split..range.end - split)
+                } else {
+                    let mut text = String::with_capacity(range.len());
+                    self.push_range(range, &mut text);
+                    Cow::Owned(text)
+                }
+            }
+        }
+    }
+
+    fn push_range(&self, range: Range<usize>, out: &mut String) {
+        if range.start >= range.end {
+            return;
+        }
+        match self {
+            Self::Leaf { text, .. } => out.push_str(&text[range]),
+            Self::Branch { left, right, .. } => {
+                let split = left.metrics().bytes;
+                left.push_range(range.start.min(split)..range.end.min(split), out);
+                right.push_range(
+                    range.start.saturating_sub(split)..range.end.saturating_sub(split),
+                    out,
+                );
+            }
+        }
+    }
+
+    fn chunks<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Self::Leaf { text, .. } => out.push(text),
+            Self::Branch { left, right, .. } => {
+                left.chunks(out);
+                right.chunks(out);
+            }
+        }
+    }
+}
+
+/// Insert `text` at byte `offset` below `node`
+fn insert(node: &mut Arc<Node>, offset: usize, text: &str) {
+    match Arc::make_mut(node) {
+        Node::Leaf {
+            text: chunk,
+            metrics,
+        } => {
+            if chunk.len() + text.len() <= MAX_CHUNK {
+                chunk.insert_str(offset, text);
+                *metrics = metrics.add(Metrics::of(text));
+            } else if text.len() <= MAX_CHUNK {
+                // Split in half so that typing on doesn't split again at once
+                let mut right = chunk.split_off(offset);
+                chunk.push_str(text);
+                chunk.push_str(&right);
+                let mut half = chunk.len() / 2;
+                while !chunk.is_char_boundary(half) {
+                    half -= 1;
+                }
+                right = chunk.split_off(half);
+                let left = std::mem::take(chunk);
+                *node = Node::branch(Node::leaf(left), Node::leaf(right));
+            } else {
+                let joined = [&chunk[..offset], text, &chunk[offset..]].concat();
+                *node = Node::from_text(&joined);
+            }
+        }
+        Node::Branch { left, right, .. } => {
+            let split = left.metrics().bytes;
+            if offset <= split {
+                insert(left, offset, text);
+            } else {
+                insert(right, offset - split, text);
+            }
+            rebalance(node);
+        }
+    }
+}
+
+/// Remove the text in a byte range below `node`
+fn remove(node: &mut Arc<Node>, range: Range<usize>) {
+    if range.start >= range.end {
+        return;
+    }
+    if range.len() == node.metrics().bytes {
+        *node = Node::leaf(String::new());
+        return;
+    }
+    match Arc::make_mut(node) {
+        Node::Leaf {
+            text: chunk,
+            metrics,
+        } => {
+            *metrics = metrics.sub(Metrics::of(&chunk[range.clone()]));
+            chunk.replace_range(range, "");
+        }
+        Node::Branch { left, right, .. } => {
+            let split = left.metrics().bytes;
+            remove(left, range.start.min(split)..range.end.min(split));
+            remove(
+                right,
+                range.start.saturating_sub(split)..range.end.saturating_sub(split),
+            );
+            rebalance(node);
+        }
+    }
+}
+
+/// Restore the cached metrics and balance of a branch whose children changed
+fn rebalance(node: &mut Arc<Node>) {
+    if let Node::Branch {
+        left,
+        right,
+        metrics,
+        height,
+    } = Arc::make_mut(node)
+    {
+        let empty = left.metrics().bytes == 0 || right.metrics().bytes == 0;
+        if !empty && left.height().abs_diff(right.height()) <= 1 {
+            *metrics = left.metrics().add(right.metrics());
+            *height = left.height().max(right.height()) + 1;
+            return;
+        }
+    }
+    let (left, right) = node.children();
+    *node = join(left, right);
+}
+
+/// Concatenate two balanced trees into one
+///
+/// Takes time in the difference of their heights.  Small neighbouring
+/// leaves are merged so that typing doesn't leave a trail of tiny chunks.
+fn join(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+    let (left_bytes, right_bytes) = (left.metrics().bytes, right.metrics().bytes);
+    if left_bytes == 0 {
+        return right;
+    }
+    if right_bytes == 0 {
+        return left;
+    }
+    if left.height() > right.height() + 1 {
+        return join_right(left, right);
+    }
+    if right.height() > left.height() + 1 {
+        return join_left(left, right);
+    }
+    if let (Node::Leaf { text: a, .. }, Node::Leaf { text: b, .. }) = (&*left, &*right) {
+        if left_bytes + right_bytes <= MAX_CHUNK {
+            return Node::leaf([a.as_str(), b.as_str()].concat());
+        }
+    }
+    Node::branch(left, right)
+}
+
+/// Join `right` onto the right spine of the taller `left`
+fn join_right(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+    let (outer, inner) = left.children();
+    let joined = if inner.height() <= right.height() + 1 {
+        join(inner, right)
+    } else {
+        join_right(inner, right)
+    };
+    if joined.height() <= outer.height() + 1 {
+        return Node::branch(outer, joined);
+    }
+    // The joined side grew two taller than its sibling
+    let (middle, far) = joined.children();
+    if middle.height() > far.height() {
+        let (a, b) = middle.children();
+        Node::branch(Node::branch(outer, a), Node::branch(b, far))
+    } else {
+        Node::branch(Node::branch(outer, middle), far)
+    }
+}
+
+/// Join `left` onto the left spine of the taller `right`
+fn join_left(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+    let (inner, outer) = right.children();
+    let joined = if inner.height() <= left.height() + 1 {
+        join(left, inner)
+    } else {
+        join_left(left, inner)
+    };
+    if joined.height() <= outer.height() + 1 {
+        return Node::branch(joined, outer);
+    }
+    let (far, middle) = joined.children();
+    if middle.height() > far.height() {
+        let (a, b) = middle.children();
+        Node::branch(Node::branch(far, a), Node::branch(b, outer))
+    } else {
+        Node::branch(far, Node::branch(middle, outer))
+    }
+}
+
+impl TextBuffer {
+    /// Create an empty buffer holding a single empty line
+    pub fn new() -> Self {
+        Self {
+            root: Node::leaf(String::new()),
+        }
+    }
+
+    /// Create a buffer from lines that contain no line breaks
+    pub fn from_lines(lines: Vec<String>) -> Self {
+        Self {
+            root: Node::from_text(&lines.join("\n")),
+        }
+    }
+
+    /// Number of lines (always at least one)
+    pub fn line_count(&self) -> usize {
+        self.root.metrics().line_breaks + 1
+    }
+
+    /// Size of the text before line `idx`, which must exist
+    fn line_start(&self, idx: usize) -> Metrics {
+        if idx == 0 {
+            Metrics::default()
+        } else {
+            self.root.line_start(idx)
+        }
+    }
+
+    /// Byte range of a line without its line break
+    fn line_bytes(&self, idx: usize) -> Option<Range<usize>> {
+        if idx >= self.line_count() {
+            return None;
+        }
+        let start = self.line_start(idx).bytes;
+        let end = if idx + 1 < self.line_count() {
+            self.line_start(idx + 1).bytes - 1
+        } else {
+            self.root.metrics().bytes
+        };
+        Some(start..end)
+    }
+
+    /// Get a line by index
+    pub fn line(&self, idx: usize) -> Option<Cow<'_, str>> {
+        Some(self.root.slice(self.line_bytes(idx)?))
+    }
+
+    /// Get a line that must exist
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no line `idx`.
+    pub fn line_text(&self, idx: usize) -> Cow<'_, str> {
+        match self.line(idx) {
+            Some(line) => line,
+            None => panic!("line {} out of range of {}", idx, self.line_count()),
+        }
+    }
+
+    /// Iterate over all lines
+    pub fn lines(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        (0..self.line_count()).map(move |idx| self.line_text(idx))
+    }
+
+    /// Length of a line in chars
+    pub fn line_chars(&self, idx: usize) -> usize {
+        if idx >= self.line_count() {
+            return 0;
+        }
+        let start = self.line_start(idx).chars;
+        if idx + 1 < self.line_count() {
+            self.line_start(idx + 1).chars - 1 - start
+        } else {
+            self.root.metrics().chars - start
+        }
+    }
+
+    /// Total length in chars, counting one for each line break
+    pub fn len_chars(&self) -> usize {
+        self.root.metrics().chars
+    }
+
+    /// Byte offset in the whole text at which a line starts
+    ///
+    /// Past the last line this is the length of the text plus one, as if
+    /// the text ended in a line break.
+    pub fn line_start_byte(&self, idx: usize) -> usize {
+        if idx < self.line_count() {
+            self.line_start(idx).bytes
+        } else {
+            self.root.metrics().bytes + 1
+        }
+    }
+
+    /// Char offset in the whole text at which a line starts, clamped to
+    /// the last line
+    pub fn line_start_char(&self, idx: usize) -> usize {
+        self.line_start(idx.min(self.line_count() - 1)).chars
+    }
+
+    /// Line holding byte `offset` of the whole text, or the last line
+    ///
+    /// The offset of a line break belongs to the line it ends.
+    pub fn line_at_byte(&self, offset: usize) -> usize {
+        self.root.breaks_before_byte(offset)
+    }
+
+    /// Line holding char `offset` of the whole text, or the last line
+    pub fn line_at_char(&self, offset: usize) -> usize {
+        self.root.breaks_before_char(offset)
+    }
+
+    /// Byte offset of a char column within a line, clamped to the line end
+    pub fn byte_of_column(&self, idx: usize, column: usize) -> usize {
+        let start = self.line_start(idx);
+        let column = column.min(self.line_chars(idx));
+        self.root.byte_of_char(start.chars + column) - start.bytes
+    }
+
+    /// Char column of a byte offset within a line, rounding up to a char boundary
+    pub fn column_of_byte(&self, idx: usize, byte: usize) -> usize {
+        let line = self.line_bytes(idx).unwrap_or(0..0);
+        let offset = (line.start + byte).min(line.end);
+        self.root.chars_before_byte(offset) - self.line_start(idx).chars
+    }
+
+    /// Byte offset in the whole text of a position
+    fn offset_of(&self, pos: CursorPosition) -> usize {
+        self.line_start(pos.line).bytes + self.byte_of_column(pos.line, pos.column)
+    }
+
+    /// Join all lines with '\n'
+    pub fn to_text(&self) -> String {
+        self.join("\n")
+    }
+
+    /// Join all lines with `separator`
+    pub fn join(&self, separator: &str) -> String {
+        let mut chunks = Vec::new();
+        self.root.chunks(&mut chunks);
+        let mut text = String::with_capacity(self.root.metrics().bytes);
+        for chunk in chunks {
+            if separator == "\n" {
+                text.push_str(chunk);
+            } else {
+                text.push_str(&chunk.replace('\n', separator));
+            }
+        }
+        text
+    }
+
+    /// Text between two positions, joined with '\n'
+    pub fn text_between(&self, start: CursorPosition, end: CursorPosition) -> String {
+        let range = self.offset_of(start)..self.offset_of(end);
+        self.root.slice(range).into_owned()
+    }
+
+    /// Replace the text between `start` and `end` with `text`
+    ///
+    /// Both positions must be valid and `start` must not come after `end`.
+    /// Returns the removed text.
+    pub fn replace_range(
+        &mut self,
+        start: CursorPosition,
+        end: CursorPosition,
+        text: &str,
+    ) -> String {
+        let range = self.offset_of(start)..self.offset_of(end);
+        let removed = self.root.slice(range.clone()).into_owned();
+        remove(&mut self.root, range.clone());
+        insert(&mut self.root, range.start, text);
+        removed
+    }
+}
+
+impl Default for TextBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: usize, column: usize) -> CursorPosition {
+        CursorPosition { line, column }
+    }
+
+    /// Check the height and cached metrics of every node
+    fn check_balanced(node: &Node) -> usize {
+        match node {
+            Node::Leaf { text, metrics } => {
+                assert_eq!(*metrics, Metrics::of(text));
+                assert!(text.len() <= MAX_CHUNK);
+                0
+            }
+            Node::Branch {
+                left,
+                right,
+                metrics,
+                height,
+            } => {
+                let (l, r) = (check_balanced(left), check_balanced(right));
+                assert!(l.abs_diff(r) <= 1, "unbalanced: {} vs {}", l, r);
+                assert_eq!(*height, l.max(r) + 1);
+                assert_eq!(*metrics, left.metrics().add(right.metrics()));
+                *height
+            }
+        }
+    }
+
+    #[test]
+    fn test_replace_across_lines() {
+        let mut buffer = TextBuffer::new();
+        buffer.replace_range(pos(0, 0), pos(0, 0), "one\ntwo\nthree");
+        assert_eq!(buffer.line_count(), 3);
+        assert_eq!(buffer.len_chars(), 13);
+
+        let removed = buffer.replace_range(pos(0, 1), pos(2, 2), "X\nY");
+        assert_eq!(removed, "ne\ntwo\nth");
+        assert_eq!(buffer.to_text(), "oX\nYree");
+        assert_eq!(buffer.line_chars(1), 4);
+        assert_eq!(buffer.len_chars(), 7);
+    }
+
+    #[test]
+    fn test_column_byte_conversions() {
+        let mut buffer = TextBuffer::new();
+        buffer.replace_range(pos(0, 0), pos(0, 0), "plain\nhéllo");
+        assert_eq!(buffer.byte_of_column(0, 3), 3);
+        assert_eq!(buffer.byte_of_column(1, 2), 3);
+        assert_eq!(buffer.column_of_byte(1, 3), 2);
+        assert_eq!(buffer.byte_of_column(1, 99), 6);
+        assert_eq!(buffer.line_chars(1), 5);
+    }
+
+    #[test]
+    fn test_lines_across_chunks() {
+        let long = "日本語テキスト".repeat(400);
+        let text = format!("first\n{}\n\nlast é", long);
+        let mut buffer = TextBuffer::from_lines(text.split('\n').map(String::from).collect());
+        check_balanced(&buffer.root);
+        assert_eq!(buffer.line_count(), 4);
+        assert_eq!(buffer.line(1).as_deref(), Some(long.as_str()));
+        assert_eq!(buffer.line(2).as_deref(), Some(""));
+        assert_eq!(buffer.line(4), None);
+        assert_eq!(buffer.line_chars(1), 2800);
+        assert_eq!(buffer.byte_of_column(1, 2000), 6000);
+        assert_eq!(buffer.column_of_byte(1, 6001), 2001);
+        assert_eq!(buffer.line_start_byte(2), 6 + long.len() + 1);
+        assert_eq!(buffer.line_start_byte(4), text.len() + 1);
+        assert_eq!(buffer.line_start_char(3), 6 + 2800 + 2);
+        assert_eq!(buffer.line_at_byte(6 + long.len()), 1);
+        assert_eq!(buffer.line_at_byte(6 + long.len() + 1), 2);
+        assert_eq!(buffer.line_at_char(6 + 2800 + 2), 3);
+        assert_eq!(buffer.line_at_char(usize::MAX), 3);
+        assert_eq!(buffer.lines().collect::<Vec<_>>().join("\n"), text);
+        assert_eq!(buffer.join("\r\n"), text.replace('\n', "\r\n"));
+
+        let removed = buffer.replace_range(pos(1, 5), pos(3, 2), "");
+        assert_eq!(removed, format!("{}\n\nla", &long[15..]));
+        assert_eq!(buffer.to_text(), "first\n日本語テキst é");
+        check_balanced(&buffer.root);
+    }
+
+    #[test]
+    fn test_typing_multibyte_into_a_long_line_is_logarithmic() {
+        let start = std::time::Instant::now();
+        let mut buffer = TextBuffer::new();
+        // Always type into the middle, the worst place for a flat string
+        for i in 0..1_000_000 {
+            let c = if i % 2 == 0 { "é" } else { "語" };
+            let column = buffer.line_chars(0) / 2;
+            buffer.replace_range(pos(0, column), pos(0, column), c);
+        }
+        assert_eq!(buffer.line_count(), 1);
+        assert_eq!(buffer.len_chars(), 1_000_000);
+        assert_eq!(buffer.byte_of_column(0, 1_000_000), 2_500_000);
+        let height = check_balanced(&buffer.root);
+        // 2.5 MB in chunks of at least a few hundred bytes
+        assert!(height < 40, "height {}", height);
+
+        for _ in 0..1000 {
+            buffer.replace_range(pos(0, 1000), pos(0, 1001), "");
+        }
+        assert_eq!(buffer.len_chars(), 999_000);
+        check_balanced(&buffer.root);
+        assert!(start.elapsed() < std::time::Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,589 @@
+//! Cargo subcommands, flags, and the packages, features and binaries of
+//! the workspace
+//!
+//! CargoCompleter finds the workspace by walking up from the working
+//! directory and reads the manifests itself, without running cargo.  Only
+//! the few keys completion needs are read, so the reader is a line scanner
+//! rather than a TOML parser.  Manifests are read again when they change.
+
+use crate::input::arg_complete::{argument, ArgCompleter, CompletionContext, FileCache};
+use crate::input::complete::CompletionInfo;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Cargo's own subcommands
+const CARGO_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("add", "Add dependencies to a manifest"),
+    ("bench", "Run the benchmarks"),
+    ("build", "Compile the current package"),
+    ("check", "Analyze the package for errors without building"),
+    ("clean", "Remove the target directory"),
+    ("clippy", "Check the package with clippy lints"),
+    ("doc", "Build the package's documentation"),
+    ("fetch", "Fetch dependencies from the network"),
+    ("fix", "Apply compiler suggestions automatically"),
+    ("fmt", "Format the package's sources"),
+    ("generate-lockfile", "Generate the lockfile"),
+    ("help", "Show help for a subcommand"),
+    ("init", "Create a package in an existing directory"),
+    ("install", "Install a Rust binary"),
+    ("locate-project", "Print the path of the manifest"),
+    ("login", "Log in to a registry"),
+    ("metadata", "Print the workspace's metadata as JSON"),
+    ("new", "Create a package"),
+    ("package", "Assemble the package into a crate file"),
+    ("publish", "Upload the package to a registry"),
+    ("remove", "Remove dependencies from a manifest"),
+    ("run", "Run a binary or example of the package"),
+    ("rustc", "Compile the package with extra compiler options"),
+    ("rustdoc", "Build documentation with extra rustdoc options"),
+    ("search", "Search the registry for crates"),
+    ("test", "Run the tests"),
+    ("tree", "Show the dependency graph"),
+    ("uninstall", "Remove an installed Rust binary"),
+    ("update", "Update dependencies in the lockfile"),
+    ("vendor", "Vendor all dependencies locally"),
+    ("version", "Show version information"),
+];
+
+/// Flags most subcommands take
+const CARGO_FLAGS: &[(&str, &str)] = &[
+    ("--all-features", "Activate all features"),
+    ("--all-targets", "Include all targets"),
+    ("--bin", "Only the given binary"),
+    ("--bins", "All binaries"),
+    ("--color", "When to use color"),
+    ("--example", "Only the given example"),
+    ("--examples", "All examples"),
+    ("--exclude", "Leave out a workspace member"),
+    ("--features", "Activate the given features"),
+    (
+        "--frozen",
+        "Require Cargo.lock and the cache to be up to date",
+    ),
+    ("--help", "Show help"),
+    ("--jobs", "Number of parallel jobs"),
+    ("--lib", "Only the library"),
+    ("--locked", "Require Cargo.lock to be up to date"),
+    ("--manifest-path", "Path to Cargo.toml"),
+    (
+        "--no-default-features",
+        "Don't activate the default features",
+    ),
+    ("--offline", "Run without accessing the network"),
+    ("--package", "Only the given package"),
+    ("--profile", "Build with the given profile"),
+    ("--quiet", "Print no output"),
+    ("--release", "Build with optimizations"),
+    ("--target", "Build for the given target triple"),
+    ("--target-dir", "Directory for generated artifacts"),
+    ("--tests", "All tests"),
+    ("--verbose", "Print more output"),
+    ("--workspace", "All packages in the workspace"),
+];
+
+/// Options whose value is the next word
+const VALUE_OPTIONS: &[&str] = &[
+    "--bin",
+    "--color",
+    "--config",
+    "--example",
+    "--exclude",
+    "--features",
+    "--jobs",
+    "--manifest-path",
+    "--package",
+    "--profile",
+    "--target",
+    "--target-dir",
+    "-C",
+    "-F",
+    "-j",
+    "-p",
+    "-Z",
+];
+
+/// What completion needs from a Cargo.toml
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Manifest {
+    /// Name of the package, None for a virtual manifest
+    name: Option<String>,
+    features: Vec<String>,
+    /// Binaries declared with [[bin]]
+    bins: Vec<String>,
+    /// Whether the manifest has a [workspace] table
+    is_workspace: bool,
+    /// Member paths of the workspace, which may end in a glob
+    members: Vec<String>,
+    exclude: Vec<String>,
+}
+
+/// Strip a `#` comment that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') => return &line[..i],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// The strings quoted in a TOML value, such as the items of an array
+fn quoted_strings(value: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' || c == '\'' {
+            let string: String = chars.by_ref().take_while(|&end| end != c).collect();
+            strings.push(string);
+        }
+    }
+    strings
+}
+
+/// Read the keys completion needs from the text of a Cargo.toml
+fn parse_manifest(text: &str) -> Manifest {
+    let mut manifest = Manifest::default();
+    let mut table = String::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let line = strip_comment(line).trim();
+        if line.starts_with('[') {
+            table = line
+                .trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_string();
+            manifest.is_workspace |= table == "workspace";
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().trim_matches(|c| c == '"' || c == '\''), value),
+            None => continue,
+        };
+        // An array may go on over several lines
+        let mut value = value.trim().to_string();
+        if value.starts_with('[') {
+            while value.matches('[').count() > value.matches(']').count() {
+                match lines.next() {
+                    Some(more) => value.push_str(strip_comment(more)),
+                    None => break,
+                }
+            }
+        }
+        let first = || quoted_strings(&value).into_iter().next();
+        match (table.as_str(), key) {
+            ("package", "name") => manifest.name = first(),
+            ("features", feature) => manifest.features.push(feature.to_string()),
+            ("bin", "name") => manifest.bins.extend(first()),
+            ("workspace", "members") => manifest.members = quoted_strings(&value),
+            ("workspace", "exclude") => manifest.exclude = quoted_strings(&value),
+            _ => {}
+        }
+    }
+    manifest
+}
+
+/// A package of the workspace
+#[derive(Debug, Clone)]
+struct Package {
+    dir: PathBuf,
+    manifest: Arc<Manifest>,
+}
+
+impl Package {
+    fn name(&self) -> &str {
+        self.manifest.name.as_deref().unwrap_or_default()
+    }
+
+    /// Binaries, declared or found where cargo looks for them
+    fn bins(&self) -> Vec<String> {
+        let mut bins = self.manifest.bins.clone();
+        let src = self.dir.join("src");
+        if src.join("main.rs").is_file() {
+            bins.push(self.name().to_string());
+        }
+        if let Ok(entries) = fs::read_dir(src.join("bin")) {
+            let mut found: Vec<String> = entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    let is_bin = path.extension().is_some_and(|ext| ext == "rs")
+                        || path.join("main.rs").is_file();
+                    let stem = path.file_stem()?.to_str()?.to_string();
+                    is_bin.then_some(stem)
+                })
+                .collect();
+            found.sort();
+            bins.extend(found);
+        }
+        let mut seen = HashSet::new();
+        bins.retain(|bin| seen.insert(bin.clone()));
+        bins
+    }
+}
+
+/// The packages of a workspace, and the one the shell is in
+#[derive(Debug, Clone)]
+struct Workspace {
+    packages: Vec<Package>,
+    /// Index of the package containing the working directory
+    current: Option<usize>,
+}
+
+/// Completes cargo subcommands, flags and their values
+#[derive(Debug, Default)]
+pub struct CargoCompleter {
+    /// Parsed manifests
+    cache: FileCache<Manifest>,
+}
+
+impl CargoCompleter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the Cargo.toml in `dir`, or reuse what it parsed to if
+    /// unchanged
+    fn load(&self, dir: &Path) -> Option<Arc<Manifest>> {
+        self.cache.get(&dir.join("Cargo.toml"), |path| {
+            Some(parse_manifest(&fs::read_to_string(path).ok()?))
+        })
+    }
+
+    /// Find the workspace containing `cwd`
+    ///
+    /// The root is the nearest manifest with a [workspace] table, or the
+    /// package's own manifest when there is none.
+    fn workspace(&self, cwd: &Path) -> Option<Workspace> {
+        let (nearest_dir, nearest) = cwd
+            .ancestors()
+            .find_map(|dir| Some((dir, self.load(dir)?)))?;
+        let (root_dir, root) = nearest_dir
+            .ancestors()
+            .find_map(|dir| {
+                let manifest = self.load(dir)?;
+                manifest.is_workspace.then_some((dir, manifest))
+            })
+            .unwrap_or((nearest_dir, Arc::clone(&nearest)));
+
+        let mut packages = Vec::new();
+        if root.name.is_some() {
+            packages.push(Package {
+                dir: root_dir.to_path_buf(),
+                manifest: Arc::clone(&root),
+            });
+        }
+        let excluded: Vec<PathBuf> = root.exclude.iter().map(|dir| root_dir.join(dir)).collect();
+        for dir in member_dirs(root_dir, &root.members) {
+            if excluded.contains(&dir) || packages.iter().any(|p| p.dir == dir) {
+                continue;
+            }
+            if let Some(manifest) = self.load(&dir).filter(|m| m.name.is_some()) {
+                packages.push(Package { dir, manifest });
+            }
+        }
+        let mut current = packages.iter().position(|p| p.dir == nearest_dir);
+        if current.is_none() && nearest.name.is_some() {
+            packages.push(Package {
+                dir: nearest_dir.to_path_buf(),
+                manifest: nearest,
+            });
+            current = Some(packages.len() - 1);
+        }
+        Some(Workspace { packages, current })
+    }
+}
+
+/// Directories of the workspace members, with globs such as `crates/*`
+/// expanded
+fn member_dirs(root: &Path, members: &[String]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for member in members {
+        let (parent, last) = match member.rsplit_once('/') {
+            Some((parent, last)) => (root.join(parent), last),
+            None => (root.to_path_buf(), member.as_str()),
+        };
+        let prefix = match last.strip_suffix('*') {
+            Some(prefix) if !prefix.contains('*') => prefix,
+            _ => {
+                dirs.push(root.join(member));
+                continue;
+            }
+        };
+        let mut found: Vec<PathBuf> = fs::read_dir(&parent)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .map(|entry| entry.path())
+            .filter(|dir| dir.join("Cargo.toml").is_file())
+            .collect();
+        found.sort();
+        dirs.extend(found);
+    }
+    dirs
+}
+
+/// The subcommand in `args`, skipping options and their values
+fn subcommand<'a>(args: &[&'a str]) -> Option<&'a str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(arg) {
+            args.next();
+        } else if !arg.starts_with('-') && !arg.starts_with('+') {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+/// Values given for any of `options` in `args`
+fn option_values<'a>(args: &[&'a str], options: &[&str]) -> Vec<&'a str> {
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if options.contains(arg) {
+            values.extend(args.next());
+        } else if let Some((option, value)) = arg.split_once('=') {
+            if options.contains(&option) {
+                values.push(value);
+            }
+        }
+    }
+    values
+}
+
+impl ArgCompleter for CargoCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        // The option being given a value, either the previous word or
+        // before "=" in this one
+        let (option, lead, typed) = match ctx.word.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                (Some(option), &ctx.word[..=option.len()], value)
+            }
+            _ => (ctx.previous_arg(), "", ctx.word),
+        };
+        let option = option.filter(|option| VALUE_OPTIONS.contains(option));
+
+        let items: Vec<(String, String)> = match option {
+            Some("--bin") => self.bins(ctx),
+            Some("--features" | "-F") => return Some(self.features(ctx, lead, typed)),
+            Some("--package" | "-p" | "--exclude") => self.package_names(ctx),
+            Some(_) => return None,
+            None if ctx.word.starts_with('-') => table(CARGO_FLAGS),
+            None if subcommand(&ctx.args).is_none() => table(CARGO_SUBCOMMANDS),
+            None => return None,
+        };
+        let items = items
+            .iter()
+            .filter(|(text, _)| text.starts_with(typed))
+            .map(|(text, description)| {
+                argument(&format!("{}{}", lead, text), description, ctx.word)
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+/// Names and descriptions of a static table
+fn table(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+    entries
+        .iter()
+        .map(|(name, description)| (name.to_string(), description.to_string()))
+        .collect()
+}
+
+impl CargoCompleter {
+    /// Packages picked with -p, else the one the shell is in, else all
+    fn selected(&self, ctx: &CompletionContext) -> Vec<Package> {
+        let workspace = match self.workspace(&ctx.cwd) {
+            Some(workspace) => workspace,
+            None => return Vec::new(),
+        };
+        let picked = option_values(&ctx.args, &["-p", "--package"]);
+        if !picked.is_empty() {
+            return workspace
+                .packages
+                .into_iter()
+                .filter(|p| picked.contains(&p.name()))
+                .collect();
+        }
+        match workspace.current {
+            Some(current) => vec![workspace.packages[current].clone()],
+            None => workspace.packages,
+        }
+    }
+
+    fn bins(&self, ctx: &CompletionContext) -> Vec<(String, String)> {
+        self.selected(ctx)
+            .iter()
+            .flat_map(|package| {
+                let description = format!("binary of {}", package.name());
+                package
+                    .bins()
+                    .into_iter()
+                    .map(move |bin| (bin, description.clone()))
+            })
+            .collect()
+    }
+
+    fn package_names(&self, ctx: &CompletionContext) -> Vec<(String, String)> {
+        let packages = self
+            .workspace(&ctx.cwd)
+            .map(|workspace| workspace.packages)
+            .unwrap_or_default();
+        packages
+            .iter()
+            .map(|package| (package.name().to_string(), "package".to_string()))
+            .collect()
+    }
+
+    /// Features for a comma-separated --features list, completing the
+    /// last one
+    ///
+    /// Across several packages features are qualified, as in `pkg/feat`.
+    fn features(&self, ctx: &CompletionContext, lead: &str, typed: &str) -> Vec<CompletionInfo> {
+        let (given, last) = match typed.rfind(',') {
+            Some(comma) => typed.split_at(comma + 1),
+            None => ("", typed),
+        };
+        let packages = self.selected(ctx);
+        let qualify = packages.len() > 1;
+        let mut items = Vec::new();
+        for package in &packages {
+            let description = format!("feature of {}", package.name());
+            for feature in &package.manifest.features {
+                let feature = if qualify {
+                    format!("{}/{}", package.name(), feature)
+                } else {
+                    feature.clone()
+                };
+                if feature.starts_with(last) {
+                    let text = format!("{}{}{}", lead, given, feature);
+                    items.push(argument(&text, &description, ctx.word));
+                }
+            }
+        }
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const WORKSPACE_TOML: &str = include_str!("testdata/cargo/workspace.toml");
+    const CORE_TOML: &str = include_str!("testdata/cargo/core.toml");
+    const CLI_TOML: &str = include_str!("testdata/cargo/cli.toml");
+
+    /// A workspace with core/ and tools/cli/, plus an excluded scratch
+    /// package
+    fn workspace() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let write = |dir: &str, manifest: &str| {
+            let dir = root.path().join(dir);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        };
+        write(".", WORKSPACE_TOML);
+        write("core", CORE_TOML);
+        write("tools/cli", CLI_TOML);
+        write("tools/scratch", "[package]\nname = \"scratch\"\n");
+        fs::write(root.path().join("core/src/main.rs"), "fn main() {}\n").unwrap();
+        root
+    }
+
+    fn complete(cwd: &Path, args: &[&str], word: &str) -> Option<Vec<String>> {
+        let ctx = CompletionContext {
+            command: "cargo",
+            args: args.to_vec(),
+            word,
+            cwd: cwd.to_path_buf(),
+            env: HashMap::new(),
+            sudo: false,
+        };
+        let items = CargoCompleter::new().complete(&ctx)?;
+        Some(items.into_iter().map(|item| item.text).collect())
+    }
+
+    #[test]
+    fn test_parse_manifests() {
+        let cli = parse_manifest(CLI_TOML);
+        assert_eq!(cli.name.as_deref(), Some("demo-cli"));
+        assert_eq!(cli.features, ["default", "color", "json-output", "tracing"]);
+        assert_eq!(cli.bins, ["demo", "demo-admin"]);
+        assert!(!cli.is_workspace);
+
+        let root = parse_manifest(WORKSPACE_TOML);
+        assert_eq!(root.name, None);
+        assert!(root.is_workspace);
+        assert_eq!(root.members, ["core", "tools/*"]);
+        assert_eq!(root.exclude, ["tools/scratch"]);
+        assert!(root.features.is_empty());
+    }
+
+    #[test]
+    fn test_subcommands_and_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            complete(dir.path(), &[], "ru").unwrap(),
+            ["run", "rustc", "rustdoc"]
+        );
+        assert_eq!(
+            complete(dir.path(), &["+nightly", "build"], "--rel").unwrap(),
+            ["--release"]
+        );
+        // Past the subcommand, arguments are paths
+        assert!(complete(dir.path(), &["test"], "").is_none());
+        assert!(complete(dir.path(), &["build", "--target-dir"], "").is_none());
+    }
+
+    #[test]
+    fn test_workspace_values() {
+        let root = workspace();
+        let cli = root.path().join("tools").join("cli");
+        let src = cli.join("src");
+
+        assert_eq!(
+            complete(root.path(), &["build", "-p"], "").unwrap(),
+            ["demo-core", "demo-cli"]
+        );
+        // In a member, its own binaries and features
+        assert_eq!(
+            complete(&src, &["run", "--bin"], "").unwrap(),
+            ["demo", "demo-admin"]
+        );
+        assert_eq!(
+            complete(&cli, &["build", "--features"], "").unwrap(),
+            ["default", "color", "json-output", "tracing"]
+        );
+        assert_eq!(
+            complete(&cli, &["build", "--features"], "color,j").unwrap(),
+            ["color,json-output"]
+        );
+        assert_eq!(
+            complete(&cli, &["build"], "--features=t").unwrap(),
+            ["--features=tracing"]
+        );
+        // At the root, every member's, qualified when it matters
+        assert_eq!(
+            complete(root.path(), &["run", "--bin"], "").unwrap(),
+            ["demo-core", "demo", "demo-admin"]
+        );
+        assert_eq!(
+            complete(root.path(), &["build", "-F"], "demo-cli/c").unwrap(),
+            ["demo-cli/color"]
+        );
+        assert_eq!(
+            complete(root.path(), &["build", "-p", "demo-cli", "--features"], "j").unwrap(),
+            ["json-output"]
+        );
+    }
+}
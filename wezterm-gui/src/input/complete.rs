@@ -6,24 +6,735 @@
 //! - History-based suggestions
 //! - Shell builtins
 
-use std::collections::HashSet;
+use crate::input::arg_complete::{
+    ArgCompleter, CompletionContext, CompletionSource, GitCompleter, ProcessCompleter,
+};
+use crate::input::bash_bridge::BashCompleterBridge;
+use crate::input::cargo_complete::CargoCompleter;
+use crate::input::dir_cache::{self, DirCache, FileSystem};
+use crate::input::environment::{EnvironmentSource, ProcessEnvironment};
+use crate::input::frecency::FrecencyStore;
+use crate::input::glob::{glob_match, has_glob_meta};
+use crate::input::help_flags::HelpFlagCompleter;
+use crate::input::make_complete::MakeCompleter;
+use crate::input::npm_complete::NpmCompleter;
+use crate::input::spec::CompletionSpecLoader;
+use crate::input::ssh_complete::SshCompleter;
+use crate::input::systemd_complete::SystemdCompleter;
+use crate::input::users::{HomeDirs, SystemHomeDirs};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use std::{env, fs};
 
-/// Maximum number of completions to return
-const MAX_COMPLETIONS: usize = 20;
+/// Completions returned at once unless configured otherwise
+const DEFAULT_MAX_RESULTS: usize = 20;
+
+/// Extensions that make a file a command on Windows when PATHEXT isn't set
+const DEFAULT_PATHEXT: &[&str] = &[".com", ".exe", ".bat", ".cmd", ".ps1"];
+
+/// Commands whose arguments are directories
+const DIRECTORY_COMMANDS: &[&str] = &["cd", "pushd", "rmdir"];
+
+/// Commands that look for relative directories in $CDPATH too
+const CDPATH_COMMANDS: &[&str] = &["cd", "pushd"];
+
+/// Minimum time between checks of PATH for changes
+const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Score added to candidates that start with the typed word, so they rank
+/// above fuzzy hits
+const PREFIX_MATCH_BONUS: i32 = 1000;
+
+/// Most aliases expanded in a row, so that recursive ones end
+const MAX_ALIAS_DEPTH: usize = 16;
+
+/// Number of history entries after which a use counts half as much
+const HISTORY_HALF_LIFE: f64 = 50.0;
+
+/// Deepest a `**` in a glob looks below its directory
+const MAX_GLOB_DEPTH: usize = 8;
+
+/// Characters besides whitespace that start a new word unless configured
+/// otherwise
+///
+/// Redirections count, so `>out` completes `out`, and so do the parentheses
+/// of `$(cmd` and `<(cmd`.  `=` only ends the name of an assignment, so
+/// `FOO=/us` completes `/us` while `--color=al` stays one word.
+pub const DEFAULT_WORD_BREAKS: &str = "|;&<>()=";
+
+/// Check whether `c` separates the word being completed from what's before it
+///
+/// Uses the default breaks, without `=`, which depends on the word before it;
+/// see word_start.
+pub fn is_word_break(c: char) -> bool {
+    c != '=' && breaks_word(c, DEFAULT_WORD_BREAKS)
+}
+
+/// Whether `c` is whitespace or one of `breaks`
+fn breaks_word(c: char, breaks: &str) -> bool {
+    c.is_whitespace() || breaks.contains(c)
+}
+
+/// Byte offset at which the word ending at the end of `text` starts
+///
+/// Breaks inside quotes or escaped with a backslash don't count, so an
+/// unterminated quote starts the word.
+pub fn word_start(text: &str) -> usize {
+    scan_quotes(text, DEFAULT_WORD_BREAKS).1
+}
+
+/// Walk `text` as the shell would, returning the quoting in effect at its
+/// end and where its last word starts, a word ending at any of `breaks`
+fn scan_quotes(text: &str, breaks: &str) -> (QuotingContext, usize) {
+    let mut context = QuotingContext::None;
+    let mut start = 0;
+    let mut chars = text.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        context = match (context, c) {
+            (QuotingContext::SingleQuoted, '\'') | (QuotingContext::DoubleQuoted, '"') => {
+                QuotingContext::None
+            }
+            (QuotingContext::SingleQuoted, _) => context,
+            (_, '\\') => {
+                chars.next();
+                context
+            }
+            (QuotingContext::None, '\'') => QuotingContext::SingleQuoted,
+            (QuotingContext::None, '"') => QuotingContext::DoubleQuoted,
+            (QuotingContext::None, c) if breaks_word(c, breaks) => {
+                if c != '=' || is_name(&text[start..idx]) {
+                    start = idx + c.len_utf8();
+                }
+                context
+            }
+            _ => context,
+        };
+    }
+    (context, start)
+}
+
+/// Length of the rest of the word after the cursor, given the text
+/// `after` it and the quoting in effect at the cursor
+///
+/// The word ends at one of `breaks` or a quote; a closing quote stays out
+/// of it.
+fn word_end(after: &str, quoting: QuotingContext, breaks: &str) -> usize {
+    let mut chars = after.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match (quoting, c) {
+            (QuotingContext::SingleQuoted, '\'') => return idx,
+            (QuotingContext::SingleQuoted, _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (QuotingContext::DoubleQuoted, '"') => return idx,
+            (QuotingContext::None, '\'' | '"') => return idx,
+            (QuotingContext::None, c) if breaks_word(c, breaks) => return idx,
+            _ => {}
+        }
+    }
+    after.len()
+}
+
+/// Length of the rest of a variable name after the cursor, with the
+/// closing brace of `${NAME}`
+fn variable_end(after: &str, braced: bool) -> usize {
+    let len = after
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(after.len());
+    if braced && after[len..].starts_with('}') {
+        len + 1
+    } else {
+        len
+    }
+}
+
+/// Check if `c` separates path components; Windows also takes backslashes
+fn is_path_separator(c: char, windows: bool) -> bool {
+    c == '/' || (windows && c == '\\')
+}
+
+/// Split a typed path into its directory part, as typed, and the name
+/// being typed
+///
+/// On Windows a drive letter ("C:") or UNC prefix ("\\\\server\\") is part of
+/// the directory.
+fn split_path(prefix: &str, windows: bool) -> (&str, &str) {
+    let split = match prefix.rfind(|c| is_path_separator(c, windows)) {
+        Some(idx) => idx + 1,
+        None if windows && has_drive_letter(prefix) => 2,
+        None => 0,
+    };
+    prefix.split_at(split)
+}
+
+/// Check if `path` starts with a drive letter, as in "C:"
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Separator to put after a completed directory, matching what was typed
+fn dir_separator(dir_part: &str, windows: bool) -> char {
+    if !windows || (dir_part.contains('/') && !dir_part.contains('\\')) {
+        '/'
+    } else {
+        '\\'
+    }
+}
+
+/// Extensions from PATHEXT, lowercased, or the usual ones if it isn't set
+fn pathext() -> Vec<String> {
+    match env::var("PATHEXT") {
+        Ok(exts) if !exts.trim().is_empty() => exts
+            .split(';')
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| ext.to_ascii_lowercase())
+            .collect(),
+        _ => DEFAULT_PATHEXT.iter().map(|ext| ext.to_string()).collect(),
+    }
+}
+
+/// Command name for `file_name` if its extension is one of `pathext`
+///
+/// The extension is matched ignoring case and dropped if `strip` is set.
+fn pathext_command_name(file_name: &str, pathext: &[String], strip: bool) -> Option<String> {
+    let dot = file_name.rfind('.').filter(|&dot| dot > 0)?;
+    let ext = file_name[dot..].to_ascii_lowercase();
+    if !pathext.contains(&ext) {
+        return None;
+    }
+    Some(if strip { &file_name[..dot] } else { file_name }.to_string())
+}
+
+/// The command and arguments before the word at byte `word_start` of
+/// `text`, back to the last pipe or separator
+///
+/// The `&` of redirections such as `&>` and `2>&1` isn't a separator, and
+/// the `(` of `$(cmd` and `<(cmd` starts a command of its own.
+fn command_words(text: &str, word_start: usize) -> std::str::SplitWhitespace<'_> {
+    let before = &text[..word_start];
+    let bytes = before.as_bytes();
+    let segment = (0..bytes.len())
+        .rev()
+        .find(|&i| match bytes[i] {
+            b'|' | b';' | b'(' => true,
+            b'&' => {
+                let redirect = |j: Option<usize>| {
+                    j.and_then(|j| bytes.get(j))
+                        .is_some_and(|b| matches!(b, b'<' | b'>'))
+                };
+                !redirect(i.checked_sub(1)) && !redirect(Some(i + 1))
+            }
+            _ => false,
+        })
+        .map_or(0, |i| i + 1);
+    before[segment..].split_whitespace()
+}
+
+/// If `word` is a redirection, whether its target is in the same word
+///
+/// `>`, `2>>` and `&>` take the next word as their target; `>out`,
+/// `<in` and `2>&1` carry it.
+fn redirection(word: &str) -> Option<bool> {
+    let rest = word.trim_start_matches(|c: char| c.is_ascii_digit());
+    let rest = if rest.len() == word.len() {
+        rest.strip_prefix('&').unwrap_or(rest)
+    } else {
+        rest
+    };
+    if !rest.starts_with(['<', '>']) {
+        return None;
+    }
+    Some(!rest.trim_start_matches(['<', '>', '&', '|']).is_empty())
+}
+
+/// Whether the word at byte `word_start` of `text` is the target of a
+/// redirection, as in `> out` or `>out`
+fn is_redirection_target(text: &str, word_start: usize) -> bool {
+    let before = text[..word_start].trim_end();
+    before.ends_with(['<', '>']) && !before[..before.len() - 1].ends_with('\\')
+}
+
+/// Commands that run the command after them, with their options that take
+/// a value
+const COMMAND_PREFIXES: &[(&str, &[&str])] = &[
+    (
+        "sudo",
+        &["-u", "-g", "-C", "-D", "-p", "-r", "-t", "-T", "-U"],
+    ),
+    ("doas", &["-u", "-C"]),
+    ("env", &["-u", "-C", "-S"]),
+    ("nice", &["-n"]),
+    ("nohup", &[]),
+    ("time", &["-f", "-o"]),
+    ("command", &[]),
+    ("builtin", &[]),
+    ("exec", &["-a"]),
+    ("stdbuf", &["-i", "-o", "-e"]),
+    ("xargs", &["-a", "-d", "-E", "-I", "-L", "-n", "-P", "-s"]),
+];
+
+/// Whether `word` is a `NAME=value` assignment
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| is_name(name))
+}
+
+/// Whether `name` can name a shell variable
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Byte offset of a `$NAME` or `${NAME` being typed at the end of `word`
+///
+/// Not in single quotes, where variables aren't expanded, nor after a
+/// backslash.
+fn variable_start(word: &str, quoting: QuotingContext) -> Option<usize> {
+    if quoting == QuotingContext::SingleQuoted {
+        return None;
+    }
+    let dollar = word.rfind('$')?;
+    if word[..dollar].ends_with('\\') {
+        return None;
+    }
+    let name = &word[dollar + 1..];
+    let name = name.strip_prefix('{').unwrap_or(name);
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        .then_some(dollar)
+}
+
+/// Substitute the `$NAME` and `${NAME}` variables in typed `text` from
+/// `environment`, escaping their values for `quoting`
+///
+/// Unknown variables and escaped dollars are left alone, and so is all of
+/// `text` in single quotes.
+fn expand_variables(
+    text: &str,
+    quoting: QuotingContext,
+    environment: &dyn EnvironmentSource,
+) -> String {
+    if quoting == QuotingContext::SingleQuoted {
+        return text.to_string();
+    }
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find(['$', '\\']) {
+        out.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        let len = if rest[idx..].starts_with('\\') {
+            // The escape stays, with what it escapes, for unquote
+            after.chars().next().map_or(0, char::len_utf8)
+        } else {
+            let (name, len) = match after.strip_prefix('{') {
+                Some(braced) => braced
+                    .find('}')
+                    .map_or(("", 0), |end| (&braced[..end], end + 2)),
+                None => {
+                    let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            };
+            let value = Some(name)
+                .filter(|name| !name.is_empty() && name.chars().all(is_name))
+                .and_then(|name| environment.get(name));
+            if let Some(value) = value {
+                out.push_str(&quoting.quote(&value));
+                rest = &after[len..];
+                continue;
+            }
+            len
+        };
+        out.push_str(&rest[idx..idx + 1 + len]);
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Clamp byte offset `idx` to `text`, backing up to the start of the char
+/// it falls inside
+fn floor_char_boundary(text: &str, idx: usize) -> usize {
+    let mut idx = idx.min(text.len());
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// The kind of quotes the cursor is inside, which decides how completed
+/// file names are escaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotingContext {
+    /// Not in quotes; special characters are backslash-escaped
+    #[default]
+    None,
+    /// Inside '...'; only a single quote needs escaping, as '\''
+    SingleQuoted,
+    /// Inside "..."; only $, `, " and \ need a backslash
+    DoubleQuoted,
+}
+
+impl QuotingContext {
+    /// Find the quoting in effect at the end of `text`
+    pub fn detect(text: &str) -> Self {
+        scan_quotes(text, DEFAULT_WORD_BREAKS).0
+    }
+
+    /// The quote that opened this context
+    fn quote_char(self) -> Option<char> {
+        match self {
+            Self::None => None,
+            Self::SingleQuoted => Some('\''),
+            Self::DoubleQuoted => Some('"'),
+        }
+    }
+
+    /// Check if `c` must be escaped in this context
+    fn is_special(self, c: char) -> bool {
+        match self {
+            Self::None => matches!(
+                c,
+                ' ' | '\t'
+                    | '\''
+                    | '"'
+                    | '\\'
+                    | '$'
+                    | '`'
+                    | '&'
+                    | ';'
+                    | '|'
+                    | '<'
+                    | '>'
+                    | '('
+                    | ')'
+                    | '*'
+                    | '?'
+                    | '['
+                    | ']'
+            ),
+            Self::SingleQuoted => c == '\'',
+            Self::DoubleQuoted => matches!(c, '$' | '`' | '"' | '\\'),
+        }
+    }
+
+    /// Append `c` to `out`, escaped for this context
+    fn quote_char_into(self, c: char, out: &mut String) {
+        if !self.is_special(c) {
+            out.push(c);
+        } else if self == Self::SingleQuoted {
+            // Close the quotes, add an escaped quote, and reopen them
+            out.push_str("'\\''");
+        } else {
+            out.push('\\');
+            out.push(c);
+        }
+    }
+
+    /// Escape `text` for this context
+    pub fn quote(self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            self.quote_char_into(c, &mut out);
+        }
+        out
+    }
+
+    /// Remove the backslash escapes from typed `text`
+    pub fn unquote(self, text: &str) -> String {
+        if self == Self::SingleQuoted {
+            return text.to_string();
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match chars.peek() {
+                Some(&next) if c == '\\' && (self == Self::None || self.is_special(next)) => {
+                    out.push(next);
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+/// How the typed word has to match a candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchMode {
+    /// The candidate starts with the word
+    #[default]
+    Prefix,
+    /// The word appears anywhere in the candidate
+    Substring,
+    /// The word's chars appear in order, as in fzf
+    Fuzzy,
+}
+
+/// When file names starting with a dot are offered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShowHidden {
+    /// Only when the typed name starts with a dot, as shells do
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ShowHidden {
+    /// Whether the entry `name` may be offered for the typed `file_prefix`
+    ///
+    /// "." and ".." are only offered when typed out in full.
+    fn allows(self, name: &str, file_prefix: &str) -> bool {
+        if !name.starts_with('.') {
+            return true;
+        }
+        if name == "." || name == ".." {
+            return name == file_prefix;
+        }
+        match self {
+            ShowHidden::Auto => file_prefix.starts_with('.'),
+            ShowHidden::Always => true,
+            ShowHidden::Never => false,
+        }
+    }
+}
+
+/// Settings of a completer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompleterConfig {
+    /// Most completions returned at once; complete_page fetches the rest
+    pub max_results: usize,
+    /// How long a cached directory listing is used while the directory's
+    /// mtime stays the same
+    pub dir_cache_ttl: Duration,
+    /// Directory listings cached; 0 reads directories every time
+    pub dir_cache_capacity: usize,
+    /// What completions replace when the cursor is inside a word
+    pub mid_word: MidWord,
+    /// Characters besides whitespace that start a new word
+    pub word_breaks: String,
+}
+
+impl Default for CompleterConfig {
+    fn default() -> Self {
+        Self {
+            max_results: DEFAULT_MAX_RESULTS,
+            dir_cache_ttl: dir_cache::DEFAULT_TTL,
+            dir_cache_capacity: dir_cache::DEFAULT_CAPACITY,
+            mid_word: MidWord::default(),
+            word_breaks: DEFAULT_WORD_BREAKS.to_string(),
+        }
+    }
+}
+
+/// What a completion replaces when the cursor is inside a word
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MidWord {
+    /// The whole word, the part after the cursor included
+    #[default]
+    ReplaceToken,
+    /// Only the part before the cursor, keeping the rest after it
+    InsertAtCursor,
+}
+
+/// What completing a word with glob characters offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GlobExpansion {
+    /// Each matching path, to pick one from
+    #[default]
+    Each,
+    /// All matching paths as one candidate, as the shell would expand them
+    All,
+}
+
+/// Parse the aliases printed by the shell's `alias` command
+///
+/// Takes bash's `alias k='kubectl'` lines as well as zsh's `k=kubectl`,
+/// mapping each name to its expansion with the shell quoting removed.
+pub fn parse_alias_output(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let line = line.strip_prefix("alias ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            let value = shlex::split(value)?.join(" ");
+            Some((name.to_string(), value))
+        })
+        .collect()
+}
+
+/// A shell alias
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Alias {
+    /// What the alias stands for, as the shell shows it
+    expansion: String,
+    /// The expansion split into words
+    words: Vec<String>,
+}
+
+/// What history completion offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistoryMatch {
+    /// Previous command lines starting with what's typed
+    #[default]
+    WholeCommand,
+    /// Single words from previous commands
+    Words,
+}
+
+/// A command found on PATH
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathCommand {
+    /// Name to complete, without a PATHEXT extension if those are stripped
+    name: String,
+    /// The file that runs, from the first PATH directory that has it
+    path: PathBuf,
+}
+
+/// PATH and the modification times of its directories, as of a scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathSnapshot {
+    path: OsString,
+    mtimes: Vec<Option<SystemTime>>,
+}
+
+impl PathSnapshot {
+    /// Stat the directories of `path`
+    fn take(path: OsString) -> Self {
+        let mtimes = env::split_paths(&path)
+            .map(|dir| fs::metadata(dir).and_then(|m| m.modified()).ok())
+            .collect();
+        Self { path, mtimes }
+    }
+}
 
 /// Completer for commands and paths
+///
+/// Commands come from a cache of PATH that is filled on a background
+/// thread; completion never scans PATH itself.  Clones share the cache.
 #[derive(Debug, Clone)]
 pub struct Completer {
-    /// Cached commands from PATH
-    path_commands: Vec<String>,
+    /// Cached commands from PATH, swapped in when a scan finishes
+    path_commands: Arc<RwLock<Vec<PathCommand>>>,
     /// Shell builtins
     builtins: Vec<String>,
     /// History entries for suggestions
     history: Vec<String>,
+    /// What history completion offers
+    history_match: HistoryMatch,
     /// Whether PATH cache is valid
-    cache_valid: bool,
+    cache_valid: Arc<AtomicBool>,
+    /// Cancellation flag of the scan in flight, if any
+    scan_cancel: Option<Arc<AtomicBool>>,
+    /// Directories to scan instead of $PATH
+    search_path: Option<OsString>,
+    /// PATH as of the last scan started, if the cache is still trusted
+    snapshot: Option<PathSnapshot>,
+    /// When maybe_refresh last looked at PATH
+    last_path_check: Option<Instant>,
+    /// How commands and file names are matched
+    match_mode: MatchMode,
+    /// Whether Windows commands are offered without their PATHEXT extension
+    strip_command_extensions: bool,
+    /// When hidden files are offered
+    show_hidden: ShowHidden,
+    /// What globs complete to
+    glob_expansion: GlobExpansion,
+    /// Result limits
+    config: CompleterConfig,
+    /// Directory listings for path completion, shared between clones
+    dir_cache: Arc<DirCache>,
+    /// Argument completion providers by command name
+    arg_completers: ArgCompleters,
+    /// Candidates added by embedders
+    sources: Sources,
+    /// Argument completion for commands without a provider, if enabled
+    bash_bridge: Option<Arc<BashCompleterBridge>>,
+    /// Options of commands from fish completion files
+    spec_loader: Arc<CompletionSpecLoader>,
+    /// Flags learned from `--help`, for commands nothing else knows
+    help_flags: Option<Arc<HelpFlagCompleter>>,
+    /// Accepted completions, to rank the ones used most lately first
+    frecency: Option<Arc<Mutex<FrecencyStore>>>,
+    /// Prefix commands such as sudo, with their options that take a value
+    command_prefixes: HashMap<String, Vec<String>>,
+    /// Shell aliases by name
+    aliases: HashMap<String, Alias>,
+    /// Users' home directories, for ~user paths
+    home_dirs: Arc<dyn HomeDirs>,
+    /// Variables of the shell being completed for
+    environment: Arc<dyn EnvironmentSource>,
+    /// Hosts and remote paths for ssh, scp, rsync and sftp
+    ssh: Arc<SshCompleter>,
+    /// Whether remote paths may be listed over ssh
+    remote_paths: bool,
+    /// All the completions of the last query, for paging through them
+    pages: PageCache,
+}
+
+/// Argument completers shared between clones of a completer
+#[derive(Clone, Default)]
+struct ArgCompleters(HashMap<String, Arc<dyn ArgCompleter>>);
+
+impl std::fmt::Debug for ArgCompleters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Handle of a registered completion source, for unregistering it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceId(u64);
+
+/// Completion sources shared between clones of a completer
+#[derive(Clone, Default)]
+struct Sources {
+    registered: Vec<(SourceId, Arc<dyn CompletionSource>)>,
+    next_id: u64,
+}
+
+impl std::fmt::Debug for Sources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.registered.iter().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+/// The last query, text and cursor, with all of its completions
+#[derive(Default)]
+struct PageCache(Mutex<Option<(String, usize, Arc<CompletionResult>)>>);
+
+/// Each clone pages through its own queries
+impl Clone for PageCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cached = self.0.lock().unwrap();
+        f.debug_tuple("PageCache")
+            .field(&cached.as_ref().map(|(text, cursor, _)| (text, cursor)))
+            .finish()
+    }
 }
 
 impl Default for Completer {
@@ -99,267 +810,991 @@ impl Completer {
         .map(String::from)
         .collect();
 
-        Self {
-            path_commands: Vec::new(),
+        let mut completer = Self {
+            path_commands: Arc::new(RwLock::new(Vec::new())),
             builtins,
             history: Vec::new(),
-            cache_valid: false,
+            history_match: HistoryMatch::default(),
+            cache_valid: Arc::new(AtomicBool::new(false)),
+            scan_cancel: None,
+            search_path: None,
+            snapshot: None,
+            last_path_check: None,
+            match_mode: MatchMode::default(),
+            strip_command_extensions: true,
+            show_hidden: ShowHidden::default(),
+            glob_expansion: GlobExpansion::default(),
+            config: CompleterConfig::default(),
+            dir_cache: Arc::new(DirCache::default()),
+            arg_completers: ArgCompleters::default(),
+            sources: Sources::default(),
+            bash_bridge: None,
+            spec_loader: Arc::new(CompletionSpecLoader::new()),
+            help_flags: None,
+            frecency: None,
+            command_prefixes: HashMap::new(),
+            aliases: HashMap::new(),
+            home_dirs: Arc::new(SystemHomeDirs),
+            environment: Arc::new(ProcessEnvironment),
+            ssh: Arc::new(SshCompleter::new()),
+            remote_paths: false,
+            pages: PageCache::default(),
+        };
+        for (command, value_options) in COMMAND_PREFIXES {
+            completer.add_command_prefix(command, value_options);
+        }
+        completer.register_arg_completer("git", Box::new(GitCompleter));
+        completer.register_arg_completer("cargo", Box::new(CargoCompleter::new()));
+        let make: Arc<dyn ArgCompleter> = Arc::new(MakeCompleter::new());
+        for command in ["make", "gmake"] {
+            completer
+                .arg_completers
+                .0
+                .insert(command.to_string(), make.clone());
         }
+        let npm: Arc<dyn ArgCompleter> = Arc::new(NpmCompleter::new());
+        for command in ["npm", "pnpm", "yarn"] {
+            completer
+                .arg_completers
+                .0
+                .insert(command.to_string(), npm.clone());
+        }
+        let systemd: Arc<dyn ArgCompleter> = Arc::new(SystemdCompleter::new());
+        for command in ["systemctl", "journalctl"] {
+            completer
+                .arg_completers
+                .0
+                .insert(command.to_string(), systemd.clone());
+        }
+        completer.set_ssh_completer(SshCompleter::new());
+        // One instance, so the commands share its process cache
+        let processes: Arc<dyn ArgCompleter> = Arc::new(ProcessCompleter::new());
+        for command in ["kill", "pkill", "killall", "renice"] {
+            completer
+                .arg_completers
+                .0
+                .insert(command.to_string(), processes.clone());
+        }
+        completer
     }
 
-    /// Complete the input at the given cursor position
-    pub fn complete(&self, text: &str, cursor_pos: usize) -> Vec<String> {
-        let text_before_cursor = &text[..cursor_pos.min(text.len())];
+    /// Complete the arguments of `command` with `completer`, replacing any
+    /// completer registered for it before
+    pub fn register_arg_completer(&mut self, command: &str, completer: Box<dyn ArgCompleter>) {
+        self.arg_completers
+            .0
+            .insert(command.to_string(), Arc::from(completer));
+    }
 
-        // Find the word being typed
-        let word_start = text_before_cursor
-            .rfind(|c: char| c.is_whitespace() || c == '|' || c == ';' || c == '&')
-            .map(|i| i + 1)
-            .unwrap_or(0);
+    /// Offer the candidates of `source` in every completion, merged with
+    /// the built-in ones by its priority
+    pub fn register_source(&mut self, source: Box<dyn CompletionSource>) -> SourceId {
+        let id = SourceId(self.sources.next_id);
+        self.sources.next_id += 1;
+        self.sources.registered.push((id, Arc::from(source)));
+        id
+    }
 
-        let word = &text_before_cursor[word_start..];
+    /// Stop offering the candidates of the source registered as `id`
+    ///
+    /// Returns whether it was registered.
+    pub fn unregister_source(&mut self, id: SourceId) -> bool {
+        let before = self.sources.registered.len();
+        self.sources
+            .registered
+            .retain(|(registered, _)| *registered != id);
+        self.sources.registered.len() != before
+    }
 
-        // Variable completion takes priority (can appear anywhere)
-        if word.starts_with('$') {
-            return self.complete_variable(word);
+    /// Merge the candidates of the registered sources for `word` into the
+    /// built-in `items`
+    ///
+    /// Higher priorities go first, with the built-in items at 0 ahead of
+    /// sources that tie; each priority is ranked like the built-in items.
+    /// A text offered twice is kept where it first appears.
+    fn with_sources(
+        &self,
+        items: Vec<CompletionInfo>,
+        text_before_cursor: &str,
+        word_start: usize,
+        word: &str,
+    ) -> Vec<CompletionInfo> {
+        if self.sources.registered.is_empty() {
+            return items;
+        }
+        let (command, args, sudo) = self
+            .resolve_command(text_before_cursor, word_start)
+            .unwrap_or_default();
+        let ctx = CompletionContext {
+            command,
+            args,
+            word,
+            cwd: self.working_dir(),
+            env: self.environment.vars().into_iter().collect(),
+            sudo,
+        };
+        let mut by_priority: BTreeMap<i32, Vec<(CompletionInfo, i32)>> = BTreeMap::new();
+        for (_, source) in &self.sources.registered {
+            let scored = source.provide(&ctx).into_iter().filter_map(|mut item| {
+                let (score, match_indices) = self.match_name(word, &item.text)?;
+                item.match_indices = match_indices;
+                Some((item, score))
+            });
+            by_priority
+                .entry(source.priority())
+                .or_default()
+                .extend(scored);
         }
 
-        // Determine if this is the first word (command) or an argument
-        let is_command = self.is_command_position(text_before_cursor, word_start);
-
-        if is_command {
-            self.complete_command(word)
-        } else if word.starts_with('~')
-            || word.starts_with('/')
-            || word.starts_with('.')
-            || word.contains('/')
-        {
-            self.complete_path(word)
-        } else {
-            // Could be either path or argument, try path first
-            let mut completions = self.complete_path(word);
-            if completions.is_empty() {
-                // Fall back to history-based completion
-                completions = self.complete_from_history(word);
+        let mut builtin = Some(items);
+        let mut merged = Vec::new();
+        for (priority, scored) in by_priority.into_iter().rev() {
+            if priority <= 0 {
+                merged.extend(builtin.take().unwrap_or_default());
             }
-            completions
+            merged.extend(self.ranked(scored));
         }
+        merged.extend(builtin.unwrap_or_default());
+        let mut seen = HashSet::new();
+        merged.retain(|item| seen.insert(item.text.clone()));
+        merged
     }
 
-    /// Check if we're in a command position
-    fn is_command_position(&self, text: &str, word_start: usize) -> bool {
-        if word_start == 0 {
-            return true;
-        }
+    /// Treat `command` like sudo: the first word after it and its options is
+    /// a command again
+    ///
+    /// `value_options` are the options that take the next word as a value,
+    /// such as `-u` for sudo.
+    pub fn add_command_prefix(&mut self, command: &str, value_options: &[&str]) {
+        self.command_prefixes.insert(
+            command.to_string(),
+            value_options.iter().map(|o| o.to_string()).collect(),
+        );
+    }
 
-        // Check what's before the word
-        let before_word = text[..word_start].trim_end();
-        if before_word.is_empty() {
-            return true;
+    /// Ask bash-completion about the arguments of commands that have no
+    /// completer registered, or stop with None
+    pub fn set_bash_bridge(&mut self, bridge: Option<BashCompleterBridge>) {
+        self.bash_bridge = bridge.map(Arc::new);
+    }
+
+    /// Read command options from fish completion files through `loader`
+    pub fn set_spec_loader(&mut self, loader: CompletionSpecLoader) {
+        self.spec_loader = Arc::new(loader);
+    }
+
+    /// Learn the flags of commands without a completer or completion file
+    /// from their `--help`, or stop with None
+    pub fn set_help_flags(&mut self, completer: Option<HelpFlagCompleter>) {
+        self.help_flags = completer.map(Arc::new);
+    }
+
+    /// Complete hosts and remote paths for ssh, scp, rsync and sftp with
+    /// `completer`
+    pub fn set_ssh_completer(&mut self, completer: SshCompleter) {
+        completer.set_remote_paths(self.remote_paths);
+        self.ssh = Arc::new(completer);
+        let ssh: Arc<dyn ArgCompleter> = self.ssh.clone();
+        for command in ["ssh", "scp", "rsync", "sftp"] {
+            self.arg_completers
+                .0
+                .insert(command.to_string(), ssh.clone());
         }
+    }
 
-        // After pipe, semicolon, or && || we're in command position
-        let last_char = before_word.chars().last();
-        matches!(last_char, Some('|') | Some(';') | Some('&'))
+    /// Allow listing remote directories over ssh for `host:path` targets
+    ///
+    /// Off by default: it is slow, and audited servers log every listing.
+    pub fn set_remote_paths(&mut self, enabled: bool) {
+        self.remote_paths = enabled;
+        self.ssh.set_remote_paths(enabled);
     }
 
-    /// Complete a command name
-    fn complete_command(&self, prefix: &str) -> Vec<String> {
-        let mut completions = HashSet::new();
+    /// Look up users' home directories for ~user paths with `home_dirs`
+    pub fn set_home_dirs(&mut self, home_dirs: Box<dyn HomeDirs>) {
+        self.home_dirs = Arc::from(home_dirs);
+    }
 
-        // Add matching builtins
-        for builtin in &self.builtins {
-            if builtin.starts_with(prefix) {
-                completions.insert(builtin.clone());
+    /// Take variables, $HOME included, from `environment` rather than
+    /// from this process
+    ///
+    /// Pass a SessionEnvironment to complete with the variables of the
+    /// shell in the pane, and keep updating it as the shell reports them.
+    pub fn set_environment(&mut self, environment: Arc<dyn EnvironmentSource>) {
+        self.environment = environment;
+    }
+
+    /// Set the shell's aliases, from names to what they expand to
+    ///
+    /// Alias names complete as commands, and an alias's arguments complete
+    /// as those of the command it expands to.
+    pub fn set_aliases(&mut self, aliases: HashMap<String, String>) {
+        self.aliases = aliases
+            .into_iter()
+            .map(|(name, expansion)| {
+                let words = shlex::split(&expansion)
+                    .unwrap_or_else(|| expansion.split_whitespace().map(String::from).collect());
+                (name, Alias { expansion, words })
+            })
+            .collect();
+    }
+
+    /// The command that the word at byte `word_start` belongs to, its
+    /// arguments so far and whether it runs under sudo or doas
+    ///
+    /// Aliases are expanded.  Prefix commands with their options, and
+    /// `NAME=value` assignments, are skipped.  None while the command
+    /// itself is being typed.
+    fn resolve_command<'a>(
+        &'a self,
+        text: &'a str,
+        word_start: usize,
+    ) -> Option<(&'a str, Vec<&'a str>, bool)> {
+        let mut words = Vec::new();
+        let mut target = false;
+        for word in command_words(text, word_start) {
+            match redirection(word) {
+                _ if target => target = false,
+                Some(attached) => target = !attached,
+                None => words.push(word),
             }
         }
-
-        // Add matching PATH commands
-        for cmd in &self.path_commands {
-            if cmd.starts_with(prefix) {
-                completions.insert(cmd.clone());
+        let mut i = 0;
+        let mut sudo = false;
+        let mut expanded = Vec::new();
+        loop {
+            while words.get(i).is_some_and(|word| is_assignment(word)) {
+                i += 1;
+            }
+            // Like the shell, don't expand an alias again inside itself
+            if let Some(&name) = words.get(i) {
+                if let Some(alias) = self.aliases.get(name) {
+                    if expanded.len() < MAX_ALIAS_DEPTH && !expanded.contains(&name) {
+                        expanded.push(name);
+                        words.splice(i..=i, alias.words.iter().map(String::as_str));
+                        continue;
+                    }
+                }
+            }
+            let value_options = match words.get(i).and_then(|w| self.command_prefixes.get(*w)) {
+                Some(value_options) => value_options,
+                None => break,
+            };
+            sudo |= matches!(words[i], "sudo" | "doas");
+            i += 1;
+            while let Some(&word) = words.get(i) {
+                if word == "--" {
+                    i += 1;
+                    break;
+                }
+                if !word.starts_with('-') || word == "-" {
+                    break;
+                }
+                i += 1;
+                if value_options.iter().any(|option| option == word) {
+                    i += 1;
+                }
             }
         }
+        let command = *words.get(i)?;
+        Some((command, words[i + 1..].to_vec(), sudo))
+    }
 
-        // If cache is empty, scan PATH on demand
-        if self.path_commands.is_empty() {
-            for cmd in Self::scan_path_commands() {
-                if cmd.starts_with(prefix) {
-                    completions.insert(cmd);
-                }
+    /// Ask the provider registered for the command, if any, to complete
+    /// the argument `word` starting at byte `word_start`
+    fn complete_arg(
+        &self,
+        text: &str,
+        word_start: usize,
+        word: &str,
+        quoting: QuotingContext,
+    ) -> Option<Vec<CompletionInfo>> {
+        let (command, args, sudo) = self.resolve_command(text, word_start)?;
+        let provider: Option<&dyn ArgCompleter> = match self.arg_completers.0.get(command) {
+            Some(provider) => Some(provider.as_ref()),
+            None => self
+                .bash_bridge
+                .as_deref()
+                .map(|bridge| bridge as &dyn ArgCompleter),
+        };
+        let ctx = CompletionContext {
+            command,
+            args,
+            word,
+            cwd: self.working_dir(),
+            env: self.environment.vars().into_iter().collect(),
+            sudo,
+        };
+        let mut items = provider.and_then(|provider| provider.complete(&ctx));
+        let mut specs = self.spec_loader.complete(command, word);
+        if specs.is_empty() && items.as_ref().is_none_or(Vec::is_empty) {
+            if let Some(learned) = self.help_flags.as_ref().and_then(|h| h.complete(&ctx)) {
+                specs = learned;
             }
         }
+        if !specs.is_empty() {
+            // Options from the spec join the provider's, which win on ties
+            let items = items.get_or_insert_with(Vec::new);
+            let seen: HashSet<String> = items.iter().map(|item| item.text.clone()).collect();
+            items.extend(specs.into_iter().filter(|item| !seen.contains(&item.text)));
+        }
+        if let (Some(items), Some(provider)) = (items.as_mut(), provider) {
+            if provider.with_paths(&ctx) {
+                items.extend(self.complete_path_with_info(word, quoting, Some(command)));
+            }
+        }
+        items
+    }
 
-        // Sort and limit
-        let mut result: Vec<_> = completions.into_iter().collect();
-        result.sort();
-        result.truncate(MAX_COMPLETIONS);
-        result
+    /// Complete the input at the given cursor position
+    ///
+    /// Each string replaces the word before the cursor; see complete_v2 for
+    /// its span.
+    pub fn complete(&self, text: &str, cursor_pos: usize) -> Vec<String> {
+        self.complete_v2(text, cursor_pos)
+            .items
+            .into_iter()
+            .map(|item| item.text)
+            .collect()
     }
 
-    /// Complete a file path
-    fn complete_path(&self, prefix: &str) -> Vec<String> {
-        let expanded = self.expand_tilde(prefix);
-        let path = Path::new(&expanded);
+    /// Working directory of the shell: $PWD of the environment, which the
+    /// host keeps current from OSC 7, else this process's own
+    fn working_dir(&self) -> PathBuf {
+        self.environment
+            .working_dir()
+            .filter(|pwd| pwd.is_absolute())
+            .or_else(|| env::current_dir().ok())
+            .unwrap_or_default()
+    }
 
-        let (dir, file_prefix) = if expanded.ends_with('/') || expanded.ends_with('\\') {
-            (PathBuf::from(&expanded), "")
-        } else if let Some(parent) = path.parent() {
-            let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
-            (parent.to_path_buf(), file_name)
-        } else {
-            (PathBuf::from("."), &*expanded)
-        };
+    /// Check if we're in a command position
+    ///
+    /// True at the start of the line, after a pipe or separator, and after
+    /// prefix commands such as `sudo -u root` or assignments, but never for
+    /// the target of a redirection.
+    fn is_command_position(&self, text: &str, word_start: usize) -> bool {
+        !is_redirection_target(text, word_start) && self.resolve_command(text, word_start).is_none()
+    }
 
-        let mut completions = Vec::new();
+    /// Whether the word at byte `word_start` of `text` is the value of an
+    /// assignment before the command, as in `FOO=/us`
+    fn is_assigned_value(&self, text: &str, word_start: usize) -> bool {
+        text[..word_start].ends_with('=') && self.resolve_command(text, word_start).is_none()
+    }
 
-        if let Ok(entries) = fs::read_dir(&dir) {
-            for entry in entries.filter_map(Result::ok) {
-                let file_name = entry.file_name();
-                let name = file_name.to_string_lossy();
+    /// Set how commands and file names are matched
+    pub fn set_match_mode(&mut self, mode: MatchMode) {
+        self.match_mode = mode;
+    }
 
-                if name.starts_with(file_prefix) {
-                    let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
-
-                    // Build the completion string
-                    let completion = if prefix.starts_with('~') {
-                        // Keep the ~ prefix
-                        let home = dirs_next::home_dir()
-                            .map(|h| h.to_string_lossy().to_string())
-                            .unwrap_or_default();
-                        let full_path = dir.join(&*name);
-                        let full_str = full_path.to_string_lossy();
-                        if full_str.starts_with(&home) {
-                            format!("~{}", &full_str[home.len()..])
-                        } else {
-                            name.to_string()
-                        }
-                    } else if prefix.contains('/') {
-                        // Keep the directory prefix
-                        let parent_str = if dir.to_string_lossy() == "." {
-                            String::new()
-                        } else {
-                            format!("{}/", dir.display())
-                        };
-                        format!("{}{}", parent_str, name)
-                    } else {
-                        name.to_string()
-                    };
+    /// Get how commands and file names are matched
+    pub fn match_mode(&self) -> MatchMode {
+        self.match_mode
+    }
 
-                    // Add trailing slash for directories
-                    let completion = if is_dir && !completion.ends_with('/') {
-                        format!("{}/", completion)
-                    } else {
-                        completion
-                    };
+    /// Set when file names starting with a dot are offered
+    pub fn set_show_hidden(&mut self, show_hidden: ShowHidden) {
+        self.show_hidden = show_hidden;
+    }
 
-                    completions.push(completion);
-                }
-            }
-        }
+    /// Set the result limits, emptying the directory cache
+    pub fn set_config(&mut self, config: CompleterConfig) {
+        self.dir_cache = Arc::new(
+            self.dir_cache
+                .with_limits(config.dir_cache_ttl, config.dir_cache_capacity),
+        );
+        self.config = config;
+    }
 
-        completions.sort();
-        completions.truncate(MAX_COMPLETIONS);
-        completions
+    /// Read directories for path completion from `fs`
+    pub fn set_file_system(&mut self, fs: Box<dyn FileSystem>) {
+        self.dir_cache = Arc::new(DirCache::new(
+            Arc::from(fs),
+            self.config.dir_cache_ttl,
+            self.config.dir_cache_capacity,
+        ));
     }
 
-    /// Complete an environment variable
-    fn complete_variable(&self, prefix: &str) -> Vec<String> {
-        let var_prefix = prefix.trim_start_matches('$').trim_start_matches('{');
-        let is_braced = prefix.starts_with("${");
+    /// Forget the cached directory listings, so the next completion reads
+    /// the directories again
+    pub fn clear_fs_cache(&self) {
+        self.dir_cache.clear();
+    }
 
-        let mut completions = Vec::new();
+    /// The result limits
+    pub fn config(&self) -> &CompleterConfig {
+        &self.config
+    }
 
-        for (key, _) in env::vars() {
-            if key.starts_with(var_prefix) {
-                let completion = if is_braced {
-                    format!("${{{}}}", key)
-                } else {
-                    format!("${}", key)
-                };
-                completions.push(completion);
+    /// Set whether globs complete to each match or to all of them
+    pub fn set_glob_expansion(&mut self, glob_expansion: GlobExpansion) {
+        self.glob_expansion = glob_expansion;
+    }
+
+    /// Match `name` against the typed word, returning its score and the
+    /// char indices that matched
+    fn match_name(&self, word: &str, name: &str) -> Option<(i32, Vec<usize>)> {
+        match self.match_mode {
+            MatchMode::Prefix => name
+                .starts_with(word)
+                .then(|| (0, (0..word.chars().count()).collect())),
+            MatchMode::Substring => {
+                let at = smart_case_find(name, word)?;
+                let start = name[..at].chars().count();
+                let indices = (start..start + word.chars().count()).collect();
+                Some((if at == 0 { PREFIX_MATCH_BONUS } else { 0 }, indices))
             }
+            MatchMode::Fuzzy => fuzzy_match(word, name),
         }
+    }
 
-        completions.sort();
-        completions.truncate(MAX_COMPLETIONS);
-        completions
+    /// Sort scored candidates best first
+    ///
+    /// Prefix matches come before substring and fuzzy hits.  Within those,
+    /// the completions accepted most often and lately go first, then
+    /// better matches, then directories, then names alphabetically ignoring
+    /// case; fuzzy matching prefers shorter names before that.  Commands,
+    /// paths and variables are all sorted here, so every caller sees the
+    /// same order.
+    fn ranked(&self, completions: Vec<(CompletionInfo, i32)>) -> Vec<CompletionInfo> {
+        let fuzzy = self.match_mode == MatchMode::Fuzzy;
+        let frecency = self.frecency.as_ref().map(|store| store.lock().unwrap());
+        let mut completions: Vec<(CompletionInfo, i32, f64)> = completions
+            .into_iter()
+            .map(|(info, score)| {
+                let used = frecency
+                    .as_ref()
+                    .map_or(0.0, |store| store.score(&info.text, info.kind));
+                (info, score, used)
+            })
+            .collect();
+        drop(frecency);
+        completions.sort_by(|(a, a_score, a_used), (b, b_score, b_used)| {
+            let by_len = if fuzzy {
+                a.text.len().cmp(&b.text.len())
+            } else {
+                Ordering::Equal
+            };
+            let prefix = |score: &i32| *score >= PREFIX_MATCH_BONUS;
+            prefix(b_score)
+                .cmp(&prefix(a_score))
+                .then(b_used.partial_cmp(a_used).unwrap_or(Ordering::Equal))
+                .then(b_score.cmp(a_score))
+                .then(b.is_directory.cmp(&a.is_directory))
+                .then(by_len)
+                .then_with(|| a.text.to_lowercase().cmp(&b.text.to_lowercase()))
+                .then_with(|| a.text.cmp(&b.text))
+        });
+        completions.into_iter().map(|(info, ..)| info).collect()
     }
 
-    /// Complete from history
-    fn complete_from_history(&self, prefix: &str) -> Vec<String> {
-        let mut completions = Vec::new();
-        let mut seen = HashSet::new();
+    /// Rank completions by how often and how lately they were accepted,
+    /// as recorded in `store`
+    pub fn set_frecency_store(&mut self, store: FrecencyStore) {
+        self.frecency = Some(Arc::new(Mutex::new(store)));
+    }
 
-        for entry in self.history.iter().rev() {
-            // Find words in history that match
-            for word in entry.split_whitespace() {
-                if word.starts_with(prefix) && seen.insert(word.to_string()) {
-                    completions.push(word.to_string());
-                    if completions.len() >= MAX_COMPLETIONS {
-                        return completions;
-                    }
-                }
+    /// Note that the user accepted `info`, so it ranks higher next time
+    ///
+    /// Does nothing without a frecency store; the store is saved each time.
+    pub fn record_accepted(&self, info: &CompletionInfo) {
+        if let Some(store) = &self.frecency {
+            let mut store = store.lock().unwrap();
+            store.record(&info.text, info.kind);
+            if let Err(err) = store.save() {
+                log::warn!("Failed to save completion frecency: {}", err);
             }
         }
+    }
 
-        completions
+    /// Set what history completion offers
+    pub fn set_history_match(&mut self, history_match: HistoryMatch) {
+        self.history_match = history_match;
     }
 
-    /// Expand ~ to home directory
-    fn expand_tilde(&self, path: &str) -> String {
-        if path.starts_with('~') {
-            if let Some(home) = dirs_next::home_dir() {
-                if path == "~" {
-                    return home.to_string_lossy().to_string();
-                } else if path.starts_with("~/") {
-                    return format!("{}{}", home.display(), &path[1..]);
+    /// Complete from history, returning the byte offset in `text` that the
+    /// items replace from
+    ///
+    /// Whole commands replace the line up to the cursor, words only the
+    /// word at `word_start`.
+    fn complete_history(&self, text: &str, word_start: usize) -> (usize, Vec<CompletionInfo>) {
+        match self.history_match {
+            HistoryMatch::WholeCommand => {
+                let typed = text.trim_start();
+                (
+                    text.len() - typed.len(),
+                    self.complete_history_commands(typed),
+                )
+            }
+            HistoryMatch::Words => (
+                word_start,
+                self.complete_from_history_with_info(&text[word_start..]),
+            ),
+        }
+    }
+
+    /// Previous command lines that start with `typed`, or failing that
+    /// contain it, most used and most recent first
+    ///
+    /// Repeats of a command are offered once, with their use count.
+    fn complete_history_commands(&self, typed: &str) -> Vec<CompletionInfo> {
+        // Each use counts for less the further back it is
+        let mut commands: HashMap<&str, (f64, usize, usize)> = HashMap::new();
+        let len = self.history.len();
+        for (i, entry) in self.history.iter().enumerate() {
+            let entry = entry.trim();
+            if entry.is_empty() || entry == typed {
+                continue;
+            }
+            let weight = 0.5f64.powf((len - 1 - i) as f64 / HISTORY_HALF_LIFE);
+            let (score, uses, last) = commands.entry(entry).or_insert((0.0, 0, i));
+            *score += weight;
+            *uses += 1;
+            *last = i;
+        }
+
+        let prefixed: Vec<_> = commands
+            .iter()
+            .filter(|(command, _)| command.starts_with(typed))
+            .collect();
+        let mut matches = if prefixed.is_empty() {
+            commands
+                .iter()
+                .filter(|(command, _)| command.contains(typed))
+                .collect()
+        } else {
+            prefixed
+        };
+        matches.sort_by(|(_, (a_score, _, a_last)), (_, (b_score, _, b_last))| {
+            b_score
+                .partial_cmp(a_score)
+                .unwrap_or(Ordering::Equal)
+                .then(b_last.cmp(a_last))
+        });
+
+        matches
+            .into_iter()
+            .map(|(command, &(_, uses, _))| {
+                let start = command[..command.find(typed).unwrap_or(0)].chars().count();
+                CompletionInfo {
+                    text: command.to_string(),
+                    description: Some(match uses {
+                        1 => "used once".to_string(),
+                        n => format!("used {} times", n),
+                    }),
+                    is_directory: false,
+                    kind: CompletionKind::History,
+                    match_indices: (start..start + typed.chars().count()).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Complete single words from history, most recent first, with how
+    /// often each was used
+    fn complete_from_history_with_info(&self, prefix: &str) -> Vec<CompletionInfo> {
+        let mut words: Vec<&str> = Vec::new();
+        let mut uses: HashMap<&str, usize> = HashMap::new();
+
+        for entry in self.history.iter().rev() {
+            for word in entry.split_whitespace() {
+                if !word.starts_with(prefix) {
+                    continue;
                 }
-                // ~user format not supported for now
+                let count = uses.entry(word).or_insert(0);
+                if *count == 0 {
+                    words.push(word);
+                }
+                *count += 1;
             }
         }
-        path.to_string()
+
+        words
+            .into_iter()
+            .map(|word| CompletionInfo {
+                text: word.to_string(),
+                description: Some(match uses[word] {
+                    1 => "used once".to_string(),
+                    n => format!("used {} times", n),
+                }),
+                is_directory: false,
+                kind: CompletionKind::History,
+                match_indices: (0..prefix.chars().count()).collect(),
+            })
+            .collect()
+    }
+
+    /// Expand ~ to the home directory, and ~user to that user's
+    ///
+    /// ~user is left alone on Windows, and for unknown users; ~ is left
+    /// alone if the environment has no home for it.
+    fn expand_tilde(&self, path: &str) -> String {
+        let rest = match path.strip_prefix('~') {
+            Some(rest) => rest,
+            None => return path.to_string(),
+        };
+        let (user, tail) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let home = if user.is_empty() {
+            self.environment.home_dir()
+        } else if cfg!(windows) {
+            None
+        } else {
+            self.home_dirs.home(user)
+        };
+        match home {
+            Some(home) => format!("{}{}", home.display(), tail),
+            None => path.to_string(),
+        }
+    }
+
+    /// Expand the glob `word` as the shell would in `cwd`
+    ///
+    /// The paths are sorted and escaped for typing, and start with the
+    /// word's directory as typed.  Hidden files only match a pattern that
+    /// starts with a dot.  Empty if `word` isn't a glob or nothing matches.
+    pub fn expand_glob(&self, word: &str, cwd: &Path) -> Vec<String> {
+        self.glob_paths(word, cwd)
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect()
     }
 
-    /// Scan PATH for available commands
-    fn scan_path_commands() -> Vec<String> {
-        let mut commands = HashSet::new();
+    /// The paths the glob `word` matches under `cwd`, as typed, with
+    /// whether each is a directory
+    fn glob_paths(&self, word: &str, cwd: &Path) -> Vec<(String, bool)> {
+        let windows = cfg!(windows);
+        let segments: Vec<&str> = word.split(|c| is_path_separator(c, windows)).collect();
+        // The components before the first pattern are kept as typed
+        let literal = segments.iter().take_while(|s| !has_glob_meta(s)).count();
+        if literal == segments.len() {
+            return Vec::new();
+        }
+        let unquote = |text: &str| {
+            if windows {
+                text.to_string()
+            } else {
+                QuotingContext::None.unquote(text)
+            }
+        };
+        let prefix = &word[..segments[..literal].iter().map(|s| s.len() + 1).sum()];
+        let separator = dir_separator(word, windows);
+        let join = |text: &str, name: &str| {
+            if text.is_empty() || text.ends_with(|c| is_path_separator(c, windows)) {
+                format!("{}{}", text, name)
+            } else {
+                format!("{}{}{}", text, separator, name)
+            }
+        };
+        let quote = |name: &str| {
+            if windows {
+                name.to_string()
+            } else {
+                QuotingContext::None.quote(name)
+            }
+        };
 
-        if let Ok(path) = env::var("PATH") {
-            for dir in env::split_paths(&path) {
-                if let Ok(entries) = fs::read_dir(&dir) {
+        let dir = if windows {
+            prefix.to_string()
+        } else {
+            expand_variables(prefix, QuotingContext::None, self.environment.as_ref())
+        };
+        let mut found = vec![(
+            cwd.join(self.expand_tilde(&unquote(&dir))),
+            prefix.to_string(),
+        )];
+        for (i, &segment) in segments.iter().enumerate().skip(literal) {
+            let last = i + 1 == segments.len();
+            let mut next = Vec::new();
+            for (dir, text) in found {
+                if segment == "**" {
+                    // Directories at any depth, this one included; at the
+                    // end, everything below it
+                    let walk = walkdir::WalkDir::new(&dir)
+                        .min_depth(usize::from(last))
+                        .max_depth(MAX_GLOB_DEPTH)
+                        .into_iter()
+                        .filter_entry(|e| {
+                            e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.')
+                        });
+                    for entry in walk.filter_map(Result::ok) {
+                        if !last && !entry.file_type().is_dir() {
+                            continue;
+                        }
+                        let relative = entry.path().strip_prefix(&dir).unwrap_or(entry.path());
+                        let text = relative.iter().fold(text.clone(), |text, name| {
+                            join(&text, &quote(&name.to_string_lossy()))
+                        });
+                        next.push((entry.path().to_path_buf(), text));
+                    }
+                } else if has_glob_meta(segment) {
+                    let entries = match fs::read_dir(&dir) {
+                        Ok(entries) => entries,
+                        Err(_) => continue,
+                    };
                     for entry in entries.filter_map(Result::ok) {
-                        if let Ok(file_type) = entry.file_type() {
-                            if file_type.is_file() || file_type.is_symlink() {
-                                // Check if executable (on Unix)
-                                #[cfg(unix)]
-                                {
-                                    use std::os::unix::fs::PermissionsExt;
-                                    if let Ok(metadata) = entry.metadata() {
-                                        let mode = metadata.permissions().mode();
-                                        if mode & 0o111 == 0 {
-                                            continue; // Not executable
-                                        }
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        let path = entry.path();
+                        if (name.starts_with('.') && !segment.starts_with('.'))
+                            || !glob_match(segment, &name)
+                            || (!last && !path.is_dir())
+                        {
+                            continue;
+                        }
+                        next.push((path, join(&text, &quote(&name))));
+                    }
+                } else if segment.is_empty() {
+                    // A trailing separator keeps only directories
+                    if dir.is_dir() {
+                        let text = if last { join(&text, "") } else { text };
+                        next.push((dir, text));
+                    }
+                } else {
+                    let path = dir.join(unquote(segment));
+                    if path.is_dir() || (last && path.exists()) {
+                        next.push((path, join(&text, segment)));
+                    }
+                }
+            }
+            found = next;
+        }
+
+        let mut paths: Vec<(String, bool)> = found
+            .into_iter()
+            .map(|(path, text)| (text, path.is_dir()))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Complete the glob `word` to the paths it matches, or to all of them
+    /// at once if so set
+    fn complete_glob(&self, word: &str, dirs_only: bool) -> Vec<CompletionInfo> {
+        let separator = dir_separator(word, cfg!(windows));
+        let mut paths = self.glob_paths(word, &self.working_dir());
+        paths.retain(|&(_, is_dir)| is_dir || !dirs_only);
+        if self.glob_expansion == GlobExpansion::All && paths.len() > 1 {
+            let texts: Vec<&str> = paths.iter().map(|(text, _)| text.as_str()).collect();
+            return vec![CompletionInfo {
+                text: texts.join(" "),
+                description: Some(format!("glob expansion, {} paths", paths.len())),
+                is_directory: false,
+                kind: CompletionKind::File,
+                match_indices: Vec::new(),
+            }];
+        }
+        paths
+            .into_iter()
+            .map(|(mut text, is_dir)| {
+                if is_dir && !text.ends_with(separator) {
+                    text.push(separator);
+                }
+                CompletionInfo {
+                    text,
+                    description: Some("glob match".to_string()),
+                    is_directory: is_dir,
+                    kind: if is_dir {
+                        CompletionKind::Directory
+                    } else {
+                        CompletionKind::File
+                    },
+                    match_indices: Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    /// Complete `~user` to `~user/` for users whose name starts with
+    /// `typed`
+    fn complete_users(&self, typed: &str) -> Vec<CompletionInfo> {
+        let mut seen = HashSet::new();
+        let completions = self
+            .home_dirs
+            .users()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(typed) && seen.insert(name.clone()))
+            .map(|(name, home)| {
+                let info = CompletionInfo {
+                    text: format!("~{}/", name),
+                    description: Some(home.display().to_string()),
+                    is_directory: true,
+                    kind: CompletionKind::Directory,
+                    match_indices: (0..=typed.chars().count()).collect(),
+                };
+                (info, 0)
+            })
+            .collect();
+        self.ranked(completions)
+    }
+
+    /// Scan the directories of `path` for executables
+    ///
+    /// Returns None if `cancel` was set part way through.
+    fn scan_path_commands(
+        path: &OsString,
+        cancel: &AtomicBool,
+        strip_extensions: bool,
+    ) -> Option<Vec<PathCommand>> {
+        // Only the first hit on PATH runs, so later ones are ignored
+        let mut commands = HashMap::new();
+        let pathext = pathext();
+
+        for dir in env::split_paths(path) {
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for entry in entries.filter_map(Result::ok) {
+                    if cancel.load(AtomicOrdering::Relaxed) {
+                        return None;
+                    }
+                    if let Ok(file_type) = entry.file_type() {
+                        if file_type.is_file() || file_type.is_symlink() {
+                            // Check if executable (on Unix)
+                            #[cfg(unix)]
+                            {
+                                use std::os::unix::fs::PermissionsExt;
+                                if let Ok(metadata) = entry.metadata() {
+                                    let mode = metadata.permissions().mode();
+                                    if mode & 0o111 == 0 {
+                                        continue; // Not executable
                                     }
                                 }
+                            }
 
-                                if let Some(name) = entry.file_name().to_str() {
-                                    commands.insert(name.to_string());
+                            let name = match entry.file_name().to_str() {
+                                Some(name) => name.to_string(),
+                                None => continue,
+                            };
+                            // Windows has no executable bit; go by extension
+                            let name = if cfg!(windows) {
+                                match pathext_command_name(&name, &pathext, strip_extensions) {
+                                    Some(name) => name,
+                                    None => continue,
                                 }
-                            }
+                            } else {
+                                name
+                            };
+                            commands.entry(name).or_insert_with(|| entry.path());
                         }
                     }
                 }
             }
         }
 
-        commands.into_iter().collect()
+        let mut commands: Vec<PathCommand> = commands
+            .into_iter()
+            .map(|(name, path)| PathCommand { name, path })
+            .collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(commands)
+    }
+
+    /// Offer Windows commands as "git" rather than "git.exe", or not
+    ///
+    /// Takes effect at the next refresh.
+    pub fn set_strip_command_extensions(&mut self, strip: bool) {
+        self.strip_command_extensions = strip;
+    }
+
+    /// Directories searched for commands
+    fn search_path(&self) -> OsString {
+        self.search_path
+            .clone()
+            .or_else(|| env::var_os("PATH"))
+            .unwrap_or_default()
+    }
+
+    /// Search `path` for commands instead of $PATH, or $PATH again if None
+    ///
+    /// Takes effect at the next refresh.
+    pub fn set_search_path(&mut self, path: Option<OsString>) {
+        self.search_path = path;
+    }
+
+    /// Cancel the scan in flight and start tracking a new one
+    fn begin_scan(&mut self) -> Arc<AtomicBool> {
+        self.snapshot = Some(PathSnapshot::take(self.search_path()));
+        if let Some(cancel) = self.scan_cancel.take() {
+            cancel.store(true, AtomicOrdering::Relaxed);
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.scan_cancel = Some(Arc::clone(&cancel));
+        cancel
     }
 
-    /// Refresh the PATH commands cache
+    /// Refresh the PATH commands cache, blocking until the scan is done
     pub fn refresh_cache(&mut self) {
-        self.path_commands = Self::scan_path_commands();
-        self.cache_valid = true;
+        let cancel = self.begin_scan();
+        let strip = self.strip_command_extensions;
+        if let Some(commands) = Self::scan_path_commands(&self.search_path(), &cancel, strip) {
+            *self.path_commands.write().unwrap() = commands;
+            self.cache_valid.store(true, AtomicOrdering::Relaxed);
+        }
+    }
+
+    /// Refresh the PATH commands cache on a background thread
+    ///
+    /// A scan already in flight is cancelled.  Completion keeps using the
+    /// old cache until the new one is swapped in.  The thread's result is
+    /// whether it swapped its scan in.
+    pub fn refresh_cache_async(&mut self) -> JoinHandle<bool> {
+        let cancel = self.begin_scan();
+        let path = self.search_path();
+        let path_commands = Arc::clone(&self.path_commands);
+        let cache_valid = Arc::clone(&self.cache_valid);
+        let strip = self.strip_command_extensions;
+        std::thread::spawn(move || {
+            let commands = match Self::scan_path_commands(&path, &cancel, strip) {
+                Some(commands) => commands,
+                None => return false,
+            };
+            let mut cache = path_commands.write().unwrap();
+            // A newer scan may have started while this one ran
+            if cancel.load(AtomicOrdering::Relaxed) {
+                return false;
+            }
+            *cache = commands;
+            cache_valid.store(true, AtomicOrdering::Relaxed);
+            true
+        })
+    }
+
+    /// Check if a PATH scan has finished since the cache was last
+    /// invalidated
+    pub fn is_cache_valid(&self) -> bool {
+        self.cache_valid.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Start a background refresh if PATH or one of its directories changed
+    /// since the last scan
+    ///
+    /// Cheap enough to call before every completion: PATH is looked at no
+    /// more than once every few seconds.
+    pub fn maybe_refresh(&mut self) -> Option<JoinHandle<bool>> {
+        self.maybe_refresh_at(Instant::now())
+    }
+
+    fn maybe_refresh_at(&mut self, now: Instant) -> Option<JoinHandle<bool>> {
+        if let Some(last) = self.last_path_check {
+            if now.saturating_duration_since(last) < PATH_CHECK_INTERVAL {
+                return None;
+            }
+        }
+        self.last_path_check = Some(now);
+        let current = PathSnapshot::take(self.search_path());
+        if self.snapshot.as_ref() == Some(&current) {
+            return None;
+        }
+        Some(self.refresh_cache_async())
+    }
+
+    /// Distrust the PATH cache, so the next maybe_refresh scans again
+    ///
+    /// For when the host knows commands were installed, e.g. after
+    /// `cargo install`.  The old cache is used until the scan finishes.
+    pub fn invalidate_cache(&mut self) {
+        self.snapshot = None;
+        self.last_path_check = None;
+        self.cache_valid.store(false, AtomicOrdering::Relaxed);
     }
 
     /// Add history entries for completion
@@ -378,7 +1813,7 @@ impl Completer {
 
     /// Check if a completion is a directory
     pub fn is_directory(&self, completion: &str) -> bool {
-        completion.ends_with('/')
+        completion.ends_with(|c| is_path_separator(c, cfg!(windows)))
     }
 
     /// Get completion for a specific index
@@ -386,10 +1821,71 @@ impl Completer {
         let completions = self.complete(text, cursor_pos);
         completions.get(index).cloned()
     }
+
+    /// Text to insert on the first Tab, replacing the span of complete_v2
+    ///
+    /// This is the longest prefix shared by every completion, if it extends
+    /// the word.  A single candidate is returned whole, with a trailing
+    /// space unless it is a directory.
+    pub fn common_prefix(&self, text: &str, cursor_pos: usize) -> Option<String> {
+        let result = self.complete_v2(text, cursor_pos);
+        let word = &text[result.prefix];
+        unambiguous_completion(word, &result.items, self.ignores_case(word))
+    }
+
+    /// Check if matching `word` ignores case
+    fn ignores_case(&self, word: &str) -> bool {
+        self.match_mode != MatchMode::Prefix && !word.chars().any(char::is_uppercase)
+    }
+}
+
+/// Completions for the word before the cursor
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CompletionResult {
+    /// Byte range in the input that an item replaces
+    ///
+    /// With the cursor inside a word, it goes on past the cursor to the
+    /// end of the word, unless the config says otherwise.
+    pub span: Range<usize>,
+    /// Byte range of the part of `span` before the cursor, which the
+    /// candidates were matched against
+    pub prefix: Range<usize>,
+    /// Candidates, best first; each text is the whole replacement for `span`
+    pub items: Vec<CompletionInfo>,
+    /// Number of candidates in all, `items` being some of them
+    pub total_matches: usize,
+    /// Whether there are candidates after `items`
+    pub truncated: bool,
+    /// Whether commands came from a PATH cache that isn't filled yet
+    pub possibly_stale: bool,
+}
+
+/// Some of the completions of a word, with how many there are in all
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CompletionSet {
+    /// Candidates, best first
+    pub items: Vec<CompletionInfo>,
+    /// Number of candidates in all
+    pub total_matches: usize,
+    /// Whether candidates were left out after `items`
+    pub truncated: bool,
+}
+
+impl CompletionSet {
+    /// The first `limit` of `items`
+    fn limited(mut items: Vec<CompletionInfo>, limit: usize) -> Self {
+        let total_matches = items.len();
+        items.truncate(limit);
+        Self {
+            truncated: items.len() < total_matches,
+            total_matches,
+            items,
+        }
+    }
 }
 
 /// Information about a completion
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CompletionInfo {
     /// The completion text
     pub text: String,
@@ -399,10 +1895,12 @@ pub struct CompletionInfo {
     pub is_directory: bool,
     /// The type of completion
     pub kind: CompletionKind,
+    /// Char indices in `text` that matched the typed word, for bolding
+    pub match_indices: Vec<usize>,
 }
 
 /// Type of completion
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum CompletionKind {
     /// Command from PATH
     Command,
@@ -416,172 +1914,701 @@ pub enum CompletionKind {
     Variable,
     /// From history
     History,
+    /// Argument from a command's ArgCompleter
+    Argument,
+    /// Shell alias
+    Alias,
+    /// From a CompletionSource; its description says what it is
+    Other,
 }
 
 impl Completer {
-    /// Get detailed completions with metadata
-    pub fn complete_with_info(&self, text: &str, cursor_pos: usize) -> Vec<CompletionInfo> {
-        let text_before_cursor = &text[..cursor_pos.min(text.len())];
+    /// Complete the word before the cursor, with the span to replace
+    ///
+    /// Variables complete anywhere, commands in command position and paths
+    /// elsewhere.  A bare word that nothing else completes falls back to
+    /// history: whole previous commands, replacing the line, or words.
+    /// Returns up to `max_results` candidates; see complete_page for more.
+    pub fn complete_v2(&self, text: &str, cursor_pos: usize) -> CompletionResult {
+        self.complete_page(text, cursor_pos, 0, self.config.max_results)
+    }
 
-        let word_start = text_before_cursor
-            .rfind(|c: char| c.is_whitespace() || c == '|' || c == ';' || c == '&')
-            .map(|i| i + 1)
-            .unwrap_or(0);
+    /// Complete the word before the cursor, returning `limit` candidates
+    /// from `offset` on
+    ///
+    /// The first page computes every candidate; later pages of the same
+    /// query come from that list, so they don't repeat the work and stay
+    /// in step while a popup scrolls.
+    pub fn complete_page(
+        &self,
+        text: &str,
+        cursor_pos: usize,
+        offset: usize,
+        limit: usize,
+    ) -> CompletionResult {
+        let cursor_pos = floor_char_boundary(text, cursor_pos);
+        let cached = match &*self.pages.0.lock().unwrap() {
+            Some((cached_text, cached_cursor, all))
+                if offset > 0 && *cached_cursor == cursor_pos && cached_text == text =>
+            {
+                Some(Arc::clone(all))
+            }
+            _ => None,
+        };
+        let all = cached.unwrap_or_else(|| {
+            let all = Arc::new(self.complete_all(text, cursor_pos));
+            *self.pages.0.lock().unwrap() = Some((text.to_string(), cursor_pos, Arc::clone(&all)));
+            all
+        });
+        let items: Vec<CompletionInfo> =
+            all.items.iter().skip(offset).take(limit).cloned().collect();
+        CompletionResult {
+            span: all.span.clone(),
+            prefix: all.prefix.clone(),
+            truncated: offset.saturating_add(items.len()) < all.items.len(),
+            total_matches: all.items.len(),
+            items,
+            possibly_stale: all.possibly_stale,
+        }
+    }
 
+    /// Every completion of the word at byte `cursor_pos` of `text`
+    fn complete_all(&self, text: &str, cursor_pos: usize) -> CompletionResult {
+        let text_before_cursor = &text[..cursor_pos];
+        let after_cursor = &text[cursor_pos..];
+        let breaks = &self.config.word_breaks;
+        let (quoting, word_start) = scan_quotes(text_before_cursor, breaks);
         let word = &text_before_cursor[word_start..];
-        let is_command = self.is_command_position(text_before_cursor, word_start);
 
-        if is_command {
-            self.complete_command_with_info(word)
-        } else if word.starts_with('$') {
-            self.complete_variable_with_info(word)
+        let mut possibly_stale = false;
+        let mut start = word_start;
+        let mut rest = word_end(after_cursor, quoting, breaks);
+        let items = if let Some(dollar) = variable_start(word, quoting) {
+            // Only the name is replaced, not what follows it in the word
+            rest = variable_end(after_cursor, word[dollar..].starts_with("${"));
+            let items = self.complete_variable_with_info(word, dollar);
+            self.with_sources(items, text_before_cursor, word_start, word)
+        } else if is_redirection_target(text_before_cursor, word_start)
+            || self.is_assigned_value(text_before_cursor, word_start)
+        {
+            let items = self.complete_path_with_info(word, quoting, None);
+            self.with_sources(items, text_before_cursor, word_start, word)
         } else {
-            self.complete_path_with_info(word)
+            let mut items = if self.is_command_position(text_before_cursor, word_start) {
+                possibly_stale = !self.is_cache_valid();
+                self.complete_command_with_info(word)
+            } else {
+                let command = self
+                    .resolve_command(text_before_cursor, word_start)
+                    .map(|(command, ..)| command);
+                match self.complete_arg(text_before_cursor, word_start, word, quoting) {
+                    Some(items) => items,
+                    None => self.complete_path_with_info(word, quoting, command),
+                }
+            };
+            items = self.with_sources(items, text_before_cursor, word_start, word);
+            let looks_like_path = word.starts_with(['~', '/', '.'])
+                || word.contains(|c| is_path_separator(c, cfg!(windows)));
+            if items.is_empty() && !word.is_empty() && !looks_like_path {
+                let (history_start, history) =
+                    self.complete_history(text_before_cursor, word_start);
+                if !history.is_empty() {
+                    start = history_start;
+                    items = history;
+                }
+            }
+            items
+        };
+
+        let end = match self.config.mid_word {
+            MidWord::ReplaceToken => cursor_pos + rest,
+            MidWord::InsertAtCursor => cursor_pos,
+        };
+        CompletionResult {
+            span: start..end,
+            prefix: start..cursor_pos,
+            total_matches: items.len(),
+            items,
+            truncated: false,
+            possibly_stale,
         }
     }
 
+    /// Get detailed completions with metadata, up to `max_results` of them
+    ///
+    /// These are the items of complete_v2, history included; see there for
+    /// the span they replace.
+    pub fn complete_with_info(&self, text: &str, cursor_pos: usize) -> CompletionSet {
+        let cursor_pos = floor_char_boundary(text, cursor_pos);
+        CompletionSet::limited(
+            self.complete_all(text, cursor_pos).items,
+            self.config.max_results,
+        )
+    }
+
     fn complete_command_with_info(&self, prefix: &str) -> Vec<CompletionInfo> {
         let mut completions = Vec::new();
 
-        // Add builtins
+        let path_commands = self.path_commands.read().unwrap();
+
+        // Add aliases, which the shell expands before anything else
+        for (name, alias) in &self.aliases {
+            if let Some((score, match_indices)) = self.match_name(prefix, name) {
+                let info = CompletionInfo {
+                    text: name.clone(),
+                    description: Some(format!("alias: {}", alias.expansion)),
+                    is_directory: false,
+                    kind: CompletionKind::Alias,
+                    match_indices,
+                };
+                completions.push((info, score));
+            }
+        }
+
+        // Add builtins, which the shell runs rather than a PATH command of
+        // the same name
         for builtin in &self.builtins {
-            if builtin.starts_with(prefix) {
-                completions.push(CompletionInfo {
+            if self.aliases.contains_key(builtin) {
+                continue;
+            }
+            if let Some((score, match_indices)) = self.match_name(prefix, builtin) {
+                let description = match path_commands.iter().find(|cmd| cmd.name == *builtin) {
+                    Some(cmd) => format!("builtin (also {})", cmd.path.display()),
+                    None => "builtin".to_string(),
+                };
+                let info = CompletionInfo {
                     text: builtin.clone(),
-                    description: Some("builtin".to_string()),
+                    description: Some(description),
                     is_directory: false,
                     kind: CompletionKind::Builtin,
-                });
+                    match_indices,
+                };
+                completions.push((info, score));
             }
         }
 
         // Add PATH commands
-        for cmd in &self.path_commands {
-            if cmd.starts_with(prefix) {
-                completions.push(CompletionInfo {
-                    text: cmd.clone(),
-                    description: Some("command".to_string()),
+        for cmd in path_commands.iter() {
+            if self.builtins.contains(&cmd.name) || self.aliases.contains_key(&cmd.name) {
+                continue;
+            }
+            if let Some((score, match_indices)) = self.match_name(prefix, &cmd.name) {
+                let info = CompletionInfo {
+                    text: cmd.name.clone(),
+                    description: Some(cmd.path.display().to_string()),
                     is_directory: false,
                     kind: CompletionKind::Command,
-                });
+                    match_indices,
+                };
+                completions.push((info, score));
             }
         }
 
-        completions.sort_by(|a, b| a.text.cmp(&b.text));
-        completions.truncate(MAX_COMPLETIONS);
-        completions
+        self.ranked(completions)
     }
 
-    fn complete_path_with_info(&self, prefix: &str) -> Vec<CompletionInfo> {
-        let expanded = self.expand_tilde(prefix);
-        let path = Path::new(&expanded);
-
-        let (dir, file_prefix) = if expanded.ends_with('/') || expanded.ends_with('\\') {
-            (PathBuf::from(&expanded), "")
-        } else if let Some(parent) = path.parent() {
-            let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
-            (parent.to_path_buf(), file_name)
+    /// Complete a file path that is an argument of `command`
+    fn complete_path_with_info(
+        &self,
+        prefix: &str,
+        quoting: QuotingContext,
+        command: Option<&str>,
+    ) -> Vec<CompletionInfo> {
+        // A quote opening the word stays in front of every completion
+        let (quote, prefix) = match (quoting.quote_char(), prefix.chars().next()) {
+            (Some(q), Some(c)) if q == c => prefix.split_at(c.len_utf8()),
+            _ => ("", prefix),
+        };
+        let dirs_only = command.is_some_and(|cmd| DIRECTORY_COMMANDS.contains(&cmd));
+        // A glob completes to what it matches, or else literally
+        if quoting == QuotingContext::None && has_glob_meta(prefix) {
+            let matches = self.complete_glob(prefix, dirs_only);
+            if !matches.is_empty() {
+                return matches;
+            }
+        }
+        // The directory part is kept as typed, so "~/Do" stays "~/Documents/"
+        let windows = cfg!(windows);
+        let user = prefix.strip_prefix('~').filter(|user| !user.contains('/'));
+        if let Some(user) = user.filter(|_| quote.is_empty() && !windows) {
+            let users = self.complete_users(user);
+            if !users.is_empty() {
+                return users;
+            }
+        }
+        let (dir_part, file_prefix) = split_path(prefix, windows);
+        // Backslashes are separators on Windows, not escapes
+        let unquote = |text: &str| {
+            if windows {
+                text.to_string()
+            } else {
+                quoting.unquote(text)
+            }
+        };
+        let file_prefix = unquote(file_prefix);
+        // Variables are expanded to look in the directory but stay as typed
+        // in the completions; Windows shells spell them differently
+        let dir_expanded = if windows {
+            dir_part.to_string()
+        } else {
+            expand_variables(dir_part, quoting, self.environment.as_ref())
+        };
+        // Relative paths are the shell's, not this process's
+        let cwd = self.working_dir();
+        let dir = if dir_part.is_empty() {
+            cwd.clone()
+        } else if quoting == QuotingContext::None {
+            cwd.join(self.expand_tilde(&unquote(&dir_expanded)))
         } else {
-            (PathBuf::from("."), &*expanded)
+            cwd.join(unquote(&dir_expanded))
         };
+        let separator = dir_separator(dir_part, windows);
+
+        // Relative directories for cd are also looked up under $CDPATH
+        let mut bases = vec![(dir, None)];
+        let relative = !(prefix.starts_with(['/', '~', '.'])
+            || dir_expanded.starts_with('/')
+            || windows && has_drive_letter(prefix));
+        if relative && command.is_some_and(|cmd| CDPATH_COMMANDS.contains(&cmd)) {
+            let cdpath = self.environment.get("CDPATH").unwrap_or_default();
+            for base in env::split_paths(&cdpath) {
+                // An empty entry means the current directory, listed already
+                if !base.as_os_str().is_empty() {
+                    let description = format!("in {}", base.display());
+                    let dir = cwd.join(base).join(unquote(&dir_expanded));
+                    bases.push((dir, Some(description)));
+                }
+            }
+        }
 
         let mut completions = Vec::new();
+        let mut seen = HashSet::new();
 
-        if let Ok(entries) = fs::read_dir(&dir) {
-            for entry in entries.filter_map(Result::ok) {
-                let file_name = entry.file_name();
+        for (dir, description) in bases {
+            let entries = match self.dir_cache.list(&dir) {
+                Some(entries) => entries,
+                None => continue,
+            };
+            // Listings leave out "." and "..", so offer them when typed
+            let dots = [".", ".."]
+                .iter()
+                .filter(|&&name| name == file_prefix)
+                .map(|&name| (OsString::from(name), true));
+            let entries = entries
+                .iter()
+                .map(|entry| (entry.name.clone(), entry.is_dir));
+            for (file_name, is_dir) in dots.chain(entries) {
                 let name = file_name.to_string_lossy();
+                if !self.show_hidden.allows(&name, &file_prefix) {
+                    continue;
+                }
 
-                if name.starts_with(file_prefix) {
-                    let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if let Some((score, match_indices)) = self.match_name(&file_prefix, &name) {
+                    if dirs_only && !is_dir {
+                        continue;
+                    }
 
-                    let completion = if prefix.contains('/') {
-                        let parent_str = if dir.to_string_lossy() == "." {
-                            String::new()
+                    // Indices are into the name; map them past the directory
+                    // and any escapes
+                    let mut completion = format!("{}{}", quote, dir_part);
+                    let mut positions = Vec::new();
+                    for c in name.chars() {
+                        if windows {
+                            completion.push(c);
                         } else {
-                            format!("{}/", dir.display())
-                        };
-                        format!("{}{}", parent_str, name)
-                    } else {
-                        name.to_string()
-                    };
-
-                    let completion = if is_dir && !completion.ends_with('/') {
-                        format!("{}/", completion)
-                    } else {
-                        completion
-                    };
+                            quoting.quote_char_into(c, &mut completion);
+                        }
+                        positions.push(completion.chars().count() - 1);
+                    }
+                    let match_indices = match_indices.iter().map(|&i| positions[i]).collect();
+                    if is_dir {
+                        completion.push(separator);
+                    }
+                    if !seen.insert(completion.clone()) {
+                        continue;
+                    }
 
-                    completions.push(CompletionInfo {
+                    let info = CompletionInfo {
                         text: completion,
-                        description: None,
+                        description: description.clone(),
                         is_directory: is_dir,
                         kind: if is_dir {
                             CompletionKind::Directory
                         } else {
                             CompletionKind::File
                         },
-                    });
+                        match_indices,
+                    };
+                    completions.push((info, score));
                 }
             }
         }
 
-        completions.sort_by(|a, b| {
-            // Directories first, then alphabetically
-            match (a.is_directory, b.is_directory) {
-                (true, false) => std::cmp::Ordering::Less,
-                (false, true) => std::cmp::Ordering::Greater,
-                _ => a.text.cmp(&b.text),
-            }
-        });
-        completions.truncate(MAX_COMPLETIONS);
-        completions
+        self.ranked(completions)
     }
 
-    fn complete_variable_with_info(&self, prefix: &str) -> Vec<CompletionInfo> {
+    /// Complete the variable starting at byte `dollar` of `word`, keeping
+    /// what comes before it
+    fn complete_variable_with_info(&self, word: &str, dollar: usize) -> Vec<CompletionInfo> {
+        let (keep, prefix) = word.split_at(dollar);
+        let offset = keep.chars().count();
         let var_prefix = prefix.trim_start_matches('$').trim_start_matches('{');
         let is_braced = prefix.starts_with("${");
 
         let mut completions = Vec::new();
 
-        for (key, value) in env::vars() {
+        for (key, value) in self.environment.vars() {
             if key.starts_with(var_prefix) {
                 let text = if is_braced {
-                    format!("${{{}}}", key)
+                    format!("{}${{{}}}", keep, key)
                 } else {
-                    format!("${}", key)
+                    format!("{}${}", keep, key)
                 };
 
                 // Truncate value for description
-                let desc = if value.len() > 30 {
-                    format!("{}...", &value[..27])
+                let desc = if value.chars().count() > 30 {
+                    format!("{}...", value.chars().take(27).collect::<String>())
                 } else {
                     value
                 };
 
-                completions.push(CompletionInfo {
+                let info = CompletionInfo {
+                    match_indices: (offset..offset + prefix.chars().count()).collect(),
                     text,
                     description: Some(desc),
                     is_directory: false,
                     kind: CompletionKind::Variable,
-                });
+                };
+                completions.push((info, 0));
+            }
+        }
+
+        self.ranked(completions)
+    }
+}
+
+/// Longest prefix shared by the texts of `items`, in the first one's casing
+fn longest_common_prefix(items: &[CompletionInfo], ignore_case: bool) -> &str {
+    let (first, rest) = match items.split_first() {
+        Some(split) => split,
+        None => return "",
+    };
+    let same = |a: char, b: char| {
+        if ignore_case {
+            a.to_lowercase().eq(b.to_lowercase())
+        } else {
+            a == b
+        }
+    };
+    let mut len = first.text.len();
+    for item in rest {
+        len = first
+            .text
+            .char_indices()
+            .zip(item.text.chars())
+            .take_while(|&((_, a), b)| same(a, b))
+            .last()
+            .map_or(0, |((idx, c), _)| idx + c.len_utf8())
+            .min(len);
+    }
+    &first.text[..len]
+}
+
+/// What the first Tab inserts in place of `word`; see Completer::common_prefix
+fn unambiguous_completion(
+    word: &str,
+    items: &[CompletionInfo],
+    ignore_case: bool,
+) -> Option<String> {
+    let first = items.first()?;
+    if items.iter().all(|item| item.text == first.text) {
+        let mut text = first.text.clone();
+        if !first.is_directory {
+            text.push(' ');
+        }
+        return Some(text);
+    }
+    let prefix = longest_common_prefix(items, ignore_case);
+    let extends_word = if ignore_case {
+        prefix.to_lowercase().starts_with(&word.to_lowercase())
+    } else {
+        prefix.starts_with(word)
+    };
+    (extends_word && prefix.chars().count() > word.chars().count()).then(|| prefix.to_string())
+}
+
+/// Find `word` in `text`, ignoring case unless `word` has capitals
+fn smart_case_find(text: &str, word: &str) -> Option<usize> {
+    if word.chars().any(char::is_uppercase) {
+        return text.find(word);
+    }
+    let lower = text.to_lowercase();
+    // Lowercasing can change lengths; only trust the offset if it didn't
+    if lower.len() == text.len() {
+        lower.find(&word.to_lowercase())
+    } else {
+        text.find(word)
+    }
+}
+
+/// Score `candidate` as a fuzzy match for `pattern`
+///
+/// The pattern's chars must appear in order.  Chars at the start of a word
+/// (after a separator, or a capital after a lowercase letter) and runs of
+/// consecutive chars score more; gaps cost a point per skipped char.
+/// Matching ignores case unless the pattern has capitals.  Returns the
+/// best score with the char indices that matched.
+pub fn fuzzy_match(pattern: &str, candidate: &str) -> Option<(i32, Vec<usize>)> {
+    const MATCH: i32 = 16;
+    const CONSECUTIVE: i32 = 12;
+    const FIRST_CHAR: i32 = 16;
+    const WORD_START: i32 = 8;
+    const CAMEL_CASE: i32 = 7;
+    const MAX_LEADING_PENALTY: i32 = 3;
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let chars: Vec<char> = candidate.chars().collect();
+    if pattern.is_empty() {
+        return Some((PREFIX_MATCH_BONUS, Vec::new()));
+    }
+    if pattern.len() > chars.len() {
+        return None;
+    }
+    let case_sensitive = pattern.iter().any(|c| c.is_uppercase());
+    let same = |a: char, b: char| {
+        if case_sensitive {
+            a == b
+        } else {
+            a.to_lowercase().eq(b.to_lowercase())
+        }
+    };
+    let bonus = |j: usize| {
+        if j == 0 {
+            return FIRST_CHAR;
+        }
+        let prev = chars[j - 1];
+        if matches!(prev, '-' | '_' | '.' | '/' | ' ' | ':') {
+            WORD_START
+        } else if prev.is_lowercase() && chars[j].is_uppercase() {
+            CAMEL_CASE
+        } else {
+            0
+        }
+    };
+
+    // best[i][j]: best score with pattern[i] matched at chars[j], and
+    // where pattern[i - 1] was matched for that score
+    let mut best: Vec<Vec<Option<(i32, usize)>>> = vec![vec![None; chars.len()]; pattern.len()];
+    for (i, &p) in pattern.iter().enumerate() {
+        for j in i..chars.len() {
+            if !same(p, chars[j]) {
+                continue;
+            }
+            let here = MATCH + bonus(j);
+            best[i][j] = if i == 0 {
+                Some((here - (j as i32).min(MAX_LEADING_PENALTY), 0))
+            } else {
+                (i - 1..j)
+                    .filter_map(|k| {
+                        let (score, _) = best[i - 1][k]?;
+                        let link = if k + 1 == j {
+                            CONSECUTIVE
+                        } else {
+                            -((j - k - 1) as i32)
+                        };
+                        Some((score + link + here, k))
+                    })
+                    .max_by_key(|&(score, k)| (score, std::cmp::Reverse(k)))
+            };
+        }
+    }
+
+    let last = pattern.len() - 1;
+    let (mut score, mut j) = (0..chars.len())
+        .filter_map(|j| best[last][j].map(|(score, _)| (score, j)))
+        .max_by_key(|&(score, j)| (score, std::cmp::Reverse(j)))?;
+    let mut indices = vec![0; pattern.len()];
+    for i in (0..pattern.len()).rev() {
+        indices[i] = j;
+        if let Some((_, prev)) = best[i][j] {
+            j = prev;
+        }
+    }
+    if pattern.iter().zip(&chars).all(|(&p, &c)| same(p, c)) {
+        score += PREFIX_MATCH_BONUS;
+    }
+    Some((score, indices))
+}
+
+/// Popup list of completions for the word at the cursor
+///
+/// Holds what every frontend needs around Completer: the highlighted item,
+/// cycling with Tab and Shift+Tab, narrowing as the user types, and the
+/// span an accepted item replaces.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionMenu {
+    /// Byte range of the word being completed
+    span: Range<usize>,
+    /// The word as typed
+    word: String,
+    items: Vec<CompletionInfo>,
+    selected: Option<usize>,
+    /// Whether the items matched the word ignoring case
+    ignore_case: bool,
+}
+
+impl CompletionMenu {
+    /// Create a closed menu
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Complete the word before `cursor`, selecting the first item
+    ///
+    /// Returns whether there is anything to show.
+    pub fn open(&mut self, completer: &Completer, text: &str, cursor: usize) -> bool {
+        let result = completer.complete_v2(text, cursor);
+        self.word = text[result.prefix].to_string();
+        self.span = result.span;
+        self.items = result.items;
+        self.ignore_case = completer.ignores_case(&self.word);
+        self.selected = if self.items.is_empty() { None } else { Some(0) };
+        self.is_open()
+    }
+
+    /// Complete again after the text changed, keeping the selected item
+    /// selected if it is still offered
+    pub fn refine(&mut self, completer: &Completer, text: &str, cursor: usize) -> bool {
+        let previous = self.current().map(|item| item.text.clone());
+        self.open(completer, text, cursor);
+        if let Some(previous) = previous {
+            if let Some(idx) = self.items.iter().position(|item| item.text == previous) {
+                self.selected = Some(idx);
             }
         }
+        self.is_open()
+    }
+
+    /// Close the menu
+    pub fn dismiss(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Check if there are items to show
+    pub fn is_open(&self) -> bool {
+        !self.items.is_empty()
+    }
+
+    /// All items, in display order
+    pub fn items(&self) -> &[CompletionInfo] {
+        &self.items
+    }
+
+    /// Index of the highlighted item
+    pub fn selected_index(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// The highlighted item
+    pub fn current(&self) -> Option<&CompletionInfo> {
+        self.items.get(self.selected?)
+    }
+
+    /// Byte range of the word an accepted item replaces
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// Highlight the next item, wrapping to the first
+    pub fn next(&mut self) {
+        if let Some(idx) = self.selected {
+            self.selected = Some((idx + 1) % self.items.len());
+        }
+    }
+
+    /// Highlight the previous item, wrapping to the last
+    pub fn prev(&mut self) {
+        if let Some(idx) = self.selected {
+            self.selected = Some(idx.checked_sub(1).unwrap_or(self.items.len() - 1));
+        }
+    }
+
+    /// Move the highlight down `n` items, stopping at the last
+    pub fn page_down(&mut self, n: usize) {
+        if let Some(idx) = self.selected {
+            self.selected = Some((idx + n).min(self.items.len() - 1));
+        }
+    }
+
+    /// Move the highlight up `n` items, stopping at the first
+    pub fn page_up(&mut self, n: usize) {
+        if let Some(idx) = self.selected {
+            self.selected = Some(idx.saturating_sub(n));
+        }
+    }
+
+    /// What the first Tab inserts in place of the word, as
+    /// Completer::common_prefix
+    ///
+    /// The prefix every item shares if it is longer than the word, or the
+    /// single item followed by a space.
+    pub fn common_prefix(&self) -> Option<String> {
+        unambiguous_completion(&self.word, &self.items, self.ignore_case)
+    }
+
+    /// Insert what all items agree on, returning the text and the span it
+    /// replaces
+    ///
+    /// A single item is accepted whole, closing the menu; otherwise the menu
+    /// stays open over the extended word.  None if nothing can be added.
+    pub fn accept_common_prefix(&mut self) -> Option<(String, Range<usize>)> {
+        let text = unambiguous_completion(&self.word, &self.items, self.ignore_case)?;
+        let span = self.span();
+        if self
+            .items
+            .iter()
+            .all(|item| item.text == self.items[0].text)
+        {
+            self.dismiss();
+        } else {
+            self.span = span.start..span.start + text.len();
+            self.word = text.clone();
+        }
+        Some((text, span))
+    }
 
-        completions.sort_by(|a, b| a.text.cmp(&b.text));
-        completions.truncate(MAX_COMPLETIONS);
-        completions
+    /// Close the menu, returning the highlighted item's text and the span
+    /// it replaces
+    pub fn accept(&mut self) -> Option<(String, Range<usize>)> {
+        let text = self.current()?.text.clone();
+        let span = self.span();
+        self.dismiss();
+        Some((text, span))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::input::arg_complete::CompletionContext;
+    use crate::input::environment::SessionEnvironment;
+    use std::sync::atomic::AtomicUsize;
+
+    fn completer_with(commands: &[&str]) -> Completer {
+        let completer = Completer::new();
+        *completer.path_commands.write().unwrap() = commands
+            .iter()
+            .map(|c| PathCommand {
+                name: c.to_string(),
+                path: PathBuf::from("/usr/bin").join(c),
+            })
+            .collect();
+        completer
+    }
 
     #[test]
     fn test_command_completion() {
-        let mut completer = Completer::new();
-        completer.path_commands = vec!["ls".to_string(), "lsof".to_string(), "grep".to_string()];
+        let completer = completer_with(&["ls", "lsof", "grep"]);
 
         let completions = completer.complete("l", 1);
         assert!(completions.contains(&"ls".to_string()));
@@ -615,9 +2642,1658 @@ mod tests {
     }
 
     #[test]
-    fn test_builtin_completion() {
+    fn test_expand_variables() {
+        env::set_var("CX_EXPAND_A", "/tmp/a b");
+        env::set_var("CX_EXPAND_B", "/c");
+        let none = QuotingContext::None;
+        let env = &ProcessEnvironment;
+        assert_eq!(
+            expand_variables("$CX_EXPAND_A$CX_EXPAND_B/x", none, env),
+            "/tmp/a\\ b/c/x"
+        );
+        assert_eq!(
+            expand_variables("${CX_EXPAND_A}/", QuotingContext::DoubleQuoted, env),
+            "/tmp/a b/"
+        );
+        assert_eq!(
+            expand_variables("$CX_EXPAND_UNSET/${CX_EXPAND_UNSET}/${oops/", none, env),
+            "$CX_EXPAND_UNSET/${CX_EXPAND_UNSET}/${oops/"
+        );
+        assert_eq!(
+            expand_variables("\\$CX_EXPAND_B/$", none, env),
+            "\\$CX_EXPAND_B/$"
+        );
+        assert_eq!(
+            expand_variables("$CX_EXPAND_B", QuotingContext::SingleQuoted, env),
+            "$CX_EXPAND_B"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path_with_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub/files")).unwrap();
+        fs::write(dir.path().join("sub/first.txt"), "").unwrap();
+        env::set_var("CX_TEST_DIR", dir.path());
+        env::set_var("CX_TEST_SUB", "/sub");
         let completer = Completer::new();
-        let completions = completer.complete("cd", 2);
-        assert!(completions.contains(&"cd".to_string()));
+
+        let result = completer.complete_v2("ls $CX_TEST_DIR/su", 18);
+        assert_eq!(result.span, 3..18);
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["$CX_TEST_DIR/sub/"]);
+        assert_eq!(
+            completer.complete("cd ${CX_TEST_DIR}/su", 20),
+            ["${CX_TEST_DIR}/sub/"]
+        );
+        assert_eq!(
+            completer.complete("cat $CX_TEST_DIR$CX_TEST_SUB/fi", 31),
+            [
+                "$CX_TEST_DIR$CX_TEST_SUB/files/",
+                "$CX_TEST_DIR$CX_TEST_SUB/first.txt"
+            ]
+        );
+        assert_eq!(
+            completer.complete("cat \"$CX_TEST_DIR/sub/fir", 25),
+            ["\"$CX_TEST_DIR/sub/first.txt"]
+        );
+        assert!(completer.complete("ls $CX_TEST_UNSET/su", 20).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_session_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("kube")).unwrap();
+        fs::create_dir(dir.path().join("notes")).unwrap();
+        env::set_var("CX_LEAKED_VAR", "1");
+        let session = Arc::new(SessionEnvironment::with_vars([
+            (
+                "CX_SESSION_DIR".to_string(),
+                dir.path().display().to_string(),
+            ),
+            ("HOME".to_string(), dir.path().display().to_string()),
+        ]));
+        let mut completer = Completer::new();
+        completer.set_environment(session.clone());
+
+        assert_eq!(completer.complete("echo $CX_", 9), ["$CX_SESSION_DIR"]);
+        assert!(completer.complete("echo $CX_LEAK", 13).is_empty());
+        assert_eq!(
+            completer.complete("ls $CX_SESSION_DIR/ku", 21),
+            ["$CX_SESSION_DIR/kube/"]
+        );
+        assert_eq!(completer.complete("ls ~/no", 7), ["~/notes/"]);
+
+        // Reports from the shell show up in the next completion
+        session.set("KUBECONFIG", "/tmp/kube");
+        assert_eq!(completer.complete("echo $KUBECON", 13), ["$KUBECONFIG"]);
+        env::remove_var("CX_LEAKED_VAR");
+
+        // Long values are cut at a character, not a byte
+        session.set("CX_WIDE", &"日本語".repeat(20));
+        let items = completer.complete_with_info("echo $CX_WI", 11).items;
+        let description = items[0].description.as_deref().unwrap();
+        assert_eq!(description.chars().count(), 30);
+        assert!(description.ends_with("本語..."));
+
+        // Without HOME, ~ isn't the terminal's own home
+        session.remove("HOME");
+        assert_eq!(completer.expand_tilde("~/notes"), "~/notes");
+    }
+
+    /// Offers the working directory it was given
+    struct CwdCompleter;
+
+    impl ArgCompleter for CwdCompleter {
+        fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+            Some(vec![CompletionInfo {
+                text: ctx.cwd.display().to_string(),
+                description: None,
+                is_directory: true,
+                kind: CompletionKind::Argument,
+                match_indices: Vec::new(),
+            }])
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shell_working_directory() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        fs::create_dir(first.path().join("notes")).unwrap();
+        fs::write(second.path().join("notes.txt"), "").unwrap();
+        let session = Arc::new(SessionEnvironment::new());
+        assert!(session.apply_osc7(&format!("file://host{}", first.path().display())));
+        let mut completer = Completer::new();
+        completer.set_environment(session.clone());
+        completer.register_arg_completer("where", Box::new(CwdCompleter));
+
+        assert_eq!(completer.complete("ls no", 5), ["notes/"]);
+        assert_eq!(completer.complete("ls *te*", 7), ["notes/"]);
+        let items = completer.complete_with_info("where ", 6).items;
+        assert_eq!(items[0].text, first.path().display().to_string());
+
+        // The shell moved on
+        assert!(session.apply_osc7(&format!("file://host{}", second.path().display())));
+        assert_eq!(completer.complete("ls no", 5), ["notes.txt"]);
+    }
+
+    #[test]
+    fn test_builtin_completion() {
+        let completer = Completer::new();
+        let completions = completer.complete("cd", 2);
+        assert!(completions.contains(&"cd".to_string()));
+    }
+
+    #[test]
+    fn test_completion_menu() {
+        let completer = completer_with(&["git", "gitk", "gist", "grep"]);
+        let mut menu = CompletionMenu::new();
+
+        assert!(menu.open(&completer, "gi", 2));
+        let texts: Vec<&str> = menu.items().iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["gist", "git", "gitk"]);
+        assert_eq!(menu.common_prefix(), None);
+        menu.next();
+        assert_eq!(menu.current().unwrap().text, "git");
+
+        // Typing narrows the list and keeps "git" highlighted
+        assert!(menu.refine(&completer, "git", 3));
+        assert_eq!(menu.items().len(), 2);
+        assert_eq!(menu.selected_index(), Some(0));
+        menu.next();
+        menu.next();
+        assert_eq!(menu.current().unwrap().text, "git");
+        menu.prev();
+        assert_eq!(menu.current().unwrap().text, "gitk");
+
+        assert_eq!(menu.accept(), Some(("gitk".to_string(), 0..3)));
+        assert!(!menu.is_open());
+        assert_eq!(menu.current(), None);
+    }
+
+    #[test]
+    fn test_completion_menu_common_prefix_and_paging() {
+        let completer = completer_with(&["cargo-fmt", "cargo-clippy", "cargo", "cat"]);
+        let mut menu = CompletionMenu::new();
+        menu.open(&completer, "echo hi; car", 12);
+        assert_eq!(menu.span(), 9..12);
+        assert_eq!(menu.common_prefix().as_deref(), Some("cargo"));
+
+        menu.page_down(10);
+        assert_eq!(menu.current().unwrap().text, "cargo-fmt");
+        menu.page_up(1);
+        assert_eq!(menu.current().unwrap().text, "cargo-clippy");
+        menu.page_up(10);
+        assert_eq!(menu.current().unwrap().text, "cargo");
+    }
+
+    #[test]
+    fn test_fuzzy_command_ranking() {
+        let mut completer = completer_with(&["desktop-file-validate", "docker", "dmesg", "gdk"]);
+        completer.set_match_mode(MatchMode::Fuzzy);
+        let completions = completer.complete("dk", 2);
+        assert_eq!(completions, ["docker", "desktop-file-validate", "gdk"]);
+
+        // Prefix hits rank above fuzzy ones
+        let completions = completer.complete("de", 2);
+        assert_eq!(
+            completions[1..],
+            ["desktop-file-validate", "dmesg", "docker"]
+        );
+        assert_eq!(fuzzy_match("gti", "git"), None);
+        assert!(fuzzy_match("dckr", "docker").is_some());
+    }
+
+    #[test]
+    fn test_fuzzy_match_indices() {
+        assert_eq!(fuzzy_match("mn", "main.rs").unwrap().1, [0, 3]);
+        // Word starts win over earlier letters mid-word
+        assert_eq!(
+            fuzzy_match("fv", "desktop-file-validate").unwrap().1,
+            [8, 13]
+        );
+        assert_eq!(fuzzy_match("GC", "getCommandGC").unwrap().1, [10, 11]);
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("main.rs"), "").unwrap();
+        fs::write(dir.path().join("mod.rs"), "").unwrap();
+        let mut completer = Completer::new();
+        completer.set_match_mode(MatchMode::Fuzzy);
+        let word = format!("{}/mn", dir.path().display());
+        let completions = completer
+            .complete_with_info(&format!("cat {}", word), 4 + word.len())
+            .items;
+        assert_eq!(completions.len(), 1);
+        let offset = completions[0].text.chars().count() - "main.rs".len();
+        assert_eq!(completions[0].match_indices, [offset, offset + 3]);
+    }
+
+    /// Replace the result's span with its first item
+    fn apply_first(text: &str, result: &CompletionResult) -> String {
+        let mut text = text.to_string();
+        text.replace_range(result.span.clone(), &result.items[0].text);
+        text
+    }
+
+    #[test]
+    fn test_result_span_for_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        fs::create_dir(dir.path().join("src/macros")).unwrap();
+        let completer = Completer::new();
+
+        let src = format!("{}/src/", dir.path().display());
+        let input = format!("cd {}ma", src);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 3..input.len());
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        // cd only offers directories
+        assert_eq!(texts, [src.clone() + "macros/"]);
+        assert_eq!(apply_first(&input, &result), format!("cd {}macros/", src));
+        assert_eq!(completer.complete(&input, input.len()), texts);
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/lib/deep")).unwrap();
+        fs::create_dir(root.join(".git")).unwrap();
+        for file in [
+            "a.rs",
+            "b.rs",
+            ".hidden.rs",
+            "file1.log",
+            "file2.log",
+            "fileA.log",
+            "src/main.rs",
+            "src/lib/mod.rs",
+            "src/lib/deep/x.rs",
+            ".git/hook.rs",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let completer = Completer::new();
+
+        assert_eq!(completer.expand_glob("*.rs", root), ["a.rs", "b.rs"]);
+        assert_eq!(completer.expand_glob(".*.rs", root), [".hidden.rs"]);
+        assert_eq!(
+            completer.expand_glob("file[0-9].log", root),
+            ["file1.log", "file2.log"]
+        );
+        assert_eq!(completer.expand_glob("file[!0-9].log", root), ["fileA.log"]);
+        assert_eq!(
+            completer.expand_glob("**/*.rs", root),
+            [
+                "a.rs",
+                "b.rs",
+                "src/lib/deep/x.rs",
+                "src/lib/mod.rs",
+                "src/main.rs"
+            ]
+        );
+        assert_eq!(
+            completer.expand_glob("src/**", root),
+            [
+                "src/lib",
+                "src/lib/deep",
+                "src/lib/deep/x.rs",
+                "src/lib/mod.rs",
+                "src/main.rs"
+            ]
+        );
+        assert_eq!(completer.expand_glob("s?c/*/", root), ["src/lib/"]);
+        assert!(completer.expand_glob("*.none", root).is_empty());
+        assert!(completer.expand_glob("src/main.rs", root).is_empty());
+    }
+
+    #[test]
+    fn test_result_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..500 {
+            fs::write(dir.path().join(format!("file{:03}", i)), "").unwrap();
+        }
+        let mut completer = Completer::new();
+        let input = format!("cat {}/file", dir.path().display());
+        let cursor = input.len();
+
+        // The default limit leaves most out, and says so
+        let set = completer.complete_with_info(&input, cursor);
+        assert_eq!(set.items.len(), 20);
+        assert_eq!(set.total_matches, 500);
+        assert!(set.truncated);
+
+        completer.set_config(CompleterConfig {
+            max_results: 200,
+            ..CompleterConfig::default()
+        });
+        let first = completer.complete_v2(&input, cursor);
+        assert_eq!(first.span, 4..cursor);
+        assert_eq!(first.items.len(), 200);
+        assert_eq!(first.total_matches, 500);
+        assert!(first.truncated);
+        assert!(first.items[0].text.ends_with("file000"));
+
+        // Later pages come from the first's list, even if files change
+        fs::write(dir.path().join("file500"), "").unwrap();
+        let second = completer.complete_page(&input, cursor, 200, 200);
+        assert_eq!(second.total_matches, 500);
+        assert!(second.items[0].text.ends_with("file200"));
+        assert!(second.truncated);
+        let last = completer.complete_page(&input, cursor, 400, 200);
+        assert_eq!(last.items.len(), 100);
+        assert!(last.items[99].text.ends_with("file499"));
+        assert!(!last.truncated);
+        assert!(completer
+            .complete_page(&input, cursor, 500, 200)
+            .items
+            .is_empty());
+
+        let pages: Vec<CompletionInfo> = vec![first, second, last]
+            .into_iter()
+            .flat_map(|page| page.items)
+            .collect();
+        assert_eq!(
+            pages,
+            completer.complete_page(&input, cursor, 0, 600).items[..500]
+        );
+
+        // A first page starts over
+        let fresh = completer.complete_page(&input, cursor, 0, 10);
+        assert_eq!(fresh.total_matches, 501);
+        assert_eq!(fresh.items.len(), 10);
+    }
+
+    /// "/fake" with a few entries, counting reads and with an mtime to bump
+    #[derive(Debug, Default)]
+    struct FakeFs {
+        reads: Arc<AtomicUsize>,
+        modified: Arc<Mutex<u64>>,
+    }
+
+    impl FileSystem for FakeFs {
+        fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<dir_cache::DirEntry>> {
+            assert_eq!(dir, Path::new("/fake"));
+            self.reads.fetch_add(1, AtomicOrdering::SeqCst);
+            let entry = |name: &str, is_dir| dir_cache::DirEntry {
+                name: name.into(),
+                is_dir,
+                is_exec: false,
+            };
+            Ok(vec![
+                entry("file.txt", false),
+                entry("files", true),
+                entry("final", false),
+                entry(".fig", false),
+                entry("other", false),
+            ])
+        }
+
+        fn modified(&self, _dir: &Path) -> std::io::Result<SystemTime> {
+            let secs = *self.modified.lock().unwrap();
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        }
+
+        fn canonicalize(&self, dir: &Path) -> std::io::Result<PathBuf> {
+            Ok(dir.components().collect())
+        }
+    }
+
+    #[test]
+    fn test_dir_cache() {
+        let fs = FakeFs::default();
+        let reads = fs.reads.clone();
+        let modified = fs.modified.clone();
+        let mut completer = Completer::new();
+        completer.set_file_system(Box::new(fs));
+        let count = || reads.load(AtomicOrdering::SeqCst);
+
+        // Refining the prefix reads the directory once
+        assert_eq!(
+            completer.complete("ls /fake/f", 10),
+            ["/fake/files/", "/fake/file.txt", "/fake/final"]
+        );
+        assert_eq!(
+            completer.complete("ls /fake/fil", 12),
+            ["/fake/files/", "/fake/file.txt"]
+        );
+        assert_eq!(completer.complete("cd /fake/fi", 11), ["/fake/files/"]);
+        assert_eq!(completer.complete("ls /fake/.f", 11), ["/fake/.fig"]);
+        assert_eq!(count(), 1);
+
+        // A changed mtime or a cleared cache reads it again
+        *modified.lock().unwrap() += 1;
+        completer.complete("ls /fake/f", 10);
+        assert_eq!(count(), 2);
+        completer.clear_fs_cache();
+        completer.complete("ls /fake/f", 10);
+        assert_eq!(count(), 3);
+
+        // As does every completion once listings expire at once
+        completer.set_config(CompleterConfig {
+            dir_cache_ttl: Duration::ZERO,
+            ..CompleterConfig::default()
+        });
+        completer.complete("ls /fake/f", 10);
+        completer.complete("ls /fake/fi", 11);
+        assert_eq!(count(), 5);
+    }
+
+    #[test]
+    fn test_dir_cache_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("setup.sh"), "").unwrap();
+        fs::write(dir.path().join(".secret"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("src"), dir.path().join("sources")).unwrap();
+        let mut cached = Completer::new();
+        cached.set_show_hidden(ShowHidden::Always);
+        let mut uncached = cached.clone();
+        uncached.set_config(CompleterConfig {
+            dir_cache_capacity: 0,
+            ..CompleterConfig::default()
+        });
+
+        for word in ["", "s", ".", "se", "src/"] {
+            let input = format!("ls {}/{}", dir.path().display(), word);
+            let with = cached.complete_with_info(&input, input.len());
+            // Twice, so the second comes from the cache
+            assert_eq!(cached.complete_with_info(&input, input.len()), with);
+            assert_eq!(uncached.complete_with_info(&input, input.len()), with);
+        }
+        #[cfg(unix)]
+        {
+            let input = format!("cd {}/so", dir.path().display());
+            assert_eq!(
+                cached.complete_with_info(&input, input.len()).items.len(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_cursor_inside_word() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("makefile"), "").unwrap();
+        let root = format!("{}/", dir.path().display());
+        let mut completer = Completer::new();
+
+        // "ma|kle": the whole word goes, so nothing is left behind
+        let input = format!("cat {}makle -n", root);
+        let cursor = input.len() - "kle -n".len();
+        let result = completer.complete_v2(&input, cursor);
+        assert_eq!(result.prefix, 4..cursor);
+        assert_eq!(result.span, 4..input.len() - 3);
+        assert_eq!(result.items[0].text, format!("{}makefile", root));
+        assert_eq!(
+            apply_first(&input, &result),
+            format!("cat {}makefile -n", root)
+        );
+
+        // Inside quotes the closing quote stays
+        let input = format!("cat \"{}ma\" -n", root);
+        let cursor = input.len() - "\" -n".len();
+        let result = completer.complete_v2(&input, cursor);
+        assert_eq!(result.span, 4..cursor);
+        assert_eq!(
+            apply_first(&input, &result),
+            format!("cat \"{}makefile\" -n", root)
+        );
+        let input = format!("cat \"{}maXY\"", root);
+        let result = completer.complete_v2(&input, input.len() - 3);
+        assert_eq!(result.span, 4..input.len() - 1);
+
+        // A variable name is replaced, not the path after it
+        env::set_var("CX_MIDWORD_VAR", "1");
+        let input = "ls $CX_MIDWO/sub";
+        let result = completer.complete_v2(input, 9);
+        assert_eq!(result.span, 3..12);
+        assert_eq!(apply_first(input, &result), "ls $CX_MIDWORD_VAR/sub");
+        let input = "echo ${CX_MID}x";
+        let result = completer.complete_v2(input, 12);
+        assert_eq!(apply_first(input, &result), "echo ${CX_MIDWORD_VAR}x");
+
+        completer.set_config(CompleterConfig {
+            mid_word: MidWord::InsertAtCursor,
+            ..CompleterConfig::default()
+        });
+        let input = format!("cat {}makle", root);
+        let cursor = input.len() - 3;
+        let result = completer.complete_v2(&input, cursor);
+        assert_eq!(result.span, 4..cursor);
+        assert_eq!(
+            apply_first(&input, &result),
+            format!("cat {}makefilekle", root)
+        );
+    }
+
+    /// Candidates of an embedder, named after it
+    struct NamedSource {
+        name: &'static str,
+        priority: i32,
+        texts: &'static [&'static str],
+    }
+
+    impl CompletionSource for NamedSource {
+        fn provide(&self, _ctx: &CompletionContext) -> Vec<CompletionInfo> {
+            self.texts
+                .iter()
+                .map(|text| CompletionInfo {
+                    text: text.to_string(),
+                    description: Some(self.name.to_string()),
+                    is_directory: false,
+                    kind: CompletionKind::Other,
+                    match_indices: Vec::new(),
+                })
+                .collect()
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+    }
+
+    #[test]
+    fn test_completion_sources() {
+        let mut completer = completer_with(&["deploy", "debug", "ls"]);
+        let agents = completer.register_source(Box::new(NamedSource {
+            name: "agent",
+            priority: 10,
+            texts: &["deploy-agent", "debug", "reviewer"],
+        }));
+        completer.register_source(Box::new(NamedSource {
+            name: "workflow",
+            priority: -5,
+            texts: &["deploy", "delete-branch"],
+        }));
+
+        let items = completer.complete_with_info("de", 2).items;
+        let summary: Vec<(&str, Option<&str>)> = items
+            .iter()
+            .map(|i| (i.text.as_str(), i.description.as_deref()))
+            .collect();
+        // The agent's "debug" wins over the command; the command "deploy"
+        // over the workflow's
+        assert_eq!(
+            summary,
+            [
+                ("debug", Some("agent")),
+                ("deploy-agent", Some("agent")),
+                ("declare", Some("builtin")),
+                ("deploy", Some("/usr/bin/deploy")),
+                ("delete-branch", Some("workflow")),
+            ]
+        );
+        assert_eq!(items[0].kind, CompletionKind::Other);
+        assert_eq!(items[0].match_indices, [0, 1]);
+        assert_eq!(completer.complete("git re", 6)[..2], ["reviewer", "rebase"]);
+
+        assert!(completer.unregister_source(agents));
+        assert!(!completer.unregister_source(agents));
+        assert_eq!(
+            completer.complete("de", 2),
+            ["debug", "declare", "deploy", "delete-branch"]
+        );
+    }
+
+    #[test]
+    fn test_info_agrees_with_plain_completion() {
+        let mut completer = completer_with(&["git", "grep"]);
+        completer.add_history(&[
+            "kubectl get pods".to_string(),
+            "kubectl logs web".to_string(),
+            "git status".to_string(),
+            "kubectl get nodes".to_string(),
+        ]);
+        let inputs = ["ku", "kubectl g", "gi", "git st", "zzz", "echo ku"];
+
+        for history_match in [HistoryMatch::WholeCommand, HistoryMatch::Words] {
+            completer.set_history_match(history_match);
+            for input in inputs {
+                let texts: Vec<String> = completer
+                    .complete_with_info(input, input.len())
+                    .items
+                    .into_iter()
+                    .map(|item| item.text)
+                    .collect();
+                assert_eq!(texts, completer.complete(input, input.len()), "{}", input);
+            }
+        }
+
+        let items = completer.complete_with_info("echo ku", 7).items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].text, "kubectl");
+        assert_eq!(items[0].kind, CompletionKind::History);
+        assert_eq!(items[0].description.as_deref(), Some("used 3 times"));
+        assert_eq!(items[0].match_indices, [0, 1]);
+    }
+
+    #[test]
+    fn test_complete_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("logs")).unwrap();
+        fs::write(dir.path().join("a.log"), "").unwrap();
+        fs::write(dir.path().join("b.log"), "").unwrap();
+        fs::write(dir.path().join("[x]y"), "").unwrap();
+        let mut completer = Completer::new();
+        let base = format!("{}/", dir.path().display());
+
+        let input = format!("rm {}*.log", base);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 3..input.len());
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, [base.clone() + "a.log", base.clone() + "b.log"]);
+        assert_eq!(result.items[0].description.as_deref(), Some("glob match"));
+        assert_eq!(result.items[0].kind, CompletionKind::File);
+
+        let input = format!("cd {}*", base);
+        let items = completer.complete_with_info(&input, input.len()).items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].text, base.clone() + "logs/");
+        assert_eq!(items[0].kind, CompletionKind::Directory);
+
+        // Without matches, the word completes with its characters literal
+        let input = format!("cat {}[x]", base);
+        assert_eq!(
+            completer.complete(&input, input.len()),
+            [base.clone() + "\\[x\\]y"]
+        );
+
+        completer.set_glob_expansion(GlobExpansion::All);
+        let input = format!("rm {}*.log", base);
+        assert_eq!(
+            completer.complete(&input, input.len()),
+            [format!("{0}a.log {0}b.log", base)]
+        );
+    }
+
+    /// Users with homes in a fixture directory
+    #[derive(Debug)]
+    struct FakeHomes(PathBuf);
+
+    impl HomeDirs for FakeHomes {
+        fn users(&self) -> Vec<(String, PathBuf)> {
+            ["alice", "albert", "bob"]
+                .iter()
+                .map(|name| (name.to_string(), self.0.join(name)))
+                .collect()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tilde_user() {
+        let homes = tempfile::tempdir().unwrap();
+        fs::create_dir_all(homes.path().join("alice").join("src")).unwrap();
+        fs::create_dir_all(homes.path().join("alice").join("Documents")).unwrap();
+        let mut completer = Completer::new();
+        completer.set_home_dirs(Box::new(FakeHomes(homes.path().to_path_buf())));
+
+        let result = completer.complete_v2("cd ~al", 6);
+        assert_eq!(result.span, 3..6);
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["~albert/", "~alice/"]);
+        assert_eq!(result.items[1].kind, CompletionKind::Directory);
+        assert_eq!(result.items[1].match_indices, [0, 1, 2]);
+        let home = homes.path().join("alice");
+        assert_eq!(
+            result.items[1].description.as_deref(),
+            Some(home.to_str().unwrap())
+        );
+
+        // Paths under ~user keep that form
+        assert_eq!(completer.complete("ls ~alice/s", 11), ["~alice/src/"]);
+        assert_eq!(
+            completer.expand_tilde("~alice/src"),
+            format!("{}/src", home.display())
+        );
+        assert_eq!(completer.expand_tilde("~carol/src"), "~carol/src");
+        assert!(completer.complete("ls ~carol/", 10).is_empty());
+    }
+
+    #[test]
+    fn test_result_span_for_variable_and_home() {
+        let home = tempfile::tempdir().unwrap();
+        fs::create_dir(home.path().join("Documents")).unwrap();
+        fs::create_dir(home.path().join("Downloads")).unwrap();
+        let mut completer = Completer::new();
+        completer.set_environment(Arc::new(SessionEnvironment::with_vars([(
+            "HOME".to_string(),
+            home.path().display().to_string(),
+        )])));
+
+        let result = completer.complete_v2("echo $HO", 8);
+        assert_eq!(result.span, 5..8);
+        assert!(result.items.iter().any(|i| i.text == "$HOME"));
+        assert!(apply_first("echo $HO", &result).starts_with("echo $HO"));
+
+        // The cursor may sit before the end of the input
+        let result = completer.complete_v2("ls ~/Do | wc", 7);
+        assert_eq!(result.span, 3..7);
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["~/Documents/", "~/Downloads/"]);
+        assert_eq!(apply_first("ls ~/Do | wc", &result), "ls ~/Documents/ | wc");
+    }
+
+    #[test]
+    fn test_common_prefix_on_first_tab() {
+        let completer = completer_with(&["docker", "dockerd", "docker-compose"]);
+        assert_eq!(completer.common_prefix("do", 2).as_deref(), Some("docker"));
+        assert_eq!(completer.common_prefix("docker", 6), None);
+        assert_eq!(
+            completer.common_prefix("dockerd", 7).as_deref(),
+            Some("dockerd ")
+        );
+
+        let mut menu = CompletionMenu::new();
+        menu.open(&completer, "ls; do", 6);
+        assert_eq!(
+            menu.accept_common_prefix(),
+            Some(("docker".to_string(), 4..6))
+        );
+        assert!(menu.is_open());
+        assert_eq!(menu.span(), 4..10);
+        assert_eq!(menu.accept_common_prefix(), None);
+    }
+
+    #[test]
+    fn test_common_prefix_single_directory_and_case() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("usr/local")).unwrap();
+        fs::create_dir(dir.path().join("usr/lib")).unwrap();
+        let root = dir.path().display();
+        let completer = Completer::new();
+        let input = format!("ls {}/usr/lo", root);
+        assert_eq!(
+            completer.common_prefix(&input, input.len()),
+            Some(format!("{}/usr/local/", root))
+        );
+
+        let mut menu = CompletionMenu::new();
+        menu.open(&completer, &input, input.len());
+        let (text, span) = menu.accept_common_prefix().unwrap();
+        assert_eq!(
+            (text, span),
+            (format!("{}/usr/local/", root), 3..input.len())
+        );
+        assert!(!menu.is_open());
+
+        // Case-insensitive matching inserts the first candidate's casing
+        let mut completer = completer_with(&["Makefile.am", "makefile.in"]);
+        completer.set_match_mode(MatchMode::Substring);
+        assert_eq!(
+            completer.common_prefix("mak", 3).as_deref(),
+            Some("Makefile.")
+        );
+        let mut menu = CompletionMenu::new();
+        menu.open(&completer, "mak", 3);
+        assert_eq!(menu.common_prefix().as_deref(), Some("Makefile."));
+        completer.set_match_mode(MatchMode::Prefix);
+        assert_eq!(
+            completer.common_prefix("mak", 3).as_deref(),
+            Some("makefile.in ")
+        );
+    }
+
+    #[cfg(unix)]
+    fn fake_path(names: &[&str]) -> tempfile::TempDir {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        for name in names {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(dir.path().join("README"), "").unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_async_cache_refresh() {
+        let dir = fake_path(&["frobnicate", "frobozz"]);
+        let mut completer = Completer::new();
+        completer.set_search_path(Some(dir.path().as_os_str().to_owned()));
+
+        // Completion uses the empty cache rather than scanning
+        let result = completer.complete_v2("frob", 4);
+        assert!(result.items.is_empty());
+        assert!(result.possibly_stale);
+
+        assert!(completer.refresh_cache_async().join().unwrap());
+        let result = completer.complete_v2("frob", 4);
+        let texts: Vec<&str> = result.items.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["frobnicate", "frobozz"]);
+        assert!(!result.possibly_stale);
+
+        // Installing a command shows up only after the next refresh
+        let new = fake_path(&["frobulate"]);
+        completer.set_search_path(Some(new.path().as_os_str().to_owned()));
+        assert_eq!(completer.complete("frob", 4).len(), 2);
+        completer.refresh_cache();
+        assert_eq!(completer.complete("frob", 4), ["frobulate"]);
+        assert!(completer.complete("READ", 4).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_commands_deduplicated_by_precedence() {
+        let first = fake_path(&["echo", "frobnicate"]);
+        let second = fake_path(&["frobnicate", "frobozz"]);
+        let path = env::join_paths([first.path(), second.path()]).unwrap();
+        let mut completer = Completer::new();
+        completer.set_search_path(Some(path));
+        completer.refresh_cache();
+
+        let items = completer.complete_with_info("ec", 2).items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].kind, CompletionKind::Builtin);
+        assert_eq!(
+            items[0].description,
+            Some(format!(
+                "builtin (also {})",
+                first.path().join("echo").display()
+            ))
+        );
+
+        let items = completer.complete_with_info("frob", 4).items;
+        let found: Vec<_> = items
+            .iter()
+            .map(|i| {
+                (
+                    i.text.as_str(),
+                    PathBuf::from(i.description.as_ref().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("frobnicate", first.path().join("frobnicate")),
+                ("frobozz", second.path().join("frobozz")),
+            ]
+        );
+        assert_eq!(completer.complete("frob", 4), ["frobnicate", "frobozz"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancelled_scan() {
+        let dir = fake_path(&["frobnicate"]);
+        let path = dir.path().as_os_str().to_owned();
+        assert_eq!(
+            Completer::scan_path_commands(&path, &AtomicBool::new(false), true),
+            Some(vec![PathCommand {
+                name: "frobnicate".to_string(),
+                path: dir.path().join("frobnicate"),
+            }])
+        );
+        assert_eq!(
+            Completer::scan_path_commands(&path, &AtomicBool::new(true), true),
+            None
+        );
+
+        // Starting a scan cancels the one in flight
+        let mut completer = Completer::new();
+        completer.set_search_path(Some(path));
+        let first = completer.begin_scan();
+        completer.refresh_cache();
+        assert!(first.load(AtomicOrdering::Relaxed));
+        assert!(completer.is_cache_valid());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_maybe_refresh_picks_up_new_commands() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = fake_path(&["frobnicate"]);
+        let mut completer = Completer::new();
+        completer.set_search_path(Some(dir.path().as_os_str().to_owned()));
+        let start = Instant::now();
+
+        // The first check always scans
+        assert!(completer.maybe_refresh_at(start).unwrap().join().unwrap());
+        let later = start + PATH_CHECK_INTERVAL;
+        assert!(completer.maybe_refresh_at(later).is_none());
+
+        let path = dir.path().join("frobulate");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        // Rate limited until the interval has passed
+        assert!(completer
+            .maybe_refresh_at(later + Duration::from_secs(1))
+            .is_none());
+        let handle = completer
+            .maybe_refresh_at(later + PATH_CHECK_INTERVAL)
+            .unwrap();
+        assert!(handle.join().unwrap());
+        assert_eq!(completer.complete("frob", 4), ["frobnicate", "frobulate"]);
+
+        // An explicit bust rescans even though nothing changed
+        completer.invalidate_cache();
+        assert!(!completer.is_cache_valid());
+        assert!(completer
+            .maybe_refresh_at(later + PATH_CHECK_INTERVAL)
+            .is_some());
+    }
+
+    #[test]
+    fn test_cursor_inside_multibyte_char() {
+        let text = "héllo wörld 日本語";
+        let completer = completer_with(&[]);
+        for cursor in 0..text.len() + 3 {
+            let result = completer.complete_v2(text, cursor);
+            assert!(text.is_char_boundary(result.span.end), "cursor {}", cursor);
+            assert!(
+                !text[result.span.clone()].contains(' '),
+                "cursor {}",
+                cursor
+            );
+            completer.complete(text, cursor);
+            completer.complete_with_info(text, cursor);
+            completer.get_completion(text, cursor, 0);
+            completer.common_prefix(text, cursor);
+        }
+
+        // Inside "é" completes "h", inside "ö" "w" and inside "本" "日",
+        // replacing the whole word
+        assert_eq!(completer.complete_v2(text, 2).prefix, 0..1);
+        assert_eq!(completer.complete_v2(text, 18).prefix, 14..17);
+        assert_eq!(completer.complete_v2(text, 9).prefix, 7..8);
+        assert_eq!(completer.complete_v2(text, 9).span, 7..13);
+        assert_eq!(completer.complete_v2(text, 99).span, 14..text.len());
+    }
+
+    #[test]
+    fn test_path_completions_are_escaped() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("My Documents")).unwrap();
+        fs::write(dir.path().join("it's here.txt"), "").unwrap();
+        fs::write(dir.path().join("$money"), "").unwrap();
+        let root = format!("{}/", dir.path().display());
+        let completer = Completer::new();
+        let complete = |typed: &str| {
+            let input = format!("ls {}", typed);
+            let result = completer.complete_v2(&input, input.len());
+            assert_eq!(result.span, 3..input.len());
+            result.items.into_iter().map(|i| i.text).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            complete(&format!("{}My", root)),
+            [format!("{}My\\ Documents/", root)]
+        );
+        assert_eq!(
+            complete(&format!("{}it", root)),
+            [format!("{}it\\'s\\ here.txt", root)]
+        );
+        assert_eq!(
+            complete(&format!("{}\\$mo", root)),
+            [format!("{}\\$money", root)]
+        );
+        assert_eq!(complete(&format!("{}it\\'s", root)).len(), 1);
+
+        assert_eq!(
+            complete(&format!("'{}My", root)),
+            [format!("'{}My Documents/", root)]
+        );
+        assert_eq!(
+            complete(&format!("'{}it", root)),
+            [format!("'{}it'\\''s here.txt", root)]
+        );
+        assert_eq!(
+            complete(&format!("'{}$mo", root)),
+            [format!("'{}$money", root)]
+        );
+
+        assert_eq!(
+            complete(&format!("\"{}My", root)),
+            [format!("\"{}My Documents/", root)]
+        );
+        assert_eq!(
+            complete(&format!("\"{}it", root)),
+            [format!("\"{}it's here.txt", root)]
+        );
+        assert_eq!(
+            complete(&format!("\"{}\\$mo", root)),
+            [format!("\"{}\\$money", root)]
+        );
+    }
+
+    #[test]
+    fn test_quoting_context() {
+        assert_eq!(QuotingContext::detect("ls foo"), QuotingContext::None);
+        assert_eq!(
+            QuotingContext::detect("ls 'a b"),
+            QuotingContext::SingleQuoted
+        );
+        assert_eq!(
+            QuotingContext::detect("ls \"it's"),
+            QuotingContext::DoubleQuoted
+        );
+        assert_eq!(QuotingContext::detect("ls \\'a"), QuotingContext::None);
+        assert_eq!(
+            QuotingContext::detect("ls \"a\\\"b"),
+            QuotingContext::DoubleQuoted
+        );
+        assert_eq!(QuotingContext::detect("ls 'a\\' b"), QuotingContext::None);
+
+        // Matched chars are found past the escapes
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a(1) b"), "").unwrap();
+        let completer = Completer::new();
+        let input = format!("ls {}/a\\(1", dir.path().display());
+        let item = &completer.complete_with_info(&input, input.len()).items[0];
+        let chars: Vec<char> = item.text.chars().collect();
+        let matched: String = item.match_indices.iter().map(|&i| chars[i]).collect();
+        assert_eq!(matched, "a(1");
+        assert!(item.text.ends_with("a\\(1\\)\\ b"));
+    }
+
+    #[test]
+    fn test_complete_inside_quotes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("My Documents")).unwrap();
+        fs::write(dir.path().join("it's late.txt"), "").unwrap();
+        let root = format!("{}/", dir.path().display());
+        let completer = Completer::new();
+
+        let input = format!("echo \"{}My ", root);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 5..input.len());
+        assert_eq!(result.items[0].text, format!("\"{}My Documents/", root));
+
+        let input = format!("cat x | wc '{}it", root);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 11..input.len());
+        assert_eq!(result.items[0].text, format!("'{}it'\\''s late.txt", root));
+
+        // Escaped breaks stay in the word
+        let input = format!("ls {}My\\ Do", root);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 3..input.len());
+        assert_eq!(result.items[0].text, format!("{}My\\ Documents/", root));
+
+        std::env::set_var("CX_QUOTED_TEST", "1");
+        let input = "echo \"hello; $CX_QUOTED_T";
+        let result = completer.complete_v2(input, input.len());
+        assert_eq!(result.span, 5..input.len());
+        assert_eq!(result.items[0].text, "\"hello; $CX_QUOTED_TEST");
+        assert_eq!(result.items[0].match_indices, (8..20).collect::<Vec<_>>());
+        assert!(completer.complete("echo '$CX_QUOTED_T", 18).is_empty());
+        std::env::remove_var("CX_QUOTED_TEST");
+    }
+
+    #[test]
+    fn test_windows_path_splitting() {
+        assert_eq!(
+            split_path(r"C:\Users\me\Doc", true),
+            (r"C:\Users\me\", "Doc")
+        );
+        assert_eq!(split_path("C:Doc", true), ("C:", "Doc"));
+        assert_eq!(
+            split_path(r"\\server\share\fo", true),
+            (r"\\server\share\", "fo")
+        );
+        assert_eq!(split_path("C:/Users/me", true), ("C:/Users/", "me"));
+        assert_eq!(split_path(r"a\ b", false), ("", r"a\ b"));
+        assert_eq!(split_path("C:Doc", false), ("", "C:Doc"));
+
+        assert_eq!(dir_separator(r"C:\Users\", true), '\\');
+        assert_eq!(dir_separator("C:/Users/", true), '/');
+        assert_eq!(dir_separator("", true), '\\');
+        assert_eq!(dir_separator(r"C:\", false), '/');
+    }
+
+    #[test]
+    fn test_pathext_command_names() {
+        let pathext: Vec<String> = DEFAULT_PATHEXT.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            pathext_command_name("git.EXE", &pathext, true).as_deref(),
+            Some("git")
+        );
+        assert_eq!(
+            pathext_command_name("build.cmd", &pathext, false).as_deref(),
+            Some("build.cmd")
+        );
+        assert_eq!(
+            pathext_command_name("setup.ps1", &pathext, true).as_deref(),
+            Some("setup")
+        );
+        assert_eq!(pathext_command_name("notes.txt", &pathext, true), None);
+        assert_eq!(pathext_command_name(".exe", &pathext, true), None);
+        assert_eq!(pathext_command_name("README", &pathext, true), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_path_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("Documents")).unwrap();
+        fs::write(dir.path().join("My File.txt"), "").unwrap();
+        let root = format!("{}\\", dir.path().display());
+        let completer = Completer::new();
+
+        let input = format!("dir {}Doc", root);
+        assert_eq!(
+            completer.complete(&input, input.len()),
+            [format!("{}Documents\\", root)]
+        );
+        let input = format!("type {}My", root);
+        assert_eq!(
+            completer.complete(&input, input.len()),
+            [format!("{}My File.txt", root)]
+        );
+    }
+
+    #[test]
+    fn test_cd_completes_directories_from_cdpath() {
+        let cwd = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir_all(cwd.path().join("proj-local/sub")).unwrap();
+        fs::write(cwd.path().join("proj-notes.txt"), "").unwrap();
+        fs::create_dir(work.path().join("proj-alpha")).unwrap();
+        fs::write(work.path().join("proj-todo"), "").unwrap();
+        fs::create_dir(src.path().join("proj-beta")).unwrap();
+        fs::create_dir(src.path().join("proj-alpha")).unwrap();
+        let cdpath = env::join_paths([work.path(), src.path()]).unwrap();
+        let mut completer = Completer::new();
+        completer.set_environment(Arc::new(SessionEnvironment::with_vars([(
+            "CDPATH".to_string(),
+            cdpath.to_string_lossy().into_owned(),
+        )])));
+
+        let root = format!("{}/", cwd.path().display());
+        let input = format!("cd {}proj", root);
+        let texts = completer.complete(&input, input.len());
+        assert_eq!(texts, [format!("{}proj-local/", root)]);
+        let input = format!("ls {}proj", root);
+        assert_eq!(completer.complete(&input, input.len()).len(), 2);
+
+        // Relative names come from each CDPATH root, once each
+        let items = completer.complete_with_info("make; pushd proj-", 17).items;
+        let found: Vec<(&str, Option<&str>)> = items
+            .iter()
+            .filter(|i| i.description.is_some())
+            .map(|i| (i.text.as_str(), i.description.as_deref()))
+            .collect();
+        let in_work = format!("in {}", work.path().display());
+        let in_src = format!("in {}", src.path().display());
+        assert_eq!(
+            found,
+            [
+                ("proj-alpha/", Some(in_work.as_str())),
+                ("proj-beta/", Some(in_src.as_str()))
+            ]
+        );
+        assert!(completer
+            .complete("rmdir proj-", 11)
+            .iter()
+            .all(|t| !t.contains("proj-alpha")));
+    }
+
+    /// Offers branch names after "checkout", and has no opinion otherwise
+    struct FakeGitCompleter;
+
+    impl ArgCompleter for FakeGitCompleter {
+        fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+            if ctx.previous_arg() != Some("checkout") {
+                return None;
+            }
+            let items = ["main", "feature"]
+                .iter()
+                .filter(|branch| branch.starts_with(ctx.word))
+                .map(|branch| CompletionInfo {
+                    text: branch.to_string(),
+                    description: Some(format!("branch ({} args)", ctx.args.len())),
+                    is_directory: false,
+                    kind: CompletionKind::Argument,
+                    match_indices: Vec::new(),
+                })
+                .collect();
+            Some(items)
+        }
+    }
+
+    #[test]
+    fn test_arg_completer_dispatch_and_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("main.rs"), "").unwrap();
+        let root = format!("{}/", dir.path().display());
+        let mut completer = Completer::new();
+        completer.register_arg_completer("git", Box::new(FakeGitCompleter));
+
+        let result = completer.complete_v2("ls | git checkout ma", 20);
+        assert_eq!(result.span, 18..20);
+        assert_eq!(result.items[0].text, "main");
+        assert_eq!(
+            result.items[0].description.as_deref(),
+            Some("branch (1 args)")
+        );
+        let items = completer.complete_with_info("git checkout f", 14).items;
+        assert_eq!(items[0].text, "feature");
+
+        // No opinion falls back to paths, an empty answer doesn't
+        let input = format!("git add {}ma", root);
+        assert_eq!(
+            completer.complete(&input, input.len()),
+            [format!("{}main.rs", root)]
+        );
+        let input = format!("git checkout {}ma", root);
+        assert!(completer.complete(&input, input.len()).is_empty());
+
+        // kill -s ships registered
+        assert_eq!(completer.complete("kill -s HU", 10), ["HUP"]);
+    }
+
+    fn aliases(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, expansion)| (name.to_string(), expansion.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_aliases_complete_and_expand() {
+        let mut completer = completer_with(&["kubectl", "kill", "grep"]);
+        completer.register_arg_completer("git", Box::new(FakeGitCompleter));
+        completer.set_aliases(aliases(&[
+            ("k", "kubectl"),
+            ("g", "git"),
+            ("gco", "g checkout"),
+            ("grep", "grep --color=auto"),
+            ("loop", "other"),
+            ("other", "loop -v"),
+        ]));
+
+        let items = completer.complete_with_info("k", 1).items;
+        assert_eq!(items[0].text, "k");
+        assert_eq!(items[0].kind, CompletionKind::Alias);
+        assert_eq!(items[0].description.as_deref(), Some("alias: kubectl"));
+
+        // The alias shadows the binary of the same name
+        let items = completer.complete_with_info("gre", 3).items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].kind, CompletionKind::Alias);
+
+        // Nested aliases reach the real command and its arguments
+        let items = completer.complete_with_info("gco ma", 6).items;
+        assert_eq!(items[0].text, "main");
+        assert_eq!(items[0].description.as_deref(), Some("branch (1 args)"));
+        assert_eq!(completer.complete("sudo g checkout f", 17), ["feature"]);
+
+        // Recursive aliases stop expanding; "loop" is then the command
+        assert_eq!(
+            completer.resolve_command("loop x ", 7),
+            Some(("loop", vec!["-v", "x"], false))
+        );
+        assert_eq!(
+            completer.resolve_command("grep x ", 7),
+            Some(("grep", vec!["--color=auto", "x"], false))
+        );
+    }
+
+    #[test]
+    fn test_parse_alias_output() {
+        let bash = "alias gs='git status'\nalias k='kubectl'\nalias say='echo it'\\''s'\n";
+        assert_eq!(
+            parse_alias_output(bash),
+            aliases(&[("gs", "git status"), ("k", "kubectl"), ("say", "echo it's")])
+        );
+        let zsh = "ll='ls -l'\nk=kubectl\n\nnot an alias\n";
+        assert_eq!(
+            parse_alias_output(zsh),
+            aliases(&[("ll", "ls -l"), ("k", "kubectl")])
+        );
+    }
+
+    #[test]
+    fn test_frecency_ranking() {
+        let now = Arc::new(Mutex::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30),
+        ));
+        let mut store = FrecencyStore::in_memory();
+        let clock = Arc::clone(&now);
+        store.set_clock(move || *clock.lock().unwrap());
+        let mut completer = completer_with(&["gimp", "git", "gzip", "itail"]);
+        completer.set_frecency_store(store);
+        assert_eq!(completer.complete("gi", 2), ["gimp", "git"]);
+
+        let git = completer.complete_with_info("gi", 2).items.remove(1);
+        for _ in 0..3 {
+            completer.record_accepted(&git);
+        }
+        assert_eq!(completer.complete("gi", 2), ["git", "gimp"]);
+
+        // Prefix matches still beat a frecent substring match
+        completer.set_match_mode(MatchMode::Substring);
+        assert_eq!(completer.complete("it", 2)[..3], ["itail", "git", "exit"]);
+
+        // Long unused, git is forgotten
+        *now.lock().unwrap() += Duration::from_secs(365 * 24 * 60 * 60);
+        completer.set_match_mode(MatchMode::Prefix);
+        assert_eq!(completer.complete("gi", 2), ["gimp", "git"]);
+    }
+
+    #[test]
+    fn test_history_offers_whole_commands() {
+        let mut completer = completer_with(&["docker"]);
+        completer.add_history(
+            &[
+                "docker compose up -d",
+                "git status",
+                "docker compose up -d",
+                "docker compose logs -f web",
+                "docker compose up -d",
+                "docker compose ps",
+                "cargo test --workspace",
+            ]
+            .map(String::from),
+        );
+
+        // Prefix matches, most used first, then most recent
+        let result = completer.complete_v2("docker comp", 11);
+        assert_eq!(result.span, 0..11);
+        let found: Vec<_> = result
+            .items
+            .iter()
+            .map(|i| (i.text.as_str(), i.description.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("docker compose up -d", "used 3 times"),
+                ("docker compose ps", "used once"),
+                ("docker compose logs -f web", "used once"),
+            ]
+        );
+        assert_eq!(result.items[0].match_indices, (0..11).collect::<Vec<_>>());
+
+        // Nothing starts with the text, so look inside commands
+        let result = completer.complete_v2("  work", 6);
+        assert_eq!(result.span, 2..6);
+        assert_eq!(result.items[0].text, "cargo test --workspace");
+        assert_eq!(result.items[0].match_indices, (13..17).collect::<Vec<_>>());
+
+        // Commands still come first in command position
+        assert_eq!(completer.complete("dock", 4), ["docker"]);
+
+        completer.set_history_match(HistoryMatch::Words);
+        let result = completer.complete_v2("echo comp", 9);
+        assert_eq!(result.span, 5..9);
+        assert_eq!(result.items[0].text, "compose");
+    }
+
+    #[test]
+    fn test_complete_and_menu_agree_on_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["Zeta", "beta", "alpha.txt", "Gamma.txt", "delta.md"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        for name in ["src", "Docs", "target"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        let root = format!("{}/", dir.path().display());
+        let mut completer = Completer::new();
+
+        let input = format!("ls {}", root);
+        let names: Vec<String> = completer
+            .complete(&input, input.len())
+            .iter()
+            .map(|n| n[root.len()..].to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Docs/",
+                "src/",
+                "target/",
+                "alpha.txt",
+                "beta",
+                "delta.md",
+                "Gamma.txt",
+                "Zeta"
+            ]
+        );
+
+        for mode in [MatchMode::Prefix, MatchMode::Substring, MatchMode::Fuzzy] {
+            completer.set_match_mode(mode);
+            for typed in ["", "t", "ta", "e"] {
+                let input = format!("ls {}{}", root, typed);
+                let texts: Vec<String> = completer
+                    .complete_with_info(&input, input.len())
+                    .items
+                    .into_iter()
+                    .map(|info| info.text)
+                    .collect();
+                assert_eq!(completer.complete(&input, input.len()), texts);
+            }
+        }
+
+        // Prefix matches come before substring matches
+        completer.set_match_mode(MatchMode::Substring);
+        let input = format!("ls {}ta", root);
+        let first = &completer.complete(&input, input.len())[0];
+        assert_eq!(first, &format!("{}target/", root));
+    }
+
+    #[test]
+    fn test_hidden_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [".bashrc", ".config", "bin", "notes.txt"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let root = format!("{}/", dir.path().display());
+        let mut completer = Completer::new();
+        let names = |completer: &Completer, typed: &str| -> Vec<String> {
+            let input = format!("ls {}{}", root, typed);
+            let names = completer.complete(&input, input.len());
+            names.iter().map(|n| n[root.len()..].to_string()).collect()
+        };
+
+        assert_eq!(names(&completer, ""), ["bin", "notes.txt"]);
+        assert_eq!(names(&completer, ".b"), [".bashrc"]);
+        assert_eq!(names(&completer, "."), ["./", ".bashrc", ".config"]);
+        assert_eq!(names(&completer, ".."), ["../"]);
+
+        completer.set_show_hidden(ShowHidden::Always);
+        assert_eq!(
+            names(&completer, ""),
+            [".bashrc", ".config", "bin", "notes.txt"]
+        );
+        completer.set_show_hidden(ShowHidden::Never);
+        assert!(names(&completer, ".b").is_empty());
+        assert_eq!(names(&completer, ".."), ["../"]);
+    }
+
+    #[test]
+    fn test_redirection_targets_complete_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("output.log"), "").unwrap();
+        let root = format!("{}/", dir.path().display());
+        let mut completer = completer_with(&["cat", "sort", "outdated"]);
+        completer.register_arg_completer("git", Box::new(FakeGitCompleter));
+
+        for op in [">", ">>", "2>", "&>", "<"] {
+            for space in ["", " "] {
+                let input = format!("cat foo {}{}{}", op, space, root);
+                let result = completer.complete_v2(&format!("{}o", input), input.len() + 1);
+                assert_eq!(result.span.start, input.len() - root.len(), "{:?}", input);
+                assert_eq!(result.items.len(), 1);
+                assert_eq!(result.items[0].text, format!("{}output.log", root));
+            }
+        }
+
+        // Bare names complete in the working directory, the package root
+        // under cargo test, and there's no command position after `>`
+        for input in [
+            "sort <Cargo.t",
+            ">Cargo.t",
+            "git checkout > Cargo.t",
+            "ls 2>&1 >Cargo.t",
+        ] {
+            assert_eq!(completer.complete(input, input.len()), ["Cargo.toml"]);
+        }
+
+        // Redirections aren't arguments, and 2>&1 doesn't end the command
+        let items = completer
+            .complete_with_info("git 2>&1 <in checkout f", 23)
+            .items;
+        assert_eq!(items[0].description.as_deref(), Some("branch (1 args)"));
+    }
+
+    /// Offers the values of `--color=`, given in the same word
+    struct ColorCompleter;
+
+    impl ArgCompleter for ColorCompleter {
+        fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+            let typed = ctx.word.strip_prefix("--color=")?;
+            let items = ["always", "auto", "never"]
+                .iter()
+                .filter(|value| value.starts_with(typed))
+                .map(|value| CompletionInfo {
+                    text: format!("--color={}", value),
+                    description: None,
+                    is_directory: false,
+                    kind: CompletionKind::Argument,
+                    match_indices: Vec::new(),
+                })
+                .collect();
+            Some(items)
+        }
+    }
+
+    #[test]
+    fn test_word_breaks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("local")).unwrap();
+        let root = format!("{}/", dir.path().display());
+        let mut completer = completer_with(&["cat", "diff", "sort"]);
+        completer.register_arg_completer("ls", Box::new(ColorCompleter));
+
+        // The value of an assignment is a path of its own
+        let input = format!("FOO={}lo", root);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, 4..input.len());
+        assert_eq!(result.items[0].text, format!("{}local/", root));
+
+        // Commands start again inside $( and <(
+        let result = completer.complete_v2("echo $(ca", 9);
+        assert_eq!(result.span, 7..9);
+        assert!(result.items.iter().any(|item| item.text == "cat"));
+        assert_eq!(completer.complete("diff <(sor", 10), ["sort"]);
+        let text = "diff <(sort a";
+        let start = scan_quotes(text, DEFAULT_WORD_BREAKS).1;
+        assert_eq!(start, 12);
+        assert!(!is_redirection_target(text, start));
+        assert_eq!(
+            completer
+                .resolve_command(text, start)
+                .map(|(command, ..)| command),
+            Some("sort")
+        );
+
+        // An option keeps its "=" for the completer that knows it
+        let result = completer.complete_v2("ls --color=al", 13);
+        assert_eq!(result.span, 3..13);
+        assert_eq!(result.items[0].text, "--color=always");
+
+        // Configured breaks, which may take more than one byte
+        completer.set_config(CompleterConfig {
+            word_breaks: "|;\u{2192}".to_string(),
+            ..CompleterConfig::default()
+        });
+        let input = format!("echo x\u{2192}{}lo", root);
+        let result = completer.complete_v2(&input, input.len());
+        assert_eq!(result.span, "echo x\u{2192}".len()..input.len());
+        assert_eq!(result.items[0].text, format!("{}local/", root));
+        let result = completer.complete_v2("FOO=/x", 6);
+        assert_eq!(result.span, 0..6);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bash_bridge_for_commands_without_a_completer() {
+        use crate::input::bash_bridge::SystemBash;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("bash_completion");
+        fs::write(
+            &script,
+            "_foo() { COMPREPLY=($(compgen -W 'start stop status' -- \"$2\")); }\n\
+             complete -F _foo foo\n",
+        )
+        .unwrap();
+        let mut bridge = BashCompleterBridge::with_runner(Box::new(SystemBash::default()));
+        bridge.set_script(&script);
+        let mut completer = Completer::new();
+        completer.set_bash_bridge(Some(bridge));
+
+        assert_eq!(completer.complete("foo st", 6), ["start", "stop", "status"]);
+        // Registered completers win, and no completion function means paths
+        assert_eq!(completer.complete("kill -s HU", 10), ["HUP"]);
+        let input = format!("bar {}/bash_c", dir.path().display());
+        assert_eq!(completer.complete(&input, input.len()).len(), 1);
+
+        completer.set_bash_bridge(None);
+        assert!(completer.complete("foo st", 6).is_empty());
+    }
+
+    #[test]
+    fn test_spec_options_merge_with_completers() {
+        use crate::input::spec::CompletionSpecLoader;
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("git.fish"),
+            "complete -c git -l version -d 'Print the version'\n\
+             complete -c git -l paginate -d 'Use a pager'\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("rg.fish"),
+            "complete -c rg -s i -l ignore-case -d 'Case insensitive search'\n",
+        )
+        .unwrap();
+        let mut completer = Completer::new();
+        completer.set_spec_loader(CompletionSpecLoader::with_dirs(vec![dir
+            .path()
+            .to_path_buf()]));
+
+        let result = completer.complete_v2("rg --ig", 7);
+        assert_eq!(result.items[0].text, "--ignore-case");
+        assert_eq!(
+            result.items[0].description.as_deref(),
+            Some("Case insensitive search")
+        );
+        assert_eq!(completer.complete("rg -", 4), ["-i", "--ignore-case"]);
+        assert_eq!(completer.complete("git --pa", 8), ["--paginate"]);
+        // Arguments that aren't options still come from paths
+        assert!(completer
+            .complete("rg --ignore-case zzz_no_such", 28)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_command_after_prefix_commands() {
+        let mut completer = completer_with(&["systemctl", "grep", "make", "mount"]);
+        let commands = |completer: &Completer, text: &str| completer.complete(text, text.len());
+
+        assert_eq!(commands(&completer, "sudo -u root sys"), ["systemctl"]);
+        assert_eq!(commands(&completer, "env A=1 B=2 gr"), ["grep"]);
+        assert_eq!(commands(&completer, "nice -n 5 -- mo"), ["mount"]);
+        assert_eq!(commands(&completer, "FOO=bar ls | xargs -n1 gr"), ["grep"]);
+        assert_eq!(commands(&completer, "sudo env X=1 nohup gr"), ["grep"]);
+
+        // Past the command, arguments complete as usual
+        assert_eq!(commands(&completer, "sudo kill -s HU"), ["HUP"]);
+        assert!(commands(&completer, "ionice -c 3 gr").is_empty());
+        completer.add_command_prefix("ionice", &["-c", "-n", "-p"]);
+        assert_eq!(commands(&completer, "ionice -c 3 gr"), ["grep"]);
     }
 }
@@ -0,0 +1,239 @@
+//! Cache of directory listings for path completion
+//!
+//! Each key typed while the completion popup is open completes again, and
+//! on a network mount every read_dir is slow.  Listings are kept by
+//! canonical path and reused until the directory's mtime changes or they
+//! get older than the TTL; the TTL bounds how stale a listing can be on
+//! filesystems with coarse mtimes.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a listing is trusted unless configured otherwise
+pub const DEFAULT_TTL: Duration = Duration::from_secs(2);
+/// Directories kept unless configured otherwise
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// An entry of a directory listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: OsString,
+    /// Whether it is a directory, or a symlink to one
+    pub is_dir: bool,
+    /// Whether anyone may execute it
+    pub is_exec: bool,
+}
+
+/// Reads directories; a trait so tests can count the reads
+pub trait FileSystem: Debug + Send + Sync {
+    /// The entries of `dir`, without "." and ".."
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>>;
+
+    /// When `dir` last changed
+    fn modified(&self, dir: &Path) -> io::Result<SystemTime>;
+
+    /// The absolute path of `dir` with symlinks resolved
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf>;
+}
+
+/// The real filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        Ok(std::fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|entry| {
+                let file_type = entry.file_type().ok();
+                // Follow symlinks, so a link to a directory counts as one
+                let metadata = match file_type {
+                    Some(t) if t.is_symlink() => std::fs::metadata(entry.path()).ok(),
+                    _ => entry.metadata().ok(),
+                };
+                DirEntry {
+                    name: entry.file_name(),
+                    is_dir: metadata.as_ref().is_some_and(|m| m.is_dir()),
+                    is_exec: metadata.as_ref().is_some_and(is_executable),
+                }
+            })
+            .collect())
+    }
+
+    fn modified(&self, dir: &Path) -> io::Result<SystemTime> {
+        std::fs::metadata(dir)?.modified()
+    }
+
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(dir)
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    metadata.is_file()
+}
+
+/// A listing with what it was taken under
+#[derive(Debug)]
+struct Listing {
+    entries: Arc<Vec<DirEntry>>,
+    modified: SystemTime,
+    taken: Instant,
+    /// Tick of the last use, to evict the least recently used
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Listings {
+    by_dir: HashMap<PathBuf, Listing>,
+    tick: u64,
+}
+
+/// Least recently used directory listings
+#[derive(Debug)]
+pub struct DirCache {
+    fs: Arc<dyn FileSystem>,
+    ttl: Duration,
+    capacity: usize,
+    listings: Mutex<Listings>,
+}
+
+impl Default for DirCache {
+    fn default() -> Self {
+        Self::new(Arc::new(RealFileSystem), DEFAULT_TTL, DEFAULT_CAPACITY)
+    }
+}
+
+impl DirCache {
+    /// Create a cache of up to `capacity` listings from `fs`, each trusted
+    /// for `ttl`; a capacity of 0 reads every time
+    pub fn new(fs: Arc<dyn FileSystem>, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            fs,
+            ttl,
+            capacity,
+            listings: Mutex::new(Listings::default()),
+        }
+    }
+
+    /// The entries of `dir`, or None if it can't be read
+    pub fn list(&self, dir: &Path) -> Option<Arc<Vec<DirEntry>>> {
+        if self.capacity == 0 {
+            return self.fs.read_dir(dir).ok().map(Arc::new);
+        }
+        let key = self.fs.canonicalize(dir).ok()?;
+        let modified = self.fs.modified(&key).ok()?;
+        let mut listings = self.listings.lock().unwrap();
+        listings.tick += 1;
+        let tick = listings.tick;
+        if let Some(listing) = listings.by_dir.get_mut(&key) {
+            if listing.modified == modified && listing.taken.elapsed() < self.ttl {
+                listing.used = tick;
+                return Some(Arc::clone(&listing.entries));
+            }
+        }
+        // Reading may be slow, so others can use the cache meanwhile
+        drop(listings);
+        let entries = Arc::new(self.fs.read_dir(&key).ok()?);
+
+        let mut listings = self.listings.lock().unwrap();
+        if !listings.by_dir.contains_key(&key) && listings.by_dir.len() >= self.capacity {
+            let oldest = listings
+                .by_dir
+                .iter()
+                .min_by_key(|(_, listing)| listing.used)
+                .map(|(dir, _)| dir.clone());
+            if let Some(oldest) = oldest {
+                listings.by_dir.remove(&oldest);
+            }
+        }
+        listings.by_dir.insert(
+            key,
+            Listing {
+                entries: Arc::clone(&entries),
+                modified,
+                taken: Instant::now(),
+                used: tick,
+            },
+        );
+        Some(entries)
+    }
+
+    /// Forget every listing
+    pub fn clear(&self) {
+        self.listings.lock().unwrap().by_dir.clear();
+    }
+
+    /// An empty cache of the same filesystem with other limits
+    pub fn with_limits(&self, ttl: Duration, capacity: usize) -> Self {
+        Self::new(Arc::clone(&self.fs), ttl, capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Empty directories anywhere, counting the reads
+    #[derive(Debug, Default)]
+    struct CountingFs(Arc<AtomicUsize>);
+
+    impl FileSystem for CountingFs {
+        fn read_dir(&self, _dir: &Path) -> io::Result<Vec<DirEntry>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+
+        fn modified(&self, _dir: &Path) -> io::Result<SystemTime> {
+            Ok(SystemTime::UNIX_EPOCH)
+        }
+
+        fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+            Ok(dir.to_path_buf())
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_goes() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let cache = DirCache::new(Arc::new(CountingFs(reads.clone())), DEFAULT_TTL, 2);
+        for dir in ["/a", "/b", "/a", "/c", "/a", "/b"] {
+            cache.list(Path::new(dir)).unwrap();
+        }
+        // /b went to make room for /c, while /a was kept in use
+        assert_eq!(reads.load(Ordering::SeqCst), 4);
+
+        cache.clear();
+        cache.list(Path::new("/a")).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_real_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let cache = DirCache::default();
+        let mut entries = cache.list(dir.path()).unwrap().to_vec();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let summary: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|e| (e.name.to_str().unwrap(), e.is_dir))
+            .collect();
+        assert_eq!(summary, [("file", false), ("sub", true)]);
+        assert!(cache.list(&dir.path().join("missing")).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//! Crash recovery for the command being composed
+//!
+//! DraftStore writes the editor's text and cursor to a per-window JSON file
+//! under ~/.config/cx-terminal/drafts/ while the user types.  A clean
+//! shutdown leaves a marker with its time; on the next start any draft
+//! written after that marker came from a session that died, and is offered
+//! back through recover().
+
+use crate::input::editor::{Editor, EditorSnapshot};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default drafts directory
+const DRAFTS_DIR: &str = "drafts";
+/// File extension for draft files
+const DRAFT_EXT: &str = "json";
+/// Marker written on clean shutdown
+const CLEAN_SHUTDOWN_FILE: &str = "clean-shutdown";
+/// Shortest time between two writes of the same draft
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A saved draft
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Draft {
+    /// Window the draft was typed in
+    pub window_id: String,
+    /// When the draft was written, in milliseconds since the Unix epoch
+    pub saved_at: u64,
+    /// Text, cursor and selection
+    pub snapshot: EditorSnapshot,
+}
+
+/// Debounced draft writer for one window
+pub struct DraftStore {
+    /// Directory holding drafts and the clean-shutdown marker
+    dir: PathBuf,
+    /// Window whose draft this store writes
+    window_id: String,
+    /// Shortest time between two writes
+    min_interval: Duration,
+    /// When the draft was last written
+    last_write: Option<SystemTime>,
+    /// What was last written, so unchanged drafts aren't rewritten
+    last_written: Option<EditorSnapshot>,
+    /// Source of the current time
+    clock: Box<dyn Fn() -> SystemTime + Send>,
+}
+
+impl DraftStore {
+    /// Create a store for `window_id` in the default directory
+    pub fn new(window_id: &str) -> Self {
+        Self::with_dir(Self::default_dir(), window_id)
+    }
+
+    /// Create a store for `window_id` in a custom directory
+    pub fn with_dir(dir: PathBuf, window_id: &str) -> Self {
+        Self {
+            dir,
+            window_id: window_id.to_string(),
+            min_interval: DEFAULT_MIN_INTERVAL,
+            last_write: None,
+            last_written: None,
+            clock: Box::new(SystemTime::now),
+        }
+    }
+
+    /// Get the default drafts directory
+    pub fn default_dir() -> PathBuf {
+        dirs_next::config_dir()
+            .unwrap_or_else(|| PathBuf::from(".config"))
+            .join("cx-terminal")
+            .join(DRAFTS_DIR)
+    }
+
+    /// Use `clock` instead of the system time
+    pub fn set_clock(&mut self, clock: impl Fn() -> SystemTime + Send + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set the shortest time between two writes
+    pub fn set_min_interval(&mut self, interval: Duration) {
+        self.min_interval = interval;
+    }
+
+    /// Path of this window's draft file
+    pub fn draft_path(&self) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", sanitize_id(&self.window_id), DRAFT_EXT))
+    }
+
+    /// Write the editor's contents if they changed and a write is due
+    ///
+    /// Unmodified editors are never written, and an empty one removes the
+    /// draft.  Returns whether a write happened; a change skipped by the
+    /// debounce is picked up by the next call, so call this from a timer
+    /// as well as after edits.
+    pub fn save_if_due(&mut self, editor: &Editor) -> io::Result<bool> {
+        if !editor.is_modified() {
+            return Ok(false);
+        }
+        if editor.line_count() == 1 && editor.text().is_empty() {
+            self.discard()?;
+            return Ok(false);
+        }
+
+        let now = (self.clock)();
+        let due = self
+            .last_write
+            .is_none_or(|last| now.duration_since(last).unwrap_or_default() >= self.min_interval);
+        if !due {
+            return Ok(false);
+        }
+        let snapshot = editor.to_snapshot();
+        if self.last_written.as_ref() == Some(&snapshot) {
+            return Ok(false);
+        }
+
+        let draft = Draft {
+            window_id: self.window_id.clone(),
+            saved_at: millis_since_epoch(now),
+            snapshot,
+        };
+        fs::create_dir_all(&self.dir)?;
+        let temp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        {
+            let mut writer = BufWriter::new(temp.as_file());
+            serde_json::to_writer(&mut writer, &draft)?;
+            writer.flush()?;
+        }
+        temp.persist(self.draft_path()).map_err(|err| err.error)?;
+
+        self.last_write = Some(now);
+        self.last_written = Some(draft.snapshot);
+        Ok(true)
+    }
+
+    /// Delete this window's draft, after a submit or an explicit clear
+    pub fn discard(&mut self) -> io::Result<()> {
+        self.last_write = None;
+        self.last_written = None;
+        remove_if_present(&self.draft_path())
+    }
+
+    /// Record a clean shutdown, so current drafts aren't offered again
+    pub fn mark_clean_shutdown(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let now = millis_since_epoch((self.clock)());
+        fs::write(self.dir.join(CLEAN_SHUTDOWN_FILE), now.to_string())
+    }
+
+    /// Drafts written after the last clean shutdown, newest first
+    ///
+    /// Files that can't be parsed are skipped with a warning.
+    pub fn recover(&self) -> io::Result<Vec<Draft>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let clean_at = fs::read_to_string(self.dir.join(CLEAN_SHUTDOWN_FILE))
+            .ok()
+            .and_then(|text| text.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let mut drafts = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some(DRAFT_EXT) {
+                continue;
+            }
+            match load_draft(&path) {
+                Ok(draft) if draft.saved_at > clean_at => drafts.push(draft),
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to load draft from {:?}: {}", path, e),
+            }
+        }
+        drafts.sort_by_key(|draft| std::cmp::Reverse(draft.saved_at));
+        Ok(drafts)
+    }
+}
+
+/// Read a draft file
+fn load_draft(path: &Path) -> io::Result<Draft> {
+    let reader = BufReader::new(fs::File::open(path)?);
+    let draft: Draft = serde_json::from_reader(reader)?;
+    draft
+        .snapshot
+        .check_version()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(draft)
+}
+
+/// Remove a file, treating a missing one as already removed
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Keep a window id safe to use as a file name
+fn sanitize_id(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn store_with_clock(dir: &Path, window_id: &str) -> (DraftStore, Arc<Mutex<SystemTime>>) {
+        let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let mut store = DraftStore::with_dir(dir.to_path_buf(), window_id);
+        let clock = Arc::clone(&now);
+        store.set_clock(move || *clock.lock().unwrap());
+        (store, now)
+    }
+
+    #[test]
+    fn test_writes_are_debounced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut store, now) = store_with_clock(dir.path(), "window-1");
+        let mut editor = Editor::new();
+
+        assert!(!store.save_if_due(&editor).unwrap());
+        editor.insert_str("git rebase -i");
+        assert!(store.save_if_due(&editor).unwrap());
+        editor.insert_str(" HEAD~3");
+        assert!(!store.save_if_due(&editor).unwrap());
+
+        *now.lock().unwrap() += Duration::from_millis(1500);
+        assert!(store.save_if_due(&editor).unwrap());
+        *now.lock().unwrap() += Duration::from_secs(5);
+        assert!(!store.save_if_due(&editor).unwrap());
+
+        store.discard().unwrap();
+        assert!(!store.draft_path().exists());
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut store, now) = store_with_clock(dir.path(), "window/2");
+        let mut editor = Editor::new();
+        editor.insert_str("for f in *.log; do\n  gzip $f\ndone");
+        store.save_if_due(&editor).unwrap();
+
+        // No clean-shutdown marker, so the draft is offered back
+        let drafts = store.recover().unwrap();
+        assert_eq!(drafts.len(), 1);
+        assert_eq!(drafts[0].window_id, "window/2");
+        let restored = Editor::from_snapshot(drafts[0].snapshot.clone()).unwrap();
+        assert_eq!(restored.full_text(), editor.full_text());
+        assert_eq!(restored.cursor_coords(), (2, 4));
+
+        // After a clean shutdown the same draft is old news
+        *now.lock().unwrap() += Duration::from_secs(1);
+        store.mark_clean_shutdown().unwrap();
+        assert!(store.recover().unwrap().is_empty());
+    }
+}
@@ -1,34 +1,162 @@
 //! Text editor component with cursor tracking, selection, and undo/redo
 //!
-//! Provides a rope-based text buffer for efficient editing of multi-line text.
+//! The text lives in a rope (see buffer.rs), so edits and conversions
+//! between positions and offsets stay fast in long multi-line text.
 
+use crate::input::buffer::TextBuffer;
+use crate::input::highlight::{highlight_line, TokenKind};
+use crate::input::keymap::EditorCommand;
+use crate::input::parser::{self, CompletionStatus};
+use crate::input::snippet::{is_command_position, Abbreviation, Snippet};
+use crate::input::wrap::WrapLayout;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
-use std::ops::Range;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use termwiz::cell::unicode_column_width;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
-/// Maximum undo history entries
-const MAX_UNDO_HISTORY: usize = 100;
+/// Default memory budget for undo history, in bytes
+const DEFAULT_UNDO_BUDGET_BYTES: usize = 4 * 1024 * 1024;
+
+/// Default maximum kill ring entries
+const DEFAULT_KILL_RING_SIZE: usize = 60;
+
+/// Longest text preview in a change description, in chars
+const MAX_PREVIEW_CHARS: usize = 40;
+
+/// Largest repeat count with_repeat and vi counts honour
+pub(crate) const MAX_REPEAT_COUNT: usize = 10_000;
+
+/// Maximum marks kept in the mark ring
+const MAX_MARKS: usize = 16;
+
+/// Current version of the EditorSnapshot format
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Generation, query and case sensitivity a match list was found for
+type MatchCache = (u64, String, bool, Arc<Vec<Range<CursorPosition>>>);
 
 /// A text editor with cursor, selection, and undo/redo support
 #[derive(Debug, Clone)]
 pub struct Editor {
-    /// The text content (stored as lines for efficient multi-line handling)
-    lines: Vec<String>,
+    /// The text content, in a rope indexed by line, char and byte
+    buffer: TextBuffer,
     /// Cursor position as (line, column)
     cursor: CursorPosition,
     /// Selection anchor (if any)
     selection_anchor: Option<CursorPosition>,
+    /// Column vertical movement aims for, valid while the cursor stays at
+    /// the position it was recorded with
+    goal_column: Option<(CursorPosition, usize)>,
+    /// Display column visual-row movement aims for, kept the same way
+    visual_goal: Option<(CursorPosition, usize)>,
+    /// Whether the selection is a rectangle rather than a text range
+    block_selection: bool,
+    /// Additional cursors that receive the same edits as the primary one
+    extra_cursors: Vec<CursorPosition>,
     /// Undo stack
-    undo_stack: VecDeque<EditorState>,
+    undo_stack: VecDeque<UndoEntry>,
     /// Redo stack
-    redo_stack: VecDeque<EditorState>,
+    redo_stack: VecDeque<UndoEntry>,
+    /// Approximate bytes held by undo_stack
+    undo_bytes: usize,
+    /// Oldest undo entries are dropped once undo_bytes goes over this
+    undo_budget_bytes: usize,
+    /// Mark ring, most recent mark last
+    marks: Vec<CursorPosition>,
     /// Kill ring (for Ctrl+K/Ctrl+Y operations)
     kill_ring: Vec<String>,
+    /// Maximum kill ring entries; the oldest are dropped beyond this
+    kill_ring_max: usize,
+    /// How kills join up while a command repeats
+    kill_merge: Option<KillMerge>,
     /// Whether the editor has been modified since last save
     modified: bool,
+    /// Id of the current buffer revision
+    revision: u64,
+    /// Last revision id handed out
+    last_revision: u64,
+    /// Revision that was current when the editor was marked unmodified
+    saved_revision: u64,
+    /// Revision left by the last set_text_merging_undo
+    merge_revision: Option<u64>,
+    /// Whether search and replace match case exactly
+    search_case_sensitive: bool,
+    /// Nesting depth of open undo transactions
+    transaction_depth: usize,
+    /// Line ending used when handing the full text back out
+    line_ending: LineEnding,
+    /// File last loaded or saved, with its modification time and size then
+    file_stamp: Option<(PathBuf, Option<SystemTime>, u64)>,
+    /// Whether a single trailing newline is dropped from pasted text
+    paste_trims_trailing_newline: bool,
+    /// Whether edits are rejected
+    read_only: bool,
+    /// Text inserted and removed by the operation in progress
+    change_tally: (TextTally, TextTally),
+    /// Summary of the last operation, for screen readers
+    last_change: Option<ChangeDescription>,
+    /// Whether change descriptions leave out the text
+    hide_change_previews: bool,
+    /// IME composition shown at the cursor but not yet in the buffer,
+    /// with the caret's char offset within it
+    preedit: Option<(String, usize)>,
+    /// Words that expand into snippets
+    abbreviations: Vec<Abbreviation>,
+    /// Placeholders of the last expanded snippet, while navigating them
+    snippet_session: Option<SnippetSession>,
+    /// Commands run since macro recording started
+    macro_recording: Option<Vec<EditorCommand>>,
+    /// Last macro recorded, for the PlayMacro command
+    last_macro: Option<Macro>,
+    /// Whether a macro is being played back
+    playing_macro: bool,
+    /// Limits on what the buffer may contain
+    options: EditorOptions,
+    /// Where the Copy, Cut and Paste commands go
+    clipboard: SharedClipboard,
+    /// Soft-wrap width and tab width for the visual-line commands
+    view_width: Option<(usize, usize)>,
+    /// Check or rewrite each typed or pasted char
+    input_filter: Option<InputFilter>,
+    /// Chars the input filter dropped from the last insertion
+    rejected_chars: usize,
+    /// Which characters word motions treat as part of a word
+    word_chars: WordCharClass,
+    /// Buffer changes not yet collected by take_events
+    events: Vec<EditorEvent>,
+    /// Bumped on every buffer change so cached layouts can tell they're stale
+    layout_generation: u64,
+    /// Last soft-wrap layout handed out, with the generation it was built at
+    layout_cache: RefCell<Option<(u64, Arc<WrapLayout>)>>,
+    /// Last match_ranges result, keyed by generation, query and case
+    match_cache: RefCell<Option<MatchCache>>,
+    /// Last stats() result, with the generation it was counted at
+    stats_cache: Cell<Option<(u64, BufferStats)>>,
+    /// Cursor and selection as of the last take_events
+    reported_cursor: CursorPosition,
+    reported_selection: (Option<CursorPosition>, bool),
+}
+
+/// Kills made by one repeated command, collected into a single entry
+#[derive(Debug, Clone, Copy)]
+struct KillMerge {
+    /// Whether later kills come before earlier ones in the text
+    backward: bool,
+    /// Whether the first kill has made its entry yet
+    started: bool,
 }
 
 /// Cursor position in the editor
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub struct CursorPosition {
     /// Line number (0-indexed)
     pub line: usize,
@@ -37,750 +165,6598 @@ pub struct CursorPosition {
 }
 
 /// Editor state for undo/redo
+///
+/// The text itself is not part of the snapshot; undo entries carry the
+/// edits needed to get back to it instead.
 #[derive(Debug, Clone)]
 struct EditorState {
-    lines: Vec<String>,
     cursor: CursorPosition,
+    extra_cursors: Vec<CursorPosition>,
+    selection_anchor: Option<CursorPosition>,
+    block_selection: bool,
+    modified: bool,
+    revision: u64,
 }
 
-/// Action type for tracking changes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum EditorAction {
-    None,
-    Insert,
-    Delete,
-    Move,
+/// Placeholders of an expanded snippet, kept in step with edits
+#[derive(Debug, Clone)]
+struct SnippetSession {
+    /// Start and end of each placeholder, in visiting order
+    placeholders: Vec<(CursorPosition, CursorPosition)>,
+    /// Placeholder last selected
+    active: Option<usize>,
 }
 
-impl Editor {
-    /// Create a new empty editor
-    pub fn new() -> Self {
-        Self {
-            lines: vec![String::new()],
-            cursor: CursorPosition::default(),
-            selection_anchor: None,
-            undo_stack: VecDeque::with_capacity(MAX_UNDO_HISTORY),
-            redo_stack: VecDeque::with_capacity(MAX_UNDO_HISTORY),
-            kill_ring: Vec::new(),
-            modified: false,
+/// A single change to the buffer: `removed` was replaced by `inserted` at `start`
+#[derive(Debug, Clone)]
+struct Edit {
+    start: CursorPosition,
+    removed: String,
+    inserted: String,
+}
+
+impl Edit {
+    /// Approximate memory held by the edit
+    fn approx_bytes(&self) -> usize {
+        std::mem::size_of::<Edit>() + self.removed.len() + self.inserted.len()
+    }
+}
+
+/// One step of undo/redo history
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    /// Edits made during the step, in the order they were applied
+    edits: Vec<Edit>,
+    /// State to restore when the step is undone (or redone)
+    state: EditorState,
+}
+
+impl UndoEntry {
+    /// Approximate memory held by the entry
+    fn approx_bytes(&self) -> usize {
+        std::mem::size_of::<UndoEntry>()
+            + self.state.extra_cursors.len() * std::mem::size_of::<CursorPosition>()
+            + self.edits.iter().map(Edit::approx_bytes).sum::<usize>()
+    }
+}
+
+/// Line ending convention for the full text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    /// Lines end with "\n"
+    #[default]
+    Unix,
+    /// Lines end with "\r\n"
+    Dos,
+}
+
+impl LineEnding {
+    /// The line break sequence
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
         }
     }
 
-    /// Get the full text content
-    pub fn text(&self) -> &str {
-        // This is a bit inefficient, but we cache internally
-        // For single-line input, this is fine
-        if self.lines.len() == 1 {
-            &self.lines[0]
+    /// Detect the dominant line ending in `text`, if it has any line breaks
+    pub fn detect(text: &str) -> Option<Self> {
+        let dos = text.matches("\r\n").count();
+        let unix = text.matches('\n').count() - dos;
+        if dos + unix == 0 {
+            None
+        } else if dos > unix {
+            Some(LineEnding::Dos)
         } else {
-            // Return reference to first line for now
-            // The full text is computed on demand
-            &self.lines[0]
+            Some(LineEnding::Unix)
         }
     }
+}
 
-    /// Get the full text as a single string
-    pub fn full_text(&self) -> String {
-        self.lines.join("\n")
-    }
+/// Limits on what the editor accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EditorOptions {
+    /// Never allow more than one line
+    pub single_line: bool,
+    /// What to do with newlines in single-line mode
+    pub newlines: NewlineHandling,
+    /// Maximum length of the buffer in chars
+    pub max_chars: Option<usize>,
+    /// Unicode normalization applied to inserted text
+    pub normalize: Option<NormalizationForm>,
+}
 
-    /// Set the text content
-    pub fn set_text(&mut self, text: &str) {
-        self.save_undo_state();
-        self.lines = text.split('\n').map(String::from).collect();
-        if self.lines.is_empty() {
-            self.lines.push(String::new());
+/// Unicode normalization form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalizationForm {
+    /// Canonical composition, so "e" + U+0301 becomes "é"
+    #[default]
+    Nfc,
+    /// Canonical decomposition
+    Nfd,
+    /// Compatibility composition, which also folds ligatures and the like
+    Nfkc,
+    /// Compatibility decomposition
+    Nfkd,
+}
+
+impl NormalizationForm {
+    /// Check whether `text` is already in this form
+    pub fn is_normalized(self, text: &str) -> bool {
+        match self {
+            Self::Nfc => unicode_normalization::is_nfc(text),
+            Self::Nfd => unicode_normalization::is_nfd(text),
+            Self::Nfkc => unicode_normalization::is_nfkc(text),
+            Self::Nfkd => unicode_normalization::is_nfkd(text),
         }
-        // Move cursor to end
-        self.cursor.line = self.lines.len() - 1;
-        self.cursor.column = self.lines[self.cursor.line].chars().count();
-        self.selection_anchor = None;
-        self.modified = true;
     }
 
-    /// Clear the editor
-    pub fn clear(&mut self) {
-        self.save_undo_state();
-        self.lines = vec![String::new()];
-        self.cursor = CursorPosition::default();
-        self.selection_anchor = None;
-        self.modified = false;
+    /// Convert `text` to this form
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Self::Nfc => text.nfc().collect(),
+            Self::Nfd => text.nfd().collect(),
+            Self::Nfkc => text.nfkc().collect(),
+            Self::Nfkd => text.nfkd().collect(),
+        }
     }
+}
 
-    /// Get current cursor position as byte offset
-    pub fn cursor_pos(&self) -> usize {
-        let mut pos = 0;
-        for (i, line) in self.lines.iter().enumerate() {
-            if i < self.cursor.line {
-                pos += line.len() + 1; // +1 for newline
-            } else {
-                pos += line
-                    .chars()
-                    .take(self.cursor.column)
-                    .map(|c| c.len_utf8())
-                    .sum::<usize>();
-                break;
+/// How a single-line editor treats inserted newlines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NewlineHandling {
+    /// Drop the newlines and insert the rest
+    #[default]
+    Strip,
+    /// Reject the whole insertion
+    Reject,
+}
+
+/// Which characters count as part of a word for word motions and kills
+#[derive(Debug, Clone, Copy, Default)]
+pub enum WordCharClass {
+    /// Words are separated by whitespace only
+    Whitespace,
+    /// Words also break at shell punctuation: / - = . : ,
+    #[default]
+    ShellWordBreaks,
+    /// Words are runs of chars the function accepts
+    Custom(fn(char) -> bool),
+}
+
+impl WordCharClass {
+    /// Check whether `c` is part of a word
+    pub fn is_word_char(self, c: char) -> bool {
+        match self {
+            WordCharClass::Whitespace => !c.is_whitespace(),
+            WordCharClass::ShellWordBreaks => {
+                !c.is_whitespace() && !matches!(c, '/' | '-' | '=' | '.' | ':' | ',')
             }
+            WordCharClass::Custom(is_word) => is_word(c),
         }
-        pos
     }
+}
 
-    /// Get cursor position as (line, column)
-    pub fn cursor_coords(&self) -> (usize, usize) {
-        (self.cursor.line, self.cursor.column)
+/// Persisted editor contents, for restoring a session
+///
+/// Fields added after the first version must default so that older
+/// session files keep loading.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditorSnapshot {
+    /// Format version
+    pub version: u32,
+    pub lines: Vec<String>,
+    pub cursor: CursorPosition,
+    #[serde(default)]
+    pub selection_anchor: Option<CursorPosition>,
+    #[serde(default)]
+    pub kill_ring: Vec<String>,
+    #[serde(default)]
+    pub modified: bool,
+    #[serde(default)]
+    pub line_ending: LineEnding,
+}
+
+impl EditorSnapshot {
+    /// Check that this build understands the snapshot's format
+    ///
+    /// Older versions load with defaults for the fields they lack; a newer
+    /// one may mean something else by the fields it shares, so it is
+    /// rejected rather than half-restored.
+    pub fn check_version(&self) -> Result<(), EditorError> {
+        if self.version > SNAPSHOT_VERSION {
+            return Err(EditorError::UnsupportedSnapshot {
+                version: self.version,
+            });
+        }
+        Ok(())
     }
+}
 
-    /// Set cursor position
-    pub fn set_cursor(&mut self, byte_pos: usize) {
-        let mut remaining = byte_pos;
-        for (line_idx, line) in self.lines.iter().enumerate() {
-            let line_len = line.len();
-            if remaining <= line_len || line_idx == self.lines.len() - 1 {
-                self.cursor.line = line_idx;
-                // Convert byte position to character position
-                self.cursor.column = line
-                    .chars()
-                    .take_while(|_| {
-                        let c_len = 1; // Simplified for now
-                        if remaining >= c_len {
-                            remaining -= c_len;
-                            true
-                        } else {
-                            false
-                        }
-                    })
-                    .count();
-                break;
+/// Every occurrence of a search query, for highlighting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchMatches {
+    /// Non-overlapping matches in buffer order
+    pub ranges: Arc<Vec<Range<CursorPosition>>>,
+    /// Index of the first match at or after the cursor
+    pub current: Option<usize>,
+}
+
+/// A replacement for apply_edits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    /// Char offsets in the full text, each line break counting as one
+    pub range: Range<usize>,
+    /// Text to put in place of the range
+    pub new_text: String,
+}
+
+/// Short form of changed text, for ChangeDescription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangePreview {
+    /// The text, cut short with "…" if long
+    Text(String),
+    /// Number of lines of multi-line text
+    Lines(usize),
+}
+
+/// A spoken summary of the last thing that happened in the editor
+///
+/// Previews are left out when the editor hides them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeDescription {
+    InsertedText {
+        preview: Option<ChangePreview>,
+        chars: usize,
+    },
+    DeletedText {
+        preview: Option<ChangePreview>,
+        chars: usize,
+    },
+    /// Text was replaced; the preview and count are of the new text
+    ReplacedText {
+        preview: Option<ChangePreview>,
+        chars: usize,
+    },
+    CursorMoved {
+        line: usize,
+        column: usize,
+    },
+    Undo,
+    Redo,
+}
+
+impl std::fmt::Display for ChangeDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = |preview: &Option<ChangePreview>, chars: usize| match preview {
+            Some(ChangePreview::Text(text)) => format!("'{}'", text),
+            Some(ChangePreview::Lines(lines)) => format!("{} lines", lines),
+            None if chars == 1 => "1 char".to_string(),
+            None => format!("{} chars", chars),
+        };
+        match self {
+            Self::InsertedText { preview, chars } => {
+                write!(f, "inserted {}", text(preview, *chars))
+            }
+            Self::DeletedText { preview, chars } => write!(f, "deleted {}", text(preview, *chars)),
+            Self::ReplacedText { preview, chars } => {
+                write!(f, "replaced with {}", text(preview, *chars))
             }
-            remaining -= line_len + 1; // +1 for newline
+            Self::CursorMoved { line, column } => {
+                write!(f, "line {}, column {}", line + 1, column + 1)
+            }
+            Self::Undo => f.write_str("undo"),
+            Self::Redo => f.write_str("redo"),
         }
     }
+}
 
-    /// Insert a character at cursor position
-    pub fn insert_char(&mut self, c: char) {
-        self.save_undo_state();
-        self.delete_selection();
-        self.insert_char_internal(c);
-    }
+/// Running size and start of some text, for previews
+#[derive(Debug, Clone, Default)]
+struct TextTally {
+    /// Up to one char more than the longest preview
+    head: String,
+    chars: usize,
+    line_breaks: usize,
+}
 
-    /// Internal character insertion without undo state save
-    fn insert_char_internal(&mut self, c: char) {
-        if c == '\n' {
-            // Split line at cursor
-            let current_line = &self.lines[self.cursor.line];
-            let char_indices: Vec<_> = current_line.char_indices().collect();
-            let byte_pos = if self.cursor.column >= char_indices.len() {
-                current_line.len()
-            } else {
-                char_indices[self.cursor.column].0
-            };
+impl TextTally {
+    fn add(&mut self, text: &str) {
+        for c in text.chars() {
+            if self.chars <= MAX_PREVIEW_CHARS {
+                self.head.push(c);
+            }
+            self.chars += 1;
+            if c == '\n' {
+                self.line_breaks += 1;
+            }
+        }
+    }
 
-            let remainder = current_line[byte_pos..].to_string();
-            self.lines[self.cursor.line].truncate(byte_pos);
-            self.cursor.line += 1;
-            self.lines.insert(self.cursor.line, remainder);
-            self.cursor.column = 0;
+    fn preview(&self) -> ChangePreview {
+        if self.line_breaks > 0 {
+            ChangePreview::Lines(self.line_breaks + 1)
+        } else if self.chars > MAX_PREVIEW_CHARS {
+            let head: String = self.head.chars().take(MAX_PREVIEW_CHARS - 1).collect();
+            ChangePreview::Text(format!("{}…", head))
         } else {
-            // Insert character
-            let current_line = &mut self.lines[self.cursor.line];
-            let char_indices: Vec<_> = current_line.char_indices().collect();
-            let byte_pos = if self.cursor.column >= char_indices.len() {
-                current_line.len()
-            } else {
-                char_indices[self.cursor.column].0
-            };
-            current_line.insert(byte_pos, c);
-            self.cursor.column += 1;
+            ChangePreview::Text(self.head.clone())
         }
+    }
+}
 
-        self.modified = true;
-        self.redo_stack.clear();
+/// What an input filter does with a char
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFilterResult {
+    Accept,
+    Reject,
+    Replace(char),
+}
+
+/// Input filter shared between clones of an editor
+#[derive(Clone)]
+struct InputFilter(Arc<dyn Fn(char) -> InputFilterResult + Send + Sync>);
+
+impl std::fmt::Debug for InputFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InputFilter")
     }
+}
 
-    /// Insert a string at cursor position
-    pub fn insert_str(&mut self, s: &str) {
-        if s.is_empty() {
-            return;
-        }
-        self.save_undo_state();
-        self.delete_selection();
-        for c in s.chars() {
-            self.insert_char_internal(c);
-        }
+/// Input filter that accepts ASCII only
+pub fn ascii_only(c: char) -> InputFilterResult {
+    if c.is_ascii() {
+        InputFilterResult::Accept
+    } else {
+        InputFilterResult::Reject
     }
+}
 
-    /// Delete character before cursor (backspace)
-    pub fn backspace(&mut self) {
-        if self.delete_selection() {
-            return;
-        }
+/// Input filter that rejects control chars, newline and tab included
+pub fn no_control_chars(c: char) -> InputFilterResult {
+    if c.is_control() {
+        InputFilterResult::Reject
+    } else {
+        InputFilterResult::Accept
+    }
+}
 
-        self.save_undo_state();
+/// Input filter that accepts ASCII digits only
+pub fn digits_only(c: char) -> InputFilterResult {
+    if c.is_ascii_digit() {
+        InputFilterResult::Accept
+    } else {
+        InputFilterResult::Reject
+    }
+}
 
-        if self.cursor.column > 0 {
-            // Delete character within line
-            let current_line = &mut self.lines[self.cursor.line];
-            let char_indices: Vec<_> = current_line.char_indices().collect();
-            if self.cursor.column <= char_indices.len() {
-                let byte_start = if self.cursor.column > 0 {
-                    char_indices[self.cursor.column - 1].0
-                } else {
-                    0
-                };
-                let byte_end = if self.cursor.column < char_indices.len() {
-                    char_indices[self.cursor.column].0
-                } else {
-                    current_line.len()
-                };
+/// Sizes of the buffer or the selection, for the status line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufferStats {
+    pub lines: usize,
+    /// Chars, each line break counting as one
+    pub chars: usize,
+    /// Runs of word chars, as word motions step over them
+    pub words: usize,
+    /// Bytes as UTF-8, each line break counting as one
+    pub bytes: usize,
+}
 
-                // Remove the character at cursor - 1
-                if self.cursor.column > 0 {
-                    let byte_start = char_indices[self.cursor.column - 1].0;
-                    let byte_end = if self.cursor.column < char_indices.len() {
-                        char_indices[self.cursor.column].0
-                    } else {
-                        current_line.len()
-                    };
-                    current_line.drain(byte_start..byte_end);
-                    self.cursor.column -= 1;
+impl BufferStats {
+    /// Count `lines` with the word rules of `word_chars`
+    fn count<S: AsRef<str>>(lines: impl Iterator<Item = S>, word_chars: WordCharClass) -> Self {
+        let mut stats = Self::default();
+        for line in lines {
+            let line = line.as_ref();
+            if stats.lines > 0 {
+                stats.chars += 1;
+                stats.bytes += 1;
+            }
+            stats.lines += 1;
+            stats.bytes += line.len();
+            let mut in_word = false;
+            for c in line.chars() {
+                stats.chars += 1;
+                let word = word_chars.is_word_char(c);
+                if word && !in_word {
+                    stats.words += 1;
                 }
+                in_word = word;
             }
-        } else if self.cursor.line > 0 {
-            // Join with previous line
-            let current_line = self.lines.remove(self.cursor.line);
-            self.cursor.line -= 1;
-            self.cursor.column = self.lines[self.cursor.line].chars().count();
-            self.lines[self.cursor.line].push_str(&current_line);
         }
-
-        self.modified = true;
-        self.redo_stack.clear();
+        stats
     }
+}
 
-    /// Delete character at cursor (delete key)
-    pub fn delete(&mut self) {
-        if self.delete_selection() {
-            return;
-        }
+/// A recorded sequence of editor commands
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Macro {
+    /// Commands in the order they ran, typed characters included
+    pub commands: Vec<EditorCommand>,
+}
 
-        self.save_undo_state();
+/// A change to the editor, for incremental redraw
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorEvent {
+    /// `count` new lines now start at line `at`
+    LinesInserted { at: usize, count: usize },
+    /// `count` lines that started at line `at` are gone
+    LinesRemoved { at: usize, count: usize },
+    /// The contents of `line` changed
+    LineChanged { line: usize },
+    /// The cursor is somewhere else
+    CursorMoved,
+    /// The selection changed
+    SelectionChanged,
+}
 
-        let current_line = &self.lines[self.cursor.line];
-        let char_count = current_line.chars().count();
+/// Editor errors
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EditorError {
+    #[error("Position {line}:{column} is outside the buffer")]
+    OutOfRange { line: usize, column: usize },
+    #[error("Edit range {start}..{end} is outside the buffer")]
+    EditOutOfRange { start: usize, end: usize },
+    #[error("Edits at {first:?} and {second:?} overlap")]
+    OverlappingEdits {
+        first: Range<usize>,
+        second: Range<usize>,
+    },
+    #[error("Editor is read-only")]
+    ReadOnly,
+    #[error(
+        "Snapshot version {version} is newer than the supported {}",
+        SNAPSHOT_VERSION
+    )]
+    UnsupportedSnapshot { version: u32 },
+}
 
-        if self.cursor.column < char_count {
-            // Delete character at cursor
-            let char_indices: Vec<_> = current_line.char_indices().collect();
-            let byte_start = char_indices[self.cursor.column].0;
-            let byte_end = if self.cursor.column + 1 < char_indices.len() {
-                char_indices[self.cursor.column + 1].0
-            } else {
-                current_line.len()
-            };
+/// System clipboard access for copy, cut and paste
+pub trait Clipboard {
+    /// Replace the clipboard contents
+    fn set(&mut self, text: &str);
+    /// Get the clipboard contents, if there are any
+    fn get(&mut self) -> Option<String>;
+}
 
-            self.lines[self.cursor.line].drain(byte_start..byte_end);
-        } else if self.cursor.line + 1 < self.lines.len() {
-            // Join with next line
-            let next_line = self.lines.remove(self.cursor.line + 1);
-            self.lines[self.cursor.line].push_str(&next_line);
-        }
+/// In-memory clipboard for tests and headless use
+#[derive(Debug, Clone, Default)]
+pub struct LocalClipboard {
+    contents: Option<String>,
+}
 
-        self.modified = true;
-        self.redo_stack.clear();
+impl Clipboard for LocalClipboard {
+    fn set(&mut self, text: &str) {
+        self.contents = Some(text.to_string());
     }
 
-    /// Delete a range of text (byte positions)
-    pub fn delete_range(&mut self, start: usize, end: usize) {
-        self.save_undo_state();
-
-        // Convert to full text, delete, then set
-        let mut text = self.full_text();
-        let start = start.min(text.len());
-        let end = end.min(text.len());
-        text.drain(start..end);
+    fn get(&mut self) -> Option<String> {
+        self.contents.clone()
+    }
+}
 
-        // Preserve cursor position temporarily
-        let full_text = text;
-        self.lines = full_text.split('\n').map(String::from).collect();
-        if self.lines.is_empty() {
-            self.lines.push(String::new());
-        }
+/// Clipboard shared between an editor and its clones
+#[derive(Clone)]
+struct SharedClipboard(Arc<Mutex<dyn Clipboard + Send>>);
 
-        self.modified = true;
-        self.redo_stack.clear();
+impl std::fmt::Debug for SharedClipboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("SharedClipboard")
     }
+}
 
-    /// Move cursor left
-    pub fn move_left(&mut self) {
-        self.selection_anchor = None;
-        if self.cursor.column > 0 {
-            self.cursor.column -= 1;
-        } else if self.cursor.line > 0 {
-            self.cursor.line -= 1;
-            self.cursor.column = self.lines[self.cursor.line].chars().count();
+/// Action type for tracking changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorAction {
+    None,
+    Insert,
+    Delete,
+    Move,
+}
+
+impl Editor {
+    /// Create a new empty editor
+    pub fn new() -> Self {
+        Self {
+            buffer: TextBuffer::new(),
+            cursor: CursorPosition::default(),
+            selection_anchor: None,
+            goal_column: None,
+            visual_goal: None,
+            block_selection: false,
+            extra_cursors: Vec::new(),
+            undo_stack: VecDeque::new(),
+            redo_stack: VecDeque::new(),
+            undo_bytes: 0,
+            undo_budget_bytes: DEFAULT_UNDO_BUDGET_BYTES,
+            marks: Vec::new(),
+            kill_ring: Vec::new(),
+            kill_ring_max: DEFAULT_KILL_RING_SIZE,
+            kill_merge: None,
+            modified: false,
+            revision: 0,
+            last_revision: 0,
+            saved_revision: 0,
+            merge_revision: None,
+            search_case_sensitive: true,
+            transaction_depth: 0,
+            line_ending: LineEnding::default(),
+            file_stamp: None,
+            paste_trims_trailing_newline: false,
+            read_only: false,
+            change_tally: Default::default(),
+            last_change: None,
+            hide_change_previews: false,
+            preedit: None,
+            abbreviations: Vec::new(),
+            snippet_session: None,
+            macro_recording: None,
+            last_macro: None,
+            playing_macro: false,
+            options: EditorOptions::default(),
+            clipboard: SharedClipboard(Arc::new(Mutex::new(LocalClipboard::default()))),
+            view_width: None,
+            input_filter: None,
+            rejected_chars: 0,
+            word_chars: WordCharClass::default(),
+            events: Vec::new(),
+            layout_generation: 0,
+            layout_cache: RefCell::new(None),
+            match_cache: RefCell::new(None),
+            stats_cache: Cell::new(None),
+            reported_cursor: CursorPosition::default(),
+            reported_selection: (None, false),
         }
     }
 
-    /// Move cursor right
-    pub fn move_right(&mut self) {
-        self.selection_anchor = None;
-        let line_len = self.lines[self.cursor.line].chars().count();
-        if self.cursor.column < line_len {
-            self.cursor.column += 1;
-        } else if self.cursor.line + 1 < self.lines.len() {
-            self.cursor.line += 1;
-            self.cursor.column = 0;
-        }
+    /// Get the full text content
+    pub fn text(&self) -> Cow<'_, str> {
+        // For single-line input, this is fine; the full text is computed
+        // on demand
+        self.buffer.line_text(0)
     }
 
-    /// Move cursor up
-    pub fn move_up(&mut self) {
-        self.selection_anchor = None;
-        if self.cursor.line > 0 {
-            self.cursor.line -= 1;
-            let line_len = self.lines[self.cursor.line].chars().count();
-            self.cursor.column = self.cursor.column.min(line_len);
+    /// Capture the text, cursor, selection and kill ring for persistence
+    ///
+    /// Undo history is not included.
+    pub fn to_snapshot(&self) -> EditorSnapshot {
+        EditorSnapshot {
+            version: SNAPSHOT_VERSION,
+            lines: self.buffer.lines().map(String::from).collect(),
+            cursor: self.cursor,
+            selection_anchor: self.selection_anchor,
+            kill_ring: self.kill_ring.clone(),
+            modified: self.is_modified(),
+            line_ending: self.line_ending,
         }
     }
 
-    /// Move cursor down
-    pub fn move_down(&mut self) {
-        self.selection_anchor = None;
-        if self.cursor.line + 1 < self.lines.len() {
-            self.cursor.line += 1;
-            let line_len = self.lines[self.cursor.line].chars().count();
-            self.cursor.column = self.cursor.column.min(line_len);
+    /// Restore an editor from a snapshot
+    ///
+    /// Positions that don't fit the restored text are clamped.  Snapshots
+    /// from a newer format version are rejected.
+    pub fn from_snapshot(snapshot: EditorSnapshot) -> Result<Self, EditorError> {
+        snapshot.check_version()?;
+        let mut editor = Self::new();
+        let text = normalize_line_endings(&snapshot.lines.join("\n")).into_owned();
+        editor
+            .buffer
+            .replace_range(CursorPosition::default(), CursorPosition::default(), &text);
+        editor.cursor = editor.clamp_position(snapshot.cursor);
+        editor.selection_anchor = snapshot
+            .selection_anchor
+            .map(|anchor| editor.clamp_position(anchor));
+        editor.kill_ring = snapshot.kill_ring;
+        editor.trim_kill_ring();
+        if snapshot.modified {
+            // Give the restored text a revision of its own so it differs
+            // from the saved one
+            editor.modified = true;
+            editor.last_revision += 1;
+            editor.revision = editor.last_revision;
         }
+        editor.line_ending = snapshot.line_ending;
+        Ok(editor)
     }
 
-    /// Move cursor to start of line
-    pub fn move_to_line_start(&mut self) {
-        self.selection_anchor = None;
-        self.cursor.column = 0;
+    /// Get the full text as a single string, joined with '\n'
+    ///
+    /// Byte offsets such as cursor_pos and byte_offset_of index into this.
+    pub fn full_text(&self) -> String {
+        self.buffer.to_text()
     }
 
-    /// Move cursor to end of line
-    pub fn move_to_line_end(&mut self) {
-        self.selection_anchor = None;
-        self.cursor.column = self.lines[self.cursor.line].chars().count();
+    /// Get the full text joined with the preferred line ending, for saving
+    /// or handing to other programs
+    pub fn text_with_line_endings(&self) -> String {
+        self.buffer.join(self.line_ending.as_str())
     }
 
-    /// Move cursor word left
-    pub fn move_word_left(&mut self) {
-        self.selection_anchor = None;
-        let line = &self.lines[self.cursor.line];
-        let chars: Vec<char> = line.chars().collect();
-
-        if self.cursor.column == 0 {
-            if self.cursor.line > 0 {
-                self.cursor.line -= 1;
-                self.cursor.column = self.lines[self.cursor.line].chars().count();
+    /// Load a file, normalizing its line endings
+    ///
+    /// The file is read a line at a time.  Its dominant line ending is
+    /// kept for save_file, and a missing final newline stays missing.
+    pub fn load_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut lines = Vec::new();
+        let (mut dos, mut unix) = (0, 0);
+        let mut line = String::new();
+        let mut ended = true;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            ended = line.ends_with('\n');
+            if ended {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                    dos += 1;
+                } else {
+                    unix += 1;
+                }
+            }
+            // A lone '\r' is a line break too
+            lines.extend(line.split('\r').map(String::from));
+            if !ended {
+                break;
             }
-            return;
         }
-
-        // Skip whitespace
-        while self.cursor.column > 0
-            && chars
-                .get(self.cursor.column - 1)
-                .map_or(false, |c| c.is_whitespace())
-        {
-            self.cursor.column -= 1;
+        // The text after a final line break is an empty last line
+        if ended {
+            lines.push(String::new());
         }
 
-        // Skip word characters
-        while self.cursor.column > 0
-            && chars
-                .get(self.cursor.column - 1)
-                .map_or(false, |c| !c.is_whitespace())
-        {
-            self.cursor.column -= 1;
+        let mut editor = Self::new();
+        editor.buffer = TextBuffer::from_lines(lines);
+        if dos > unix {
+            editor.line_ending = LineEnding::Dos;
         }
+        editor.record_file_stamp(path);
+        Ok(editor)
     }
 
-    /// Move cursor word right
-    pub fn move_word_right(&mut self) {
-        self.selection_anchor = None;
-        let line = &self.lines[self.cursor.line];
-        let chars: Vec<char> = line.chars().collect();
-        let len = chars.len();
-
-        if self.cursor.column >= len {
-            if self.cursor.line + 1 < self.lines.len() {
-                self.cursor.line += 1;
-                self.cursor.column = 0;
+    /// Write the buffer to `path` atomically, then mark it unmodified
+    ///
+    /// The text goes to a temporary file in the same directory, which is
+    /// then renamed over `path`, so readers never see a partial file.
+    /// This doesn't check file_changed_on_disk; callers that care should.
+    pub fn save_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp = tempfile::NamedTempFile::new_in(dir)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            temp.as_file().set_permissions(metadata.permissions())?;
+        }
+        {
+            let mut writer = BufWriter::new(temp.as_file());
+            for (idx, line) in self.buffer.lines().enumerate() {
+                if idx > 0 {
+                    writer.write_all(self.line_ending.as_str().as_bytes())?;
+                }
+                writer.write_all(line.as_bytes())?;
             }
-            return;
+            writer.flush()?;
         }
+        temp.as_file().sync_all()?;
+        temp.persist(path).map_err(|err| err.error)?;
 
-        // Skip word characters
-        while self.cursor.column < len && !chars[self.cursor.column].is_whitespace() {
-            self.cursor.column += 1;
-        }
+        self.record_file_stamp(path);
+        self.mark_unmodified();
+        Ok(())
+    }
 
-        // Skip whitespace
-        while self.cursor.column < len && chars[self.cursor.column].is_whitespace() {
-            self.cursor.column += 1;
+    /// Check whether the last loaded or saved file changed since then
+    ///
+    /// A file that has gone missing counts as changed.  Returns false if
+    /// no file was loaded or saved.
+    pub fn file_changed_on_disk(&self) -> bool {
+        match &self.file_stamp {
+            Some((path, mtime, len)) => match std::fs::metadata(path) {
+                Ok(metadata) => metadata.modified().ok() != *mtime || metadata.len() != *len,
+                Err(_) => true,
+            },
+            None => false,
         }
     }
 
-    /// Kill to end of line (Ctrl+K)
-    pub fn kill_to_line_end(&mut self) {
-        self.save_undo_state();
+    /// Remember the modification time and size of `path`
+    fn record_file_stamp(&mut self, path: &Path) {
+        self.file_stamp = std::fs::metadata(path)
+            .ok()
+            .map(|metadata| (path.to_path_buf(), metadata.modified().ok(), metadata.len()));
+    }
 
-        let line = &self.lines[self.cursor.line];
-        let chars: Vec<char> = line.chars().collect();
-        let len = chars.len();
+    /// Get the preferred line ending
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
 
-        if self.cursor.column < len {
-            // Kill rest of line
-            let killed: String = chars[self.cursor.column..].iter().collect();
-            self.kill_ring.push(killed);
+    /// Set the line ending used by `text_with_line_endings` and save_file
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Set the text content, returning false if read-only or rejected
+    ///
+    /// Line endings are normalized to '\n', and the dominant one in `text`
+    /// becomes the preferred line ending.  The text is subject to the
+    /// single-line and length limits in the editor options.
+    pub fn set_text(&mut self, text: &str) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let new_text = match self.conform_text(text) {
+            Some(new_text) => new_text,
+            None => return false,
+        };
+        if let Some(line_ending) = LineEnding::detect(text) {
+            self.line_ending = line_ending;
+        }
+        // Setting the same text again leaves no undo entry
+        let unchanged = self.buffer.lines().eq(new_text.split('\n'));
+        if !unchanged {
+            self.save_undo_state();
+            self.replace_buffer(&new_text);
+            self.modified = true;
+        }
+        // Move cursor to end
+        self.cursor.line = self.buffer.line_count() - 1;
+        self.cursor.column = self.buffer.line_chars(self.cursor.line);
+        self.selection_anchor = None;
+        self.extra_cursors.clear();
+        self.snippet_session = None;
+        true
+    }
+
+    /// Text with line endings normalized and the single-line, normalization
+    /// and length options applied, or None if it is rejected
+    fn conform_text(&self, text: &str) -> Option<String> {
+        let normalized = normalize_line_endings(text);
+        let mut new_text = self.apply_newline_option(&normalized)?.into_owned();
+        if let Some(form) = self.options.normalize {
+            if !form.is_normalized(&new_text) {
+                new_text = form.apply(&new_text);
+            }
+        }
+        if let Some(max) = self.options.max_chars {
+            new_text.truncate(byte_at_char(&new_text, max));
+        }
+        Some(new_text)
+    }
+
+    /// Replace the text content while keeping the cursor where it was,
+    /// returning false if read-only or rejected
+    ///
+    /// Only the span between the common prefix and suffix of the old and
+    /// new text is replaced, so the cursor and extra cursors shift with
+    /// edits before them; a cursor inside the replaced span is clamped to its
+    /// end.  Identical text leaves no undo entry.  As with set_text, the
+    /// dominant line ending in `text` becomes the preferred one, and the
+    /// text is subject to the limits in the editor options.
+    pub fn set_text_preserve_cursor(&mut self, text: &str) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let new_text = match self.conform_text(text) {
+            Some(new_text) => new_text,
+            None => return false,
+        };
+        if let Some(line_ending) = LineEnding::detect(text) {
+            self.line_ending = line_ending;
+        }
+        let old = self.buffer.to_text();
+        let new = new_text.as_str();
+        let (old_len, new_len) = (self.buffer.len_chars(), new.chars().count());
+
+        let prefix = old
+            .chars()
+            .zip(new.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let max_suffix = old_len.min(new_len) - prefix;
+        let suffix = old
+            .chars()
+            .rev()
+            .zip(new.chars().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let old_end = old_len - suffix;
+        let new_end = new_len - suffix;
+        if prefix == old_end && prefix == new_end {
+            return true;
+        }
+
+        let remap = |offset: usize| {
+            if offset <= prefix {
+                offset
+            } else if offset >= old_end {
+                offset - old_end + new_end
+            } else {
+                new_end
+            }
+        };
+        let cursor = remap(self.char_offset_of(self.cursor));
+        let extra: Vec<usize> = self
+            .extra_cursors
+            .iter()
+            .map(|&c| remap(self.char_offset_of(c)))
+            .collect();
+
+        self.save_undo_state();
+        let start = self.position_of_char_offset(prefix);
+        let end = self.position_of_char_offset(old_end);
+        let replacement = &new[byte_at_char(new, prefix)..byte_at_char(new, new_end)];
+        self.splice(start, end, replacement);
+
+        self.selection_anchor = None;
+        self.cursor = self.position_of_char_offset(cursor);
+        self.extra_cursors = extra
+            .into_iter()
+            .map(|offset| self.position_of_char_offset(offset))
+            .collect();
+        true
+    }
+
+    /// Replace the text like set_text_preserve_cursor, folding consecutive
+    /// calls into one undo entry
+    ///
+    /// Calls with no other edit in between share the entry, so swapping
+    /// through history entries and back leaves a single undo step.  Returns
+    /// false if read-only or rejected.
+    pub fn set_text_merging_undo(&mut self, text: &str) -> bool {
+        let merge = self.merge_revision == Some(self.revision) && !self.undo_stack.is_empty();
+        // An open transaction makes the edits land in the last entry
+        if merge {
+            self.transaction_depth += 1;
+        }
+        let accepted = self.set_text_preserve_cursor(text);
+        if merge {
+            self.transaction_depth -= 1;
+        }
+        self.merge_revision = Some(self.revision);
+        accepted
+    }
+
+    /// Clear the editor, returning false if read-only
+    pub fn clear(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        self.save_undo_state();
+        self.replace_buffer("");
+        self.discard_empty_undo_entry();
+        self.cursor = CursorPosition::default();
+        self.selection_anchor = None;
+        self.extra_cursors.clear();
+        self.snippet_session = None;
+        self.modified = false;
+        true
+    }
+
+    /// Get current cursor position as byte offset
+    pub fn cursor_pos(&self) -> usize {
+        self.byte_offset_of(self.cursor)
+    }
+
+    /// Byte offset of a position in the full text, counting '\n' as one byte
+    pub fn byte_offset_of(&self, pos: CursorPosition) -> usize {
+        let pos = self.clamp_position(pos);
+        self.byte_offset_of_line_start(pos.line) + self.buffer.byte_of_column(pos.line, pos.column)
+    }
+
+    /// Position of a byte offset in the full text, clamped to the buffer end
+    ///
+    /// An offset inside a multi-byte char rounds up to the next char.
+    pub fn position_of_byte(&self, offset: usize) -> CursorPosition {
+        let line = self.buffer.line_at_byte(offset);
+        let offset = offset.saturating_sub(self.buffer.line_start_byte(line));
+        CursorPosition {
+            line,
+            column: self.buffer.column_of_byte(line, offset),
+        }
+    }
+
+    /// Text between two byte offsets of the full text
+    pub fn text_in_range(&self, range: Range<usize>) -> String {
+        let start = self.position_of_byte(range.start);
+        let end = self.position_of_byte(range.end.max(range.start));
+        self.buffer.text_between(start, end)
+    }
+
+    /// Get cursor position as (line, column)
+    pub fn cursor_coords(&self) -> (usize, usize) {
+        (self.cursor.line, self.cursor.column)
+    }
+
+    /// Set cursor position
+    pub fn set_cursor(&mut self, byte_pos: usize) {
+        self.cursor = self.position_of_byte(byte_pos);
+    }
+
+    /// Move the cursor to (line, column), clamped to the buffer
+    ///
+    /// Clears the selection and returns the position actually used.
+    pub fn set_cursor_coords(&mut self, line: usize, column: usize) -> CursorPosition {
+        self.selection_anchor = None;
+        self.move_cursor_to(line, column)
+    }
+
+    /// Move the cursor to (line, column), extending the selection
+    ///
+    /// Starts a selection at the old cursor position if there is none.
+    pub fn extend_selection_to_coords(&mut self, line: usize, column: usize) -> CursorPosition {
+        if self.selection_anchor.is_none() {
+            self.start_selection();
+        }
+        self.move_cursor_to(line, column)
+    }
+
+    /// Move the cursor to (line, column), failing if that is outside the buffer
+    pub fn try_set_cursor_coords(
+        &mut self,
+        line: usize,
+        column: usize,
+    ) -> Result<CursorPosition, EditorError> {
+        let pos = CursorPosition { line, column };
+        if self.clamp_position(pos) != pos {
+            return Err(EditorError::OutOfRange { line, column });
+        }
+        Ok(self.set_cursor_coords(line, column))
+    }
+
+    /// Move the cursor to a clamped position and reset the goal column
+    fn move_cursor_to(&mut self, line: usize, column: usize) -> CursorPosition {
+        self.cursor = self.clamp_position(CursorPosition { line, column });
+        self.goal_column = None;
+        self.visual_goal = None;
+        self.cursor
+    }
+
+    /// Insert a character at cursor position, returning whether it was accepted
+    pub fn insert_char(&mut self, c: char) -> bool {
+        self.insert_str(c.encode_utf8(&mut [0; 4])) > 0
+    }
+
+    /// Internal character insertion without undo state save
+    fn insert_char_internal(&mut self, c: char) {
+        self.insert_str_internal(c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Internal string insertion without undo state save
+    ///
+    /// The text is spliced in as a whole, so the cost depends on the size
+    /// of the pasted text rather than on its number of characters times the
+    /// line length.
+    fn insert_str_internal(&mut self, s: &str) {
+        let pos = self.cursor;
+        self.splice(pos, pos, s);
+        self.cursor = end_of_insert(pos, s);
+    }
+
+    /// Insert a string at cursor position
+    ///
+    /// The text goes through the input filter, then is cut down to fit the
+    /// single-line and length limits in the editor options.  Returns the
+    /// number of chars accepted, which is zero if the editor is read-only;
+    /// rejected_chars tells how many the filter dropped.
+    pub fn insert_str(&mut self, s: &str) -> usize {
+        self.rejected_chars = 0;
+        if self.read_only {
+            return 0;
+        }
+        let s = normalize_line_endings(s);
+        let s = self.filter_input(&s);
+        let s = match self.constrain_insertion(&s) {
+            Some(s) if !s.is_empty() => s,
+            _ => return 0,
+        };
+        let accepted = s.chars().count();
+
+        self.save_undo_state();
+        let before = self.cursor_lines();
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_each_cursor(|editor| editor.insert_str_internal(&s));
+        } else if !s.contains('\n') && self.has_block_selection() {
+            self.insert_in_block(&s);
+        } else {
+            self.delete_selection_internal();
+            self.insert_str_internal(&s);
+        }
+        if let Some(form) = self.options.normalize {
+            // Inserted marks may combine with the text around them, so
+            // whole lines are normalized rather than just the insertion
+            let after = self.cursor_lines();
+            self.normalize_lines(before.start.min(after.start)..after.end, form);
+        }
+        accepted
+    }
+
+    /// Run text through the input filter, counting rejected chars
+    fn filter_input<'a>(&mut self, text: &'a str) -> Cow<'a, str> {
+        let filter = match &self.input_filter {
+            Some(filter) => Arc::clone(&filter.0),
+            None => return Cow::Borrowed(text),
+        };
+        let mut filtered = String::with_capacity(text.len());
+        for c in text.chars() {
+            match filter(c) {
+                InputFilterResult::Accept => filtered.push(c),
+                InputFilterResult::Replace(r) => filtered.push(r),
+                InputFilterResult::Reject => self.rejected_chars += 1,
+            }
+        }
+        Cow::Owned(filtered)
+    }
+
+    /// Check or rewrite every typed and pasted char with `filter`
+    ///
+    /// Text set with set_text is not filtered.
+    pub fn set_input_filter(
+        &mut self,
+        filter: Box<dyn Fn(char) -> InputFilterResult + Send + Sync>,
+    ) {
+        self.input_filter = Some(InputFilter(Arc::from(filter)));
+    }
+
+    /// Stop filtering input
+    pub fn clear_input_filter(&mut self) {
+        self.input_filter = None;
+    }
+
+    /// Number of chars the input filter dropped from the last insertion
+    pub fn rejected_chars(&self) -> usize {
+        self.rejected_chars
+    }
+
+    /// Lines from the first to the last cursor or selection anchor
+    fn cursor_lines(&self) -> Range<usize> {
+        let positions = || {
+            std::iter::once(self.cursor)
+                .chain(self.selection_anchor)
+                .chain(self.extra_cursors.iter().copied())
+        };
+        let first = positions().map(|pos| pos.line).min().unwrap_or(0);
+        let last = positions().map(|pos| pos.line).max().unwrap_or(0);
+        first..(last + 1).min(self.buffer.line_count())
+    }
+
+    /// Normalize every line in `lines` that isn't in `form` yet
+    ///
+    /// Cursors stay on the same grapheme.  Changes go into the open undo
+    /// entry.  Returns whether any line changed.
+    fn normalize_lines(&mut self, lines: Range<usize>, form: NormalizationForm) -> bool {
+        let mut changed = false;
+        for line in lines {
+            let text = &self.buffer.line_text(line);
+            if form.is_normalized(text) {
+                continue;
+            }
+            let normalized = form.apply(text);
+            let to_grapheme =
+                |pos: CursorPosition| (pos.line == line).then(|| grapheme_index(text, pos.column));
+            let cursor = to_grapheme(self.cursor);
+            let anchor = self.selection_anchor.and_then(to_grapheme);
+            let extra: Vec<Option<usize>> = self
+                .extra_cursors
+                .iter()
+                .map(|&pos| to_grapheme(pos))
+                .collect();
+
+            let end = CursorPosition {
+                line,
+                column: self.buffer.line_chars(line),
+            };
+            self.splice(CursorPosition { line, column: 0 }, end, &normalized);
+
+            let at = |grapheme: usize| CursorPosition {
+                line,
+                column: column_of_grapheme(&normalized, grapheme),
+            };
+            if let Some(g) = cursor {
+                self.cursor = at(g);
+            }
+            if let Some(g) = anchor {
+                self.selection_anchor = Some(at(g));
+            }
+            for (pos, g) in self.extra_cursors.iter_mut().zip(extra) {
+                if let Some(g) = g {
+                    *pos = at(g);
+                }
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// Normalize the whole buffer in one undo step
+    ///
+    /// Uses the form from the editor options, or NFC if normalization is
+    /// off.  Returns whether anything changed.
+    pub fn normalize_buffer(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let form = self.options.normalize.unwrap_or_default();
+        let lines = 0..self.buffer.line_count();
+        if lines
+            .clone()
+            .all(|line| form.is_normalized(&self.buffer.line_text(line)))
+        {
+            return false;
+        }
+        self.save_undo_state();
+        self.normalize_lines(lines, form)
+    }
+
+    /// Set the limits on what the editor accepts
+    ///
+    /// Existing text is left alone; the limits apply to later edits.
+    pub fn set_options(&mut self, options: EditorOptions) {
+        self.options = options;
+    }
+
+    /// Get the limits on what the editor accepts
+    pub fn options(&self) -> EditorOptions {
+        self.options
+    }
+
+    /// Apply the single-line option to text, or None if it is rejected
+    fn apply_newline_option<'a>(&self, text: &'a str) -> Option<Cow<'a, str>> {
+        if !self.options.single_line || !text.contains('\n') {
+            return Some(Cow::Borrowed(text));
+        }
+        match self.options.newlines {
+            NewlineHandling::Strip => Some(Cow::Owned(text.replace('\n', ""))),
+            NewlineHandling::Reject => None,
+        }
+    }
+
+    /// Cut text about to be inserted down to what the options allow
+    fn constrain_insertion<'a>(&self, text: &'a str) -> Option<Cow<'a, str>> {
+        let mut text = self.apply_newline_option(text)?;
+        if let Some(max) = self.options.max_chars {
+            // Every cursor, or every line of a block selection, gets a copy
+            let (copies, replaced) = if !self.extra_cursors.is_empty() {
+                (self.extra_cursors.len() + 1, 0)
+            } else if let Some((lines, cols)) = self.block_bounds() {
+                let replaced = lines
+                    .clone()
+                    .map(|line| self.block_columns(line, &cols).len())
+                    .sum();
+                (lines.len(), replaced)
+            } else if let Some((start, end)) = self.selection() {
+                (1, self.char_offset_of(end) - self.char_offset_of(start))
+            } else {
+                (1, 0)
+            };
+            let room = max.saturating_sub(self.char_len() - replaced) / copies;
+            let end = byte_at_char(&text, room);
+            if end < text.len() {
+                text = Cow::Owned(text[..end].to_string());
+            }
+        }
+        Some(text)
+    }
+
+    /// Insert pasted text as a single undoable edit
+    ///
+    /// Control characters other than newline and tab are stripped, along
+    /// with any escape sequences, so a clipboard can't smuggle in hidden
+    /// commands.  Returns the number of characters inserted.
+    pub fn insert_paste(&mut self, text: &str) -> usize {
+        if self.read_only {
+            return 0;
+        }
+        let mut text = sanitize_paste(text);
+        if self.paste_trims_trailing_newline && text.ends_with('\n') {
+            text.pop();
+        }
+        if text.is_empty() {
+            return 0;
+        }
+
+        self.transaction().insert_str(&text)
+    }
+
+    /// Set whether edits are rejected
+    ///
+    /// A read-only editor still moves, selects and copies, but every method
+    /// that would change the text does nothing and reports that.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Check whether edits are rejected
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Set whether a single trailing newline is dropped from pasted text
+    pub fn set_paste_trims_trailing_newline(&mut self, trim: bool) {
+        self.paste_trims_trailing_newline = trim;
+    }
+
+    /// Check whether a single trailing newline is dropped from pasted text
+    pub fn paste_trims_trailing_newline(&self) -> bool {
+        self.paste_trims_trailing_newline
+    }
+
+    /// Delete character before cursor (backspace), returning false if read-only
+    pub fn backspace(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        self.save_undo_state();
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_each_cursor(Self::backspace_internal);
+        } else if !self.delete_selection_internal() {
+            self.backspace_internal();
+        }
+        // Backspace at the start of the buffer changes nothing
+        self.discard_empty_undo_entry();
+        true
+    }
+
+    /// Internal backspace without undo state save
+    fn backspace_internal(&mut self) {
+        let pos = self.cursor;
+        if pos.column > 0 {
+            // Delete character within line
+            self.cursor.column -= 1;
+            self.splice(self.cursor, pos, "");
+        } else if pos.line > 0 {
+            // Join with previous line
+            self.cursor.line -= 1;
+            self.cursor.column = self.buffer.line_chars(self.cursor.line);
+            self.splice(self.cursor, pos, "");
+        }
+    }
+
+    /// Delete character at cursor (delete key), returning false if read-only
+    pub fn delete(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        self.save_undo_state();
+        if !self.extra_cursors.is_empty() {
+            self.edit_at_each_cursor(Self::delete_internal);
+        } else if !self.delete_selection_internal() {
+            self.delete_internal();
+        }
+        // Delete at the end of the buffer changes nothing
+        self.discard_empty_undo_entry();
+        true
+    }
+
+    /// Internal forward delete without undo state save
+    fn delete_internal(&mut self) {
+        let pos = self.cursor;
+        let char_count = self.buffer.line_chars(pos.line);
+
+        if pos.column < char_count {
+            // Delete character at cursor
+            let next = CursorPosition {
+                line: pos.line,
+                column: pos.column + 1,
+            };
+            self.splice(pos, next, "");
+        } else if pos.line + 1 < self.buffer.line_count() {
+            // Join with next line
+            let next = CursorPosition {
+                line: pos.line + 1,
+                column: 0,
+            };
+            self.splice(pos, next, "");
+        }
+    }
+
+    /// Delete a range of text (byte positions), returning false if read-only
+    pub fn delete_range(&mut self, start: usize, end: usize) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let start = self.position_of_byte(start);
+        let end = self.position_of_byte(end);
+        if start.line < end.line || (start.line == end.line && start.column < end.column) {
+            self.save_undo_state();
+            self.splice(start, end, "");
+        }
+        self.cursor = self.clamp_position(self.cursor);
+        true
+    }
+
+    /// Move cursor left, returning whether it moved
+    pub fn move_left(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        if self.cursor.column > 0 {
+            self.cursor.column -= 1;
+        } else if self.cursor.line > 0 {
+            self.cursor.line -= 1;
+            self.cursor.column = self.buffer.line_chars(self.cursor.line);
+        }
+        self.note_move(before)
+    }
+
+    /// Move cursor right, returning whether it moved
+    pub fn move_right(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        let line_len = self.buffer.line_chars(self.cursor.line);
+        if self.cursor.column < line_len {
+            self.cursor.column += 1;
+        } else if self.cursor.line + 1 < self.buffer.line_count() {
+            self.cursor.line += 1;
+            self.cursor.column = 0;
+        }
+        self.note_move(before)
+    }
+
+    /// Move cursor up, returning whether it moved
+    pub fn move_up(&mut self) -> bool {
+        if self.options.single_line {
+            return false;
+        }
+        self.selection_anchor = None;
+        let before = self.cursor;
+        if self.cursor.line > 0 {
+            let goal = self.goal_column();
+            self.cursor.line -= 1;
+            let line_len = self.buffer.line_chars(self.cursor.line);
+            self.cursor.column = goal.min(line_len);
+            self.goal_column = Some((self.cursor, goal));
+        }
+        self.note_move(before)
+    }
+
+    /// Move cursor down, returning whether it moved
+    pub fn move_down(&mut self) -> bool {
+        if self.options.single_line {
+            return false;
+        }
+        self.selection_anchor = None;
+        let before = self.cursor;
+        if self.cursor.line + 1 < self.buffer.line_count() {
+            let goal = self.goal_column();
+            self.cursor.line += 1;
+            let line_len = self.buffer.line_chars(self.cursor.line);
+            self.cursor.column = goal.min(line_len);
+            self.goal_column = Some((self.cursor, goal));
+        }
+        self.note_move(before)
+    }
+
+    /// Column that vertical movement should aim for
+    fn goal_column(&self) -> usize {
+        match self.goal_column {
+            Some((pos, column)) if pos == self.cursor => column,
+            _ => self.cursor.column,
+        }
+    }
+
+    /// Move cursor up one visual row when soft-wrapped at `width`
+    ///
+    /// Falls back to move_up when no line wraps.
+    pub fn move_up_visual(&mut self, width: usize, tab_width: usize) -> bool {
+        self.selection_anchor = None;
+        self.move_visual(false, width, tab_width)
+    }
+
+    /// Move cursor down one visual row when soft-wrapped at `width`
+    ///
+    /// Falls back to move_down when no line wraps.
+    pub fn move_down_visual(&mut self, width: usize, tab_width: usize) -> bool {
+        self.selection_anchor = None;
+        self.move_visual(true, width, tab_width)
+    }
+
+    /// Move cursor up one visual row, extending the selection
+    pub fn extend_selection_up_visual(&mut self, width: usize, tab_width: usize) -> bool {
+        if self.selection_anchor.is_none() {
+            self.start_selection();
+        }
+        self.move_visual(false, width, tab_width)
+    }
+
+    /// Move cursor down one visual row, extending the selection
+    pub fn extend_selection_down_visual(&mut self, width: usize, tab_width: usize) -> bool {
+        if self.selection_anchor.is_none() {
+            self.start_selection();
+        }
+        self.move_visual(true, width, tab_width)
+    }
+
+    /// Soft-wrap the visual-line commands at `width` display cells
+    pub fn set_view_width(&mut self, width: usize, tab_width: usize) {
+        self.view_width = Some((width, tab_width));
+    }
+
+    /// Run `motion`, keeping or starting the selection at the old cursor
+    fn extend_selection_with(&mut self, motion: impl FnOnce(&mut Self) -> bool) -> bool {
+        let block = self.has_block_selection();
+        let anchor = self.selection_anchor.unwrap_or(self.cursor);
+        let moved = motion(self);
+        self.selection_anchor = Some(anchor);
+        self.block_selection = block;
+        moved
+    }
+
+    /// Move one visual row keeping the goal display column
+    fn move_visual(&mut self, down: bool, width: usize, tab_width: usize) -> bool {
+        let layout = self.wrap_layout(width, tab_width);
+        if layout.visual_row_count() == self.buffer.line_count() {
+            // Nothing wraps, so rows and lines are the same thing
+            let anchor = self.selection_anchor;
+            let moved = if down {
+                self.move_down()
+            } else {
+                self.move_up()
+            };
+            self.selection_anchor = anchor;
+            return moved;
+        }
+
+        let before = self.cursor;
+        let (row, vcol) = layout.visual_row(self.cursor.line, self.cursor.column);
+        let goal = match self.visual_goal {
+            Some((pos, goal)) if pos == self.cursor => goal,
+            _ => vcol,
+        };
+        let target = if down {
+            row + 1
+        } else {
+            match row.checked_sub(1) {
+                Some(row) => row,
+                None => return false,
+            }
+        };
+        if target >= layout.visual_row_count() {
+            return false;
+        }
+        self.cursor = layout.position_of_visual(target, goal);
+        self.visual_goal = Some((self.cursor, goal));
+        self.note_move(before)
+    }
+
+    /// Move cursor to the start of its visual row, returning whether it moved
+    pub fn move_to_visual_line_start(&mut self, width: usize, tab_width: usize) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        let layout = self.wrap_layout(width, tab_width);
+        let (row, _) = layout.visual_row(self.cursor.line, self.cursor.column);
+        let (_, columns) = layout.row_bounds(row);
+        self.cursor.column = columns.start;
+        self.note_move(before)
+    }
+
+    /// Move cursor to the end of its visual row, returning whether it moved
+    ///
+    /// On a row that wraps this is just before the row's last char, since the
+    /// position after it belongs to the next row.
+    pub fn move_to_visual_line_end(&mut self, width: usize, tab_width: usize) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        let layout = self.wrap_layout(width, tab_width);
+        let (row, _) = layout.visual_row(self.cursor.line, self.cursor.column);
+        let (_, columns) = layout.row_bounds(row);
+        self.cursor.column = if columns.end < self.buffer.line_chars(self.cursor.line) {
+            columns.end.saturating_sub(1).max(columns.start)
+        } else {
+            columns.end
+        };
+        self.note_move(before)
+    }
+
+    /// Move cursor to start of line, returning whether it moved
+    pub fn move_to_line_start(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        self.cursor.column = 0;
+        self.note_move(before)
+    }
+
+    /// Move cursor to the first non-whitespace char, or to column 0 if
+    /// already there, returning whether it moved
+    pub fn move_to_line_start_smart(&mut self) -> bool {
+        self.selection_anchor = None;
+        self.smart_home()
+    }
+
+    /// Smart Home, extending the selection
+    pub fn extend_selection_to_line_start_smart(&mut self) -> bool {
+        if self.selection_anchor.is_none() {
+            self.start_selection();
+        }
+        self.smart_home()
+    }
+
+    fn smart_home(&mut self) -> bool {
+        let before = self.cursor;
+        let indent = self.first_non_whitespace_column(self.cursor.line);
+        self.cursor.column = if self.cursor.column == indent {
+            0
+        } else {
+            indent
+        };
+        self.note_move(before)
+    }
+
+    /// Column of the first non-whitespace char on a line
+    ///
+    /// For a line that is all whitespace this is the line end.
+    pub fn first_non_whitespace_column(&self, line: usize) -> usize {
+        self.buffer.line(line).map_or(0, |text| {
+            text.chars().take_while(|c| c.is_whitespace()).count()
+        })
+    }
+
+    /// Move cursor to end of line, returning whether it moved
+    pub fn move_to_line_end(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        self.cursor.column = self.buffer.line_chars(self.cursor.line);
+        self.note_move(before)
+    }
+
+    /// Move cursor to the start of the buffer, pushing a mark
+    pub fn move_to_buffer_start(&mut self) -> bool {
+        self.selection_anchor = None;
+        self.jump_to(CursorPosition::default())
+    }
+
+    /// Move cursor to the end of the buffer, pushing a mark
+    pub fn move_to_buffer_end(&mut self) -> bool {
+        self.selection_anchor = None;
+        let line = self.buffer.line_count() - 1;
+        self.jump_to(CursorPosition {
+            line,
+            column: self.buffer.line_chars(line),
+        })
+    }
+
+    /// Move to the blank line after the paragraph, pushing a mark (M-})
+    ///
+    /// Blank lines at the cursor are skipped first, so repeating moves on
+    /// paragraph by paragraph.  Past the last paragraph this goes to the
+    /// end of the buffer.
+    pub fn move_paragraph_forward(&mut self) -> bool {
+        self.selection_anchor = None;
+        let count = self.buffer.line_count();
+        let mut line = self.cursor.line;
+        while line < count && self.is_blank_line(line) {
+            line += 1;
+        }
+        while line < count && !self.is_blank_line(line) {
+            line += 1;
+        }
+        let pos = if line < count {
+            CursorPosition { line, column: 0 }
+        } else {
+            self.buffer_end()
+        };
+        self.jump_to(pos)
+    }
+
+    /// Move to the blank line before the paragraph, pushing a mark (M-{)
+    ///
+    /// Before the first paragraph this goes to the start of the buffer.
+    pub fn move_paragraph_backward(&mut self) -> bool {
+        self.selection_anchor = None;
+        let mut line = self.cursor.line;
+        while line > 0 && self.is_blank_line(line) {
+            line -= 1;
+        }
+        while line > 0 && !self.is_blank_line(line) {
+            line -= 1;
+        }
+        self.jump_to(CursorPosition { line, column: 0 })
+    }
+
+    /// Select the paragraph around the cursor as whole lines
+    ///
+    /// On a blank line the next paragraph is selected.  Returns false if
+    /// there is none.
+    pub fn select_paragraph(&mut self) -> bool {
+        let count = self.buffer.line_count();
+        let mut start = self.cursor.line;
+        while start < count && self.is_blank_line(start) {
+            start += 1;
+        }
+        if start == count {
+            return false;
+        }
+        while start > 0 && !self.is_blank_line(start - 1) {
+            start -= 1;
+        }
+        let mut end = start;
+        while end + 1 < count && !self.is_blank_line(end + 1) {
+            end += 1;
+        }
+
+        self.selection_anchor = Some(CursorPosition {
+            line: start,
+            column: 0,
+        });
+        self.block_selection = false;
+        self.cursor = CursorPosition {
+            line: end,
+            column: self.buffer.line_chars(end),
+        };
+        true
+    }
+
+    /// Whether a line is empty or only whitespace
+    fn is_blank_line(&self, line: usize) -> bool {
+        self.buffer.line_text(line).trim().is_empty()
+    }
+
+    /// Move cursor to the next occurrence of `query` after it, pushing a mark
+    pub fn move_to_next_match(&mut self, query: &str) -> bool {
+        let from = self.position_of_char_offset(self.char_offset_of(self.cursor) + 1);
+        match self.find_next(query, from) {
+            Some(found) => {
+                self.selection_anchor = None;
+                self.jump_to(found.start)
+            }
+            None => false,
+        }
+    }
+
+    /// Move the cursor somewhere far away, remembering where it was
+    fn jump_to(&mut self, pos: CursorPosition) -> bool {
+        if pos == self.cursor {
+            return false;
+        }
+        self.push_mark();
+        let before = self.cursor;
+        self.cursor = pos;
+        self.note_move(before)
+    }
+
+    /// Describe the cursor moving from `before`, returning whether it moved
+    ///
+    /// Every motion ends here so that last_change_description reports
+    /// it, whether it came from execute or a direct call.
+    fn note_move(&mut self, before: CursorPosition) -> bool {
+        if self.cursor == before {
+            return false;
+        }
+        self.last_change = Some(ChangeDescription::CursorMoved {
+            line: self.cursor.line,
+            column: self.cursor.column,
+        });
+        true
+    }
+
+    /// Push the cursor position onto the mark ring
+    pub fn push_mark(&mut self) {
+        if self.marks.last() == Some(&self.cursor) {
+            return;
+        }
+        self.marks.push(self.cursor);
+        if self.marks.len() > MAX_MARKS {
+            self.marks.remove(0);
+        }
+    }
+
+    /// Jump to the most recent mark, rotating it to the back of the ring
+    ///
+    /// Returns false if the ring is empty.
+    pub fn pop_mark_and_jump(&mut self) -> bool {
+        let mark = match self.marks.pop() {
+            Some(mark) => mark,
+            None => return false,
+        };
+        self.marks.insert(0, mark);
+        self.selection_anchor = None;
+        let before = self.cursor;
+        self.cursor = self.clamp_position(mark);
+        self.note_move(before);
+        true
+    }
+
+    /// Swap the cursor and the selection anchor
+    ///
+    /// Without a selection the most recent mark stands in for the anchor,
+    /// and the region between them becomes selected.  The mark takes the
+    /// cursor's place rather than being consumed, so doing it again swaps
+    /// back as in emacs.
+    pub fn exchange_point_and_mark(&mut self) -> bool {
+        let anchor = match (self.selection_anchor, self.marks.last_mut()) {
+            (Some(anchor), _) => anchor,
+            (None, Some(mark)) => std::mem::replace(mark, self.cursor),
+            (None, None) => return false,
+        };
+        let before = self.cursor;
+        self.selection_anchor = Some(before);
+        self.cursor = self.clamp_position(anchor);
+        self.note_move(before);
+        true
+    }
+
+    /// Marks in the ring, most recent last
+    pub fn marks(&self) -> &[CursorPosition] {
+        &self.marks
+    }
+
+    /// Move cursor word left, returning whether it moved
+    pub fn move_word_left(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        if self.cursor.column == 0 {
+            if self.cursor.line > 0 {
+                self.cursor.line -= 1;
+                self.cursor.column = self.buffer.line_chars(self.cursor.line);
+            }
+            return self.note_move(before);
+        }
+
+        self.cursor.column = self.word_start_before(self.cursor);
+        self.note_move(before)
+    }
+
+    /// Move cursor word right, returning whether it moved
+    pub fn move_word_right(&mut self) -> bool {
+        self.selection_anchor = None;
+        let before = self.cursor;
+        let chars: Vec<char> = self.buffer.line_text(self.cursor.line).chars().collect();
+        let len = chars.len();
+
+        if self.cursor.column >= len {
+            if self.cursor.line + 1 < self.buffer.line_count() {
+                self.cursor.line += 1;
+                self.cursor.column = 0;
+            }
+            return self.note_move(before);
+        }
+
+        // Skip word characters
+        let word = self.word_chars;
+        while self.cursor.column < len && word.is_word_char(chars[self.cursor.column]) {
+            self.cursor.column += 1;
+        }
+
+        // Skip separators
+        while self.cursor.column < len && !word.is_word_char(chars[self.cursor.column]) {
+            self.cursor.column += 1;
+        }
+        self.note_move(before)
+    }
+
+    /// Column where the word ending at or before `pos` starts
+    ///
+    /// Separators right before `pos` are skipped first, then the word.
+    fn word_start_before(&self, pos: CursorPosition) -> usize {
+        let word = self.word_chars;
+        let chars: Vec<char> = self
+            .buffer
+            .line_text(pos.line)
+            .chars()
+            .take(pos.column)
+            .collect();
+        let mut column = chars.len();
+
+        // Skip separators
+        while column > 0 && !word.is_word_char(chars[column - 1]) {
+            column -= 1;
+        }
+
+        // Skip word characters
+        while column > 0 && word.is_word_char(chars[column - 1]) {
+            column -= 1;
+        }
+        column
+    }
+
+    /// Set which characters word motions and word kills treat as part of a word
+    pub fn set_word_char_class(&mut self, class: WordCharClass) {
+        self.word_chars = class;
+        self.stats_cache.set(None);
+    }
+
+    /// Get which characters word motions and word kills treat as part of a word
+    pub fn word_char_class(&self) -> WordCharClass {
+        self.word_chars
+    }
+
+    /// Replace the word before the cursor, as when accepting a completion
+    ///
+    /// The word runs back from the cursor to just after the last char for
+    /// which `boundaries` is true; pass complete::is_word_break to match
+    /// the Completer.  Only the part before the cursor is replaced, and an
+    /// empty word makes this a plain insertion.  The cursor ends up after
+    /// the replacement.  Returns the char range that was replaced.
+    pub fn replace_word_before_cursor(
+        &mut self,
+        boundaries: &dyn Fn(char) -> bool,
+        replacement: &str,
+    ) -> Range<usize> {
+        self.replace_word(boundaries, replacement, false)
+    }
+
+    /// Replace the whole word around the cursor, including the part after it
+    ///
+    /// Otherwise the same as replace_word_before_cursor.
+    pub fn replace_word_at_cursor(
+        &mut self,
+        boundaries: &dyn Fn(char) -> bool,
+        replacement: &str,
+    ) -> Range<usize> {
+        self.replace_word(boundaries, replacement, true)
+    }
+
+    fn replace_word(
+        &mut self,
+        boundaries: &dyn Fn(char) -> bool,
+        replacement: &str,
+        whole: bool,
+    ) -> Range<usize> {
+        let cursor = self.clamp_position(self.cursor);
+        let offset = self.char_offset_of(cursor);
+        if self.read_only {
+            return offset..offset;
+        }
+        let chars: Vec<char> = self.buffer.line_text(cursor.line).chars().collect();
+        let mut start = cursor.column;
+        while start > 0 && !boundaries(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = cursor.column;
+        while whole && end < chars.len() && !boundaries(chars[end]) {
+            end += 1;
+        }
+
+        let start = CursorPosition {
+            line: cursor.line,
+            column: start,
+        };
+        let end = CursorPosition {
+            line: cursor.line,
+            column: end,
+        };
+        let replaced =
+            offset - (cursor.column - start.column)..offset + (end.column - cursor.column);
+        self.save_undo_state();
+        self.splice(start, end, replacement);
+        self.cursor = end_of_insert(start, replacement);
+        self.selection_anchor = None;
+        self.extra_cursors.clear();
+        self.discard_empty_undo_entry();
+        replaced
+    }
+
+    /// Char right before the cursor, '\n' at the start of a later line
+    pub fn char_before_cursor(&self) -> Option<char> {
+        match self.cursor.column {
+            0 if self.cursor.line > 0 => Some('\n'),
+            0 => None,
+            column => self
+                .buffer
+                .line_text(self.cursor.line)
+                .chars()
+                .nth(column - 1),
+        }
+    }
+
+    /// Char under the cursor, '\n' at the end of a line that has another
+    /// after it
+    pub fn char_at_cursor(&self) -> Option<char> {
+        let pos = self.cursor;
+        self.buffer
+            .line_text(pos.line)
+            .chars()
+            .nth(pos.column)
+            .or_else(|| Some('\n').filter(|_| pos.line + 1 < self.buffer.line_count()))
+    }
+
+    /// Word chars running up to the cursor, or None if the char before
+    /// the cursor isn't a word char
+    pub fn word_before_cursor(&self) -> Option<Cow<'_, str>> {
+        let text = self.buffer.line_text(self.cursor.line);
+        let start = self.word_start_at(self.cursor);
+        if start == self.cursor.column {
+            return None;
+        }
+        let line = self.cursor.line;
+        let from = self.buffer.byte_of_column(line, start);
+        let to = self.buffer.byte_of_column(line, self.cursor.column);
+        Some(match text {
+            Cow::Borrowed(text) => Cow::Borrowed(&text[from..to]),
+            Cow::Owned(text) => Cow::Owned(text[from..to].to_string()),
+        })
+    }
+
+    /// Word the cursor is in or right after, with its char columns
+    pub fn word_at_cursor(&self) -> Option<(String, Range<usize>)> {
+        let word = self.word_chars;
+        let chars: Vec<char> = self.buffer.line_text(self.cursor.line).chars().collect();
+        let start = self.word_start_at(self.cursor);
+        let mut end = self.cursor.column.min(chars.len());
+        while end < chars.len() && word.is_word_char(chars[end]) {
+            end += 1;
+        }
+        if start == end {
+            return None;
+        }
+        Some((chars[start..end].iter().collect(), start..end))
+    }
+
+    /// Column where the run of word chars ending at `pos` starts
+    fn word_start_at(&self, pos: CursorPosition) -> usize {
+        let word = self.word_chars;
+        let chars: Vec<char> = self
+            .buffer
+            .line_text(pos.line)
+            .chars()
+            .take(pos.column)
+            .collect();
+        let mut column = chars.len();
+        while column > 0 && word.is_word_char(chars[column - 1]) {
+            column -= 1;
+        }
+        column
+    }
+
+    /// Copy the cursor line, or the lines the selection covers, below itself
+    ///
+    /// The cursor and selection move onto the copy, keeping their columns.
+    pub fn duplicate_line(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let lines = self.selected_lines();
+        let end = CursorPosition {
+            line: lines.end - 1,
+            column: self.buffer.line_chars(lines.end - 1),
+        };
+        let copy = format!(
+            "\n{}",
+            self.buffer.text_between(
+                CursorPosition {
+                    line: lines.start,
+                    column: 0
+                },
+                end
+            )
+        );
+
+        self.save_undo_state();
+        self.splice(end, end, &copy);
+        let count = lines.len();
+        self.shift_lines(|line| {
+            if line >= lines.start {
+                line + count
+            } else {
+                line
+            }
+        });
+        true
+    }
+
+    /// Swap the cursor line, or the lines the selection covers, with the line above
+    ///
+    /// Returns false if the block is already at the top.
+    pub fn move_line_up(&mut self) -> bool {
+        let lines = self.selected_lines();
+        if self.read_only || lines.start == 0 {
+            return false;
+        }
+        self.swap_lines(lines.start - 1, lines.start..lines.end);
+        self.shift_lines(|line| {
+            if lines.contains(&line) {
+                line - 1
+            } else if line == lines.start - 1 {
+                lines.end - 1
+            } else {
+                line
+            }
+        });
+        true
+    }
+
+    /// Swap the cursor line, or the lines the selection covers, with the line below
+    ///
+    /// Returns false if the block is already at the bottom.
+    pub fn move_line_down(&mut self) -> bool {
+        let lines = self.selected_lines();
+        if self.read_only || lines.end >= self.buffer.line_count() {
+            return false;
+        }
+        self.swap_lines(lines.start, lines.end..lines.end + 1);
+        self.shift_lines(|line| {
+            if lines.contains(&line) {
+                line + 1
+            } else if line == lines.end {
+                lines.start
+            } else {
+                line
+            }
+        });
+        true
+    }
+
+    /// Remove the cursor line, newline included, onto the kill ring
+    ///
+    /// The cursor keeps its column, clamped, on the line that takes its place.
+    pub fn delete_line(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let line = self.cursor.line;
+        let last = self.buffer.line_count() - 1;
+        let line_end = CursorPosition {
+            line,
+            column: self.buffer.line_chars(line),
+        };
+        let (start, end) = if line < last {
+            (
+                CursorPosition { line, column: 0 },
+                CursorPosition {
+                    line: line + 1,
+                    column: 0,
+                },
+            )
+        } else if line > 0 {
+            let prev_end = CursorPosition {
+                line: line - 1,
+                column: self.buffer.line_chars(line - 1),
+            };
+            (prev_end, line_end)
+        } else {
+            (CursorPosition::default(), line_end)
+        };
+
+        self.save_undo_state();
+        self.selection_anchor = None;
+        let mut killed = self.splice(start, end, "");
+        if killed.starts_with('\n') {
+            // The last line takes the newline before it; keep the kill in
+            // the same "line + newline" shape as the others
+            killed.remove(0);
+            killed.push('\n');
+        }
+        self.push_kill(killed);
+        self.cursor = self.clamp_position(CursorPosition {
+            line: start.line,
+            column: self.cursor.column,
+        });
+        true
+    }
+
+    /// Join the next line onto the cursor line
+    ///
+    /// Whitespace around the join collapses to a single space, or to
+    /// nothing if either side is blank.  The cursor goes to the join point.
+    pub fn join_lines(&mut self) -> bool {
+        let line = self.cursor.line;
+        if self.read_only || line + 1 >= self.buffer.line_count() {
+            return false;
+        }
+        let current = &self.buffer.line_text(line);
+        let next = &self.buffer.line_text(line + 1);
+        let kept = current.trim_end().chars().count();
+        let skipped = next.chars().take_while(|c| c.is_whitespace()).count();
+        let separator = if kept == 0 || skipped == next.chars().count() {
+            ""
+        } else {
+            " "
+        };
+
+        self.save_undo_state();
+        self.selection_anchor = None;
+        let start = CursorPosition { line, column: kept };
+        let end = CursorPosition {
+            line: line + 1,
+            column: skipped,
+        };
+        self.splice(start, end, separator);
+        self.cursor = start;
+        true
+    }
+
+    /// Comment or uncomment the lines covered by the selection, or the cursor line
+    ///
+    /// If every non-blank line already starts with `prefix` after its
+    /// indentation, the prefix and one following space are removed;
+    /// otherwise "prefix " is inserted at the block's smallest indentation.
+    /// Blank lines are left alone.
+    pub fn toggle_comment(&mut self, prefix: &str) -> bool {
+        if self.read_only || prefix.is_empty() {
+            return false;
+        }
+        let lines: Vec<usize> = self
+            .selected_lines()
+            .filter(|&line| !self.buffer.line_text(line).trim().is_empty())
+            .collect();
+        if lines.is_empty() {
+            return false;
+        }
+        let indent = |text: &str| text.chars().take_while(|c| c.is_whitespace()).count();
+        let commented = lines
+            .iter()
+            .all(|&line| self.buffer.line_text(line).trim_start().starts_with(prefix));
+
+        self.save_undo_state();
+        if commented {
+            let prefix_len = prefix.chars().count();
+            for line in lines {
+                let text = &self.buffer.line_text(line);
+                let start = indent(text);
+                let after = text.trim_start()[prefix.len()..].starts_with(' ');
+                let len = prefix_len + after as usize;
+                self.splice(
+                    CursorPosition {
+                        line,
+                        column: start,
+                    },
+                    CursorPosition {
+                        line,
+                        column: start + len,
+                    },
+                    "",
+                );
+                self.shift_columns(line, start, -(len as isize));
+            }
+        } else {
+            let column = lines
+                .iter()
+                .map(|&line| indent(&self.buffer.line_text(line)))
+                .min()
+                .unwrap_or(0);
+            let insertion = format!("{} ", prefix);
+            let len = insertion.chars().count();
+            for line in lines {
+                let pos = CursorPosition { line, column };
+                self.splice(pos, pos, &insertion);
+                self.shift_columns(line, column, len as isize);
+            }
+        }
+        true
+    }
+
+    /// Shift cursors on `line` at or after `column` by `delta` chars
+    ///
+    /// Cursors inside a removed span end up at its start.
+    fn shift_columns(&mut self, line: usize, column: usize, delta: isize) {
+        let shift = |pos: CursorPosition| {
+            if pos.line != line || pos.column < column {
+                pos
+            } else if delta < 0 {
+                CursorPosition {
+                    line,
+                    column: column.max((pos.column as isize + delta) as usize),
+                }
+            } else {
+                CursorPosition {
+                    line,
+                    column: pos.column + delta as usize,
+                }
+            }
+        };
+        self.cursor = shift(self.cursor);
+        self.selection_anchor = self.selection_anchor.map(shift);
+        self.extra_cursors = self.extra_cursors.iter().map(|&pos| shift(pos)).collect();
+    }
+
+    /// Remove spaces and tabs from the end of every line
+    ///
+    /// All lines change in one undo step; a cursor in the removed
+    /// whitespace ends up at the new line end.
+    pub fn trim_trailing_whitespace(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let trimmed: Vec<(usize, usize, usize)> = (0..self.buffer.line_count())
+            .filter_map(|line| {
+                let text = &self.buffer.line_text(line);
+                let kept = text.trim_end_matches([' ', '\t']).chars().count();
+                let len = self.buffer.line_chars(line);
+                Some((line, kept, len)).filter(|_| kept < len)
+            })
+            .collect();
+        if trimmed.is_empty() {
+            return false;
+        }
+
+        self.save_undo_state();
+        for (line, kept, len) in trimmed {
+            self.splice(
+                CursorPosition { line, column: kept },
+                CursorPosition { line, column: len },
+                "",
+            );
+            self.shift_columns(line, kept, -((len - kept) as isize));
+        }
+        true
+    }
+
+    /// Re-wrap the paragraph around the cursor to `width` display cells
+    ///
+    /// A paragraph is a run of non-blank lines.  A leading comment or
+    /// quote marker shared by its lines, like "# " or "> ", is kept on
+    /// every wrapped line.  Words wider than a line are split between
+    /// graphemes, so unspaced CJK text wraps too.
+    pub fn fill_paragraph(&mut self, width: usize) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let prefix = paragraph_prefix(&self.buffer.line_text(self.cursor.line)).to_string();
+        let in_paragraph = |text: &str| {
+            text.starts_with(prefix.as_str()) && !text[prefix.len()..].trim().is_empty()
+        };
+        if !in_paragraph(&self.buffer.line_text(self.cursor.line)) {
+            return false;
+        }
+        let mut first = self.cursor.line;
+        while first > 0 && in_paragraph(&self.buffer.line_text(first - 1)) {
+            first -= 1;
+        }
+        let mut last = self.cursor.line;
+        while last + 1 < self.buffer.line_count() && in_paragraph(&self.buffer.line_text(last + 1))
+        {
+            last += 1;
+        }
+
+        // Words of the paragraph, and how many non-space chars come
+        // before the cursor so it can be put back on the same char
+        let mut words: Vec<String> = Vec::new();
+        let mut before_cursor = 0;
+        for line in first..=last {
+            let body = &self.buffer.line_text(line)[prefix.len()..];
+            if line == self.cursor.line {
+                let column = self.cursor.column.saturating_sub(prefix.chars().count());
+                before_cursor += body
+                    .chars()
+                    .take(column)
+                    .filter(|c| !c.is_whitespace())
+                    .count();
+            } else if line < self.cursor.line {
+                before_cursor += body.chars().filter(|c| !c.is_whitespace()).count();
+            }
+            for (idx, word) in body.split_whitespace().enumerate() {
+                // Wide text broken across lines had no space there
+                let joins = idx == 0
+                    && words
+                        .last()
+                        .and_then(|prev| prev.chars().last())
+                        .is_some_and(|c| char_width(c) > 1)
+                    && word.chars().next().is_some_and(|c| char_width(c) > 1);
+                match words.last_mut() {
+                    Some(prev) if joins => prev.push_str(word),
+                    _ => words.push(word.to_string()),
+                }
+            }
+        }
+
+        let available = width
+            .saturating_sub(unicode_column_width(&prefix, None))
+            .max(1);
+        let lines: Vec<String> = wrap_words(&words, available)
+            .into_iter()
+            .map(|line| format!("{}{}", prefix, line))
+            .collect();
+        let text = lines.join("\n");
+
+        self.save_undo_state();
+        self.selection_anchor = None;
+        self.extra_cursors.clear();
+        let end = CursorPosition {
+            line: last,
+            column: self.buffer.line_chars(last),
+        };
+        self.splice(
+            CursorPosition {
+                line: first,
+                column: 0,
+            },
+            end,
+            &text,
+        );
+
+        // Put the cursor back after the same number of non-space chars
+        let prefix_chars = prefix.chars().count();
+        let mut remaining = before_cursor;
+        self.cursor = CursorPosition {
+            line: first + lines.len() - 1,
+            column: lines.last().map_or(0, |l| l.chars().count()),
+        };
+        for (idx, line) in lines.iter().enumerate() {
+            let body: Vec<char> = line.chars().skip(prefix_chars).collect();
+            let count = body.iter().filter(|c| !c.is_whitespace()).count();
+            if remaining <= count {
+                let mut column = 0;
+                while remaining > 0 {
+                    if !body[column].is_whitespace() {
+                        remaining -= 1;
+                    }
+                    column += 1;
+                }
+                self.cursor = CursorPosition {
+                    line: first + idx,
+                    column: prefix_chars + column,
+                };
+                break;
+            }
+            remaining -= count;
+        }
+        true
+    }
+
+    /// Lines covered by the selection, or the cursor line
+    fn selected_lines(&self) -> Range<usize> {
+        match self.selection() {
+            Some((start, end)) => start.line..end.line + 1,
+            None => self.cursor.line..self.cursor.line + 1,
+        }
+    }
+
+    /// Exchange the lines `first..second.start` with the lines in `second`
+    ///
+    /// The two ranges must be adjacent.  Done as one splice so that it is a
+    /// single undoable edit.
+    fn swap_lines(&mut self, first: usize, second: Range<usize>) {
+        let start = CursorPosition {
+            line: first,
+            column: 0,
+        };
+        let middle = CursorPosition {
+            line: second.start,
+            column: 0,
+        };
+        let end = CursorPosition {
+            line: second.end - 1,
+            column: self.buffer.line_chars(second.end - 1),
+        };
+        let head = self.buffer.text_between(start, middle);
+        let tail = self.buffer.text_between(middle, end);
+
+        self.save_undo_state();
+        self.splice(
+            start,
+            end,
+            &format!("{}\n{}", tail, &head[..head.len() - 1]),
+        );
+    }
+
+    /// Move the cursor, selection anchor and extra cursors to new lines
+    fn shift_lines(&mut self, map: impl Fn(usize) -> usize) {
+        let shift = |pos: CursorPosition| CursorPosition {
+            line: map(pos.line),
+            column: pos.column,
+        };
+        self.cursor = self.clamp_position(shift(self.cursor));
+        self.selection_anchor = self
+            .selection_anchor
+            .map(|anchor| self.clamp_position(shift(anchor)));
+        self.extra_cursors = self
+            .extra_cursors
+            .iter()
+            .map(|&pos| self.clamp_position(shift(pos)))
+            .collect();
+    }
+
+    /// Kill to end of line (Ctrl+K), returning false if read-only
+    pub fn kill_to_line_end(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let pos = self.cursor;
+        let len = self.buffer.line_chars(pos.line);
+        if pos.column >= len && pos.line + 1 >= self.buffer.line_count() {
+            return true;
+        }
+        self.save_undo_state();
+
+        if pos.column < len {
+            // Kill rest of line
+            let end = CursorPosition {
+                line: pos.line,
+                column: len,
+            };
+            let killed = self.splice(pos, end, "");
+            self.push_kill(killed);
+        } else if pos.line + 1 < self.buffer.line_count() {
+            // Kill newline (join with next line)
+            let end = CursorPosition {
+                line: pos.line + 1,
+                column: 0,
+            };
+            let killed = self.splice(pos, end, "");
+            self.push_kill(killed);
+        }
+        true
+    }
+
+    /// Kill to start of line (Ctrl+U), returning false if read-only
+    pub fn kill_to_line_start(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let pos = self.cursor;
+        if pos.column == 0 {
+            return true;
+        }
+        self.save_undo_state();
+
+        let start = CursorPosition {
+            line: pos.line,
+            column: 0,
+        };
+        let killed = self.splice(start, pos, "");
+        self.push_kill(killed);
+        self.cursor.column = 0;
+        true
+    }
+
+    /// Kill from the cursor to the end of the buffer, returning false if
+    /// read-only
+    pub fn kill_to_buffer_end(&mut self) -> bool {
+        let end = self.buffer_end();
+        self.kill_between(self.cursor, end)
+    }
+
+    /// Kill from the start of the buffer to the cursor, returning false if
+    /// read-only
+    pub fn kill_to_buffer_start(&mut self) -> bool {
+        self.kill_between(CursorPosition::default(), self.cursor)
+    }
+
+    /// Kill all the text, returning false if read-only
+    ///
+    /// Unlike clear, the text can be yanked back and the buffer counts as
+    /// modified.
+    pub fn kill_whole_buffer(&mut self) -> bool {
+        let end = self.buffer_end();
+        self.kill_between(CursorPosition::default(), end)
+    }
+
+    /// Position after the last char of the buffer
+    fn buffer_end(&self) -> CursorPosition {
+        let line = self.buffer.line_count() - 1;
+        CursorPosition {
+            line,
+            column: self.buffer.line_chars(line),
+        }
+    }
+
+    /// Kill the text between two positions as one kill ring entry and one
+    /// undo step, leaving the cursor at `start`
+    fn kill_between(&mut self, start: CursorPosition, end: CursorPosition) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let (start, end) = (self.clamp_position(start), self.clamp_position(end));
+        if start >= end {
+            return true;
+        }
+        self.save_undo_state();
+        let killed = self.splice(start, end, "");
+        self.push_kill(killed);
+        self.cursor = start;
+        self.selection_anchor = None;
+        self.extra_cursors.clear();
+        true
+    }
+
+    /// Kill word backward (Ctrl+W), returning false if read-only
+    pub fn kill_word_backward(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        if self.cursor.column == 0 {
+            return true;
+        }
+        self.save_undo_state();
+
+        let start_column = self.cursor.column;
+        let end_column = self.word_start_before(self.cursor);
+
+        // Delete the word
+        let start = CursorPosition {
+            line: self.cursor.line,
+            column: end_column,
+        };
+        let end = CursorPosition {
+            line: self.cursor.line,
+            column: start_column,
+        };
+        let killed = self.splice(start, end, "");
+        self.push_kill(killed);
+        self.cursor.column = end_column;
+        true
+    }
+
+    /// Kill to the end of the next word (Alt+D), returning false if read-only
+    ///
+    /// At the end of a line the line break is killed instead.
+    pub fn kill_word_forward(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let start = self.cursor;
+        let chars: Vec<char> = self.buffer.line_text(start.line).chars().collect();
+        let end = if start.column >= chars.len() {
+            if start.line + 1 >= self.buffer.line_count() {
+                return true;
+            }
+            CursorPosition {
+                line: start.line + 1,
+                column: 0,
+            }
+        } else {
+            let word = self.word_chars;
+            let mut column = start.column;
+
+            // Skip separators
+            while column < chars.len() && !word.is_word_char(chars[column]) {
+                column += 1;
+            }
+
+            // Skip word characters
+            while column < chars.len() && word.is_word_char(chars[column]) {
+                column += 1;
+            }
+            CursorPosition {
+                line: start.line,
+                column,
+            }
+        };
+
+        self.save_undo_state();
+        let killed = self.splice(start, end, "");
+        self.push_kill(killed);
+        self.cursor = start;
+        self.selection_anchor = None;
+        true
+    }
+
+    /// Yank (paste from kill ring), returning false if read-only
+    pub fn yank(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        match self.kill_ring.last().cloned() {
+            Some(text) => self.insert_str(&text) > 0,
+            None => true,
+        }
+    }
+
+    /// Yank a specific kill ring entry, indexed as in kill_ring_entries
+    pub fn yank_nth(&mut self, index: usize) -> bool {
+        match self.kill_ring.get(index).cloned() {
+            Some(text) => self.insert_str(&text) > 0,
+            None => false,
+        }
+    }
+
+    /// Get the kill ring contents, oldest first
+    pub fn kill_ring_entries(&self) -> &[String] {
+        &self.kill_ring
+    }
+
+    /// Forget everything in the kill ring
+    pub fn kill_ring_clear(&mut self) {
+        self.kill_ring.clear();
+    }
+
+    /// Set the maximum number of kill ring entries
+    pub fn set_kill_ring_max(&mut self, max: usize) {
+        self.kill_ring_max = max;
+        self.trim_kill_ring();
+    }
+
+    /// Push killed text, dropping the oldest entries beyond the cap
+    ///
+    /// While a command repeats, every kill after the first joins the
+    /// entry the first one made.
+    fn push_kill(&mut self, text: String) {
+        if let Some(merge) = &mut self.kill_merge {
+            if merge.started {
+                if let Some(last) = self.kill_ring.last_mut() {
+                    if merge.backward {
+                        last.insert_str(0, &text);
+                    } else {
+                        last.push_str(&text);
+                    }
+                    return;
+                }
+            }
+            merge.started = true;
+        }
+        self.kill_ring.push(text);
+        self.trim_kill_ring();
+    }
+
+    /// Drop the oldest kill ring entries beyond the cap
+    fn trim_kill_ring(&mut self) {
+        if self.kill_ring.len() > self.kill_ring_max {
+            let excess = self.kill_ring.len() - self.kill_ring_max;
+            self.kill_ring.drain(..excess);
+        }
+    }
+
+    /// Copy the selection to `clipboard` and the kill ring
+    pub fn copy_selection(&mut self, clipboard: &mut dyn Clipboard) -> bool {
+        match self.selected_text() {
+            Some(text) if !text.is_empty() => {
+                clipboard.set(&text);
+                self.push_kill(text);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Move the selection to `clipboard` and the kill ring, returning it
+    pub fn cut_selection(&mut self, clipboard: &mut dyn Clipboard) -> Option<String> {
+        let text = self.delete_selection()?;
+        clipboard.set(&text);
+        Some(text)
+    }
+
+    /// Paste from `clipboard`, returning the number of characters inserted
+    pub fn paste_from(&mut self, clipboard: &mut dyn Clipboard) -> usize {
+        match clipboard.get() {
+            Some(text) => self.insert_paste(&text),
+            None => 0,
+        }
+    }
+
+    /// Use `clipboard` for the Copy, Cut and Paste commands
+    ///
+    /// Until one is set they use a clipboard private to the editor.
+    pub fn set_clipboard(&mut self, clipboard: Arc<Mutex<dyn Clipboard + Send>>) {
+        self.clipboard = SharedClipboard(clipboard);
+    }
+
+    /// Run `f` with the clipboard the commands use
+    fn with_clipboard<R>(&mut self, f: impl FnOnce(&mut Self, &mut dyn Clipboard) -> R) -> R {
+        let clipboard = Arc::clone(&self.clipboard.0);
+        let mut clipboard = clipboard.lock().unwrap();
+        f(self, &mut *clipboard)
+    }
+
+    /// Select the whole buffer, leaving the cursor at the end
+    pub fn select_all(&mut self) {
+        let line = self.buffer.line_count() - 1;
+        self.selection_anchor = Some(CursorPosition::default());
+        self.block_selection = false;
+        self.cursor = CursorPosition {
+            line,
+            column: self.buffer.line_chars(line),
+        };
+    }
+
+    /// Start selection at current cursor position
+    pub fn start_selection(&mut self) {
+        self.selection_anchor = Some(self.cursor);
+        self.block_selection = false;
+    }
+
+    /// Start a rectangular selection at current cursor position
+    pub fn start_block_selection(&mut self) {
+        self.selection_anchor = Some(self.cursor);
+        self.block_selection = true;
+    }
+
+    /// Check if a rectangular selection is active
+    pub fn has_block_selection(&self) -> bool {
+        self.block_selection && self.selection_anchor.is_some()
+    }
+
+    /// Get the rectangular selection as a line range and a display column range
+    pub fn block_bounds(&self) -> Option<(Range<usize>, Range<usize>)> {
+        if !self.block_selection {
+            return None;
+        }
+        self.selection_anchor.map(|anchor| {
+            let anchor_col = self.display_column(anchor);
+            let cursor_col = self.display_column(self.cursor);
+            (
+                anchor.line.min(self.cursor.line)..anchor.line.max(self.cursor.line) + 1,
+                anchor_col.min(cursor_col)..anchor_col.max(cursor_col),
+            )
+        })
+    }
+
+    /// Char column range of a line covered by the display columns `cols`
+    fn block_columns(&self, line: usize, cols: &Range<usize>) -> Range<usize> {
+        let text = &self.buffer.line_text(line);
+        column_at_display(text, cols.start)..column_at_display(text, cols.end)
+    }
+
+    /// Display column of the cursor, ignoring any IME preedit
+    pub fn cursor_display_column(&self) -> usize {
+        self.display_column(self.cursor)
+    }
+
+    /// Display column the caret is drawn at, inside the preedit if any
+    pub fn caret_display_column(&self) -> usize {
+        let preedit = self.preedit.as_ref().map_or(0, |(text, caret)| {
+            text.chars().take(*caret).map(char_width).sum()
+        });
+        self.display_column(self.cursor) + preedit
+    }
+
+    /// Expand `trigger` into `expansion` when typed in command position
+    ///
+    /// The expansion is a snippet template; see input::snippet.
+    pub fn register_abbreviation(&mut self, trigger: &str, expansion: &str) {
+        self.add_abbreviation(trigger, expansion, false);
+    }
+
+    /// Expand `trigger` into `expansion` wherever it is typed
+    pub fn register_abbreviation_anywhere(&mut self, trigger: &str, expansion: &str) {
+        self.add_abbreviation(trigger, expansion, true);
+    }
+
+    fn add_abbreviation(&mut self, trigger: &str, expansion: &str, anywhere: bool) {
+        self.abbreviations.retain(|a| a.trigger != trigger);
+        self.abbreviations.push(Abbreviation {
+            trigger: trigger.to_string(),
+            snippet: Snippet::parse(expansion),
+            anywhere,
+        });
+    }
+
+    /// Expand the abbreviation right before the cursor, if there is one
+    ///
+    /// The expansion is a single undo entry.  If it has placeholders the
+    /// first one is selected, ready to be typed over.
+    pub fn expand_at_cursor(&mut self) -> bool {
+        if self.read_only || self.selection_anchor.is_some() {
+            return false;
+        }
+        let line = &self.buffer.line_text(self.cursor.line);
+        let before: Vec<char> = line.chars().take(self.cursor.column).collect();
+        let word_start = before
+            .iter()
+            .rposition(|c| c.is_whitespace() || matches!(c, '|' | ';' | '&' | '('))
+            .map_or(0, |idx| idx + 1);
+        let word: String = before[word_start..].iter().collect();
+        if word.is_empty() {
+            return false;
+        }
+        let prefix: String = before[..word_start].iter().collect();
+        let snippet = match self
+            .abbreviations
+            .iter()
+            .find(|a| a.trigger == word && (a.anywhere || is_command_position(&prefix)))
+        {
+            Some(abbreviation) => abbreviation.snippet.clone(),
+            None => return false,
+        };
+
+        let start = CursorPosition {
+            line: self.cursor.line,
+            column: word_start,
+        };
+        self.begin_transaction();
+        self.splice(start, self.cursor, "");
+        self.cursor = start;
+        let accepted = self.insert_str(&snippet.text);
+        self.end_transaction();
+
+        // Placeholders are only trustworthy if the text went in untouched
+        self.snippet_session = None;
+        if accepted == snippet.text.chars().count() && !snippet.placeholders.is_empty() {
+            let position = |offset: usize| {
+                let text: String = snippet.text.chars().take(offset).collect();
+                end_of_insert(start, &text)
+            };
+            let placeholders = snippet
+                .placeholders
+                .iter()
+                .map(|range| (position(range.start), position(range.end)))
+                .collect();
+            self.snippet_session = Some(SnippetSession {
+                placeholders,
+                active: None,
+            });
+            self.next_placeholder();
+        }
+        true
+    }
+
+    /// Select the next placeholder of the last expanded snippet
+    ///
+    /// Moving past the last placeholder ends placeholder navigation.
+    pub fn next_placeholder(&mut self) -> bool {
+        let next = match &self.snippet_session {
+            Some(session) => session.active.map_or(0, |idx| idx + 1),
+            None => return false,
+        };
+        self.select_placeholder(next)
+    }
+
+    /// Select the previous placeholder of the last expanded snippet
+    pub fn prev_placeholder(&mut self) -> bool {
+        let prev = match &self.snippet_session {
+            Some(session) => match session.active {
+                Some(idx) if idx > 0 => idx - 1,
+                _ => return false,
+            },
+            None => return false,
+        };
+        self.select_placeholder(prev)
+    }
+
+    /// Check whether placeholder navigation is active
+    pub fn in_snippet(&self) -> bool {
+        self.snippet_session.is_some()
+    }
+
+    fn select_placeholder(&mut self, idx: usize) -> bool {
+        let session = match self.snippet_session.as_mut() {
+            Some(session) => session,
+            None => return false,
+        };
+        let (from, to) = match session.placeholders.get(idx) {
+            Some(&range) => range,
+            None => {
+                self.snippet_session = None;
+                return false;
+            }
+        };
+        session.active = Some(idx);
+        self.cursor = self.clamp_position(to);
+        self.selection_anchor = if from == to {
+            None
+        } else {
+            Some(self.clamp_position(from))
+        };
+        self.block_selection = false;
+        true
+    }
+
+    /// Show IME composition text at the cursor
+    ///
+    /// The text stays out of the buffer and the undo history until
+    /// commit_preedit; `cursor_within` is the caret's char offset in it.
+    /// Empty text clears the preedit.
+    pub fn set_preedit(&mut self, text: &str, cursor_within: usize) {
+        if text.is_empty() || self.read_only {
+            self.preedit = None;
+            return;
+        }
+        let caret = cursor_within.min(text.chars().count());
+        self.preedit = Some((text.to_string(), caret));
+    }
+
+    /// Drop the IME composition without inserting it
+    pub fn clear_preedit(&mut self) {
+        self.preedit = None;
+    }
+
+    /// Insert the IME composition as one undo entry
+    ///
+    /// Returns false if there was nothing to commit or it was rejected.
+    pub fn commit_preedit(&mut self) -> bool {
+        match self.preedit.take() {
+            Some((text, _)) => self.insert_str(&text) > 0,
+            None => false,
+        }
+    }
+
+    /// Current IME composition and the caret's char offset within it
+    pub fn preedit(&self) -> Option<(&str, usize)> {
+        self.preedit
+            .as_ref()
+            .map(|(text, caret)| (text.as_str(), *caret))
+    }
+
+    /// Display column of a position, counting wide chars as two cells
+    fn display_column(&self, pos: CursorPosition) -> usize {
+        self.buffer
+            .line_text(pos.line)
+            .chars()
+            .take(pos.column)
+            .map(char_width)
+            .sum()
+    }
+
+    /// Remove the rectangle from every line it covers
+    fn delete_block(&mut self) {
+        let (lines, cols) = match self.block_bounds() {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        for line in lines {
+            let range = self.block_columns(line, &cols);
+            if !range.is_empty() {
+                self.splice(
+                    CursorPosition {
+                        line,
+                        column: range.start,
+                    },
+                    CursorPosition {
+                        line,
+                        column: range.end,
+                    },
+                    "",
+                );
+            }
+        }
+    }
+
+    /// Replace the rectangle with `s` on every line it covers
+    ///
+    /// Lines that end before the rectangle's left edge are padded with
+    /// spaces.  The selection collapses to a zero-width rectangle after the
+    /// inserted text so that further typing keeps going to every line.
+    fn insert_in_block(&mut self, s: &str) {
+        let (lines, cols) = match self.block_bounds() {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        self.delete_block();
+
+        let left = cols.start;
+        for line in lines {
+            let text = &self.buffer.line_text(line);
+            let width: usize = text.chars().map(char_width).sum();
+            let mut insertion = s.to_string();
+            if width < left {
+                insertion.insert_str(0, &" ".repeat(left - width));
+            }
+            let pos = CursorPosition {
+                line,
+                column: column_at_display(text, left),
+            };
+            self.splice(pos, pos, &insertion);
+        }
+
+        let right = left + unicode_column_width(s, None);
+        let anchor = self.selection_anchor.unwrap_or(self.cursor);
+        self.selection_anchor = Some(CursorPosition {
+            line: anchor.line,
+            column: column_at_display(&self.buffer.line_text(anchor.line), right),
+        });
+        self.cursor.column = column_at_display(&self.buffer.line_text(self.cursor.line), right);
+    }
+
+    /// Get current selection range
+    pub fn selection(&self) -> Option<(CursorPosition, CursorPosition)> {
+        self.selection_anchor.map(|anchor| {
+            if anchor.line < self.cursor.line
+                || (anchor.line == self.cursor.line && anchor.column <= self.cursor.column)
+            {
+                (anchor, self.cursor)
+            } else {
+                (self.cursor, anchor)
+            }
+        })
+    }
+
+    /// Get the selection as a byte range of the full text
+    ///
+    /// For a block selection this spans from one corner to the other.
+    pub fn selection_byte_range(&self) -> Option<Range<usize>> {
+        self.selection()
+            .map(|(start, end)| self.byte_offset_of(start)..self.byte_offset_of(end))
+    }
+
+    /// Char columns of `line` covered by the selection
+    ///
+    /// Lines inside a multi-line selection are covered from 0 to their
+    /// end.  An empty range at the line end means only the line break
+    /// is selected.  Returns None for lines outside the selection, or if
+    /// the selection is empty.
+    pub fn selection_span_for_line(&self, line: usize) -> Option<Range<usize>> {
+        if line >= self.buffer.line_count() {
+            return None;
+        }
+        if let Some((lines, cols)) = self.block_bounds() {
+            return Some(self.block_columns(line, &cols)).filter(|_| lines.contains(&line));
+        }
+        let (start, end) = self.selection()?;
+        let (start, end) = (self.clamp_position(start), self.clamp_position(end));
+        if start == end || line < start.line || line > end.line {
+            return None;
+        }
+        let first = if line == start.line { start.column } else { 0 };
+        let last = if line == end.line {
+            end.column
+        } else {
+            self.buffer.line_chars(line)
+        };
+        Some(first..last)
+    }
+
+    /// Display columns of `line` covered by the selection
+    ///
+    /// Like selection_span_for_line, with tabs expanded to `tab_width`
+    /// stops and wide chars taking two cells.
+    pub fn selection_display_span_for_line(
+        &self,
+        line: usize,
+        tab_width: usize,
+    ) -> Option<Range<usize>> {
+        let span = self.selection_span_for_line(line)?;
+        let text = &self.buffer.line_text(line);
+        Some(display_x(text, span.start, tab_width)..display_x(text, span.end, tab_width))
+    }
+
+    /// Delete the selection, returning the removed text
+    ///
+    /// The text goes to the kill ring and the deletion is one undo step.
+    /// Returns None and changes nothing if there is no non-empty selection.
+    pub fn delete_selection(&mut self) -> Option<String> {
+        if self.read_only {
+            return None;
+        }
+        let text = self.selected_text().filter(|text| !text.is_empty())?;
+        self.save_undo_state();
+        self.delete_selection_internal();
+        self.push_kill(text.clone());
+        Some(text)
+    }
+
+    /// Replace the selection with `text` in one undo step
+    ///
+    /// Returns the replaced text, or None with nothing changed if there is
+    /// no non-empty selection.
+    pub fn replace_selection(&mut self, text: &str) -> Option<String> {
+        if self.read_only {
+            return None;
+        }
+        let removed = self.selected_text().filter(|text| !text.is_empty())?;
+        let mut editor = self.transaction();
+        editor.delete_selection_internal();
+        editor.insert_str(text);
+        Some(removed)
+    }
+
+    /// Internal selection deletion without undo state save
+    fn delete_selection_internal(&mut self) -> bool {
+        if let Some((lines, cols)) = self.block_bounds() {
+            if cols.is_empty() {
+                self.selection_anchor = None;
+                return false;
+            }
+            self.delete_block();
+            self.cursor = CursorPosition {
+                line: lines.start,
+                column: column_at_display(&self.buffer.line_text(lines.start), cols.start),
+            };
+            self.selection_anchor = None;
+            return true;
+        }
+
+        if let Some((start, end)) = self.selection() {
+            let start = self.clamp_position(start);
+            let end = self.clamp_position(end);
+            self.selection_anchor = None;
+
+            // Move cursor to start of selection
+            self.cursor = start;
+            self.splice(start, end, "");
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get selected text
+    pub fn selected_text(&self) -> Option<String> {
+        if let Some((lines, cols)) = self.block_bounds() {
+            let slices: Vec<String> = lines
+                .map(|line| {
+                    let range = self.block_columns(line, &cols);
+                    self.buffer
+                        .line_text(line)
+                        .chars()
+                        .skip(range.start)
+                        .take(range.end - range.start)
+                        .collect()
+                })
+                .collect();
+            return Some(slices.join("\n"));
+        }
+
+        self.selection().map(|(start, end)| {
+            let start = self.clamp_position(start);
+            let end = self.clamp_position(end);
+            self.buffer.text_between(start, end)
+        })
+    }
+
+    /// Set whether search and replace match case exactly
+    pub fn set_search_case_sensitive(&mut self, case_sensitive: bool) {
+        self.search_case_sensitive = case_sensitive;
+    }
+
+    /// Check whether search and replace match case exactly
+    pub fn search_case_sensitive(&self) -> bool {
+        self.search_case_sensitive
+    }
+
+    /// Find the next occurrence of `query` at or after `from`
+    pub fn find_next(&self, query: &str, from: CursorPosition) -> Option<Range<CursorPosition>> {
+        let text: Vec<char> = self.buffer.to_text().chars().collect();
+        let query: Vec<char> = query.chars().collect();
+        let start = self.char_offset_of(from);
+        self.find_in_chars(&text, &query, start)
+            .map(|m| self.position_of_char_offset(m)..self.position_of_char_offset(m + query.len()))
+    }
+
+    /// Find every occurrence of `query`, plus which one is at the cursor
+    ///
+    /// Matches don't overlap: the search resumes after the end of each one.
+    /// Results are cached until the buffer or the query changes, so this is
+    /// cheap to call on every keystroke.
+    pub fn match_ranges(&self, query: &str, case_sensitive: bool) -> SearchMatches {
+        let ranges = self.cached_match_ranges(query, case_sensitive);
+        let current = Some(ranges.partition_point(|range| range.start < self.cursor))
+            .filter(|&idx| idx < ranges.len());
+        SearchMatches { ranges, current }
+    }
+
+    fn cached_match_ranges(
+        &self,
+        query: &str,
+        case_sensitive: bool,
+    ) -> Arc<Vec<Range<CursorPosition>>> {
+        let mut cache = self.match_cache.borrow_mut();
+        if let Some((generation, cached_query, cached_case, ranges)) = cache.as_ref() {
+            if *generation == self.layout_generation
+                && cached_query == query
+                && *cached_case == case_sensitive
+            {
+                return Arc::clone(ranges);
+            }
+        }
+
+        let text: Vec<char> = self.buffer.to_text().chars().collect();
+        let query_chars: Vec<char> = query.chars().collect();
+        // Char offset at which each line starts
+        let mut line_starts = Vec::with_capacity(self.buffer.line_count());
+        let mut offset = 0;
+        for line in 0..self.buffer.line_count() {
+            line_starts.push(offset);
+            offset += self.buffer.line_chars(line) + 1;
+        }
+        let position = |offset: usize| {
+            let line = line_starts.partition_point(|&start| start <= offset) - 1;
+            CursorPosition {
+                line,
+                column: offset - line_starts[line],
+            }
+        };
+
+        let mut ranges = Vec::new();
+        let mut from = 0;
+        while let Some(m) = find_chars(&text, &query_chars, from, case_sensitive) {
+            let end = m + query_chars.len();
+            ranges.push(position(m)..position(end));
+            from = end;
+        }
+        let ranges = Arc::new(ranges);
+        *cache = Some((
+            self.layout_generation,
+            query.to_string(),
+            case_sensitive,
+            Arc::clone(&ranges),
+        ));
+        ranges
+    }
+
+    /// Replace the next occurrence of `query` at or after `from`
+    ///
+    /// Returns the range of the inserted replacement; the cursor is left
+    /// at its end.
+    pub fn replace_next(
+        &mut self,
+        query: &str,
+        replacement: &str,
+        from: CursorPosition,
+    ) -> Option<Range<CursorPosition>> {
+        if self.read_only {
+            return None;
+        }
+        let text: Vec<char> = self.buffer.to_text().chars().collect();
+        let query: Vec<char> = query.chars().collect();
+        let start = self.find_in_chars(&text, &query, self.char_offset_of(from))?;
+
+        self.save_undo_state();
+        let match_start = self.position_of_char_offset(start);
+        let match_end = self.position_of_char_offset(start + query.len());
+        self.splice(match_start, match_end, replacement);
+        self.cursor = end_of_insert(match_start, replacement);
+        self.selection_anchor = None;
+        Some(match_start..self.cursor)
+    }
+
+    /// Replace every occurrence of `query`, returning the number of replacements
+    ///
+    /// Matches are found in the original text only, so a replacement that
+    /// contains the query is never matched again.
+    pub fn replace_all(&mut self, query: &str, replacement: &str) -> usize {
+        if self.read_only {
+            return 0;
+        }
+        let text: Vec<char> = self.buffer.to_text().chars().collect();
+        let query: Vec<char> = query.chars().collect();
+        let replacement_len = replacement.chars().count();
+
+        let mut matches = Vec::new();
+        let mut pos = 0;
+        while let Some(m) = self.find_in_chars(&text, &query, pos) {
+            matches.push(m);
+            pos = m + query.len();
+        }
+        if matches.is_empty() {
+            return 0;
+        }
+
+        self.save_undo_state();
+
+        // Shift the cursor by every replacement before it; a cursor inside
+        // a match lands at the end of that match's replacement
+        let cursor = self.char_offset_of(self.cursor);
+        let mut new_cursor = cursor;
+        for &m in &matches {
+            let end = m + query.len();
+            if end <= cursor {
+                new_cursor = new_cursor + replacement_len - query.len();
+            } else if m < cursor {
+                new_cursor = new_cursor - (cursor - m) + replacement_len;
+            }
+        }
+
+        // Work back to front so earlier match offsets stay valid
+        for &m in matches.iter().rev() {
+            let start = self.position_of_char_offset(m);
+            let end = self.position_of_char_offset(m + query.len());
+            self.splice(start, end, replacement);
+        }
+
+        self.cursor = self.position_of_char_offset(new_cursor);
+        self.selection_anchor = None;
+        matches.len()
+    }
+
+    /// Apply several edits as one undoable change, returning the new cursor
+    ///
+    /// Ranges refer to the text before any of the edits.  Insertions at
+    /// the same offset go in the order given.  The cursor and selection
+    /// follow the text around them; a position inside a replaced range
+    /// moves to the end of its replacement.  Nothing changes if any range
+    /// is out of bounds or overlaps another.
+    pub fn apply_edits(&mut self, edits: &[TextEdit]) -> Result<CursorPosition, EditorError> {
+        if self.read_only {
+            return Err(EditorError::ReadOnly);
+        }
+        let len = self.char_offset_of(self.buffer_end());
+        let mut sorted: Vec<&TextEdit> = edits.iter().collect();
+        sorted.sort_by_key(|edit| (edit.range.start, edit.range.end));
+        for edit in &sorted {
+            if edit.range.start > edit.range.end || edit.range.end > len {
+                return Err(EditorError::EditOutOfRange {
+                    start: edit.range.start,
+                    end: edit.range.end,
+                });
+            }
+        }
+        for pair in sorted.windows(2) {
+            if pair[1].range.start < pair[0].range.end {
+                return Err(EditorError::OverlappingEdits {
+                    first: pair[0].range.clone(),
+                    second: pair[1].range.clone(),
+                });
+            }
+        }
+        if sorted.is_empty() {
+            return Ok(self.cursor);
+        }
+
+        let remap = |offset: usize| {
+            let mut mapped = offset;
+            for edit in &sorted {
+                let new_len = edit.new_text.chars().count();
+                if edit.range.end <= offset {
+                    mapped = mapped + new_len - edit.range.len();
+                } else if edit.range.start < offset {
+                    mapped = mapped - (offset - edit.range.start) + new_len;
+                }
+            }
+            mapped
+        };
+        let cursor = remap(self.char_offset_of(self.cursor));
+        let anchor = self
+            .selection_anchor
+            .map(|anchor| remap(self.char_offset_of(anchor)));
+        let extra: Vec<usize> = self
+            .extra_cursors
+            .iter()
+            .map(|&pos| remap(self.char_offset_of(pos)))
+            .collect();
+
+        self.save_undo_state();
+        // Work back to front so earlier offsets stay valid
+        for edit in sorted.iter().rev() {
+            let start = self.position_of_char_offset(edit.range.start);
+            let end = self.position_of_char_offset(edit.range.end);
+            self.splice(start, end, &edit.new_text);
+        }
+
+        self.cursor = self.position_of_char_offset(cursor);
+        self.selection_anchor = anchor.map(|anchor| self.position_of_char_offset(anchor));
+        self.extra_cursors = extra
+            .into_iter()
+            .map(|offset| self.position_of_char_offset(offset))
+            .collect();
+        self.check_selection_anchor();
+        Ok(self.cursor)
+    }
+
+    /// Find `query` in `text` at or after char offset `from`
+    fn find_in_chars(&self, text: &[char], query: &[char], from: usize) -> Option<usize> {
+        find_chars(text, query, from, self.search_case_sensitive)
+    }
+
+    /// Char offset of a position in the full text (newlines count as one)
+    fn char_offset_of(&self, pos: CursorPosition) -> usize {
+        let line = pos.line.min(self.buffer.line_count() - 1);
+        self.buffer.line_start_char(line) + pos.column.min(self.buffer.line_chars(line))
+    }
+
+    /// Position of a char offset in the full text, clamped to the buffer end
+    fn position_of_char_offset(&self, offset: usize) -> CursorPosition {
+        let line = self.buffer.line_at_char(offset);
+        let column = offset - self.buffer.line_start_char(line);
+        CursorPosition {
+            line,
+            column: column.min(self.buffer.line_chars(line)),
+        }
+    }
+
+    /// Replace the whole buffer with `text`
+    fn replace_buffer(&mut self, text: &str) {
+        let last = self.buffer.line_count() - 1;
+        let end = CursorPosition {
+            line: last,
+            column: self.buffer.line_chars(last),
+        };
+        self.splice(CursorPosition::default(), end, text);
+    }
+
+    /// Replace the text between `start` and `end` with `text`
+    ///
+    /// Every change to the buffer goes through here so that it can be
+    /// recorded in the open undo entry.  Returns the removed text.
+    fn splice(&mut self, start: CursorPosition, end: CursorPosition, text: &str) -> String {
+        if start == end && text.is_empty() {
+            return String::new();
+        }
+        let removed = self.replace_and_notify(start, end, text);
+        self.change_tally.0.add(text);
+        self.change_tally.1.add(&removed);
+        if let Some(change) = self.describe_tallied_change() {
+            self.last_change = Some(change);
+        }
+        if let Some(entry) = self.undo_stack.back_mut() {
+            let edit = Edit {
+                start,
+                removed: removed.clone(),
+                inserted: text.to_string(),
+            };
+            self.undo_bytes += edit.approx_bytes();
+            entry.edits.push(edit);
+            self.trim_undo_history();
+        }
+        self.modified = true;
+        self.redo_stack.clear();
+        removed
+    }
+
+    /// Replace text in the buffer and queue the matching change events
+    fn replace_and_notify(
+        &mut self,
+        start: CursorPosition,
+        end: CursorPosition,
+        text: &str,
+    ) -> String {
+        let removed = self.buffer.replace_range(start, end, text);
+        if removed.is_empty() && text.is_empty() {
+            return removed;
+        }
+        self.layout_generation += 1;
+        for mark in &mut self.marks {
+            *mark = shift_position(*mark, start, end, text);
+        }
+        if let Some(session) = self.snippet_session.as_mut() {
+            for (from, to) in &mut session.placeholders {
+                // Text typed at a placeholder's end becomes part of it
+                *to = if *to == start && !text.is_empty() {
+                    end_of_insert(start, text)
+                } else {
+                    shift_position(*to, start, end, text)
+                };
+                *from = shift_position(*from, start, end, text).min(*to);
+            }
+        }
+
+        // Lines that were overwritten rather than added or removed change
+        let removed_lines = removed.matches('\n').count();
+        let inserted_lines = text.matches('\n').count();
+        let overwritten = removed_lines.min(inserted_lines);
+        for line in start.line..=start.line + overwritten {
+            let changed = EditorEvent::LineChanged { line };
+            if self.events.last() != Some(&changed) {
+                self.events.push(changed);
+            }
+        }
+        let at = start.line + overwritten + 1;
+        if inserted_lines > removed_lines {
+            let count = inserted_lines - removed_lines;
+            self.events.push(EditorEvent::LinesInserted { at, count });
+        } else if removed_lines > inserted_lines {
+            let count = removed_lines - inserted_lines;
+            self.events.push(EditorEvent::LinesRemoved { at, count });
+        }
+        removed
+    }
+
+    /// Drop a selection anchor that no longer points into the buffer
+    ///
+    /// Edits that leave the anchor alone can shorten the line it is on, so
+    /// this runs before each edit; readers of the selection clamp it too.
+    /// Block selections span display columns past line ends on purpose, so
+    /// they are left alone.
+    fn check_selection_anchor(&mut self) {
+        let anchor = match self.selection_anchor {
+            Some(anchor) if !self.block_selection => anchor,
+            _ => return,
+        };
+        if self.clamp_position(anchor) != anchor {
+            if cfg!(debug_assertions) {
+                log::warn!(
+                    "selection anchor {}:{} is past the end of the buffer",
+                    anchor.line,
+                    anchor.column
+                );
+            }
+            self.selection_anchor = None;
+        }
+    }
+
+    /// Soft-wrap layout of the buffer at `width` display cells
+    ///
+    /// The layout is cached until the next edit or a call with different
+    /// dimensions.
+    pub fn wrap_layout(&self, width: usize, tab_width: usize) -> Arc<WrapLayout> {
+        let mut cache = self.layout_cache.borrow_mut();
+        if let Some((generation, layout)) = cache.as_ref() {
+            if *generation == self.layout_generation
+                && layout.width() == width.max(1)
+                && layout.tab_width() == tab_width.max(1)
+            {
+                return Arc::clone(layout);
+            }
+        }
+        let layout = Arc::new(WrapLayout::new(self.buffer.lines(), width, tab_width));
+        *cache = Some((self.layout_generation, Arc::clone(&layout)));
+        layout
+    }
+
+    /// Collect the changes made since the last call
+    ///
+    /// Buffer changes come first, in the order they happened, followed by
+    /// CursorMoved and SelectionChanged if either differs from last time.
+    pub fn take_events(&mut self) -> Vec<EditorEvent> {
+        let mut events = std::mem::take(&mut self.events);
+        if self.cursor != self.reported_cursor {
+            self.reported_cursor = self.cursor;
+            events.push(EditorEvent::CursorMoved);
+        }
+        let selection = (self.selection_anchor, self.block_selection);
+        if selection != self.reported_selection {
+            self.reported_selection = selection;
+            events.push(EditorEvent::SelectionChanged);
+        }
+        events
+    }
+
+    /// Add an extra cursor, returning false if one is already there
+    pub fn add_cursor_at(&mut self, pos: CursorPosition) -> bool {
+        let pos = self.clamp_position(pos);
+        if pos == self.cursor || self.extra_cursors.contains(&pos) {
+            return false;
+        }
+        self.extra_cursors.push(pos);
+        true
+    }
+
+    /// Add a cursor on the line below the lowest cursor
+    pub fn add_cursor_below(&mut self) -> bool {
+        let lowest = self
+            .extra_cursors
+            .iter()
+            .fold(self.cursor.line, |line, c| line.max(c.line));
+        if lowest + 1 >= self.buffer.line_count() {
+            return false;
+        }
+        self.add_cursor_at(CursorPosition {
+            line: lowest + 1,
+            column: self.cursor.column,
+        })
+    }
+
+    /// Add a cursor on the line above the highest cursor
+    pub fn add_cursor_above(&mut self) -> bool {
+        let highest = self
+            .extra_cursors
+            .iter()
+            .fold(self.cursor.line, |line, c| line.min(c.line));
+        if highest == 0 {
+            return false;
+        }
+        self.add_cursor_at(CursorPosition {
+            line: highest - 1,
+            column: self.cursor.column,
+        })
+    }
+
+    /// Remove all extra cursors, keeping the primary one
+    pub fn clear_extra_cursors(&mut self) {
+        self.extra_cursors.clear();
+    }
+
+    /// Get the extra cursors
+    pub fn extra_cursors(&self) -> &[CursorPosition] {
+        &self.extra_cursors
+    }
+
+    /// Apply an edit at the primary cursor and every extra cursor
+    ///
+    /// Cursors are visited front to back so each edit's length change can
+    /// be carried over to the cursors after it; cursors that end up on the
+    /// same spot are merged.
+    fn edit_at_each_cursor(&mut self, mut edit: impl FnMut(&mut Self)) {
+        self.selection_anchor = None;
+        let primary = self.char_offset_of(self.cursor);
+        let mut offsets: Vec<usize> = self
+            .extra_cursors
+            .iter()
+            .map(|&c| self.char_offset_of(c))
+            .chain(std::iter::once(primary))
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+        let primary_idx = offsets.binary_search(&primary).unwrap_or(0);
+
+        for i in 0..offsets.len() {
+            let len_before = self.char_len();
+            self.cursor = self.position_of_char_offset(offsets[i]);
+            edit(self);
+            let delta = self.char_len() as isize - len_before as isize;
+            offsets[i] = self.char_offset_of(self.cursor);
+            for offset in &mut offsets[i + 1..] {
+                *offset = (*offset as isize + delta).max(0) as usize;
+            }
+        }
+
+        let primary = offsets[primary_idx];
+        offsets.dedup();
+        self.cursor = self.position_of_char_offset(primary);
+        self.extra_cursors = offsets
+            .into_iter()
+            .filter(|&offset| offset != primary)
+            .map(|offset| self.position_of_char_offset(offset))
+            .collect();
+    }
+
+    /// Total length of the buffer in chars, counting newlines
+    fn char_len(&self) -> usize {
+        self.buffer.len_chars()
+    }
+
+    /// Clamp a position to the buffer contents
+    fn clamp_position(&self, pos: CursorPosition) -> CursorPosition {
+        let line = pos.line.min(self.buffer.line_count() - 1);
+        CursorPosition {
+            line,
+            column: pos.column.min(self.buffer.line_chars(line)),
+        }
+    }
+
+    /// Capture the current state for the undo/redo stacks
+    fn current_state(&self) -> EditorState {
+        EditorState {
+            cursor: self.cursor,
+            extra_cursors: self.extra_cursors.clone(),
+            selection_anchor: self.selection_anchor,
+            block_selection: self.block_selection,
+            modified: self.modified,
+            revision: self.revision,
+        }
+    }
+
+    /// Restore a state taken from the undo/redo stacks
+    fn restore_state(&mut self, state: EditorState) {
+        self.cursor = state.cursor;
+        self.extra_cursors = state.extra_cursors;
+        self.selection_anchor = state.selection_anchor;
+        self.block_selection = state.block_selection;
+        self.modified = state.modified;
+        self.revision = state.revision;
+    }
+
+    /// Start collecting edits into a single undo entry
+    ///
+    /// Transactions nest; only the outermost begin takes a snapshot and
+    /// only the matching outermost end closes it.
+    pub fn begin_transaction(&mut self) {
+        if self.transaction_depth == 0 {
+            self.push_undo_state();
+        }
+        self.transaction_depth += 1;
+    }
+
+    /// Finish a transaction started with begin_transaction
+    pub fn end_transaction(&mut self) {
+        if self.transaction_depth == 0 {
+            return;
+        }
+        self.transaction_depth -= 1;
+        if self.transaction_depth > 0 {
+            return;
+        }
+
+        // A transaction that didn't change the text leaves no undo entry
+        self.discard_empty_undo_entry();
+    }
+
+    /// Drop the newest undo entry if no edits were recorded in it
+    ///
+    /// For operations that only find out they have nothing to do after
+    /// saving undo state.  Does nothing inside a transaction, whose
+    /// entry belongs to the whole transaction.
+    fn discard_empty_undo_entry(&mut self) {
+        if self.transaction_depth > 0 {
+            return;
+        }
+        let unchanged = self
+            .undo_stack
+            .back()
+            .is_some_and(|entry| entry.edits.is_empty());
+        if unchanged {
+            if let Some(entry) = self.pop_undo_entry() {
+                self.revision = entry.state.revision;
+            }
+        }
+    }
+
+    /// Begin a transaction that ends when the returned guard is dropped
+    pub fn transaction(&mut self) -> TransactionGuard<'_> {
+        self.begin_transaction();
+        TransactionGuard { editor: self }
+    }
+
+    /// Check if an undo transaction is open
+    pub fn in_transaction(&self) -> bool {
+        self.transaction_depth > 0
+    }
+
+    /// Save current state for undo
+    ///
+    /// Inside a transaction the snapshot taken at its start covers
+    /// everything, so this does nothing.
+    fn save_undo_state(&mut self) {
+        self.check_selection_anchor();
+        if self.transaction_depth == 0 {
+            self.push_undo_state();
+        }
+    }
+
+    /// Push the current state onto the undo stack
+    fn push_undo_state(&mut self) {
+        self.change_tally = Default::default();
+        let state = self.current_state();
+        self.push_undo_entry(UndoEntry {
+            edits: Vec::new(),
+            state,
+        });
+
+        // Whatever happens next is a new revision of the buffer
+        self.last_revision += 1;
+        self.revision = self.last_revision;
+    }
+
+    /// Push an entry onto the undo stack, keeping within the budget
+    fn push_undo_entry(&mut self, entry: UndoEntry) {
+        self.undo_bytes += entry.approx_bytes();
+        self.undo_stack.push_back(entry);
+        self.trim_undo_history();
+    }
+
+    /// Pop the newest undo entry
+    fn pop_undo_entry(&mut self) -> Option<UndoEntry> {
+        let entry = self.undo_stack.pop_back()?;
+        self.undo_bytes -= entry.approx_bytes();
+        Some(entry)
+    }
+
+    /// Drop the oldest undo entries until under budget, keeping at least one
+    fn trim_undo_history(&mut self) {
+        while self.undo_bytes > self.undo_budget_bytes && self.undo_stack.len() > 1 {
+            if let Some(entry) = self.undo_stack.pop_front() {
+                self.undo_bytes -= entry.approx_bytes();
+            }
+        }
+    }
+
+    /// Set the memory budget for undo history, in bytes
+    ///
+    /// The newest step is always kept, even if it alone is over budget.
+    pub fn set_undo_budget_bytes(&mut self, bytes: usize) {
+        self.undo_budget_bytes = bytes;
+        self.trim_undo_history();
+    }
+
+    /// Memory budget for undo history, in bytes
+    pub fn undo_budget_bytes(&self) -> usize {
+        self.undo_budget_bytes
+    }
+
+    /// Number of steps that can be undone
+    pub fn undo_depth(&self) -> usize {
+        self.undo_stack.len()
+    }
+
+    /// Undo last action, returning false if read-only
+    pub fn undo(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        if let Some(entry) = self.pop_undo_entry() {
+            // Revert the edits, newest first
+            for edit in entry.edits.iter().rev() {
+                let end = end_of_insert(edit.start, &edit.inserted);
+                self.replace_and_notify(edit.start, end, &edit.removed);
+            }
+
+            // Save current state to redo stack
+            let current = self.current_state();
+            self.redo_stack.push_back(UndoEntry {
+                edits: entry.edits,
+                state: current,
+            });
+
+            // Restore previous state
+            self.restore_state(entry.state);
+            self.last_change = Some(ChangeDescription::Undo);
+        }
+        true
+    }
+
+    /// Redo last undone action, returning false if read-only
+    pub fn redo(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        if let Some(entry) = self.redo_stack.pop_back() {
+            // Reapply the edits in their original order
+            for edit in &entry.edits {
+                let end = end_of_insert(edit.start, &edit.removed);
+                self.replace_and_notify(edit.start, end, &edit.inserted);
+            }
+
+            // Save current state to undo stack
+            let current = self.current_state();
+            self.push_undo_entry(UndoEntry {
+                edits: entry.edits,
+                state: current,
+            });
+
+            // Restore redo state
+            self.restore_state(entry.state);
+            self.last_change = Some(ChangeDescription::Redo);
+        }
+        true
+    }
+
+    /// Approximate memory retained by the undo and redo history, in bytes
+    pub fn undo_memory_bytes(&self) -> usize {
+        self.undo_bytes
+            + self
+                .redo_stack
+                .iter()
+                .map(UndoEntry::approx_bytes)
+                .sum::<usize>()
+    }
+
+    /// Check if editor has been modified
+    pub fn is_modified(&self) -> bool {
+        self.modified && self.revision != self.saved_revision
+    }
+
+    /// Mark editor as unmodified
+    pub fn mark_unmodified(&mut self) {
+        self.modified = false;
+        self.saved_revision = self.revision;
+    }
+
+    /// Run a command, returning whether it did anything
+    ///
+    /// Commands whose method doesn't report success count as done.
+    pub fn execute(&mut self, command: EditorCommand) -> bool {
+        if let Some(recording) = self.macro_recording.as_mut() {
+            if !command.is_macro_control() {
+                recording.push(command.clone());
+            }
+        }
+        let moves = !command.is_edit() && !command.is_macro_control();
+        let before = self.cursor;
+        let done = self.run_command(command);
+        if moves {
+            self.note_move(before);
+        }
+        done
+    }
+
+    /// Carry out a command for execute
+    fn run_command(&mut self, command: EditorCommand) -> bool {
+        use EditorCommand::*;
+        match command {
+            InsertChar(c) => self.insert_char(c),
+            InsertText(text) => self.insert_str(&text) > 0,
+            InsertNewline => self.insert_char('\n'),
+            Backspace => self.backspace(),
+            Delete => self.delete(),
+            MoveLeft => self.move_left(),
+            MoveRight => self.move_right(),
+            MoveUp => self.move_up(),
+            MoveDown => self.move_down(),
+            MoveWordLeft => self.move_word_left(),
+            MoveWordRight => self.move_word_right(),
+            MoveToLineStart => self.move_to_line_start(),
+            MoveToLineStartSmart => self.move_to_line_start_smart(),
+            MoveToLineEnd => self.move_to_line_end(),
+            MoveToBufferStart => self.move_to_buffer_start(),
+            MoveToBufferEnd => self.move_to_buffer_end(),
+            MoveParagraphForward => self.move_paragraph_forward(),
+            MoveParagraphBackward => self.move_paragraph_backward(),
+            MoveUpVisual => match self.view_width {
+                Some((width, tab_width)) => self.move_up_visual(width, tab_width),
+                None => self.move_up(),
+            },
+            MoveDownVisual => match self.view_width {
+                Some((width, tab_width)) => self.move_down_visual(width, tab_width),
+                None => self.move_down(),
+            },
+            MoveToVisualLineStart => match self.view_width {
+                Some((width, tab_width)) => self.move_to_visual_line_start(width, tab_width),
+                None => self.move_to_line_start(),
+            },
+            MoveToVisualLineEnd => match self.view_width {
+                Some((width, tab_width)) => self.move_to_visual_line_end(width, tab_width),
+                None => self.move_to_line_end(),
+            },
+            MoveToNextMatch(query) => self.move_to_next_match(&query),
+            SelectParagraph => self.select_paragraph(),
+            SelectLeft => self.extend_selection_with(Self::move_left),
+            SelectRight => self.extend_selection_with(Self::move_right),
+            SelectUp => self.extend_selection_with(Self::move_up),
+            SelectDown => self.extend_selection_with(Self::move_down),
+            SelectWordLeft => self.extend_selection_with(Self::move_word_left),
+            SelectWordRight => self.extend_selection_with(Self::move_word_right),
+            SelectToLineStart => self.extend_selection_with(Self::move_to_line_start),
+            SelectToLineStartSmart => self.extend_selection_to_line_start_smart(),
+            SelectToLineEnd => self.extend_selection_with(Self::move_to_line_end),
+            SelectToBufferStart => self.extend_selection_with(Self::move_to_buffer_start),
+            SelectToBufferEnd => self.extend_selection_with(Self::move_to_buffer_end),
+            SelectUpVisual => match self.view_width {
+                Some((width, tab_width)) => self.extend_selection_up_visual(width, tab_width),
+                None => self.extend_selection_with(Self::move_up),
+            },
+            SelectDownVisual => match self.view_width {
+                Some((width, tab_width)) => self.extend_selection_down_visual(width, tab_width),
+                None => self.extend_selection_with(Self::move_down),
+            },
+            KillToLineEnd => self.kill_to_line_end(),
+            KillToLineStart => self.kill_to_line_start(),
+            KillWordBackward => self.kill_word_backward(),
+            KillWordForward => self.kill_word_forward(),
+            KillToBufferEnd => self.kill_to_buffer_end(),
+            KillToBufferStart => self.kill_to_buffer_start(),
+            KillWholeBuffer => self.kill_whole_buffer(),
+            Yank => self.yank(),
+            YankNth(index) => self.yank_nth(index),
+            Undo => self.undo(),
+            Redo => self.redo(),
+            SelectAll => {
+                self.select_all();
+                true
+            }
+            StartSelection => {
+                self.start_selection();
+                true
+            }
+            StartBlockSelection => {
+                self.start_block_selection();
+                true
+            }
+            DeleteSelection => self.delete_selection().is_some(),
+            Copy => self.with_clipboard(|editor, clipboard| editor.copy_selection(clipboard)),
+            Cut => {
+                self.with_clipboard(|editor, clipboard| editor.cut_selection(clipboard).is_some())
+            }
+            Paste => self.with_clipboard(|editor, clipboard| editor.paste_from(clipboard) > 0),
+            DuplicateLine => self.duplicate_line(),
+            MoveLineUp => self.move_line_up(),
+            MoveLineDown => self.move_line_down(),
+            DeleteLine => self.delete_line(),
+            JoinLines => self.join_lines(),
+            ToggleComment(prefix) => self.toggle_comment(&prefix),
+            TrimTrailingWhitespace => self.trim_trailing_whitespace(),
+            FillParagraph(width) => self.fill_paragraph(width),
+            NormalizeBuffer => self.normalize_buffer(),
+            ReplaceAll { query, replacement } => self.replace_all(&query, &replacement) > 0,
+            ExpandAbbreviation => self.expand_at_cursor(),
+            NextPlaceholder => self.next_placeholder(),
+            PrevPlaceholder => self.prev_placeholder(),
+            PushMark => {
+                self.push_mark();
+                true
+            }
+            PopMarkAndJump => self.pop_mark_and_jump(),
+            ExchangePointAndMark => self.exchange_point_and_mark(),
+            AddCursorAbove => self.add_cursor_above(),
+            AddCursorBelow => self.add_cursor_below(),
+            ClearExtraCursors => {
+                self.clear_extra_cursors();
+                true
+            }
+            Clear => self.clear(),
+            StartMacroRecording => {
+                self.start_macro_recording();
+                true
+            }
+            StopMacroRecording => {
+                self.stop_macro_recording();
+                true
+            }
+            PlayMacro => match self.last_macro.clone() {
+                Some(recorded) => self.play_macro(&recorded, 1) > 0,
+                None => false,
+            },
+            EditInExternalEditor => false,
+        }
+    }
+
+    /// Summary of the last edit, undo, redo or command that moved the cursor
+    ///
+    /// Edits made in one undo step are described together, so a paste
+    /// or a repeated command is one change.
+    pub fn last_change_description(&self) -> Option<ChangeDescription> {
+        self.last_change.clone()
+    }
+
+    /// Set whether change descriptions leave out the text, as for passwords
+    pub fn set_hide_change_previews(&mut self, hide: bool) {
+        self.hide_change_previews = hide;
+    }
+
+    /// Describe the edits tallied for the current operation
+    fn describe_tallied_change(&self) -> Option<ChangeDescription> {
+        let (inserted, removed) = &self.change_tally;
+        let preview =
+            |tally: &TextTally| Some(tally.preview()).filter(|_| !self.hide_change_previews);
+        if inserted.chars > 0 && removed.chars > 0 {
+            Some(ChangeDescription::ReplacedText {
+                preview: preview(inserted),
+                chars: inserted.chars,
+            })
+        } else if inserted.chars > 0 {
+            Some(ChangeDescription::InsertedText {
+                preview: preview(inserted),
+                chars: inserted.chars,
+            })
+        } else if removed.chars > 0 {
+            Some(ChangeDescription::DeletedText {
+                preview: preview(removed),
+                chars: removed.chars,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Start recording commands into a macro, dropping any unfinished one
+    pub fn start_macro_recording(&mut self) {
+        self.macro_recording = Some(Vec::new());
+    }
+
+    /// Stop recording, returning the macro
+    ///
+    /// A non-empty macro is also kept for the PlayMacro command.  Returns
+    /// an empty macro if nothing was being recorded.
+    pub fn stop_macro_recording(&mut self) -> Macro {
+        let recorded = Macro {
+            commands: self.macro_recording.take().unwrap_or_default(),
+        };
+        if !recorded.commands.is_empty() {
+            self.last_macro = Some(recorded.clone());
+        }
+        recorded
+    }
+
+    /// Check if a macro is being recorded
+    pub fn is_recording_macro(&self) -> bool {
+        self.macro_recording.is_some()
+    }
+
+    /// Play a macro `times` times, returning how many runs finished
+    ///
+    /// Each run is one undo entry.  Playback stops as soon as a command
+    /// runs into the edge of the buffer, such as moving down from the last
+    /// line.  Counts are clamped as for with_repeat, and a macro can't
+    /// play another from inside itself.
+    pub fn play_macro(&mut self, recorded: &Macro, times: usize) -> usize {
+        if self.playing_macro {
+            return 0;
+        }
+        self.playing_macro = true;
+        let mut finished = 0;
+        'runs: for _ in 0..times.clamp(1, MAX_REPEAT_COUNT) {
+            let mut editor = self.transaction();
+            for command in &recorded.commands {
+                if !editor.execute(command.clone()) && command.fails_at_boundary() {
+                    break 'runs;
+                }
+            }
+            finished += 1;
+        }
+        self.playing_macro = false;
+        finished
+    }
+
+    /// Run a command `count` times, returning whether it did anything
+    ///
+    /// A count of 0 runs once and counts above 10,000 are clamped.  Edits
+    /// make a single undo entry and kills a single kill ring entry.
+    /// Repeating stops early once the command stops doing anything, such
+    /// as moving down from the last line.
+    pub fn with_repeat(&mut self, count: usize, command: EditorCommand) -> bool {
+        use EditorCommand::*;
+        let count = count.clamp(1, MAX_REPEAT_COUNT);
+        let backward = matches!(
+            command,
+            KillToLineStart | KillWordBackward | KillToBufferStart
+        );
+        let edit = command.is_edit();
+        if edit {
+            self.begin_transaction();
+        }
+        self.kill_merge = Some(KillMerge {
+            backward,
+            started: false,
+        });
+
+        let mut done = false;
+        for _ in 0..count {
+            if !self.execute(command.clone()) {
+                break;
+            }
+            done = true;
+        }
+
+        self.kill_merge = None;
+        if edit {
+            self.end_transaction();
+        }
+        done
+    }
+
+    /// Get number of lines
+    pub fn line_count(&self) -> usize {
+        self.buffer.line_count()
+    }
+
+    /// Get a specific line
+    pub fn line(&self, idx: usize) -> Option<Cow<'_, str>> {
+        self.buffer.line(idx)
+    }
+
+    /// Iterate over the lines without joining them
+    pub fn lines_iter(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.buffer.lines()
+    }
+
+    /// Length of a line in chars, or 0 past the last line
+    pub fn line_char_count(&self, idx: usize) -> usize {
+        self.buffer.line_chars(idx)
+    }
+
+    /// Length of a line in bytes, or 0 past the last line
+    pub fn line_byte_len(&self, idx: usize) -> usize {
+        self.buffer.line(idx).map_or(0, |line| line.len())
+    }
+
+    /// Byte offset in the full text at which a line starts
+    ///
+    /// Each line break counts as one byte.  Past the last line this is
+    /// the length of the full text plus one.
+    pub fn byte_offset_of_line_start(&self, idx: usize) -> usize {
+        self.buffer.line_start_byte(idx)
+    }
+
+    /// Line, char, word and byte counts for the whole buffer
+    ///
+    /// Counted without joining the lines, and cached until the next edit.
+    pub fn stats(&self) -> BufferStats {
+        if let Some((generation, stats)) = self.stats_cache.get() {
+            if generation == self.layout_generation {
+                return stats;
+            }
+        }
+        let stats = BufferStats::count(self.lines_iter(), self.word_chars);
+        self.stats_cache.set(Some((self.layout_generation, stats)));
+        stats
+    }
+
+    /// Counts for the selected text, or None without a selection
+    ///
+    /// Rows of a block selection count as separate lines.
+    pub fn selection_stats(&self) -> Option<BufferStats> {
+        let text = self.selected_text().filter(|text| !text.is_empty())?;
+        Some(BufferStats::count(text.split('\n'), self.word_chars))
+    }
+
+    /// Check whether the buffer could be run as it is
+    ///
+    /// See input::parser for what counts as left open.
+    pub fn is_input_complete(&self) -> CompletionStatus {
+        parser::is_input_complete(&self.buffer.to_text())
+    }
+
+    /// Shell highlighting for every line, as display column ranges
+    ///
+    /// Tabs are expanded to `tab_width` stops so the ranges can be painted
+    /// directly.  Each line is tokenized on its own.
+    pub fn highlight_spans(&self, tab_width: usize) -> Vec<Vec<(Range<usize>, TokenKind)>> {
+        let tab_width = tab_width.max(1);
+        (0..self.line_count())
+            .map(|idx| {
+                let text = &self.buffer.line_text(idx);
+                // Display column at each byte offset that starts a char
+                let mut columns = Vec::with_capacity(text.len() + 1);
+                let mut x = 0;
+                for c in text.chars() {
+                    columns.resize(columns.len() + c.len_utf8(), x);
+                    x += if c == '\t' {
+                        tab_width - x % tab_width
+                    } else {
+                        char_width(c)
+                    };
+                }
+                columns.push(x);
+                highlight_line(text)
+                    .into_iter()
+                    .map(|(range, kind)| (columns[range.start]..columns[range.end], kind))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Guard that keeps an undo transaction open until dropped
+///
+/// Ending on drop means a panic part way through a transaction still
+/// closes it, leaving the undo stack usable.
+pub struct TransactionGuard<'a> {
+    editor: &'a mut Editor,
+}
+
+impl Deref for TransactionGuard<'_> {
+    type Target = Editor;
+
+    fn deref(&self) -> &Editor {
+        self.editor
+    }
+}
+
+impl DerefMut for TransactionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Editor {
+        self.editor
+    }
+}
+
+impl Drop for TransactionGuard<'_> {
+    fn drop(&mut self) {
+        self.editor.end_transaction();
+    }
+}
+
+/// Convert "\r\n" and lone '\r' line breaks to '\n'
+fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Strip escape sequences and control characters from pasted text
+///
+/// Line endings are normalized first; newlines and tabs survive.
+fn sanitize_paste(text: &str) -> String {
+    let text = normalize_line_endings(text);
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\t' => result.push(c),
+            '\x1b' => match chars.next() {
+                // CSI: parameters up to a final byte in '@'..='~'
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC, DCS, SOS, PM, APC: up to BEL or ST (ESC \)
+                Some(']' | 'P' | 'X' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                // Any other escape is ESC plus one character
+                _ => {}
+            },
+            c if c.is_control() => {}
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Byte offset of the char at index `n`, or the length if there are fewer chars
+fn byte_at_char(text: &str, n: usize) -> usize {
+    text.char_indices()
+        .nth(n)
+        .map_or(text.len(), |(idx, _)| idx)
+}
+
+/// Position just past `text` when it is inserted at `start`
+fn end_of_insert(start: CursorPosition, text: &str) -> CursorPosition {
+    match text.rfind('\n') {
+        Some(idx) => CursorPosition {
+            line: start.line + text.matches('\n').count(),
+            column: text[idx + 1..].chars().count(),
+        },
+        None => CursorPosition {
+            line: start.line,
+            column: start.column + text.chars().count(),
+        },
+    }
+}
+
+/// Where `pos` ends up after the text between `start` and `end` is replaced
+///
+/// Positions inside the replaced range collapse to its start.
+fn shift_position(
+    pos: CursorPosition,
+    start: CursorPosition,
+    end: CursorPosition,
+    text: &str,
+) -> CursorPosition {
+    if pos <= start {
+        pos
+    } else if pos < end {
+        start
+    } else {
+        let new_end = end_of_insert(start, text);
+        if pos.line == end.line {
+            CursorPosition {
+                line: new_end.line,
+                column: new_end.column + pos.column - end.column,
+            }
+        } else {
+            CursorPosition {
+                line: pos.line - end.line + new_end.line,
+                column: pos.column,
+            }
+        }
+    }
+}
+
+/// Find `query` in `text` at or after char offset `from`
+fn find_chars(text: &[char], query: &[char], from: usize, case_sensitive: bool) -> Option<usize> {
+    if query.is_empty() || query.len() > text.len() {
+        return None;
+    }
+    (from..=text.len() - query.len()).find(|&start| {
+        text[start..start + query.len()]
+            .iter()
+            .zip(query)
+            .all(|(a, b)| a == b || (!case_sensitive && a.to_lowercase().eq(b.to_lowercase())))
+    })
+}
+
+/// Comment or quote marker at the start of a line, with its indentation
+/// and the spaces after it
+fn paragraph_prefix(line: &str) -> &str {
+    let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+    let rest = &line[indent..];
+    let marker = ["//", "--", "#", ">", ";", "*"]
+        .iter()
+        .find(|marker| rest.starts_with(*marker))
+        .map_or(0, |marker| {
+            rest.len() - rest.trim_start_matches(*marker).len()
+        });
+    if marker == 0 {
+        return &line[..indent];
+    }
+    let after = &rest[marker..];
+    let spaces = after.len() - after.trim_start_matches(' ').len();
+    &line[..indent + marker + spaces]
+}
+
+/// Greedily fill lines of at most `width` display cells with `words`
+///
+/// Words wider than `width` are split between graphemes.
+fn wrap_words(words: &[String], width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut line_width = 0;
+    for word in words {
+        let word_width = unicode_column_width(word, None);
+        let current = lines.last_mut().unwrap();
+        if line_width > 0 && line_width + 1 + word_width <= width {
+            current.push(' ');
+            current.push_str(word);
+            line_width += 1 + word_width;
+            continue;
+        }
+        if line_width > 0 {
+            lines.push(String::new());
+            line_width = 0;
+        }
+        for grapheme in word.graphemes(true) {
+            let w = unicode_column_width(grapheme, None);
+            if line_width > 0 && line_width + w > width {
+                lines.push(String::new());
+                line_width = 0;
+            }
+            lines.last_mut().unwrap().push_str(grapheme);
+            line_width += w;
+        }
+    }
+    lines
+}
+
+/// Number of graphemes that start before char column `column`
+fn grapheme_index(text: &str, column: usize) -> usize {
+    let byte = byte_at_char(text, column);
+    text.grapheme_indices(true)
+        .take_while(|&(idx, _)| idx < byte)
+        .count()
+}
+
+/// Char column at which grapheme `index` starts, or the end of `text`
+fn column_of_grapheme(text: &str, index: usize) -> usize {
+    text.graphemes(true)
+        .take(index)
+        .map(|g| g.chars().count())
+        .sum()
+}
+
+/// Display column of char column `column`, with tabs at `tab_width` stops
+fn display_x(text: &str, column: usize, tab_width: usize) -> usize {
+    let tab_width = tab_width.max(1);
+    text.chars().take(column).fold(0, |x, c| {
+        if c == '\t' {
+            x + tab_width - x % tab_width
+        } else {
+            x + char_width(c)
+        }
+    })
+}
+
+/// Display width of a single char
+pub(crate) fn char_width(c: char) -> usize {
+    unicode_column_width(c.encode_utf8(&mut [0; 4]), None)
+}
+
+/// Char column of the first char starting at or after display column `col`
+fn column_at_display(text: &str, col: usize) -> usize {
+    let mut width = 0;
+    for (idx, c) in text.chars().enumerate() {
+        if width >= col {
+            return idx;
+        }
+        width += char_width(c);
+    }
+    text.chars().count()
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serialize for Editor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_snapshot().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Editor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let snapshot = EditorSnapshot::deserialize(deserializer)?;
+        Editor::from_snapshot(snapshot).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_backspace() {
+        let mut editor = Editor::new();
+        editor.insert_char('h');
+        editor.insert_char('i');
+        assert_eq!(editor.text(), "hi");
+
+        editor.backspace();
+        assert_eq!(editor.text(), "h");
+    }
+
+    #[test]
+    fn test_newline() {
+        let mut editor = Editor::new();
+        editor.insert_str("hello");
+        editor.insert_char('\n');
+        editor.insert_str("world");
+
+        assert_eq!(editor.line_count(), 2);
+        assert_eq!(editor.line(0).as_deref(), Some("hello"));
+        assert_eq!(editor.line(1).as_deref(), Some("world"));
+    }
+
+    #[test]
+    fn test_cursor_movement() {
+        let mut editor = Editor::new();
+        editor.insert_str("hello");
+
+        editor.move_left();
+        editor.move_left();
+        editor.insert_char('X');
+
+        assert_eq!(editor.text(), "helXlo");
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut editor = Editor::new();
+        editor.insert_str("hello");
+        editor.insert_str(" world");
+
+        editor.undo();
+        assert_eq!(editor.text(), "hello");
+
+        editor.redo();
+        assert_eq!(editor.text(), "hello world");
+    }
+
+    #[test]
+    fn test_replace_all_multiline() {
+        let mut editor = Editor::new();
+        editor.set_text("cp /tmp/a /tmp/b\nls /tmp");
+        assert_eq!(editor.replace_all("/tmp", "/var\n"), 3);
+        assert_eq!(editor.line_count(), 5);
+        assert_eq!(editor.full_text(), "cp /var\n/a /var\n/b\nls /var\n");
+
+        // A single undo reverts every replacement
+        editor.undo();
+        assert_eq!(editor.full_text(), "cp /tmp/a /tmp/b\nls /tmp");
+    }
+
+    #[test]
+    fn test_replace_all_self_containing() {
+        let mut editor = Editor::new();
+        editor.set_text("abab");
+        editor.set_cursor(3);
+        assert_eq!(editor.replace_all("a", "aa"), 2);
+        assert_eq!(editor.text(), "aabaab");
+        // The cursor stays after the second 'a', shifted by the first replacement
+        assert_eq!(editor.cursor_coords(), (0, 5));
+    }
+
+    #[test]
+    fn test_replace_next_case_insensitive() {
+        let mut editor = Editor::new();
+        editor.set_text("Echo echo");
+        editor.set_search_case_sensitive(false);
+        let range = editor.replace_next("ECHO", "print", CursorPosition { line: 0, column: 1 });
+        assert_eq!(editor.text(), "Echo print");
+        assert_eq!(range.map(|r| (r.start.column, r.end.column)), Some((5, 10)));
+        assert_eq!(editor.cursor_coords(), (0, 10));
+    }
+
+    #[test]
+    fn test_multi_cursor_typing() {
+        let mut editor = Editor::new();
+        editor.set_text("a\nb\nc");
+        editor.set_cursor(0);
+        assert!(editor.add_cursor_below());
+        assert!(editor.add_cursor_below());
+        assert!(!editor.add_cursor_below());
+
+        editor.insert_str("> ");
+        editor.insert_char('x');
+        assert_eq!(editor.full_text(), "> xa\n> xb\n> xc");
+        assert_eq!(editor.cursor_coords(), (0, 3));
+
+        // Undo restores the text and the full cursor set
+        editor.undo();
+        assert_eq!(editor.full_text(), "> a\n> b\n> c");
+        assert_eq!(editor.extra_cursors().len(), 2);
+    }
+
+    #[test]
+    fn test_multi_cursor_backspace_merges() {
+        let mut editor = Editor::new();
+        editor.set_text("ab\ncd");
+        editor.set_cursor(3);
+        editor.add_cursor_at(CursorPosition { line: 1, column: 1 });
+
+        // Joining the lines brings both cursors together
+        editor.backspace();
+        assert_eq!(editor.full_text(), "abd");
+        assert_eq!(editor.cursor_coords(), (0, 2));
+        assert!(editor.extra_cursors().is_empty());
+    }
+
+    #[test]
+    fn test_block_selection_ragged_lines() {
+        let mut editor = Editor::new();
+        editor.set_text("a,bb,c\nd\ne,ff,g");
+        editor.set_cursor(1);
+        editor.start_block_selection();
+        editor.set_cursor(14);
+
+        // The short middle line contributes an empty slice
+        assert_eq!(editor.selected_text().as_deref(), Some(",bb,\n\n,ff,"));
+
+        editor.backspace();
+        assert_eq!(editor.full_text(), "ac\nd\neg");
+        assert_eq!(editor.cursor_coords(), (0, 1));
+    }
+
+    #[test]
+    fn test_block_insert_pads_and_undoes_at_once() {
+        let mut editor = Editor::new();
+        editor.set_text("abc\na\nabc");
+        editor.set_cursor(2);
+        editor.start_block_selection();
+        editor.set_cursor(8);
+
+        editor.insert_char('|');
+        assert_eq!(editor.full_text(), "ab|c\na |\nab|c");
+        editor.insert_char('|');
+        assert_eq!(editor.full_text(), "ab||c\na ||\nab||c");
+
+        editor.undo();
+        editor.undo();
+        assert_eq!(editor.full_text(), "abc\na\nabc");
+    }
+
+    #[test]
+    fn test_undo_restores_modified_flag() {
+        let mut editor = Editor::new();
+        editor.insert_char('x');
+        assert!(editor.is_modified());
+        editor.undo();
+        assert!(!editor.is_modified());
+        editor.redo();
+        assert!(editor.is_modified());
+
+        // Undoing back to the saved revision counts as unmodified too
+        editor.mark_unmodified();
+        editor.insert_char('y');
+        editor.undo();
+        assert!(!editor.is_modified());
+    }
+
+    #[test]
+    fn test_undo_restores_selection() {
+        let mut editor = Editor::new();
+        editor.insert_str("hello world");
+        editor.set_cursor(6);
+        editor.start_selection();
+        editor.set_cursor(11);
+        editor.insert_char('x');
+        assert_eq!(editor.text(), "hello x");
+
+        editor.undo();
+        assert_eq!(editor.text(), "hello world");
+        assert_eq!(editor.selected_text().as_deref(), Some("world"));
+    }
+
+    #[test]
+    fn test_transaction_is_one_undo_entry() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo");
+        {
+            let mut tx = editor.transaction();
+            tx.insert_str(" hi");
+            tx.begin_transaction();
+            tx.backspace();
+            tx.end_transaction();
+            tx.insert_char('!');
+        }
+        assert_eq!(editor.text(), "echo h!");
+
+        editor.undo();
+        assert_eq!(editor.text(), "echo");
+
+        // An empty transaction leaves nothing to undo
+        editor.begin_transaction();
+        editor.move_left();
+        editor.end_transaction();
+        editor.undo();
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn test_transaction_survives_panic() {
+        let mut editor = Editor::new();
+        editor.insert_str("ls");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut tx = editor.transaction();
+            tx.insert_str(" -la");
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+        assert!(!editor.in_transaction());
+
+        editor.insert_char('x');
+        editor.undo();
+        assert_eq!(editor.text(), "ls -la");
+        editor.undo();
+        assert_eq!(editor.text(), "ls");
+    }
+
+    #[test]
+    fn test_undo_memory_stays_bounded() {
+        let mut editor = Editor::new();
+        let script: Vec<String> = (0..5000)
+            .map(|i| format!("echo line {} of a long pasted script", i))
+            .collect();
+        editor.set_text(&script.join("\n"));
+        // The paste itself is one step holding the pasted text
+        let pasted = editor.undo_memory_bytes();
+
+        for _ in 0..1000 {
+            editor.insert_char('x');
+        }
+        // A full snapshot would retain a ~200 KB buffer per keystroke
+        assert_eq!(editor.undo_depth(), 1001);
+        assert!((editor.undo_memory_bytes() - pasted) / 1000 < 1024);
+
+        editor.undo();
+        assert_eq!(editor.line(4999).map(|l| l.matches('x').count()), Some(999));
+    }
+
+    #[test]
+    fn test_undo_budget_drops_oldest_steps() {
+        let mut editor = Editor::new();
+        let script: Vec<String> = (0..5000)
+            .map(|i| format!("echo line {} of a long pasted script", i))
+            .collect();
+        editor.set_undo_budget_bytes(64 * 1024);
+        editor.set_text(&script.join("\n"));
+        for _ in 0..1000 {
+            editor.insert_char('x');
+        }
+        assert!(editor.undo_memory_bytes() <= 64 * 1024);
+        assert!(editor.undo_depth() < 1001);
+
+        // The paste and the first keystrokes went, so undoing all that is
+        // left keeps them
+        while editor.undo_depth() > 0 {
+            editor.undo();
+        }
+        assert_eq!(editor.line_count(), 5000);
+        let kept = editor.line(4999).map_or(0, |l| l.matches('x').count());
+        assert!(kept > 0 && kept < 1000);
+    }
+
+    #[test]
+    fn test_mixed_line_endings_are_normalized() {
+        let mut editor = Editor::new();
+        editor.set_text("one\r\ntwo\rthree\nfour\r\n");
+        assert_eq!(editor.line_count(), 5);
+        assert_eq!(editor.line(1).as_deref(), Some("two"));
+        assert_eq!(editor.line(2).as_deref(), Some("three"));
+        assert_eq!(editor.line_ending(), LineEnding::Dos);
+
+        editor.insert_str("five\r\nsix");
+        assert_eq!(editor.line(4).as_deref(), Some("five"));
+        assert_eq!(editor.line(5).as_deref(), Some("six"));
+    }
+
+    #[test]
+    fn test_dos_line_endings_round_trip() {
+        let mut editor = Editor::new();
+        editor.set_text("echo a\r\necho b");
+        assert_eq!(editor.line(0).as_deref(), Some("echo a"));
+        assert_eq!(editor.text_with_line_endings(), "echo a\r\necho b");
+        // Offsets stay in the '\n' joined text
+        assert_eq!(editor.full_text(), "echo a\necho b");
+        editor.move_to_buffer_end();
+        assert_eq!(editor.cursor_pos(), editor.full_text().len());
+
+        editor.set_line_ending(LineEnding::Unix);
+        assert_eq!(editor.text_with_line_endings(), "echo a\necho b");
+    }
+
+    #[test]
+    fn test_backspace_after_crlf() {
+        let mut editor = Editor::new();
+        editor.set_text("ls\r\npwd");
+        editor.move_up();
+        editor.move_down();
+        editor.move_to_line_start();
+        editor.backspace();
+
+        assert_eq!(editor.line_count(), 1);
+        assert_eq!(editor.text(), "lspwd");
+        assert_eq!(editor.cursor_coords(), (0, 2));
+    }
+
+    #[test]
+    fn test_paste_strips_escape_sequences() {
+        let mut editor = Editor::new();
+        let inserted = editor.insert_paste("echo \x1b]0;owned\x07hi\x1b[2K\x1b[1;31m!\x00");
+        assert_eq!(editor.text(), "echo hi!");
+        assert_eq!(inserted, 8);
+
+        editor.undo();
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn test_paste_crlf_and_trailing_newline() {
+        let mut editor = Editor::new();
+        editor.set_paste_trims_trailing_newline(true);
+        let inserted = editor.insert_paste("cd /tmp\r\nls\r\n");
+        assert_eq!(inserted, 10);
+        assert_eq!(editor.full_text(), "cd /tmp\nls");
+        assert_eq!(editor.cursor_coords(), (1, 2));
+    }
+
+    #[test]
+    fn test_paste_replaces_selection() {
+        let mut editor = Editor::new();
+        editor.insert_str("rm old.txt");
+        editor.set_cursor(3);
+        editor.start_selection();
+        editor.set_cursor(10);
+        editor.insert_paste("new.txt");
+        assert_eq!(editor.text(), "rm new.txt");
+
+        editor.undo();
+        assert_eq!(editor.text(), "rm old.txt");
+        assert!(editor.selection().is_some());
+    }
+
+    #[test]
+    fn test_cut_multi_line_selection() {
+        let mut editor = Editor::new();
+        let mut clipboard = LocalClipboard::default();
+        editor.set_text("one\ntwo\nthree");
+        editor.set_cursor(1);
+        editor.start_selection();
+        editor.set_cursor(9);
+
+        assert_eq!(
+            editor.cut_selection(&mut clipboard).as_deref(),
+            Some("ne\ntwo\nt")
+        );
+        assert_eq!(clipboard.get().as_deref(), Some("ne\ntwo\nt"));
+        assert_eq!(editor.full_text(), "ohree");
+        assert_eq!(editor.cut_selection(&mut clipboard), None);
+
+        editor.move_to_line_end();
+        editor.yank();
+        assert_eq!(editor.full_text(), "ohreene\ntwo\nt");
+
+        editor.undo();
+        editor.undo();
+        assert_eq!(editor.full_text(), "one\ntwo\nthree");
+    }
+
+    #[test]
+    fn test_clipboard_and_selection_commands() {
+        let clipboard = Arc::new(Mutex::new(LocalClipboard::default()));
+        let mut editor = Editor::new();
+        editor.set_clipboard(clipboard.clone());
+        editor.set_text("echo hello");
+        editor.set_cursor(0);
+
+        for _ in 0..4 {
+            assert!(editor.execute(EditorCommand::SelectRight));
+        }
+        assert_eq!(editor.selected_text().as_deref(), Some("echo"));
+        assert!(editor.execute(EditorCommand::Copy));
+        assert_eq!(clipboard.lock().unwrap().get().as_deref(), Some("echo"));
+
+        assert!(editor.execute(EditorCommand::SelectToLineEnd));
+        assert_eq!(editor.selected_text().as_deref(), Some("echo hello"));
+        assert!(editor.execute(EditorCommand::Cut));
+        assert_eq!(editor.full_text(), "");
+        assert!(editor.execute(EditorCommand::Paste));
+        assert!(editor.execute(EditorCommand::Paste));
+        assert_eq!(editor.full_text(), "echo helloecho hello");
+        assert!(!editor.execute(EditorCommand::Copy));
+
+        editor.set_text("abcdefghij");
+        editor.set_cursor(0);
+        assert!(editor.execute(EditorCommand::MoveToVisualLineEnd));
+        assert_eq!(editor.cursor_coords(), (0, 10));
+        editor.set_view_width(4, 8);
+        assert!(editor.execute(EditorCommand::MoveToVisualLineStart));
+        assert_eq!(editor.cursor_coords(), (0, 8));
+        assert!(editor.execute(EditorCommand::SelectUpVisual));
+        assert_eq!(editor.cursor_coords(), (0, 4));
+        assert_eq!(editor.selected_text().as_deref(), Some("efgh"));
+    }
+
+    #[test]
+    fn test_paste_from_clipboard_into_empty_buffer() {
+        let mut editor = Editor::new();
+        let mut clipboard = LocalClipboard::default();
+        assert_eq!(editor.paste_from(&mut clipboard), 0);
+
+        clipboard.set("make test");
+        assert_eq!(editor.paste_from(&mut clipboard), 9);
+        assert_eq!(editor.text(), "make test");
+        assert_eq!(editor.cursor_pos(), 9);
+    }
+
+    #[test]
+    fn test_kill_ring_cap() {
+        let mut editor = Editor::new();
+        editor.set_kill_ring_max(3);
+        for word in &["one ", "two ", "three ", "four "] {
+            editor.insert_str(word);
+            editor.kill_word_backward();
+        }
+
+        assert_eq!(editor.kill_ring_entries(), ["two ", "three ", "four "]);
+        assert!(editor.yank_nth(0));
+        assert_eq!(editor.text(), "two ");
+    }
+
+    #[test]
+    fn test_yank_nth_out_of_range() {
+        let mut editor = Editor::new();
+        editor.insert_str("secret");
+        editor.kill_to_line_start();
+        assert!(!editor.yank_nth(1));
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn test_kill_ring_clear() {
+        let mut editor = Editor::new();
+        editor.insert_str("hunter2");
+        editor.kill_to_line_start();
+        editor.kill_ring_clear();
+
+        editor.yank();
+        assert!(editor.kill_ring_entries().is_empty());
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn test_movement_reports_whether_it_moved() {
+        let mut editor = Editor::new();
+        editor.set_text("first\nsecond");
+        assert!(!editor.move_down());
+        assert!(!editor.move_right());
+        assert!(editor.move_up());
+
+        editor.move_left();
+        assert!(!editor.move_up());
+        assert_eq!(editor.cursor_coords(), (0, 4));
+        assert!(editor.move_to_line_start());
+        assert!(!editor.move_to_line_start());
+        assert!(!editor.move_left());
+        assert!(!editor.move_word_left());
+    }
+
+    #[test]
+    fn test_click_past_end_of_line() {
+        let mut editor = Editor::new();
+        editor.set_text("short\na much longer line");
+        editor.set_cursor(2);
+        editor.start_selection();
+
+        let pos = editor.set_cursor_coords(0, 40);
+        assert_eq!(pos, CursorPosition { line: 0, column: 5 });
+        assert!(editor.selection().is_none());
+        assert_eq!(
+            editor.try_set_cursor_coords(0, 6),
+            Err(EditorError::OutOfRange { line: 0, column: 6 })
+        );
+        assert_eq!(editor.cursor_coords(), (0, 5));
+    }
+
+    #[test]
+    fn test_click_below_last_line() {
+        let mut editor = Editor::new();
+        editor.set_text("one\ntwo");
+        let pos = editor.set_cursor_coords(7, 1);
+        assert_eq!(pos, CursorPosition { line: 1, column: 1 });
+        assert!(editor.try_set_cursor_coords(2, 0).is_err());
+        assert!(editor.try_set_cursor_coords(0, 3).is_ok());
+        assert_eq!(editor.cursor_coords(), (0, 3));
+    }
+
+    #[test]
+    fn test_paragraph_motion_over_blank_runs() {
+        let mut editor = Editor::new();
+        editor.set_text("cd src\nmake\n\n  \t\nmake install\n\nexit");
+        editor.set_cursor_coords(0, 3);
+
+        assert!(editor.move_paragraph_forward());
+        assert_eq!(editor.cursor_coords(), (2, 0));
+        assert!(editor.move_paragraph_forward());
+        assert_eq!(editor.cursor_coords(), (5, 0));
+        assert!(editor.move_paragraph_forward());
+        assert_eq!(editor.cursor_coords(), (6, 4));
+        assert!(!editor.move_paragraph_forward());
+
+        assert!(editor.move_paragraph_backward());
+        assert_eq!(editor.cursor_coords(), (5, 0));
+        assert!(editor.move_paragraph_backward());
+        assert_eq!(editor.cursor_coords(), (3, 0));
+        assert!(editor.move_paragraph_backward());
+        assert_eq!(editor.cursor_coords(), (0, 0));
+        assert!(!editor.move_paragraph_backward());
+
+        // Each jump left a mark behind
+        assert!(editor.pop_mark_and_jump());
+        assert_eq!(editor.cursor_coords(), (3, 0));
+    }
+
+    #[test]
+    fn test_select_paragraph_from_blank_line() {
+        let mut editor = Editor::new();
+        editor.set_text("echo one\n\n\nls -la\nrm -rf build\n\npwd");
+        editor.set_cursor_coords(1, 0);
+
+        assert!(editor.select_paragraph());
+        assert_eq!(
+            editor.selected_text().as_deref(),
+            Some("ls -la\nrm -rf build")
+        );
+        assert!(editor.toggle_comment("#"));
+        assert_eq!(
+            editor.full_text(),
+            "echo one\n\n\n# ls -la\n# rm -rf build\n\npwd"
+        );
+
+        editor.set_cursor_coords(5, 0);
+        editor.move_to_buffer_end();
+        editor.insert_str("\n  ");
+        assert!(!editor.select_paragraph());
+    }
+
+    #[test]
+    fn test_apply_edits_remaps_cursor() {
+        let mut editor = Editor::new();
+        editor.set_text("grep -r foo . | sort");
+        editor.set_cursor_coords(0, 11);
+        editor.extend_selection_to_coords(0, 20);
+
+        let edits = [
+            TextEdit {
+                range: 16..20,
+                new_text: "sort -u".to_string(),
+            },
+            TextEdit {
+                range: 0..4,
+                new_text: "rg".to_string(),
+            },
+            TextEdit {
+                range: 5..8,
+                new_text: "\\\n  ".to_string(),
+            },
+        ];
+        let depth = editor.undo_depth();
+        let cursor = editor.apply_edits(&edits).unwrap();
+        assert_eq!(editor.undo_depth(), depth + 1);
+        assert_eq!(editor.full_text(), "rg \\\n  foo . | sort -u");
+        assert_eq!(
+            cursor,
+            CursorPosition {
+                line: 1,
+                column: 17
+            }
+        );
+        assert_eq!(editor.selected_text().as_deref(), Some(" . | sort -u"));
+
+        editor.undo();
+        assert_eq!(editor.full_text(), "grep -r foo . | sort");
+    }
+
+    #[test]
+    fn test_apply_edits_rejects_overlap() {
+        let mut editor = Editor::new();
+        editor.set_text("echo hello world");
+        let depth = editor.undo_depth();
+        let edits = [
+            TextEdit {
+                range: 5..11,
+                new_text: "bye".to_string(),
+            },
+            TextEdit {
+                range: 10..16,
+                new_text: "there".to_string(),
+            },
+        ];
+        assert_eq!(
+            editor.apply_edits(&edits),
+            Err(EditorError::OverlappingEdits {
+                first: 5..11,
+                second: 10..16,
+            })
+        );
+        assert_eq!(
+            editor.apply_edits(&[TextEdit {
+                range: 12..40,
+                new_text: String::new(),
+            }]),
+            Err(EditorError::EditOutOfRange { start: 12, end: 40 })
+        );
+        assert_eq!(editor.full_text(), "echo hello world");
+        assert_eq!(editor.undo_depth(), depth);
+    }
+
+    #[test]
+    fn test_macro_replay_comments_lines() {
+        use EditorCommand::*;
+        let mut editor = Editor::new();
+        editor.set_text("make\nmake test\nmake install\nmake clean");
+        editor.set_cursor_coords(0, 2);
+
+        editor.execute(StartMacroRecording);
+        assert!(editor.is_recording_macro());
+        for command in [MoveToLineStart, InsertChar('#'), InsertChar(' '), MoveDown] {
+            editor.execute(command);
+        }
+        editor.execute(StopMacroRecording);
+        let recorded = editor.stop_macro_recording();
+        assert!(recorded.commands.is_empty());
+        let recorded = editor.last_macro.clone().unwrap();
+        assert_eq!(
+            recorded.commands,
+            [MoveToLineStart, InsertChar('#'), InsertChar(' '), MoveDown]
+        );
+
+        // The last run can't move down, so it stops there
+        assert_eq!(editor.play_macro(&recorded, 3), 2);
+        assert_eq!(
+            editor.full_text(),
+            "# make\n# make test\n# make install\n# make clean"
+        );
+
+        editor.undo();
+        assert_eq!(
+            editor.full_text(),
+            "# make\n# make test\n# make install\nmake clean"
+        );
+        editor.undo();
+        assert_eq!(
+            editor.full_text(),
+            "# make\n# make test\nmake install\nmake clean"
+        );
+    }
+
+    #[test]
+    fn test_stats_with_multibyte_text() {
+        let mut editor = Editor::new();
+        editor.set_text("echo  héllo   wörld\n🎉🎉\ncd ~/src/cx-terminal");
+        assert_eq!(
+            editor.stats(),
+            BufferStats {
+                lines: 3,
+                chars: 19 + 1 + 2 + 1 + 20,
+                words: 3 + 1 + 5,
+                bytes: 21 + 1 + 8 + 1 + 20,
+            }
+        );
+
+        editor.set_word_char_class(WordCharClass::Whitespace);
+        assert_eq!(editor.stats().words, 3 + 1 + 2);
+
+        editor.set_cursor_coords(0, 6);
+        editor.extend_selection_to_coords(1, 1);
+        assert_eq!(
+            editor.selection_stats(),
+            Some(BufferStats {
+                lines: 2,
+                chars: 13 + 1 + 1,
+                words: 3,
+                bytes: 15 + 1 + 4,
+            })
+        );
+
+        editor.move_to_buffer_end();
+        assert_eq!(editor.selection_stats(), None);
+        editor.insert_str(" && ls");
+        assert_eq!(editor.stats().chars, 43 + 6);
+    }
+
+    #[test]
+    fn test_input_filter_on_paste() {
+        let mut editor = Editor::new();
+        editor.set_input_filter(Box::new(digits_only));
+        assert_eq!(editor.insert_paste("abc123"), 3);
+        assert_eq!(editor.rejected_chars(), 3);
+        assert_eq!(editor.text(), "123");
+        assert!(!editor.insert_char('x'));
+        assert_eq!(editor.rejected_chars(), 1);
+
+        editor.set_input_filter(Box::new(|c: char| {
+            InputFilterResult::Replace(c.to_ascii_uppercase())
+        }));
+        editor.insert_str("ab");
+        assert_eq!(editor.rejected_chars(), 0);
+        assert_eq!(editor.text(), "123AB");
+
+        // Programmatic text skips the filter
+        editor.set_input_filter(Box::new(no_control_chars));
+        editor.set_text("first\nsecond");
+        assert_eq!(editor.line_count(), 2);
+        assert_eq!(editor.insert_paste("\tmore\n"), 4);
+        assert_eq!(editor.full_text(), "first\nsecondmore");
+    }
+
+    #[test]
+    fn test_change_descriptions() {
+        let mut editor = Editor::new();
+        assert_eq!(editor.last_change_description(), None);
+        editor.insert_paste("git status\ngit add -p\ngit commit");
+        let change = editor.last_change_description().unwrap();
+        assert_eq!(
+            change,
+            ChangeDescription::InsertedText {
+                preview: Some(ChangePreview::Lines(3)),
+                chars: 32,
+            }
+        );
+        assert_eq!(change.to_string(), "inserted 3 lines");
+
+        editor.set_cursor_coords(0, 10);
+        editor.kill_word_backward();
+        let change = editor.last_change_description().unwrap();
+        assert_eq!(
+            change,
+            ChangeDescription::DeletedText {
+                preview: Some(ChangePreview::Text("status".to_string())),
+                chars: 6,
+            }
+        );
+        assert_eq!(change.to_string(), "deleted 'status'");
+
+        editor.undo();
+        assert_eq!(
+            editor.last_change_description(),
+            Some(ChangeDescription::Undo)
+        );
+        editor.execute(EditorCommand::MoveDown);
+        assert_eq!(
+            editor.last_change_description(),
+            Some(ChangeDescription::CursorMoved {
+                line: 1,
+                column: 10
+            })
+        );
+
+        // Direct calls are described too, as vi mode makes them
+        editor.move_to_line_start();
+        assert_eq!(
+            editor.last_change_description().unwrap().to_string(),
+            "line 2, column 1"
+        );
+
+        // Text that merely ends in " lines" is still quoted
+        editor.insert_str("two lines");
+        assert_eq!(
+            editor.last_change_description().unwrap().to_string(),
+            "inserted 'two lines'"
+        );
+
+        editor.set_hide_change_previews(true);
+        editor.execute(EditorCommand::InsertChar('x'));
+        assert_eq!(
+            editor.last_change_description().unwrap().to_string(),
+            "inserted 1 char"
+        );
+    }
+
+    #[test]
+    fn test_replace_word_matches_completer() {
+        use crate::input::complete::{is_word_break, word_start};
+        for (text, completion, expected) in [
+            ("ls src/ma", "src/main.rs", "ls src/main.rs"),
+            ("cat log|gre", "grep", "cat log|grep"),
+            ("make && ", "install", "make && install"),
+            ("echo héllo;ech", "echo", "echo héllo;echo"),
+            ("", "git", "git"),
+        ] {
+            let mut editor = Editor::new();
+            editor.insert_str(text);
+            let start = text[..word_start(text)].chars().count();
+            let depth = editor.undo_depth();
+
+            let replaced = editor.replace_word_before_cursor(&is_word_break, completion);
+            assert_eq!(replaced, start..text.chars().count(), "{:?}", text);
+            assert_eq!(editor.full_text(), expected);
+            assert_eq!(editor.cursor_pos(), expected.len());
+            assert_eq!(editor.undo_depth(), depth + 1);
+        }
+    }
+
+    #[test]
+    fn test_replace_word_mid_word() {
+        use crate::input::complete::is_word_break;
+        let mut editor = Editor::new();
+        editor.set_text("cd /tmp\ngit chekout main");
+        editor.set_cursor_coords(1, 7);
+
+        let mut prefix_only = editor.clone();
+        assert_eq!(
+            prefix_only.replace_word_before_cursor(&is_word_break, "checkout"),
+            12..15
+        );
+        assert_eq!(prefix_only.full_text(), "cd /tmp\ngit checkoutkout main");
+
+        assert_eq!(
+            editor.replace_word_at_cursor(&is_word_break, "checkout"),
+            12..19
+        );
+        assert_eq!(editor.full_text(), "cd /tmp\ngit checkout main");
+        assert_eq!(editor.cursor_coords(), (1, 12));
+        editor.undo();
+        assert_eq!(editor.full_text(), "cd /tmp\ngit chekout main");
+    }
+
+    #[test]
+    fn test_vertical_movement_keeps_goal_column() {
+        let mut editor = Editor::new();
+        editor.set_text("a long line\nab\nanother long line");
+        editor.set_cursor_coords(0, 8);
+        editor.move_down();
+        assert_eq!(editor.cursor_coords(), (1, 2));
+        editor.move_down();
+        assert_eq!(editor.cursor_coords(), (2, 8));
+
+        editor.set_cursor_coords(1, 1);
+        editor.move_down();
+        assert_eq!(editor.cursor_coords(), (2, 1));
+    }
+
+    #[test]
+    fn test_selection_byte_range_with_emoji() {
+        let mut editor = Editor::new();
+        editor.set_text("echo 🎉\nprintf '✓'\ndone 🚀 ok");
+        editor.set_cursor_coords(0, 5);
+        editor.extend_selection_to_coords(2, 6);
+
+        let range = editor.selection_byte_range().unwrap();
+        let text = editor.full_text();
+        assert_eq!(range, 5..text.find(" ok").unwrap());
+        assert_eq!(editor.text_in_range(range.clone()), &text[range.clone()]);
+        assert_eq!(editor.text_in_range(range), editor.selected_text().unwrap());
+
+        assert_eq!(editor.byte_offset_of(editor.position_of_byte(18)), 18);
+        assert_eq!(editor.byte_offset_of(editor.position_of_byte(20)), 21);
+        assert_eq!(
+            editor.byte_offset_of(CursorPosition { line: 2, column: 6 }),
+            editor.cursor_pos()
+        );
+    }
+
+    #[test]
+    fn test_text_in_range_starting_on_newline() {
+        let mut editor = Editor::new();
+        editor.set_text("ab\ncd");
+        assert_eq!(
+            editor.position_of_byte(2),
+            CursorPosition { line: 0, column: 2 }
+        );
+        assert_eq!(
+            editor.position_of_byte(3),
+            CursorPosition { line: 1, column: 0 }
+        );
+        assert_eq!(editor.text_in_range(2..4), "\nc");
+        assert_eq!(editor.text_in_range(2..99), "\ncd");
+    }
+
+    #[test]
+    fn test_events_for_newline_split() {
+        let mut editor = Editor::new();
+        editor.insert_str("abcd");
+        editor.take_events();
+
+        editor.set_cursor(2);
+        editor.insert_char('\n');
+        assert_eq!(
+            editor.take_events(),
+            vec![
+                EditorEvent::LineChanged { line: 0 },
+                EditorEvent::LinesInserted { at: 1, count: 1 },
+                EditorEvent::CursorMoved,
+            ]
+        );
+        assert!(editor.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_events_for_undoing_multi_line_delete() {
+        let mut editor = Editor::new();
+        editor.set_text("one\ntwo\nthree\nfour");
+        editor.set_cursor(2);
+        editor.start_selection();
+        editor.set_cursor(10);
+        editor.take_events();
+
+        editor.backspace();
+        assert_eq!(
+            editor.take_events(),
+            vec![
+                EditorEvent::LineChanged { line: 0 },
+                EditorEvent::LinesRemoved { at: 1, count: 2 },
+                EditorEvent::CursorMoved,
+                EditorEvent::SelectionChanged,
+            ]
+        );
+        assert_eq!(editor.full_text(), "onree\nfour");
+
+        editor.undo();
+        assert_eq!(
+            editor.take_events(),
+            vec![
+                EditorEvent::LineChanged { line: 0 },
+                EditorEvent::LinesInserted { at: 1, count: 2 },
+                EditorEvent::CursorMoved,
+                EditorEvent::SelectionChanged,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_events_for_multi_line_replacement() {
+        let mut editor = Editor::new();
+        editor.set_text("a\nb\nc");
+        editor.take_events();
+
+        editor.set_text("x\ny\nz");
+        let changed: Vec<EditorEvent> = editor
+            .take_events()
+            .into_iter()
+            .filter(|event| !matches!(event, EditorEvent::CursorMoved))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                EditorEvent::LineChanged { line: 0 },
+                EditorEvent::LineChanged { line: 1 },
+                EditorEvent::LineChanged { line: 2 },
+            ]
+        );
+
+        editor.set_text("p\nq");
+        let changed: Vec<EditorEvent> = editor
+            .take_events()
+            .into_iter()
+            .filter(|event| !matches!(event, EditorEvent::CursorMoved))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                EditorEvent::LineChanged { line: 0 },
+                EditorEvent::LineChanged { line: 1 },
+                EditorEvent::LinesRemoved { at: 2, count: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut editor = Editor::new();
+        editor.set_text("git commit -m 'wip'\ngit push");
+        editor.kill_word_backward();
+        editor.set_cursor(4);
+        editor.extend_selection_to_coords(0, 10);
+
+        let json = serde_json::to_string(&editor).unwrap();
+        let restored: Editor = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.full_text(), "git commit -m 'wip'\ngit ");
+        assert_eq!(restored.cursor_coords(), (0, 10));
+        assert_eq!(restored.selected_text().as_deref(), Some("commit"));
+        assert_eq!(restored.kill_ring_entries(), ["push"]);
+        assert!(restored.is_modified());
+        assert_eq!(restored.to_snapshot(), editor.to_snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_with_out_of_range_cursor() {
+        let json = r#"{"version":1,"lines":["ls","pwd"],"cursor":{"line":9,"column":9},
+            "selection_anchor":{"line":0,"column":40}}"#;
+        let editor: Editor = serde_json::from_str(json).unwrap();
+        assert_eq!(editor.cursor_coords(), (1, 3));
+        assert_eq!(editor.selected_text().as_deref(), Some("\npwd"));
+        assert!(!editor.is_modified());
+    }
+
+    #[test]
+    fn test_snapshot_from_newer_version_is_rejected() {
+        let json = r#"{"version":99,"lines":["ls"],"cursor":{"line":0,"column":2}}"#;
+        let err = serde_json::from_str::<Editor>(json).unwrap_err();
+        assert!(err.to_string().contains("version 99"), "{}", err);
+
+        let mut snapshot = Editor::new().to_snapshot();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            Editor::from_snapshot(snapshot).unwrap_err(),
+            EditorError::UnsupportedSnapshot {
+                version: SNAPSHOT_VERSION + 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_preserve_cursor_after_prepend() {
+        let mut editor = Editor::new();
+        editor.insert_str("ll /tmp");
+        editor.set_cursor(2);
+        editor.set_text_preserve_cursor("ls -alF /tmp");
+
+        assert_eq!(editor.text(), "ls -alF /tmp");
+        assert_eq!(editor.cursor_coords(), (0, 7));
+        editor.undo();
+        assert_eq!(editor.text(), "ll /tmp");
+    }
+
+    #[test]
+    fn test_preserve_cursor_inside_replaced_span() {
+        let mut editor = Editor::new();
+        editor.set_text("echo fóo bar");
+        editor.set_cursor_coords(0, 6);
+        editor.set_text_preserve_cursor("echo bäzz bar");
+        assert_eq!(editor.text(), "echo bäzz bar");
+        assert_eq!(editor.cursor_coords(), (0, 9));
+    }
+
+    #[test]
+    fn test_preserve_cursor_on_middle_line_rewrite() {
+        let mut editor = Editor::new();
+        editor.set_text("for f in *; do\n  echo $f\ndone");
+        editor.set_cursor_coords(2, 2);
+        editor.set_text_preserve_cursor("for f in *; do\n  echo \"$f\"\ndone");
+
+        assert_eq!(editor.line(1).as_deref(), Some("  echo \"$f\""));
+        assert_eq!(editor.cursor_coords(), (2, 2));
+    }
+
+    #[test]
+    fn test_preserve_cursor_detects_line_ending() {
+        let mut editor = Editor::new();
+        editor.set_text("make\nmake install");
+        editor.set_text_preserve_cursor("make\r\nmake check\r\n");
+        assert_eq!(editor.line_ending(), LineEnding::Dos);
+        assert_eq!(editor.line(1).as_deref(), Some("make check"));
+        editor.set_text_preserve_cursor("make\nmake check\n");
+        assert_eq!(editor.line_ending(), LineEnding::Unix);
+    }
+
+    #[test]
+    fn test_preserve_cursor_with_identical_text() {
+        let mut editor = Editor::new();
+        editor.insert_str("make");
+        editor.mark_unmodified();
+        let depth = editor.undo_stack.len();
+
+        editor.set_text_preserve_cursor("make");
+        assert_eq!(editor.undo_stack.len(), depth);
+        assert!(!editor.is_modified());
+    }
+
+    #[test]
+    fn test_preserve_cursor_respects_options() {
+        let mut editor = Editor::new();
+        editor.set_options(EditorOptions {
+            single_line: true,
+            max_chars: Some(8),
+            ..Default::default()
+        });
+        assert!(editor.set_text_merging_undo("for f in *\ndo echo $f\ndone"));
+        assert_eq!(editor.full_text(), "for f in");
+
+        editor.set_options(EditorOptions {
+            single_line: true,
+            newlines: NewlineHandling::Reject,
+            max_chars: None,
+            normalize: None,
+        });
+        assert!(!editor.set_text_preserve_cursor("ls\npwd"));
+        assert!(!editor.set_text_merging_undo("ls\npwd"));
+        assert_eq!(editor.full_text(), "for f in");
+
+        editor.set_read_only(true);
+        assert!(!editor.set_text_preserve_cursor("ls"));
+        assert_eq!(editor.full_text(), "for f in");
+    }
+
+    #[test]
+    fn test_read_only_rejects_edits() {
+        let mut editor = Editor::new();
+        editor.set_text("sudo rm -rf /tmp/cache");
+        editor.kill_word_backward();
+        editor.undo();
+        editor.set_read_only(true);
+        let depth = (editor.undo_stack.len(), editor.redo_stack.len());
+
+        assert!(!editor.insert_char('x'));
+        assert_eq!(editor.insert_str("y"), 0);
+        assert!(!editor.backspace());
+        assert!(!editor.delete());
+        assert!(!editor.delete_range(0, 4));
+        assert!(!editor.kill_to_line_start());
+        assert!(!editor.kill_to_line_end());
+        assert!(!editor.kill_word_backward());
+        assert!(!editor.yank());
+        assert!(!editor.set_text("reboot"));
+        assert!(!editor.clear());
+        assert!(!editor.undo());
+        assert!(!editor.redo());
+        assert_eq!(editor.insert_paste("z"), 0);
+        assert_eq!(editor.replace_all("rm", "ls"), 0);
+
+        assert_eq!(editor.full_text(), "sudo rm -rf /tmp/cache");
+        assert_eq!((editor.undo_stack.len(), editor.redo_stack.len()), depth);
+
+        let mut clipboard = LocalClipboard::default();
+        assert!(editor.move_word_left());
+        editor.extend_selection_to_coords(0, 22);
+        assert_eq!(editor.cut_selection(&mut clipboard), None);
+        assert!(editor.copy_selection(&mut clipboard));
+        assert_eq!(clipboard.get().as_deref(), Some("cache"));
+    }
+
+    #[test]
+    fn test_single_line_paste() {
+        let mut editor = Editor::new();
+        editor.set_options(EditorOptions {
+            single_line: true,
+            ..Default::default()
+        });
+        assert_eq!(editor.insert_paste("new\nname\n"), 7);
+        assert_eq!(editor.full_text(), "newname");
+        assert!(!editor.insert_char('\n'));
+        assert!(!editor.move_up());
+
+        editor.set_options(EditorOptions {
+            single_line: true,
+            newlines: NewlineHandling::Reject,
+            max_chars: None,
+            normalize: None,
+        });
+        assert_eq!(editor.insert_str("a\nb"), 0);
+        assert!(!editor.set_text("x\ny"));
+        assert_eq!(editor.full_text(), "newname");
+    }
+
+    #[test]
+    fn test_typing_at_max_chars() {
+        let mut editor = Editor::new();
+        editor.set_options(EditorOptions {
+            max_chars: Some(6),
+            ..Default::default()
+        });
+        assert_eq!(editor.insert_str("hunter2"), 6);
+        assert!(!editor.insert_char('!'));
+        assert_eq!(editor.text(), "hunter");
+
+        editor.set_cursor(0);
+        editor.extend_selection_to_coords(0, 3);
+        assert_eq!(editor.insert_str("pass"), 3);
+        assert_eq!(editor.text(), "paster");
+
+        assert!(editor.set_text("correct horse"));
+        assert_eq!(editor.text(), "correc");
+    }
+
+    #[test]
+    fn test_word_left_stops_at_path_separators() {
+        let mut editor = Editor::new();
+        editor.insert_str("cat path/to/file");
+        editor.move_word_left();
+        assert_eq!(editor.cursor_coords(), (0, 12));
+        editor.move_word_left();
+        assert_eq!(editor.cursor_coords(), (0, 9));
+        editor.move_word_left();
+        assert_eq!(editor.cursor_coords(), (0, 4));
+
+        editor.set_word_char_class(WordCharClass::Whitespace);
+        editor.move_to_line_end();
+        editor.move_word_left();
+        assert_eq!(editor.cursor_coords(), (0, 4));
+    }
+
+    #[test]
+    fn test_repeated_kill_word_forward_yanks_as_one_entry() {
+        let mut editor = Editor::new();
+        editor.set_text("git log --oneline --graph -n 5 HEAD");
+        editor.set_cursor_coords(0, 3);
+
+        assert!(editor.with_repeat(5, EditorCommand::KillWordForward));
+        assert_eq!(editor.text(), "git HEAD");
+        assert_eq!(editor.kill_ring_entries(), [" log --oneline --graph -n 5"]);
+
+        editor.yank();
+        assert_eq!(editor.text(), "git log --oneline --graph -n 5 HEAD");
+        editor.undo();
+        editor.undo();
+        assert_eq!(editor.text(), "git log --oneline --graph -n 5 HEAD");
+        assert_eq!(editor.cursor_coords(), (0, 3));
+    }
+
+    #[test]
+    fn test_repeated_backward_kill_keeps_text_order() {
+        let mut editor = Editor::new();
+        editor.insert_str("cargo build --release");
+        editor.kill_word_backward();
+        editor.with_repeat(2, EditorCommand::KillWordBackward);
+        assert_eq!(editor.text(), "");
+        assert_eq!(editor.kill_ring_entries(), ["release", "cargo build --"]);
+    }
+
+    #[test]
+    fn test_repeated_move_down_keeps_goal_column() {
+        let mut editor = Editor::new();
+        editor.set_text("for f in *; do\n  x\n\n  echo \"$f\"\ndone");
+        editor.set_cursor_coords(0, 9);
+
+        assert!(editor.with_repeat(3, EditorCommand::MoveDown));
+        assert_eq!(editor.cursor_coords(), (3, 9));
+        assert!(editor.with_repeat(0, EditorCommand::MoveDown));
+        assert_eq!(editor.cursor_coords(), (4, 4));
+        assert!(!editor.with_repeat(usize::MAX, EditorCommand::MoveDown));
+    }
+
+    #[test]
+    fn test_repeated_insert_is_one_undo_step() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo ");
+        editor.with_repeat(3, EditorCommand::InsertChar('-'));
+        assert_eq!(editor.text(), "echo ---");
+        editor.undo();
+        assert_eq!(editor.text(), "echo ");
+    }
+
+    #[test]
+    fn test_kill_word_backward_on_flag() {
+        let mut editor = Editor::new();
+        editor.insert_str("ls --no-color");
+        editor.kill_word_backward();
+        assert_eq!(editor.text(), "ls --no-");
+        editor.kill_word_backward();
+        assert_eq!(editor.text(), "ls --");
+        editor.kill_word_backward();
+        assert_eq!(editor.text(), "");
+        assert_eq!(editor.kill_ring_entries(), ["color", "no-", "ls --"]);
+    }
+
+    #[test]
+    fn test_move_selected_lines_down_past_shorter_line() {
+        let mut editor = Editor::new();
+        editor.set_text("first line\nsecond line\nthird line\nx\nlast");
+        editor.set_cursor_coords(0, 3);
+        editor.extend_selection_to_coords(2, 8);
+
+        assert!(editor.move_line_down());
+        assert_eq!(
+            editor.full_text(),
+            "x\nfirst line\nsecond line\nthird line\nlast"
+        );
+        assert_eq!(editor.cursor_coords(), (3, 8));
+        assert_eq!(
+            editor.selection(),
+            Some((
+                CursorPosition { line: 1, column: 3 },
+                CursorPosition { line: 3, column: 8 }
+            ))
+        );
+
+        assert!(editor.move_line_down());
+        assert!(!editor.move_line_down());
+        assert!(editor.move_line_up());
+        assert_eq!(editor.line(4).as_deref(), Some("last"));
+
+        editor.undo();
+        editor.undo();
+        editor.undo();
+        assert_eq!(
+            editor.full_text(),
+            "first line\nsecond line\nthird line\nx\nlast"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_last_line() {
+        let mut editor = Editor::new();
+        editor.set_text("cd build\nmake -j8");
+        editor.set_cursor_coords(1, 4);
+
+        assert!(editor.duplicate_line());
+        assert_eq!(editor.full_text(), "cd build\nmake -j8\nmake -j8");
+        assert_eq!(editor.cursor_coords(), (2, 4));
+        assert!(!editor.move_line_down());
+
+        editor.undo();
+        assert_eq!(editor.full_text(), "cd build\nmake -j8");
+        assert_eq!(editor.cursor_coords(), (1, 4));
+    }
+
+    #[test]
+    fn test_delete_only_line() {
+        let mut editor = Editor::new();
+        editor.insert_str("shutdown now");
+        assert!(editor.delete_line());
+        assert_eq!(editor.line_count(), 1);
+        assert_eq!(editor.full_text(), "");
+        assert_eq!(editor.cursor_coords(), (0, 0));
+        assert_eq!(editor.kill_ring_entries(), ["shutdown now"]);
+    }
+
+    #[test]
+    fn test_delete_line_keeps_column() {
+        let mut editor = Editor::new();
+        editor.set_text("one\ntwo two\nx");
+        editor.set_cursor_coords(0, 2);
+        editor.delete_line();
+        assert_eq!(editor.full_text(), "two two\nx");
+        assert_eq!(editor.cursor_coords(), (0, 2));
+
+        editor.set_cursor_coords(1, 1);
+        editor.delete_line();
+        assert_eq!(editor.full_text(), "two two");
+        assert_eq!(editor.cursor_coords(), (0, 1));
+        assert_eq!(editor.kill_ring_entries(), ["one\n", "x\n"]);
+    }
+
+    #[test]
+    fn test_join_lines() {
+        let mut editor = Editor::new();
+        editor.set_text("echo one  \n    two\n   \nthree");
+        editor.set_cursor_coords(0, 0);
+
+        assert!(editor.join_lines());
+        assert_eq!(editor.line(0).as_deref(), Some("echo one two"));
+        assert_eq!(editor.cursor_coords(), (0, 8));
+
+        // All-whitespace next line
+        assert!(editor.join_lines());
+        assert_eq!(editor.full_text(), "echo one two\nthree");
+        assert_eq!(editor.cursor_coords(), (0, 12));
+
+        editor.move_down();
+        assert!(!editor.join_lines());
+    }
+
+    #[test]
+    fn test_toggle_comment_on_mixed_block() {
+        let mut editor = Editor::new();
+        editor.set_text("if true; then\n  # echo a\n\n  echo b\nfi");
+        editor.set_cursor_coords(1, 4);
+        editor.extend_selection_to_coords(3, 6);
+
+        assert!(editor.toggle_comment("#"));
+        assert_eq!(
+            editor.full_text(),
+            "if true; then\n  # # echo a\n\n  # echo b\nfi"
+        );
+        assert_eq!(editor.cursor_coords(), (3, 8));
+        assert_eq!(
+            editor.selected_text().as_deref(),
+            Some("echo a\n\n  # echo")
+        );
+
+        editor.undo();
+        assert_eq!(
+            editor.full_text(),
+            "if true; then\n  # echo a\n\n  echo b\nfi"
+        );
+    }
+
+    #[test]
+    fn test_uncomment_without_trailing_space() {
+        let mut editor = Editor::new();
+        editor.set_text("#ls\n    #pwd\n# id");
+        editor.set_cursor_coords(0, 0);
+        editor.extend_selection_to_coords(2, 4);
+
+        assert!(editor.toggle_comment("#"));
+        assert_eq!(editor.full_text(), "ls\n    pwd\nid");
+        assert_eq!(editor.cursor_coords(), (2, 2));
+    }
+
+    #[test]
+    fn test_paste_many_lines() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo start; ");
+        let pasted: Vec<String> = (0..10_000).map(|i| format!("line {}", i)).collect();
+        editor.insert_str(&pasted.join("\n"));
 
-            let char_indices: Vec<_> = line.char_indices().collect();
-            let byte_pos = if self.cursor.column < char_indices.len() {
-                char_indices[self.cursor.column].0
-            } else {
-                line.len()
-            };
-            self.lines[self.cursor.line].truncate(byte_pos);
-        } else if self.cursor.line + 1 < self.lines.len() {
-            // Kill newline (join with next line)
-            let next_line = self.lines.remove(self.cursor.line + 1);
-            self.lines[self.cursor.line].push_str(&next_line);
-            self.kill_ring.push("\n".to_string());
+        assert_eq!(editor.line_count(), 10_000);
+        assert_eq!(editor.line(0).as_deref(), Some("echo start; line 0"));
+        assert_eq!(editor.line(9_999).as_deref(), Some("line 9999"));
+        assert_eq!(editor.cursor_coords(), (9_999, 9));
+
+        editor.undo();
+        assert_eq!(editor.full_text(), "echo start; ");
+    }
+
+    #[test]
+    fn test_paste_into_middle_of_line() {
+        let mut editor = Editor::new();
+        editor.insert_str("git commit");
+        editor.set_cursor(3);
+        editor.insert_str(" -C repo");
+
+        assert_eq!(editor.text(), "git -C repo commit");
+        assert_eq!(editor.cursor_coords(), (0, 11));
+        editor.undo();
+        assert_eq!(editor.text(), "git commit");
+    }
+
+    #[test]
+    fn test_long_line_typing_is_fast() {
+        let start = std::time::Instant::now();
+        let mut editor = Editor::new();
+        for _ in 0..1_000_000 {
+            editor.insert_char('a');
+        }
+        editor.set_cursor(500_000);
+        for _ in 0..1000 {
+            editor.insert_char('b');
         }
+        assert_eq!(editor.cursor_pos(), 501_000);
+        assert_eq!(editor.line(0).map(|line| line.len()), Some(1_001_000));
+        assert!(start.elapsed() < std::time::Duration::from_secs(30));
+    }
 
-        self.modified = true;
-        self.redo_stack.clear();
+    #[test]
+    fn test_wrap_layout_is_cached_until_edit() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo hello world");
+        let first = editor.wrap_layout(10, 4);
+        assert!(Arc::ptr_eq(&first, &editor.wrap_layout(10, 4)));
+        assert_eq!(first.visual_row_count(), 2);
+
+        editor.insert_str(" again and again");
+        let second = editor.wrap_layout(10, 4);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(second.visual_row_count(), 4);
     }
 
-    /// Kill to start of line (Ctrl+U)
-    pub fn kill_to_line_start(&mut self) {
-        self.save_undo_state();
+    #[test]
+    fn test_visual_movement_stays_within_wrapped_line() {
+        let long: String = "lorem ipsum dolor sit amet "
+            .repeat(8)
+            .chars()
+            .take(200)
+            .collect();
+        let mut editor = Editor::new();
+        editor.set_text(&format!("{}\nend", long));
+        editor.set_cursor_coords(0, 3);
+        for _ in 0..5 {
+            assert!(editor.move_down_visual(40, 4));
+            assert_eq!(editor.cursor_coords().0, 0);
+        }
+        assert!(editor.move_down_visual(40, 4));
+        assert_eq!(editor.cursor_coords(), (1, 3));
+        editor.move_up_visual(40, 4);
+        assert_eq!(editor.cursor_coords().0, 0);
 
-        let line = &self.lines[self.cursor.line];
-        let chars: Vec<char> = line.chars().collect();
+        editor.set_text(&"x".repeat(200));
+        editor.set_cursor_coords(0, 45);
+        editor.extend_selection_up_visual(40, 4);
+        assert_eq!(editor.selected_text().as_deref(), Some(&"x".repeat(40)[..]));
 
-        if self.cursor.column > 0 {
-            let killed: String = chars[..self.cursor.column].iter().collect();
-            self.kill_ring.push(killed);
+        editor.set_cursor_coords(0, 45);
+        editor.move_to_visual_line_start(40, 4);
+        assert_eq!(editor.cursor_coords(), (0, 40));
+        editor.move_to_visual_line_end(40, 4);
+        assert_eq!(editor.cursor_coords(), (0, 79));
+    }
+
+    #[test]
+    fn test_mark_ring_survives_shortening_edit() {
+        let mut clipboard = LocalClipboard::default();
+        let mut editor = Editor::new();
+        editor.set_text("for f in *.log; do\n  gzip \"$f\"\n  echo done\ndone");
+        editor.set_cursor_coords(2, 7);
+        assert!(editor.move_to_buffer_start());
+        assert_eq!(editor.marks(), &[CursorPosition { line: 2, column: 7 }]);
+
+        // Drop the first line; the mark follows its text up a line
+        editor.set_cursor_coords(1, 0);
+        editor.extend_selection_to_coords(0, 0);
+        editor.cut_selection(&mut clipboard);
+        assert!(editor.pop_mark_and_jump());
+        assert_eq!(editor.cursor_coords(), (1, 7));
+        assert_eq!(editor.line(1).as_deref(), Some("  echo done"));
 
-            let char_indices: Vec<_> = line.char_indices().collect();
-            let byte_pos = if self.cursor.column < char_indices.len() {
-                char_indices[self.cursor.column].0
+        // A mark inside deleted text collapses to where the deletion started
+        editor.push_mark();
+        editor.move_to_buffer_end();
+        editor.set_cursor_coords(0, 2);
+        editor.extend_selection_to_coords(2, 0);
+        editor.cut_selection(&mut clipboard);
+        assert_eq!(editor.text(), "  done");
+        assert!(editor.pop_mark_and_jump());
+        assert_eq!(editor.cursor_coords(), (0, 2));
+    }
+
+    #[test]
+    fn test_exchange_point_and_mark() {
+        let mut editor = Editor::new();
+        editor.set_text("git commit -m msg");
+        editor.set_cursor_coords(0, 4);
+        editor.push_mark();
+        editor.move_to_line_end();
+        assert!(editor.exchange_point_and_mark());
+        assert_eq!(editor.cursor_coords(), (0, 4));
+        assert_eq!(editor.selected_text().as_deref(), Some("commit -m msg"));
+        assert!(editor.exchange_point_and_mark());
+        assert_eq!(editor.cursor_coords(), (0, 17));
+
+        // The mark isn't used up, so it swaps back without a selection too
+        editor.set_cursor_coords(0, 4);
+        assert!(editor.exchange_point_and_mark());
+        assert_eq!(editor.cursor_coords(), (0, 17));
+        editor.set_cursor_coords(0, 17);
+        assert!(editor.exchange_point_and_mark());
+        assert_eq!(editor.cursor_coords(), (0, 4));
+        assert_eq!(editor.marks().len(), 1);
+    }
+
+    #[test]
+    fn test_replacing_selection_is_one_undo_step() {
+        let mut editor = Editor::new();
+        editor.set_text("ls -la /tmp");
+        editor.set_cursor_coords(0, 3);
+        editor.extend_selection_to_coords(0, 6);
+        let depth = editor.undo_stack.len();
+        editor.insert_char('x');
+        assert_eq!(editor.text(), "ls x /tmp");
+        assert_eq!(editor.undo_stack.len(), depth + 1);
+
+        assert!(editor.undo());
+        assert_eq!(editor.text(), "ls -la /tmp");
+        assert_eq!(editor.selected_text().as_deref(), Some("-la"));
+        assert_eq!(editor.cursor_coords(), (0, 6));
+    }
+
+    #[test]
+    fn test_deleting_selection_is_one_undo_step() {
+        for backspace in [true, false] {
+            let mut editor = Editor::new();
+            editor.set_text("ls -la /tmp");
+            editor.set_cursor_coords(0, 6);
+            editor.extend_selection_to_coords(0, 3);
+            let depth = editor.undo_stack.len();
+            if backspace {
+                editor.backspace();
             } else {
-                line.len()
-            };
+                editor.delete();
+            }
+            assert_eq!(editor.text(), "ls  /tmp");
+            assert_eq!(editor.undo_stack.len(), depth + 1);
+            assert_eq!(editor.cursor_coords(), (0, 3));
 
-            let remaining = self.lines[self.cursor.line][byte_pos..].to_string();
-            self.lines[self.cursor.line] = remaining;
-            self.cursor.column = 0;
+            assert!(editor.undo());
+            assert_eq!(editor.text(), "ls -la /tmp");
+            assert_eq!(editor.selected_text().as_deref(), Some("-la"));
+            assert_eq!(editor.cursor_coords(), (0, 3));
         }
+    }
 
-        self.modified = true;
-        self.redo_stack.clear();
+    #[test]
+    fn test_selection_past_shortened_line_does_not_panic() {
+        let mut editor = Editor::new();
+        editor.set_text("echo hello world");
+        editor.set_cursor_coords(0, 16);
+        editor.start_selection();
+        editor.set_cursor(5);
+        assert_eq!(editor.selected_text().as_deref(), Some("hello world"));
+
+        // delete_range leaves the anchor where it was, past the new line end
+        editor.delete_range(4, 16);
+        assert_eq!(editor.text(), "echo");
+        assert_eq!(editor.selected_text().as_deref(), Some(""));
+
+        // The next edit notices and drops the selection
+        editor.insert_char('!');
+        assert_eq!(editor.text(), "echo!");
+        assert_eq!(editor.selected_text(), None);
+        assert!(editor.undo());
+        assert_eq!(editor.selected_text(), None);
     }
 
-    /// Kill word backward (Ctrl+W)
-    pub fn kill_word_backward(&mut self) {
-        self.save_undo_state();
+    #[test]
+    fn test_ime_preedit_commits_as_one_edit() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo ");
+        let depth = editor.undo_stack.len();
 
-        let line = &self.lines[self.cursor.line];
-        let chars: Vec<char> = line.chars().collect();
+        editor.set_preedit("に", 1);
+        editor.set_preedit("にほ", 2);
+        editor.set_preedit("にほんご", 4);
+        assert_eq!(editor.preedit(), Some(("にほんご", 4)));
+        assert_eq!(editor.full_text(), "echo ");
+        assert_eq!(editor.cursor_pos(), 5);
+        assert_eq!(editor.cursor_display_column(), 5);
+        assert_eq!(editor.caret_display_column(), 13);
+        assert_eq!(editor.undo_stack.len(), depth);
 
-        if self.cursor.column == 0 {
-            return;
-        }
+        // Caret moved back inside the composition
+        editor.set_preedit("日本ご", 2);
+        assert_eq!(editor.caret_display_column(), 9);
 
-        let start_column = self.cursor.column;
-        let mut end_column = self.cursor.column;
+        assert!(editor.commit_preedit());
+        assert_eq!(editor.preedit(), None);
+        assert_eq!(editor.full_text(), "echo 日本ご");
+        assert_eq!(editor.caret_display_column(), 11);
+        assert_eq!(editor.undo_stack.len(), depth + 1);
 
-        // Skip whitespace
-        while end_column > 0
-            && chars
-                .get(end_column - 1)
-                .map_or(false, |c| c.is_whitespace())
-        {
-            end_column -= 1;
-        }
+        assert!(editor.undo());
+        assert_eq!(editor.full_text(), "echo ");
+        assert!(!editor.commit_preedit());
+    }
 
-        // Skip word characters
-        while end_column > 0
-            && chars
-                .get(end_column - 1)
-                .map_or(false, |c| !c.is_whitespace())
-        {
-            end_column -= 1;
-        }
+    #[test]
+    fn test_abbreviation_with_placeholder() {
+        let mut editor = Editor::new();
+        editor.register_abbreviation("gco", "git checkout ${1:branch} ${2}");
+        editor.insert_str("gco");
+        let depth = editor.undo_stack.len();
 
-        let killed: String = chars[end_column..start_column].iter().collect();
-        self.kill_ring.push(killed);
+        assert!(editor.expand_at_cursor());
+        assert_eq!(editor.text(), "git checkout branch ");
+        assert_eq!(editor.undo_stack.len(), depth + 1);
+        assert_eq!(editor.selected_text().as_deref(), Some("branch"));
 
-        // Delete the word
-        let line = &self.lines[self.cursor.line];
-        let char_indices: Vec<_> = line.char_indices().collect();
+        editor.insert_str("main");
+        assert_eq!(editor.text(), "git checkout main ");
+        assert!(editor.next_placeholder());
+        assert_eq!(editor.cursor_coords(), (0, 18));
+        editor.insert_str("--");
+        assert!(editor.prev_placeholder());
+        assert_eq!(editor.selected_text().as_deref(), Some("main"));
+        assert!(editor.next_placeholder());
+        assert!(!editor.next_placeholder());
+        assert!(!editor.in_snippet());
 
-        let byte_start = if end_column < char_indices.len() {
-            char_indices[end_column].0
-        } else {
-            line.len()
-        };
-        let byte_end = if start_column < char_indices.len() {
-            char_indices[start_column].0
-        } else {
-            line.len()
-        };
+        editor.undo();
+        editor.undo();
+        editor.undo();
+        assert_eq!(editor.text(), "gco");
+    }
 
-        self.lines[self.cursor.line].drain(byte_start..byte_end);
-        self.cursor.column = end_column;
+    #[test]
+    fn test_abbreviation_only_in_command_position() {
+        let mut editor = Editor::new();
+        editor.register_abbreviation("gs", "git status");
+        editor.register_abbreviation_anywhere("@@", "--all");
+        editor.insert_str("echo gs");
+        assert!(!editor.expand_at_cursor());
+        editor.insert_str(" @@");
+        assert!(editor.expand_at_cursor());
+        assert_eq!(editor.text(), "echo gs --all");
 
-        self.modified = true;
-        self.redo_stack.clear();
+        editor.insert_str(" && gs");
+        assert!(editor.expand_at_cursor());
+        assert_eq!(editor.text(), "echo gs --all && git status");
+        assert!(!editor.in_snippet());
     }
 
-    /// Yank (paste from kill ring)
-    pub fn yank(&mut self) {
-        if let Some(text) = self.kill_ring.last().cloned() {
-            self.insert_str(&text);
-        }
+    #[test]
+    fn test_highlight_spans_use_display_columns() {
+        let mut editor = Editor::new();
+        editor.insert_str("echo\t日本 | wc\nls -l");
+        let spans = editor.highlight_spans(8);
+        assert_eq!(
+            spans[0],
+            vec![
+                (0..4, TokenKind::Command),
+                (8..12, TokenKind::Argument),
+                (13..14, TokenKind::Operator),
+                (15..17, TokenKind::Command),
+            ]
+        );
+        assert_eq!(
+            spans[1],
+            vec![(0..2, TokenKind::Command), (3..5, TokenKind::Flag)]
+        );
     }
 
-    /// Start selection at current cursor position
-    pub fn start_selection(&mut self) {
-        self.selection_anchor = Some(self.cursor);
+    #[test]
+    fn test_match_ranges_full_line() {
+        let mut editor = Editor::new();
+        editor.insert_str("make\nmake test\nmake");
+        editor.set_cursor_coords(1, 2);
+        let matches = editor.match_ranges("make", true);
+        assert_eq!(matches.ranges.len(), 3);
+        assert_eq!(
+            matches.ranges[2],
+            CursorPosition { line: 2, column: 0 }..CursorPosition { line: 2, column: 4 }
+        );
+        assert_eq!(matches.current, Some(2));
+
+        // A query spanning lines, and overlapping occurrences
+        let matches = editor.match_ranges("make\nmake", true);
+        assert_eq!(matches.ranges.len(), 1);
+        editor.clear();
+        editor.insert_str("aaaa");
+        assert_eq!(editor.match_ranges("aa", true).ranges.len(), 2);
+        assert_eq!(editor.match_ranges("aa", true).current, None);
     }
 
-    /// Get current selection range
-    pub fn selection(&self) -> Option<(CursorPosition, CursorPosition)> {
-        self.selection_anchor.map(|anchor| {
-            if anchor.line < self.cursor.line
-                || (anchor.line == self.cursor.line && anchor.column <= self.cursor.column)
-            {
-                (anchor, self.cursor)
-            } else {
-                (self.cursor, anchor)
-            }
-        })
+    #[test]
+    fn test_match_ranges_case_insensitive() {
+        let mut editor = Editor::new();
+        editor.insert_str("Grep grep GREP");
+        editor.move_to_line_start();
+        assert_eq!(editor.match_ranges("GREP", true).ranges.len(), 1);
+        let matches = editor.match_ranges("GREP", false);
+        assert_eq!(matches.ranges.len(), 3);
+        assert_eq!(matches.current, Some(0));
+        assert_eq!(
+            matches.ranges[1],
+            CursorPosition { line: 0, column: 5 }..CursorPosition { line: 0, column: 9 }
+        );
     }
 
-    /// Delete selection and return true if there was a selection
-    fn delete_selection(&mut self) -> bool {
-        if let Some((start, end)) = self.selection() {
-            self.save_undo_state();
+    #[test]
+    fn test_giant_edit_keeps_its_undo_entry() {
+        let mut editor = Editor::new();
+        editor.set_undo_budget_bytes(1024);
+        editor.insert_str("ls");
+        editor.insert_str(&"x".repeat(10_000));
+        assert_eq!(editor.undo_depth(), 1);
+        assert!(editor.undo_memory_bytes() > 1024);
+        assert!(editor.undo());
+        assert_eq!(editor.text(), "ls");
+    }
 
-            // Convert to byte positions and delete
-            // This is simplified - a full implementation would be more complex
-            self.selection_anchor = None;
+    #[test]
+    fn test_default_budget_keeps_many_small_edits() {
+        let mut editor = Editor::new();
+        for _ in 0..1000 {
+            editor.insert_char('x');
+        }
+        assert_eq!(editor.undo_depth(), 1000);
+        assert!(editor.undo_memory_bytes() <= editor.undo_budget_bytes());
+    }
 
-            // Move cursor to start of selection
-            self.cursor = start;
+    #[test]
+    fn test_line_offsets_match_cursor_pos() {
+        let mut editor = Editor::new();
+        editor.insert_str("échø 日本\n\nls ñ");
+        assert_eq!(editor.lines_iter().count(), 3);
+        assert_eq!(editor.line_char_count(0), 7);
+        assert_eq!(editor.line_byte_len(0), 13);
+        assert_eq!(editor.line_byte_len(9), 0);
+        for line in 0..editor.line_count() {
+            editor.set_cursor_coords(line, 0);
+            assert_eq!(editor.byte_offset_of_line_start(line), editor.cursor_pos());
+        }
+        editor.set_cursor_coords(2, 4);
+        assert_eq!(editor.cursor_pos(), editor.full_text().len());
 
-            // Delete from start to end
-            if start.line == end.line {
-                let line = &self.lines[start.line];
-                let char_indices: Vec<_> = line.char_indices().collect();
-                let byte_start = if start.column < char_indices.len() {
-                    char_indices[start.column].0
-                } else {
-                    line.len()
-                };
-                let byte_end = if end.column < char_indices.len() {
-                    char_indices[end.column].0
-                } else {
-                    line.len()
-                };
-                self.lines[start.line].drain(byte_start..byte_end);
-            } else {
-                // Multi-line selection - join first and last line with content between removed
-                let first_line = &self.lines[start.line];
-                let char_indices: Vec<_> = first_line.char_indices().collect();
-                let byte_start = if start.column < char_indices.len() {
-                    char_indices[start.column].0
-                } else {
-                    first_line.len()
-                };
-                let first_part = first_line[..byte_start].to_string();
+        // Delete "日本" and both line breaks by byte offsets
+        let start = editor.byte_offset_of_line_start(0) + "échø ".len();
+        assert!(editor.delete_range(start, editor.byte_offset_of_line_start(1) + 1));
+        assert_eq!(editor.full_text(), "échø ls ñ");
+    }
 
-                let last_line = &self.lines[end.line];
-                let char_indices: Vec<_> = last_line.char_indices().collect();
-                let byte_end = if end.column < char_indices.len() {
-                    char_indices[end.column].0
-                } else {
-                    last_line.len()
-                };
-                let last_part = last_line[byte_end..].to_string();
+    #[test]
+    fn test_file_round_trip_keeps_crlf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deploy.sh");
+        std::fs::write(&path, "set -e\r\nmake\r\nmake install\r\n").unwrap();
 
-                // Remove lines between
-                for _ in start.line..=end.line {
-                    self.lines.remove(start.line);
-                }
+        let mut editor = Editor::load_file(&path).unwrap();
+        assert_eq!(editor.line_ending(), LineEnding::Dos);
+        assert_eq!(editor.line(1).as_deref(), Some("make"));
+        assert_eq!(editor.line_count(), 4);
+        assert!(!editor.is_modified());
 
-                self.lines
-                    .insert(start.line, format!("{}{}", first_part, last_part));
-            }
+        editor.set_cursor_coords(1, 4);
+        editor.insert_str(" -j8");
+        editor.save_file(&path).unwrap();
+        assert!(!editor.is_modified());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "set -e\r\nmake -j8\r\nmake install\r\n"
+        );
+    }
 
-            self.modified = true;
-            self.redo_stack.clear();
-            true
-        } else {
-            false
-        }
+    #[test]
+    fn test_save_keeps_missing_final_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.sh");
+        std::fs::write(&path, "echo a\necho b").unwrap();
+
+        let mut editor = Editor::load_file(&path).unwrap();
+        assert_eq!(editor.line_count(), 2);
+        editor.save_file(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo a\necho b");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
     }
 
-    /// Get selected text
-    pub fn selected_text(&self) -> Option<String> {
-        self.selection().map(|(start, end)| {
-            if start.line == end.line {
-                let line = &self.lines[start.line];
-                let chars: Vec<char> = line.chars().collect();
-                chars[start.column..end.column].iter().collect()
-            } else {
-                let mut result = String::new();
-                for line_idx in start.line..=end.line {
-                    let line = &self.lines[line_idx];
-                    let chars: Vec<char> = line.chars().collect();
-
-                    if line_idx == start.line {
-                        result.push_str(&chars[start.column..].iter().collect::<String>());
-                        result.push('\n');
-                    } else if line_idx == end.line {
-                        result.push_str(&chars[..end.column].iter().collect::<String>());
-                    } else {
-                        result.push_str(line);
-                        result.push('\n');
-                    }
-                }
-                result
-            }
-        })
+    #[test]
+    fn test_file_changed_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "one\n").unwrap();
+
+        let mut editor = Editor::load_file(&path).unwrap();
+        assert!(!editor.file_changed_on_disk());
+        std::fs::write(&path, "one\ntwo from elsewhere\n").unwrap();
+        assert!(editor.file_changed_on_disk());
+
+        editor.save_file(&path).unwrap();
+        assert!(!editor.file_changed_on_disk());
+        std::fs::remove_file(&path).unwrap();
+        assert!(editor.file_changed_on_disk());
     }
 
-    /// Save current state for undo
-    fn save_undo_state(&mut self) {
-        let state = EditorState {
-            lines: self.lines.clone(),
-            cursor: self.cursor,
-        };
+    #[test]
+    fn test_delete_multi_line_selection() {
+        let mut editor = Editor::new();
+        editor.set_text("cd /srv\nmake\nmake install");
+        let depth = editor.undo_depth();
+        assert_eq!(editor.delete_selection(), None);
+        assert_eq!(editor.undo_depth(), depth);
 
-        self.undo_stack.push_back(state);
+        editor.set_cursor_coords(0, 3);
+        editor.extend_selection_to_coords(2, 5);
+        assert_eq!(
+            editor.delete_selection().as_deref(),
+            Some("/srv\nmake\nmake ")
+        );
+        assert_eq!(editor.full_text(), "cd install");
+        assert_eq!(
+            editor.kill_ring_entries().last().map(String::as_str),
+            Some("/srv\nmake\nmake ")
+        );
+        assert_eq!(editor.undo_depth(), depth + 1);
 
-        // Limit undo history
-        while self.undo_stack.len() > MAX_UNDO_HISTORY {
-            self.undo_stack.pop_front();
-        }
+        editor.undo();
+        assert_eq!(editor.full_text(), "cd /srv\nmake\nmake install");
     }
 
-    /// Undo last action
-    pub fn undo(&mut self) {
-        if let Some(state) = self.undo_stack.pop_back() {
-            // Save current state to redo stack
-            let current = EditorState {
-                lines: self.lines.clone(),
-                cursor: self.cursor,
-            };
-            self.redo_stack.push_back(current);
+    #[test]
+    fn test_replace_selection_with_newlines() {
+        let mut editor = Editor::new();
+        editor.set_text("git commit -m msg");
+        assert_eq!(editor.replace_selection("x"), None);
 
-            // Restore previous state
-            self.lines = state.lines;
-            self.cursor = state.cursor;
-            self.selection_anchor = None;
-        }
+        editor.set_cursor_coords(0, 4);
+        editor.extend_selection_to_coords(0, 10);
+        let depth = editor.undo_depth();
+        assert_eq!(
+            editor.replace_selection("add -p\ngit commit").as_deref(),
+            Some("commit")
+        );
+        assert_eq!(editor.full_text(), "git add -p\ngit commit -m msg");
+        assert_eq!(editor.cursor_coords(), (1, 10));
+        assert_eq!(editor.undo_depth(), depth + 1);
+
+        editor.undo();
+        assert_eq!(editor.full_text(), "git commit -m msg");
     }
 
-    /// Redo last undone action
-    pub fn redo(&mut self) {
-        if let Some(state) = self.redo_stack.pop_back() {
-            // Save current state to undo stack
-            let current = EditorState {
-                lines: self.lines.clone(),
-                cursor: self.cursor,
-            };
-            self.undo_stack.push_back(current);
+    #[test]
+    fn test_fill_paragraph_keeps_comment_prefix() {
+        let mut editor = Editor::new();
+        editor.set_text(
+            "# Summary\n\n# Rewrite the\n# cache so that entries expire after a configurable delay instead of never\n\nfooter",
+        );
+        editor.set_cursor_coords(3, 10);
+        assert!(editor.fill_paragraph(40));
+        assert_eq!(
+            editor.full_text(),
+            "# Summary\n\n# Rewrite the cache so that entries\n# expire after a configurable delay\n# instead of never\n\nfooter"
+        );
+        // Still right after "so"
+        assert_eq!(editor.cursor_coords(), (2, 22));
+        assert!(editor.undo());
+        assert_eq!(
+            editor.line(3).as_deref(),
+            Some("# cache so that entries expire after a configurable delay instead of never")
+        );
 
-            // Restore redo state
-            self.lines = state.lines;
-            self.cursor = state.cursor;
-            self.selection_anchor = None;
-        }
+        editor.set_text("日本語のテキストです 日本語");
+        assert!(editor.fill_paragraph(10));
+        assert_eq!(editor.full_text(), "日本語のテ\nキストです\n日本語");
     }
 
-    /// Check if editor has been modified
-    pub fn is_modified(&self) -> bool {
-        self.modified
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        let mut editor = Editor::new();
+        editor.set_text("git add -p  \ngit commit\t\n  ");
+        editor.set_cursor_coords(0, 12);
+        let depth = editor.undo_depth();
+        assert!(editor.trim_trailing_whitespace());
+        assert_eq!(editor.full_text(), "git add -p\ngit commit\n");
+        assert_eq!(editor.cursor_coords(), (0, 10));
+        assert_eq!(editor.undo_depth(), depth + 1);
+        assert!(!editor.trim_trailing_whitespace());
     }
 
-    /// Mark editor as unmodified
-    pub fn mark_unmodified(&mut self) {
-        self.modified = false;
+    #[test]
+    fn test_decomposed_input_is_composed() {
+        let mut editor = Editor::new();
+        editor.set_options(EditorOptions {
+            normalize: Some(NormalizationForm::Nfc),
+            ..Default::default()
+        });
+        editor.insert_str("cafe\u{301} ");
+        assert_eq!(editor.text(), "caf\u{e9} ");
+        assert_eq!(editor.cursor_coords(), (0, 5));
+
+        // A combining mark typed on its own joins the char before it
+        editor.insert_char('e');
+        editor.insert_char('\u{301}');
+        assert_eq!(editor.text(), "caf\u{e9} \u{e9}");
+        assert_eq!(editor.cursor_coords(), (0, 6));
+        editor.undo();
+        assert_eq!(editor.text(), "caf\u{e9} e");
     }
 
-    /// Get number of lines
-    pub fn line_count(&self) -> usize {
-        self.lines.len()
+    #[test]
+    fn test_normalize_buffer_keeps_cursor() {
+        let mut editor = Editor::new();
+        editor.set_text("re\u{301}sume\u{301} ok\nna\u{ef}ve");
+        editor.set_cursor_coords(0, 9);
+        assert!(editor.normalize_buffer());
+        assert_eq!(editor.full_text(), "r\u{e9}sum\u{e9} ok\nna\u{ef}ve");
+        // Still before "ok"
+        assert_eq!(editor.cursor_coords(), (0, 7));
+        assert!(!editor.normalize_buffer());
+        editor.undo();
+        assert_eq!(editor.line(0).as_deref(), Some("re\u{301}sume\u{301} ok"));
     }
 
-    /// Get a specific line
-    pub fn line(&self, idx: usize) -> Option<&str> {
-        self.lines.get(idx).map(|s| s.as_str())
+    /// Run `op` and check that it left no undo entry behind
+    fn assert_no_undo_entry(editor: &mut Editor, op: impl FnOnce(&mut Editor) -> bool) {
+        let depth = editor.undo_depth();
+        let text = editor.full_text();
+        assert!(op(editor));
+        assert_eq!(editor.undo_depth(), depth);
+        assert_eq!(editor.full_text(), text);
     }
-}
 
-impl Default for Editor {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_no_op_edits_leave_no_undo_entry() {
+        let mut editor = Editor::new();
+        editor.set_text("git status\nls");
+        editor.set_cursor_coords(1, 0);
+        assert_no_undo_entry(&mut editor, Editor::kill_to_line_start);
+        assert_no_undo_entry(&mut editor, Editor::kill_word_backward);
+
+        editor.set_cursor_coords(1, 2);
+        assert_no_undo_entry(&mut editor, Editor::delete);
+        assert_no_undo_entry(&mut editor, Editor::kill_to_line_end);
+
+        editor.set_cursor_coords(0, 0);
+        assert_no_undo_entry(&mut editor, Editor::backspace);
+        assert_no_undo_entry(&mut editor, |editor| editor.set_text("git status\nls"));
+
+        // Undo goes straight back to the empty buffer
+        assert!(editor.undo());
+        assert_eq!(editor.full_text(), "");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_no_op_clear_and_delete_range_leave_no_undo_entry() {
+        let mut editor = Editor::new();
+        assert_no_undo_entry(&mut editor, Editor::clear);
+        assert!(!editor.is_modified());
+
+        editor.set_text("echo hi");
+        editor.insert_str("!");
+        assert!(editor.undo());
+        let revision = editor.revision;
+        assert_no_undo_entry(&mut editor, |editor| editor.delete_range(3, 3));
+        assert_no_undo_entry(&mut editor, |editor| editor.delete_range(5, 2));
+        assert_eq!(editor.revision, revision);
+        // Redo survives the no-ops
+        assert!(editor.redo());
+        assert_eq!(editor.text(), "echo hi!");
+    }
 
     #[test]
-    fn test_insert_and_backspace() {
+    fn test_selection_span_for_three_lines() {
         let mut editor = Editor::new();
-        editor.insert_char('h');
-        editor.insert_char('i');
-        assert_eq!(editor.text(), "hi");
+        editor.set_text("if true; then\n\techo 日本\nfi");
+        // Anchor after the cursor
+        editor.set_cursor_coords(2, 1);
+        editor.extend_selection_to_coords(0, 3);
+        assert_eq!(editor.selection_span_for_line(0), Some(3..13));
+        assert_eq!(editor.selection_span_for_line(1), Some(0..8));
+        assert_eq!(editor.selection_span_for_line(2), Some(0..1));
+        assert_eq!(editor.selection_span_for_line(3), None);
+        assert_eq!(editor.selection_display_span_for_line(1, 4), Some(0..13));
 
-        editor.backspace();
-        assert_eq!(editor.text(), "h");
+        editor.set_cursor_coords(1, 1);
+        editor.extend_selection_to_coords(1, 7);
+        assert_eq!(editor.selection_span_for_line(0), None);
+        assert_eq!(editor.selection_span_for_line(1), Some(1..7));
+        assert_eq!(editor.selection_display_span_for_line(1, 8), Some(8..15));
     }
 
     #[test]
-    fn test_newline() {
+    fn test_selection_starting_at_line_end() {
         let mut editor = Editor::new();
-        editor.insert_str("hello");
-        editor.insert_char('\n');
-        editor.insert_str("world");
+        editor.set_text("make\nmake install");
+        editor.set_cursor_coords(0, 4);
+        editor.extend_selection_to_coords(1, 4);
+        assert_eq!(editor.selection_span_for_line(0), Some(4..4));
+        assert_eq!(editor.selection_span_for_line(1), Some(0..4));
 
-        assert_eq!(editor.line_count(), 2);
-        assert_eq!(editor.line(0), Some("hello"));
-        assert_eq!(editor.line(1), Some("world"));
+        editor.set_cursor_coords(0, 4);
+        editor.extend_selection_to_coords(0, 4);
+        assert_eq!(editor.selection_span_for_line(0), None);
     }
 
     #[test]
-    fn test_cursor_movement() {
+    fn test_peek_at_line_boundaries() {
         let mut editor = Editor::new();
-        editor.insert_str("hello");
+        assert_eq!(editor.char_before_cursor(), None);
+        assert_eq!(editor.char_at_cursor(), None);
+        assert_eq!(editor.word_at_cursor(), None);
 
-        editor.move_left();
-        editor.move_left();
-        editor.insert_char('X');
+        editor.set_text("cd ~/données\n(ls)");
+        editor.set_cursor_coords(0, 0);
+        assert_eq!(editor.char_before_cursor(), None);
+        assert_eq!(editor.char_at_cursor(), Some('c'));
+        assert_eq!(editor.word_before_cursor(), None);
+        assert_eq!(editor.word_at_cursor(), Some(("cd".to_string(), 0..2)));
 
-        assert_eq!(editor.text(), "helXlo");
+        editor.set_cursor_coords(0, 12);
+        assert_eq!(editor.char_before_cursor(), Some('s'));
+        assert_eq!(editor.char_at_cursor(), Some('\n'));
+        assert_eq!(editor.word_before_cursor().as_deref(), Some("données"));
+
+        editor.set_cursor_coords(1, 0);
+        assert_eq!(editor.char_before_cursor(), Some('\n'));
+        assert_eq!(editor.char_at_cursor(), Some('('));
+
+        editor.set_cursor_coords(1, 4);
+        assert_eq!(editor.char_before_cursor(), Some(')'));
+        assert_eq!(editor.char_at_cursor(), None);
     }
 
     #[test]
-    fn test_undo_redo() {
+    fn test_word_at_cursor_multibyte() {
         let mut editor = Editor::new();
-        editor.insert_str("hello");
-        editor.insert_str(" world");
+        editor.set_text("echo héllo-wörld");
+        editor.set_cursor_coords(0, 7);
+        assert_eq!(editor.word_before_cursor().as_deref(), Some("hé"));
+        assert_eq!(editor.word_at_cursor(), Some(("héllo".to_string(), 5..10)));
 
-        editor.undo();
-        assert_eq!(editor.text(), "hello");
+        editor.set_word_char_class(WordCharClass::Whitespace);
+        assert_eq!(
+            editor.word_at_cursor(),
+            Some(("héllo-wörld".to_string(), 5..16))
+        );
+        editor.set_cursor_coords(0, 4);
+        assert_eq!(editor.word_at_cursor(), Some(("echo".to_string(), 0..4)));
+        editor.set_cursor_coords(0, 5);
+        assert_eq!(editor.word_before_cursor(), None);
+    }
 
-        editor.redo();
-        assert_eq!(editor.text(), "hello world");
+    #[test]
+    fn test_smart_home_toggles() {
+        let mut editor = Editor::new();
+        editor.set_text("if true; then\n \t  echo hi\n  \t");
+        editor.set_cursor_coords(1, 9);
+        assert!(editor.move_to_line_start_smart());
+        assert_eq!(editor.cursor_coords(), (1, 4));
+        assert!(editor.move_to_line_start_smart());
+        assert_eq!(editor.cursor_coords(), (1, 0));
+        assert!(editor.move_to_line_start_smart());
+        assert_eq!(editor.cursor_coords(), (1, 4));
+
+        // An all-whitespace line has its "text" at the end
+        editor.set_cursor_coords(2, 0);
+        assert!(editor.move_to_line_start_smart());
+        assert_eq!(editor.cursor_coords(), (2, 3));
+
+        // Unindented lines just go to column 0
+        editor.set_cursor_coords(0, 5);
+        editor.extend_selection_to_line_start_smart();
+        assert_eq!(editor.selected_text().as_deref(), Some("if tr"));
+        assert!(!editor.move_to_line_start_smart());
+    }
+
+    #[test]
+    fn test_kill_whole_buffer_and_yank() {
+        let text = "cat <<EOF\nfirst\n\n\nlast\nEOF\n";
+        let mut editor = Editor::new();
+        editor.set_text(text);
+        editor.mark_unmodified();
+        let depth = editor.undo_depth();
+
+        assert!(editor.kill_whole_buffer());
+        assert_eq!(editor.line_count(), 1);
+        assert_eq!(editor.full_text(), "");
+        assert_eq!(editor.cursor_coords(), (0, 0));
+        assert!(editor.is_modified());
+        assert_eq!(editor.undo_depth(), depth + 1);
+        assert_eq!(
+            editor.kill_ring_entries().last().map(String::as_str),
+            Some(text)
+        );
+
+        assert!(editor.yank());
+        assert_eq!(editor.full_text(), text);
+    }
+
+    #[test]
+    fn test_kill_to_buffer_bounds() {
+        let mut editor = Editor::new();
+        editor.set_text("one\ntwo\nthree");
+        editor.set_cursor_coords(1, 1);
+        assert!(editor.kill_to_buffer_end());
+        assert_eq!(editor.full_text(), "one\nt");
+        assert_eq!(
+            editor.kill_ring_entries().last().map(String::as_str),
+            Some("wo\nthree")
+        );
+
+        assert!(editor.kill_to_buffer_start());
+        assert_eq!(editor.full_text(), "");
+        assert_eq!(
+            editor.kill_ring_entries().last().map(String::as_str),
+            Some("one\nt")
+        );
+
+        let depth = editor.undo_depth();
+        assert!(editor.kill_to_buffer_start());
+        assert_eq!(editor.undo_depth(), depth);
     }
 }
@@ -0,0 +1,201 @@
+//! Environment variables that completion sees
+//!
+//! The shell in a pane often has variables the GUI process never had:
+//! ones exported by hand, or by direnv on entering a directory.  Completion
+//! asks an EnvironmentSource instead of std::env, so the host can hand it
+//! the shell's environment once it knows it.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// Knows the environment variables of the shell being completed for
+pub trait EnvironmentSource: Debug + Send + Sync {
+    /// All variables with their values
+    fn vars(&self) -> Vec<(String, String)>;
+
+    /// Value of the variable `key`
+    fn get(&self, key: &str) -> Option<String> {
+        self.vars()
+            .into_iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Working directory of the shell, from PWD
+    fn working_dir(&self) -> Option<PathBuf> {
+        self.get("PWD").map(PathBuf::from)
+    }
+
+    /// Home directory of the shell's user, from HOME
+    fn home_dir(&self) -> Option<PathBuf> {
+        self.get("HOME").map(PathBuf::from)
+    }
+}
+
+/// The environment of the terminal process itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessEnvironment;
+
+impl EnvironmentSource for ProcessEnvironment {
+    fn vars(&self) -> Vec<(String, String)> {
+        std::env::vars().collect()
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok()
+    }
+
+    /// The process's own directory; PWD is only what it started in
+    fn working_dir(&self) -> Option<PathBuf> {
+        std::env::current_dir().ok()
+    }
+
+    /// HOME, else the home directory of the process's user
+    fn home_dir(&self) -> Option<PathBuf> {
+        dirs_next::home_dir()
+    }
+}
+
+/// The environment of a shell session, as last reported by the host
+///
+/// Starts empty.  The host fills it from the pty's foreground process with
+/// load_process, and keeps it current from the shell's reports: OSC 7 for
+/// the working directory and user variables such as OSC 1337 SetUserVar.
+#[derive(Debug, Default)]
+pub struct SessionEnvironment {
+    vars: RwLock<HashMap<String, String>>,
+}
+
+impl SessionEnvironment {
+    /// Create an empty session environment
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a session environment holding `vars`
+    pub fn with_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            vars: RwLock::new(vars.into_iter().collect()),
+        }
+    }
+
+    /// Replace all the variables with `vars`
+    pub fn replace(&self, vars: impl IntoIterator<Item = (String, String)>) {
+        *self.vars.write().unwrap() = vars.into_iter().collect();
+    }
+
+    /// Set the variable `key` to `value`, as reported by the shell
+    pub fn set(&self, key: &str, value: &str) {
+        self.vars
+            .write()
+            .unwrap()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Remove the variable `key`
+    pub fn remove(&self, key: &str) {
+        self.vars.write().unwrap().remove(key);
+    }
+
+    /// Take PWD from the `file://host/path` URI of an OSC 7 report
+    ///
+    /// Returns false if `uri` isn't a file URI.
+    pub fn apply_osc7(&self, uri: &str) -> bool {
+        let path = match uri.strip_prefix("file://") {
+            Some(rest) => &rest[rest.find('/').unwrap_or(rest.len())..],
+            None => return false,
+        };
+        match percent_decode(path) {
+            Some(path) if !path.is_empty() => {
+                self.set("PWD", &path);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replace the variables with the environment of process `pid`, the
+    /// pty's foreground process
+    #[cfg(target_os = "linux")]
+    pub fn load_process(&self, pid: u32) -> std::io::Result<()> {
+        let environ = std::fs::read(format!("/proc/{}/environ", pid))?;
+        self.replace(parse_environ(&environ));
+        Ok(())
+    }
+}
+
+impl EnvironmentSource for SessionEnvironment {
+    fn vars(&self) -> Vec<(String, String)> {
+        let vars = self.vars.read().unwrap();
+        vars.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.vars.read().unwrap().get(key).cloned()
+    }
+}
+
+/// Parse the NUL separated `KEY=value` entries of /proc/<pid>/environ
+pub fn parse_environ(data: &[u8]) -> Vec<(String, String)> {
+    data.split(|&b| b == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (key, value) = entry.split_once('=')?;
+            (!key.is_empty()).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Decode the %XX escapes of a URI path; None if one is malformed
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_environ() {
+        let environ = b"HOME=/home/me\0KUBECONFIG=/tmp/kube=dev\0=broken\0EMPTY=\0junk\0";
+        assert_eq!(
+            parse_environ(environ),
+            [
+                ("HOME".to_string(), "/home/me".to_string()),
+                ("KUBECONFIG".to_string(), "/tmp/kube=dev".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_updates() {
+        let session = SessionEnvironment::with_vars([("A".to_string(), "1".to_string())]);
+        session.set("B", "2");
+        session.remove("A");
+        assert_eq!(session.get("A"), None);
+        assert_eq!(session.get("B").as_deref(), Some("2"));
+
+        assert!(session.apply_osc7("file://laptop/home/me/my%20project"));
+        assert_eq!(session.get("PWD").as_deref(), Some("/home/me/my project"));
+        assert!(!session.apply_osc7("https://example.com/"));
+        assert!(!session.apply_osc7("file://laptop/bad%2"));
+        assert_eq!(session.vars().len(), 2);
+    }
+}
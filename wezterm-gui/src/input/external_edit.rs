@@ -0,0 +1,165 @@
+//! Editing the input in $VISUAL or $EDITOR (Ctrl+X Ctrl+E)
+//!
+//! The buffer is written to a private temp file and the user's editor is
+//! run on it; when it exits successfully the file is read back into the
+//! editor as one undoable change.  The GUI runs the command itself, so the
+//! work is split into prepare() and finish() around it.
+
+use crate::input::editor::Editor;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+
+/// Editor run when neither $VISUAL nor $EDITOR is set
+const FALLBACK_EDITOR: &str = "vi";
+
+/// The buffer written out for an external editor
+///
+/// The temp file is removed when the session is dropped.
+pub struct ExternalEditSession {
+    file: tempfile::NamedTempFile,
+    /// Editor command, without the file name
+    command: Vec<String>,
+}
+
+impl ExternalEditSession {
+    /// Write the editor's text out for the user's $VISUAL or $EDITOR
+    pub fn prepare(editor: &Editor) -> io::Result<Self> {
+        Self::with_command(editor, &editor_command())
+    }
+
+    /// Write the editor's text out for `command`, a shell-quoted command line
+    pub fn with_command(editor: &Editor, command: &str) -> io::Result<Self> {
+        let command = match shlex::split(command) {
+            Some(words) if !words.is_empty() => words,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid editor command '{}'", command),
+                ))
+            }
+        };
+        // A .sh suffix gets shell highlighting in most editors
+        let mut file = tempfile::Builder::new()
+            .prefix("cx-edit-")
+            .suffix(".sh")
+            .tempfile()?;
+        writeln!(file, "{}", editor.text_with_line_endings())?;
+        file.flush()?;
+        Ok(Self { file, command })
+    }
+
+    /// Path of the temp file holding the text
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Command line to run, with the temp file as the last argument
+    pub fn command(&self) -> Vec<String> {
+        let mut command = self.command.clone();
+        command.push(self.path().to_string_lossy().into_owned());
+        command
+    }
+
+    /// Read the edited text back into `editor` and remove the temp file
+    ///
+    /// Nothing is applied if the external editor failed.  Returns whether
+    /// the text changed; an unchanged file leaves no undo entry.
+    pub fn finish(self, editor: &mut Editor, succeeded: bool) -> io::Result<bool> {
+        if !succeeded {
+            log::warn!("External editor failed, keeping the input unchanged");
+            return Ok(false);
+        }
+        let mut text = fs::read_to_string(self.path())?;
+        // Editors add a final newline; drop it rather than submit it
+        if text.ends_with('\n') {
+            text.pop();
+            if text.ends_with('\r') {
+                text.pop();
+            }
+        }
+        if text == editor.text_with_line_endings() {
+            return Ok(false);
+        }
+        Ok(editor.set_text_preserve_cursor(&text))
+    }
+}
+
+/// Edit the buffer in the user's editor, waiting for it to exit
+///
+/// For callers that can block; the GUI uses ExternalEditSession directly.
+pub fn edit_in_external_editor(editor: &mut Editor) -> io::Result<bool> {
+    let session = ExternalEditSession::prepare(editor)?;
+    let status = run(&session.command())?;
+    session.finish(editor, status)
+}
+
+/// Run a command line, returning whether it exited successfully
+fn run(command: &[String]) -> io::Result<bool> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Empty editor command"))?;
+    Ok(Command::new(program).args(args).status()?.success())
+}
+
+/// The user's editor command, from $VISUAL, then $EDITOR, then vi
+fn editor_command() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|command| !command.trim().is_empty())
+        .unwrap_or_else(|| FALLBACK_EDITOR.to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Shell script run as the editor, called through sh so that it
+    /// needn't be executable
+    fn fake_editor(dir: &Path, body: &str) -> String {
+        let path = dir.join("fake-editor");
+        fs::write(&path, body).unwrap();
+        format!("sh {}", shlex::try_quote(&path.to_string_lossy()).unwrap())
+    }
+
+    fn edit_with(editor: &mut Editor, command: &str) -> bool {
+        let session = ExternalEditSession::with_command(editor, command).unwrap();
+        let path = session.path().to_path_buf();
+        let succeeded = run(&session.command()).unwrap();
+        let changed = session.finish(editor, succeeded).unwrap();
+        assert!(!path.exists());
+        changed
+    }
+
+    #[test]
+    fn test_round_trip_through_editor() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = Editor::new();
+        editor.insert_str("for f in *.txt; do\n  wc -l \"$f\"");
+
+        let append = fake_editor(dir.path(), "echo done >> \"$1\"");
+        assert!(edit_with(&mut editor, &append));
+        assert_eq!(
+            editor.full_text(),
+            "for f in *.txt; do\n  wc -l \"$f\"\ndone"
+        );
+        editor.undo();
+        assert_eq!(editor.full_text(), "for f in *.txt; do\n  wc -l \"$f\"");
+    }
+
+    #[test]
+    fn test_failed_or_unchanged_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = Editor::new();
+        editor.insert_str("make -j8");
+        let depth = editor.undo_depth();
+
+        let fail = fake_editor(dir.path(), "echo oops > \"$1\"; exit 1");
+        assert!(!edit_with(&mut editor, &fail));
+        assert!(!edit_with(&mut editor, "true"));
+        assert_eq!(editor.full_text(), "make -j8");
+        assert_eq!(editor.undo_depth(), depth);
+    }
+}
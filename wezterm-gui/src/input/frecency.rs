@@ -0,0 +1,270 @@
+//! Ranking completions by how often and how lately they were accepted
+//!
+//! FrecencyStore counts the accepted completions, keyed by text and kind,
+//! with the time each was last used.  A use counts for half as much every
+//! week, entries unused for three months are dropped, and the store keeps
+//! at most a fixed number of entries.  It is saved as JSON under
+//! ~/.config/cx-terminal/.
+
+use crate::input::complete::CompletionKind;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File the store is saved to, in the config directory
+const FRECENCY_FILE: &str = "frecency.json";
+/// Most entries kept unless set otherwise
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+/// Time after which a use counts half as much
+const HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Time after which an unused entry is forgotten
+const MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Uses of one completion
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FrecencyEntry {
+    text: String,
+    kind: CompletionKind,
+    uses: u32,
+    /// Seconds since the Unix epoch
+    last_used: u64,
+}
+
+/// Accepted completions, for ranking the ones used often and lately first
+pub struct FrecencyStore {
+    entries: HashMap<(CompletionKind, String), FrecencyEntry>,
+    /// Where the store is saved, if anywhere
+    path: Option<PathBuf>,
+    /// Most entries kept
+    max_entries: usize,
+    /// Source of the current time
+    clock: Box<dyn Fn() -> SystemTime + Send>,
+}
+
+impl Default for FrecencyStore {
+    fn default() -> Self {
+        Self::in_memory()
+    }
+}
+
+impl FrecencyStore {
+    /// Create an empty store that is never saved
+    pub fn in_memory() -> Self {
+        Self {
+            entries: HashMap::new(),
+            path: None,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            clock: Box::new(SystemTime::now),
+        }
+    }
+
+    /// Open the store in the default location
+    pub fn open_default() -> io::Result<Self> {
+        Self::open(Self::default_path())
+    }
+
+    /// Open the store saved at `path`, which needn't exist yet
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut store = Self::in_memory();
+        match fs::File::open(&path) {
+            Ok(file) => {
+                let entries: Vec<FrecencyEntry> = serde_json::from_reader(BufReader::new(file))?;
+                store.entries = entries
+                    .into_iter()
+                    .map(|entry| ((entry.kind, entry.text.clone()), entry))
+                    .collect();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        store.path = Some(path);
+        Ok(store)
+    }
+
+    /// Get the default location of the store
+    pub fn default_path() -> PathBuf {
+        dirs_next::config_dir()
+            .unwrap_or_else(|| PathBuf::from(".config"))
+            .join("cx-terminal")
+            .join(FRECENCY_FILE)
+    }
+
+    /// Use `clock` instead of the system time
+    pub fn set_clock(&mut self, clock: impl Fn() -> SystemTime + Send + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set the most entries kept
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.prune();
+    }
+
+    /// Number of completions the store knows
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if no completion has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Record that the completion `text` of `kind` was accepted
+    pub fn record(&mut self, text: &str, kind: CompletionKind) {
+        let now = secs_since_epoch((self.clock)());
+        let entry = self
+            .entries
+            .entry((kind, text.to_string()))
+            .or_insert_with(|| FrecencyEntry {
+                text: text.to_string(),
+                kind,
+                uses: 0,
+                last_used: now,
+            });
+        entry.uses = entry.uses.saturating_add(1);
+        entry.last_used = now;
+        self.prune();
+    }
+
+    /// How much the completion `text` of `kind` is used, decayed by the
+    /// time since its last use; zero if it never was
+    pub fn score(&self, text: &str, kind: CompletionKind) -> f64 {
+        let now = secs_since_epoch((self.clock)());
+        self.entries
+            .get(&(kind, text.to_string()))
+            .map_or(0.0, |entry| decayed(entry, now))
+    }
+
+    /// Write the store to where it was opened from, if anywhere
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+        fs::create_dir_all(dir)?;
+        let mut entries: Vec<&FrecencyEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| (a.kind as u8, &a.text).cmp(&(b.kind as u8, &b.text)));
+        let temp = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = BufWriter::new(temp.as_file());
+            serde_json::to_writer(&mut writer, &entries)?;
+            writer.flush()?;
+        }
+        temp.persist(path).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// Forget entries unused for too long, then the least used beyond the
+    /// size cap
+    fn prune(&mut self) {
+        let now = secs_since_epoch((self.clock)());
+        self.entries
+            .retain(|_, entry| now.saturating_sub(entry.last_used) <= MAX_AGE.as_secs());
+        if self.entries.len() <= self.max_entries {
+            return;
+        }
+        let mut scores: Vec<(f64, (CompletionKind, String))> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (decayed(entry, now), key.clone()))
+            .collect();
+        scores.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        for (_, key) in scores.drain(self.max_entries..) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl std::fmt::Debug for FrecencyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrecencyStore")
+            .field("entries", &self.entries.len())
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// Uses of `entry`, each halved for every HALF_LIFE since the last, or
+/// zero once it is older than MAX_AGE
+fn decayed(entry: &FrecencyEntry, now: u64) -> f64 {
+    let age = now.saturating_sub(entry.last_used);
+    if age > MAX_AGE.as_secs() {
+        return 0.0;
+    }
+    entry.uses as f64 * 0.5f64.powf(age as f64 / HALF_LIFE.as_secs() as f64)
+}
+
+fn secs_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn store_with_clock(path: Option<PathBuf>) -> (FrecencyStore, Arc<Mutex<SystemTime>>) {
+        let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_000_000_000)));
+        let mut store = match path {
+            Some(path) => FrecencyStore::open(path).unwrap(),
+            None => FrecencyStore::in_memory(),
+        };
+        let clock = Arc::clone(&now);
+        store.set_clock(move || *clock.lock().unwrap());
+        (store, now)
+    }
+
+    #[test]
+    fn test_scores_decay_and_age_out() {
+        let (mut store, now) = store_with_clock(None);
+        for _ in 0..4 {
+            store.record("git", CompletionKind::Command);
+        }
+        assert_eq!(store.score("git", CompletionKind::Command), 4.0);
+        assert_eq!(store.score("git", CompletionKind::Builtin), 0.0);
+
+        *now.lock().unwrap() += HALF_LIFE * 2;
+        assert_eq!(store.score("git", CompletionKind::Command), 1.0);
+
+        // Recording anything forgets what has gone unused too long
+        *now.lock().unwrap() += MAX_AGE;
+        store.record("ls", CompletionKind::Command);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.score("git", CompletionKind::Command), 0.0);
+    }
+
+    #[test]
+    fn test_size_is_capped() {
+        let (mut store, _) = store_with_clock(None);
+        store.set_max_entries(2);
+        store.record("make", CompletionKind::Command);
+        store.record("make", CompletionKind::Command);
+        store.record("src/", CompletionKind::Directory);
+        store.record("cargo", CompletionKind::Command);
+        assert_eq!(store.len(), 2);
+        assert!(store.score("make", CompletionKind::Command) > 0.0);
+    }
+
+    #[test]
+    fn test_saved_and_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(FRECENCY_FILE);
+        let (mut store, _) = store_with_clock(Some(path.clone()));
+        store.record("docker", CompletionKind::Command);
+        store.record("docker", CompletionKind::Command);
+        store.save().unwrap();
+
+        let (reopened, _) = store_with_clock(Some(path.clone()));
+        assert_eq!(reopened.score("docker", CompletionKind::Command), 2.0);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(FrecencyStore::open(path).is_err());
+    }
+}
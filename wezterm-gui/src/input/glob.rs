@@ -0,0 +1,127 @@
+//! Shell glob patterns, for previewing what a word expands to
+//!
+//! A pattern matches one path component: `*` any run of characters, `?`
+//! any one, and `[...]` one of a set, with ranges such as `a-z` and `!` or
+//! `^` to negate it.  A backslash makes the next character literal, as it
+//! does in an unquoted shell word.  `**` spanning directories is handled
+//! by the caller, which walks the tree.
+
+/// Check if the typed `word` has unescaped glob metacharacters
+///
+/// A `[` only counts when a `]` closes it, so `[` alone stays literal.
+pub fn has_glob_meta(word: &str) -> bool {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if match_class(&chars[i + 1..], '\0').is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Check if `name` matches all of `pattern`
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => {
+            // A run of stars matches the same as one
+            let rest = &pattern[pattern.iter().take_while(|&&c| c == '*').count()..];
+            (0..=name.len()).any(|skip| matches(rest, &name[skip..]))
+        }
+        Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), match_class(&pattern[1..], '\0')) {
+            (Some(&c), Some(_)) => {
+                let (hit, len) = match_class(&pattern[1..], c).unwrap();
+                hit && matches(&pattern[len..], &name[1..])
+            }
+            (None, Some(_)) => false,
+            // An unclosed bracket is literal
+            (_, None) => name.first() == Some(&'[') && matches(&pattern[1..], &name[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && matches(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Match `c` against the class whose body starts `class`, just after the
+/// `[`, giving whether it matched and the length of the class brackets
+/// included; None if no `]` closes it
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let mut hit = false;
+    let mut first = true;
+    loop {
+        let mut lo = *class.get(i)?;
+        // "]" first in the class is a member, not its end
+        if lo == ']' && !first {
+            break;
+        }
+        first = false;
+        if lo == '\\' {
+            i += 1;
+            lo = *class.get(i)?;
+        }
+        match (class.get(i + 1), class.get(i + 2)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                hit |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+            _ => {
+                hit |= lo == c;
+                i += 1;
+            }
+        }
+    }
+    Some((hit != negated, i + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("*.rs", ".rs"));
+        assert!(!glob_match("*.rs", "main.rsx"));
+        assert!(glob_match("m??n.*", "main.rs"));
+        assert!(!glob_match("m?n", "main"));
+        assert!(glob_match("**x", "abx"));
+
+        assert!(glob_match("file[0-9].txt", "file3.txt"));
+        assert!(!glob_match("file[0-9].txt", "fileA.txt"));
+        assert!(glob_match("file[!0-9].txt", "fileA.txt"));
+        assert!(glob_match("file[^abc]", "filed"));
+        assert!(glob_match("[]x]", "]"));
+        assert!(glob_match("[a-]", "-"));
+        // Unclosed brackets and escapes are literal
+        assert!(glob_match("a[b", "a[b"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+
+    #[test]
+    fn test_has_glob_meta() {
+        assert!(has_glob_meta("*.log"));
+        assert!(has_glob_meta("src/**/mod.rs"));
+        assert!(has_glob_meta("file?"));
+        assert!(has_glob_meta("file[12]"));
+        assert!(!has_glob_meta("file["));
+        assert!(!has_glob_meta("a\\*b"));
+        assert!(!has_glob_meta("plain/path"));
+    }
+}
@@ -0,0 +1,545 @@
+//! Flag completion learned from `--help` output
+//!
+//! For commands nothing else knows, HelpFlagCompleter runs `<cmd> --help`
+//! once, picks the option lines out of what it prints and offers those
+//! flags for `-` and `--` words.  The flags are saved on disk keyed by the
+//! binary's path and modification time, so the command is run again only
+//! after it is upgraded.  Commands that may be interactive or do something
+//! on their own are never run, and neither is anything but a binary found
+//! on PATH, so a script in a cloned repository can't run on Tab.  Nothing
+//! is learned unless the host opts in with Completer::set_help_flags.
+
+use crate::input::arg_complete::{argument, output_capped, ArgCompleter, CompletionContext};
+use crate::input::complete::CompletionInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use std::{env, fs};
+
+/// File the learned flags are saved to, in the cache directory
+const HELP_FLAGS_FILE: &str = "help-flags.json";
+/// Longest `--help` may take
+const HELP_TIMEOUT: Duration = Duration::from_millis(300);
+/// Most help text read
+const MAX_HELP_OUTPUT: usize = 32 * 1024;
+
+/// Commands never run for their help, because they may wait for input,
+/// take over the terminal or act even when given `--help`
+///
+/// Versioned or suffixed names count too: `mkfs.ext4` and `python3.12`
+/// are denied by `mkfs` and `python`.
+const DENYLIST: &[&str] = &[
+    "bash", "dash", "dd", "doas", "emacs", "fdisk", "fish", "ftp", "halt", "htop", "irb", "kill",
+    "killall", "less", "login", "mkfs", "mkswap", "more", "nano", "node", "nvim", "parted",
+    "passwd", "pkill", "poweroff", "python", "reboot", "rm", "sh", "shred", "shutdown", "ssh",
+    "su", "sudo", "telnet", "top", "vi", "vim", "wipefs", "yes", "zsh",
+];
+
+/// The name a command is denied by: before any `.` suffix, without a
+/// trailing version, as `python` for `python3.12`
+fn name_stem(name: &str) -> &str {
+    let base = name.split('.').next().unwrap_or(name);
+    let stem = base.trim_end_matches(|c: char| c.is_ascii_digit() || c == '-');
+    if stem.is_empty() {
+        base
+    } else {
+        stem
+    }
+}
+
+/// A flag found in help text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelpFlag {
+    /// The flag as offered, ending in `=` for `--opt=VALUE` forms
+    pub text: String,
+    pub description: Option<String>,
+    /// Whether the flag is followed by a value
+    pub takes_value: bool,
+}
+
+/// Check if `token` names a value, such as `<FILE>`, `SIZE` or `[=WHEN]`
+fn is_value_token(token: &str) -> bool {
+    token.starts_with('<')
+        || token.starts_with('[')
+        || token
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c == '_' || c == '-' || c == '.')
+}
+
+/// Parse the option lines of help text, such as
+/// `  -I, --ignore=PATTERN   do not list entries matching PATTERN`
+///
+/// Lines whose option part holds anything else are skipped as prose.
+pub fn parse_help(text: &str) -> Vec<HelpFlag> {
+    let mut flags = Vec::new();
+    let mut seen = HashSet::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.len() == line.len() || !trimmed.starts_with('-') {
+            continue;
+        }
+        // Two spaces or a tab separate the options from the description
+        let split = [trimmed.find("  "), trimmed.find('\t')]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        let (options, description) = match split {
+            Some(at) => (&trimmed[..at], Some(trimmed[at..].trim().to_string())),
+            None => (trimmed, None),
+        };
+
+        let mut spellings: Vec<(String, bool)> = Vec::new();
+        let mut takes_value = false;
+        let tokens = options.split(|c: char| c == ',' || c.is_whitespace());
+        for token in tokens.filter(|token| !token.is_empty()) {
+            let token = token.trim_end_matches("...");
+            if let Some(long) = token.strip_prefix("--") {
+                let end = long.find(['=', '[', '<']).unwrap_or(long.len());
+                let (name, value) = long.split_at(end);
+                if name.len() < 2 || !name.chars().all(|c| c.is_alphanumeric() || c == '-') {
+                    spellings.clear();
+                    break;
+                }
+                let equals = value.starts_with('=');
+                takes_value |= equals || value.starts_with('<');
+                spellings.push((format!("--{}", name), equals));
+            } else if token.starts_with('-')
+                && token.len() == 2
+                && token[1..].chars().all(|c| c.is_alphanumeric() || c == '?')
+            {
+                spellings.push((token.to_string(), false));
+            } else if !spellings.is_empty() && is_value_token(token) {
+                // `[=WHEN]` is optional, the others are required
+                takes_value |= !token.starts_with('[');
+            } else {
+                spellings.clear();
+                break;
+            }
+        }
+        for (spelling, equals) in spellings {
+            let text = if equals {
+                format!("{}=", spelling)
+            } else {
+                spelling
+            };
+            if seen.insert(text.clone()) {
+                flags.push(HelpFlag {
+                    text,
+                    description: description.clone().filter(|d| !d.is_empty()),
+                    takes_value,
+                });
+            }
+        }
+    }
+    flags
+}
+
+/// Runs a command's `--help` for the completer
+pub trait HelpRunner: Send + Sync {
+    /// Help text of `program`, or None if it fails or outlasts `timeout`
+    fn help(&self, program: &Path, timeout: Duration, max_output: usize) -> Option<String>;
+}
+
+/// Runs the command itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemHelp;
+
+impl HelpRunner for SystemHelp {
+    fn help(&self, program: &Path, timeout: Duration, max_output: usize) -> Option<String> {
+        let mut command = Command::new(program);
+        command.arg("--help");
+        output_capped(&mut command, timeout, max_output)
+    }
+}
+
+/// Flags learned from one binary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LearnedFlags {
+    /// The binary's modification time, in seconds since the Unix epoch
+    modified: u64,
+    flags: Vec<HelpFlag>,
+}
+
+/// Completes flags of any command from its `--help` output
+pub struct HelpFlagCompleter {
+    runner: Box<dyn HelpRunner>,
+    /// Where learned flags are saved, if anywhere
+    path: Option<PathBuf>,
+    denylist: HashSet<String>,
+    /// Learned flags by binary path, read from disk on first use
+    learned: Mutex<Option<HashMap<PathBuf, LearnedFlags>>>,
+}
+
+impl Default for HelpFlagCompleter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HelpFlagCompleter {
+    /// Create a completer running commands and saving what it learns in
+    /// the default location
+    pub fn new() -> Self {
+        Self::with_runner(Box::new(SystemHelp), Some(Self::default_path()))
+    }
+
+    /// Create a completer getting help text from `runner`, saving what it
+    /// learns at `path` if given
+    pub fn with_runner(runner: Box<dyn HelpRunner>, path: Option<PathBuf>) -> Self {
+        Self {
+            runner,
+            path,
+            denylist: DENYLIST.iter().map(|name| name.to_string()).collect(),
+            learned: Mutex::new(None),
+        }
+    }
+
+    /// Get the default location of the learned flags
+    pub fn default_path() -> PathBuf {
+        dirs_next::cache_dir()
+            .unwrap_or_else(|| PathBuf::from(".cache"))
+            .join("cx-terminal")
+            .join(HELP_FLAGS_FILE)
+    }
+
+    /// Never run `command`, or versions of it, for its help
+    pub fn deny(&mut self, command: &str) {
+        self.denylist.insert(command.to_string());
+    }
+
+    /// Flags of the binary at `program`, learned now unless known for its
+    /// current modification time
+    fn flags(&self, program: &Path) -> Vec<HelpFlag> {
+        let modified = fs::metadata(program)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let mut learned = self.learned.lock().unwrap();
+        let learned = learned.get_or_insert_with(|| self.load());
+        if let Some(known) = learned.get(program) {
+            if known.modified == modified {
+                return known.flags.clone();
+            }
+        }
+        // A failure is remembered too, so it isn't retried on every Tab
+        let flags = self
+            .runner
+            .help(program, HELP_TIMEOUT, MAX_HELP_OUTPUT)
+            .map(|text| parse_help(&text))
+            .unwrap_or_default();
+        learned.insert(
+            program.to_path_buf(),
+            LearnedFlags {
+                modified,
+                flags: flags.clone(),
+            },
+        );
+        if let Err(err) = self.save(learned) {
+            log::warn!("Failed to save learned flags: {}", err);
+        }
+        flags
+    }
+
+    /// Read the saved flags, or none if there are none or they are unreadable
+    fn load(&self) -> HashMap<PathBuf, LearnedFlags> {
+        let text = match &self.path {
+            Some(path) => fs::read_to_string(path).unwrap_or_default(),
+            None => return HashMap::new(),
+        };
+        serde_json::from_str(&text).unwrap_or_default()
+    }
+
+    fn save(&self, learned: &HashMap<PathBuf, LearnedFlags>) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+        let temp = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = BufWriter::new(temp.as_file());
+            serde_json::to_writer(&mut writer, learned)?;
+            writer.flush()?;
+        }
+        temp.persist(path).map_err(|err| err.error)?;
+        Ok(())
+    }
+}
+
+/// Find the binary `command` runs on `path`
+///
+/// None for a command with a directory in it, such as `./deploy.sh`,
+/// which may be anything.
+fn find_program(command: &str, path: Option<OsString>) -> Option<PathBuf> {
+    if command.contains(['/', std::path::MAIN_SEPARATOR]) {
+        return None;
+    }
+    env::split_paths(&path?)
+        .map(|dir| dir.join(command))
+        .find(|program| is_executable(program))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+impl ArgCompleter for HelpFlagCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        if !ctx.word.starts_with('-') {
+            return None;
+        }
+        let name = ctx.command;
+        if self.denylist.contains(name) || self.denylist.contains(name_stem(name)) {
+            return None;
+        }
+        let path = ctx
+            .env
+            .get("PATH")
+            .map(OsString::from)
+            .or_else(|| env::var_os("PATH"));
+        let program = find_program(ctx.command, path)?;
+        let items: Vec<CompletionInfo> = self
+            .flags(&program)
+            .iter()
+            .filter(|flag| {
+                // "-" alone wants everything, "-x" short flags
+                ctx.word == "-"
+                    || flag.text.starts_with(ctx.word)
+                        && (ctx.word.starts_with("--") || !flag.text.starts_with("--"))
+            })
+            .map(|flag| {
+                let description = flag.description.as_deref().unwrap_or("option");
+                argument(&flag.text, description, ctx.word)
+            })
+            .collect();
+        (!items.is_empty()).then_some(items)
+    }
+}
+
+impl std::fmt::Debug for HelpFlagCompleter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HelpFlagCompleter")
+            .field("path", &self.path)
+            .field("denylist", &self.denylist.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const LS_HELP: &str = include_str!("testdata/help/ls.txt");
+    const GREP_HELP: &str = include_str!("testdata/help/grep.txt");
+    const CARGO_HELP: &str = include_str!("testdata/help/cargo.txt");
+
+    fn flag(text: &str, description: &str, takes_value: bool) -> HelpFlag {
+        HelpFlag {
+            text: text.to_string(),
+            description: Some(description.to_string()),
+            takes_value,
+        }
+    }
+
+    fn texts(flags: &[HelpFlag]) -> Vec<&str> {
+        flags.iter().map(|flag| flag.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_ls_help() {
+        let flags = parse_help(LS_HELP);
+        assert_eq!(
+            texts(&flags),
+            [
+                "-a",
+                "--all",
+                "-A",
+                "--almost-all",
+                "--author",
+                "-b",
+                "--escape",
+                "--block-size=",
+                "-B",
+                "--ignore-backups",
+                "--color",
+                "-d",
+                "--directory",
+                "-h",
+                "--human-readable",
+                "-I",
+                "--ignore=",
+                "-l",
+                "-r",
+                "--reverse",
+                "-1",
+                "--help",
+                "--version",
+            ]
+        );
+        assert_eq!(
+            flags[0],
+            flag("-a", "do not ignore entries starting with .", false)
+        );
+        assert_eq!(
+            flags[7],
+            flag(
+                "--block-size=",
+                "with -l, scale sizes by SIZE when printing them;",
+                true
+            )
+        );
+        assert!(!flags[10].takes_value);
+        assert!(flags[15].takes_value);
+    }
+
+    #[test]
+    fn test_name_stem() {
+        assert_eq!(name_stem("mkfs.ext4"), "mkfs");
+        assert_eq!(name_stem("python3.12"), "python");
+        assert_eq!(name_stem("python3"), "python");
+        assert_eq!(name_stem("x86_64-linux-gnu-gcc-12"), "x86_64-linux-gnu-gcc");
+        assert_eq!(name_stem("7z"), "7z");
+        assert_eq!(name_stem("grep"), "grep");
+    }
+
+    #[test]
+    fn test_parse_grep_help() {
+        let flags = parse_help(GREP_HELP);
+        assert_eq!(flags.len(), 20);
+        assert_eq!(
+            flags[5],
+            flag("--regexp=", "use PATTERNS for matching", true)
+        );
+        assert!(flags[4].takes_value);
+        assert_eq!(
+            flags.last(),
+            Some(&flag(
+                "--after-context=",
+                "print NUM lines of trailing context",
+                true
+            ))
+        );
+        assert!(texts(&flags).contains(&"--include="));
+    }
+
+    #[test]
+    fn test_parse_cargo_help() {
+        let flags = parse_help(CARGO_HELP);
+        assert_eq!(
+            texts(&flags),
+            [
+                "-V",
+                "--version",
+                "--list",
+                "--explain",
+                "-v",
+                "--verbose",
+                "-q",
+                "--quiet",
+                "--color",
+                "-C",
+                "--locked",
+                "--config",
+                "-Z",
+                "-h",
+                "--help",
+            ]
+        );
+        assert!(flags[3].takes_value);
+        assert!(!flags[5].takes_value);
+        assert_eq!(
+            flags[9],
+            flag(
+                "-C",
+                "Change to DIRECTORY before doing anything (nightly-only)",
+                true
+            )
+        );
+    }
+
+    /// Answers with canned help text, counting the runs
+    struct FakeHelp(Arc<Mutex<Vec<PathBuf>>>);
+
+    impl HelpRunner for FakeHelp {
+        fn help(&self, program: &Path, timeout: Duration, max_output: usize) -> Option<String> {
+            assert_eq!((timeout, max_output), (HELP_TIMEOUT, MAX_HELP_OUTPUT));
+            self.0.lock().unwrap().push(program.to_path_buf());
+            Some(GREP_HELP.to_string())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_learned_flags_are_saved() {
+        use std::os::unix::fs::PermissionsExt;
+        let bin = tempfile::tempdir().unwrap();
+        for name in ["grep", "rm", "mkfs.ext4", "python3.12", "shred"] {
+            let program = bin.path().join(name);
+            fs::write(&program, "").unwrap();
+            fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let cache = tempfile::tempdir().unwrap();
+        let path = cache.path().join(HELP_FLAGS_FILE);
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let completer = |runs: &Arc<Mutex<Vec<PathBuf>>>| {
+            HelpFlagCompleter::with_runner(Box::new(FakeHelp(Arc::clone(runs))), Some(path.clone()))
+        };
+        let complete = |completer: &HelpFlagCompleter, command: &str, word: &str| {
+            let mut env = HashMap::new();
+            env.insert("PATH".to_string(), bin.path().display().to_string());
+            let ctx = CompletionContext {
+                command,
+                args: vec![],
+                word,
+                cwd: PathBuf::from("/"),
+                env,
+                sudo: false,
+            };
+            completer
+                .complete(&ctx)
+                .map(|items| items.into_iter().map(|item| item.text).collect::<Vec<_>>())
+        };
+
+        let first = completer(&runs);
+        assert_eq!(
+            complete(&first, "grep", "--ignore").unwrap(),
+            ["--ignore-case"]
+        );
+        assert_eq!(complete(&first, "grep", "-m").unwrap(), ["-m"]);
+        assert_eq!(complete(&first, "grep", "-").unwrap().len(), 20);
+        assert!(complete(&first, "grep", "foo").is_none());
+        for denied in ["rm", "mkfs.ext4", "python3.12", "shred"] {
+            assert!(complete(&first, denied, "-").is_none(), "{}", denied);
+        }
+        // Only what PATH finds is run, never a path such as a local script
+        let grep = bin.path().join("grep").display().to_string();
+        assert!(complete(&first, &grep, "-").is_none());
+        assert!(complete(&first, "./grep", "-").is_none());
+        assert!(complete(&first, "no-such-command", "-").is_none());
+        assert_eq!(runs.lock().unwrap().len(), 1);
+
+        // Another completer reads them back instead of running grep
+        let second = completer(&runs);
+        assert_eq!(
+            complete(&second, "grep", "--max").unwrap(),
+            ["--max-count="]
+        );
+        assert_eq!(runs.lock().unwrap().len(), 1);
+    }
+}
@@ -62,27 +62,6 @@ pub enum HighlightStyle {
     Keyword,
 }
 
-/// Token type for lexing
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum TokenType {
-    Word,
-    Flag,
-    String(char), // char is the quote character
-    Variable,
-    Comment,
-    Operator,
-    Whitespace,
-    Path,
-}
-
-/// A token from the lexer
-#[derive(Debug, Clone)]
-struct Token {
-    text: String,
-    range: Range<usize>,
-    token_type: TokenType,
-}
-
 /// Shell keywords
 const KEYWORDS: &[&str] = &[
     "if", "then", "else", "elif", "fi", "case", "esac", "for", "in", "do", "done", "while",
@@ -90,89 +69,14 @@ const KEYWORDS: &[&str] = &[
     "local", "export", "declare", "typeset", "readonly", "unset",
 ];
 
-/// Common command names (partial list for highlighting first command)
-const COMMON_COMMANDS: &[&str] = &[
-    "ls",
-    "cd",
-    "pwd",
-    "mkdir",
-    "rmdir",
-    "rm",
-    "cp",
-    "mv",
-    "cat",
-    "less",
-    "more",
-    "head",
-    "tail",
-    "grep",
-    "find",
-    "echo",
-    "printf",
-    "read",
-    "source",
-    ".",
-    "exec",
-    "git",
-    "docker",
-    "npm",
-    "cargo",
-    "python",
-    "node",
-    "sudo",
-    "su",
-    "ssh",
-    "scp",
-    "rsync",
-    "vim",
-    "nano",
-    "emacs",
-    "code",
-    "make",
-    "cmake",
-    "gcc",
-    "clang",
-    "curl",
-    "wget",
-    "ping",
-    "nc",
-    "telnet",
-    "ps",
-    "top",
-    "htop",
-    "kill",
-    "killall",
-    "tar",
-    "gzip",
-    "gunzip",
-    "zip",
-    "unzip",
-    "chmod",
-    "chown",
-    "chgrp",
-    "apt",
-    "yum",
-    "dnf",
-    "pacman",
-    "brew",
-    "systemctl",
-    "service",
-    "journalctl",
-];
-
 /// Syntax highlighter for shell commands
 #[derive(Debug, Clone, Default)]
-pub struct SyntaxHighlighter {
-    /// Cache of PATH executables (for command highlighting)
-    cached_commands: Vec<String>,
-}
+pub struct SyntaxHighlighter;
 
 impl SyntaxHighlighter {
     /// Create a new syntax highlighter
     pub fn new() -> Self {
-        Self {
-            cached_commands: COMMON_COMMANDS.iter().map(|s| s.to_string()).collect(),
-        }
+        Self
     }
 
     /// Highlight the given text
@@ -181,317 +85,271 @@ impl SyntaxHighlighter {
             return vec![];
         }
 
-        let tokens = self.tokenize(text);
-        self.style_tokens(&tokens)
-    }
-
-    /// Tokenize the input text
-    fn tokenize(&self, text: &str) -> Vec<Token> {
-        let mut tokens = Vec::new();
-        let chars: Vec<char> = text.chars().collect();
-        let mut pos = 0;
-        let mut byte_pos = 0;
-
-        while pos < chars.len() {
-            let start_byte = byte_pos;
-            let start_pos = pos;
-            let c = chars[pos];
-
-            match c {
-                // Whitespace
-                ' ' | '\t' | '\n' | '\r' => {
-                    while pos < chars.len() && chars[pos].is_whitespace() {
-                        byte_pos += chars[pos].len_utf8();
-                        pos += 1;
-                    }
-                    tokens.push(Token {
-                        text: chars[start_pos..pos].iter().collect(),
-                        range: start_byte..byte_pos,
-                        token_type: TokenType::Whitespace,
-                    });
-                }
-
-                // Comment
-                '#' => {
-                    while pos < chars.len() && chars[pos] != '\n' {
-                        byte_pos += chars[pos].len_utf8();
-                        pos += 1;
-                    }
-                    tokens.push(Token {
-                        text: chars[start_pos..pos].iter().collect(),
-                        range: start_byte..byte_pos,
-                        token_type: TokenType::Comment,
-                    });
-                }
-
-                // String literals
-                '"' | '\'' => {
-                    let quote = c;
-                    byte_pos += c.len_utf8();
-                    pos += 1;
-
-                    while pos < chars.len() {
-                        let curr = chars[pos];
-                        byte_pos += curr.len_utf8();
-                        pos += 1;
-
-                        if curr == quote {
-                            break;
-                        }
-                        // Handle escape in double quotes
-                        if quote == '"' && curr == '\\' && pos < chars.len() {
-                            byte_pos += chars[pos].len_utf8();
-                            pos += 1;
-                        }
-                    }
-
-                    tokens.push(Token {
-                        text: chars[start_pos..pos].iter().collect(),
-                        range: start_byte..byte_pos,
-                        token_type: TokenType::String(quote),
-                    });
+        let mut spans = Vec::new();
+        let mut line_start = 0;
+        for line in text.split('\n') {
+            let mut covered = line_start;
+            for (range, kind) in highlight_line(line) {
+                let range = line_start + range.start..line_start + range.end;
+                if covered < range.start {
+                    spans.push(span(text, covered..range.start, HighlightStyle::Default));
                 }
+                covered = range.end;
+                let word = &text[range.clone()];
+                let style = match kind {
+                    TokenKind::Command if KEYWORDS.contains(&word) => HighlightStyle::Keyword,
+                    TokenKind::Command => HighlightStyle::Command,
+                    TokenKind::Argument => HighlightStyle::Argument,
+                    TokenKind::Flag => HighlightStyle::Flag,
+                    TokenKind::String => HighlightStyle::String,
+                    TokenKind::Variable => HighlightStyle::Variable,
+                    TokenKind::Operator => HighlightStyle::Operator,
+                    TokenKind::Comment => HighlightStyle::Comment,
+                    TokenKind::Path => HighlightStyle::Path,
+                };
+                spans.push(span(text, range, style));
+            }
+            // Whitespace after the last token, and the newline
+            line_start += line.len() + 1;
+            let end = line_start.min(text.len());
+            if covered < end {
+                spans.push(span(text, covered..end, HighlightStyle::Default));
+            }
+        }
+        spans
+    }
+}
 
-                // Variable
-                '$' => {
-                    byte_pos += c.len_utf8();
-                    pos += 1;
-
-                    // Handle ${...} or $(...) or $VAR
-                    if pos < chars.len() {
-                        match chars[pos] {
-                            '{' => {
-                                byte_pos += chars[pos].len_utf8();
-                                pos += 1;
-                                while pos < chars.len() && chars[pos] != '}' {
-                                    byte_pos += chars[pos].len_utf8();
-                                    pos += 1;
-                                }
-                                if pos < chars.len() {
-                                    byte_pos += chars[pos].len_utf8();
-                                    pos += 1;
-                                }
-                            }
-                            '(' => {
-                                byte_pos += chars[pos].len_utf8();
-                                pos += 1;
-                                let mut depth = 1;
-                                while pos < chars.len() && depth > 0 {
-                                    match chars[pos] {
-                                        '(' => depth += 1,
-                                        ')' => depth -= 1,
-                                        _ => {}
-                                    }
-                                    byte_pos += chars[pos].len_utf8();
-                                    pos += 1;
-                                }
-                            }
-                            _ => {
-                                // Regular variable name
-                                while pos < chars.len()
-                                    && (chars[pos].is_alphanumeric() || chars[pos] == '_')
-                                {
-                                    byte_pos += chars[pos].len_utf8();
-                                    pos += 1;
-                                }
-                            }
-                        }
-                    }
-
-                    tokens.push(Token {
-                        text: chars[start_pos..pos].iter().collect(),
-                        range: start_byte..byte_pos,
-                        token_type: TokenType::Variable,
-                    });
-                }
+/// Span of `text` covering `range`
+fn span(text: &str, range: Range<usize>, style: HighlightStyle) -> HighlightedSpan {
+    HighlightedSpan {
+        text: text[range.clone()].to_string(),
+        range,
+        style,
+    }
+}
 
-                // Operators
-                '|' | '&' | ';' | '>' | '<' | '(' | ')' | '{' | '}' | '[' | ']' => {
-                    byte_pos += c.len_utf8();
-                    pos += 1;
-
-                    // Handle double operators
-                    if pos < chars.len() {
-                        let next = chars[pos];
-                        let is_double = match (c, next) {
-                            ('|', '|') | ('&', '&') | ('>', '>') | ('<', '<') => true,
-                            ('>', '&') | ('<', '&') | ('>', '|') => true, // Redirections
-                            _ => false,
-                        };
-                        if is_double {
-                            byte_pos += next.len_utf8();
-                            pos += 1;
-                        }
-                    }
+/// Kind of token found by highlight_line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Command,
+    Argument,
+    Flag,
+    String,
+    Variable,
+    Operator,
+    Comment,
+    Path,
+}
 
-                    tokens.push(Token {
-                        text: chars[start_pos..pos].iter().collect(),
-                        range: start_byte..byte_pos,
-                        token_type: TokenType::Operator,
-                    });
-                }
+/// Chars that end an unquoted word
+const WORD_BREAKS: &str = "|&;<>()";
 
-                // Flags or words
-                '-' => {
-                    // Could be a flag or part of a word/path
-                    byte_pos += c.len_utf8();
-                    pos += 1;
-
-                    // Check if it looks like a flag
-                    let is_flag =
-                        pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '-');
-
-                    while pos < chars.len()
-                        && !chars[pos].is_whitespace()
-                        && !"\"'#$|&;><(){}[]".contains(chars[pos])
-                    {
-                        byte_pos += chars[pos].len_utf8();
-                        pos += 1;
-                    }
+/// Words after which another command is expected
+const COMMAND_PREFIXES: &[&str] = &[
+    "if", "then", "else", "elif", "do", "while", "until", "!", "time", "exec", "sudo",
+];
 
-                    let text: String = chars[start_pos..pos].iter().collect();
-                    tokens.push(Token {
-                        text,
-                        range: start_byte..byte_pos,
-                        token_type: if is_flag {
-                            TokenType::Flag
-                        } else {
-                            TokenType::Word
-                        },
-                    });
+/// Split one line of shell into byte ranges by token kind
+///
+/// Quotes, escapes and `$VAR`s inside a word get their own ranges, so
+/// `"foo $BAR"` is a String range with a Variable range inside it.
+/// Unterminated quotes run to the end of the line.  Whitespace is not
+/// covered by any range.
+pub fn highlight_line(line: &str) -> Vec<(Range<usize>, TokenKind)> {
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let offset = |idx: usize| chars.get(idx).map_or(line.len(), |&(byte, _)| byte);
+    let mut spans = Vec::new();
+    let mut expect_command = true;
+    let mut redirect_target = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i].1;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '#' {
+            spans.push((offset(i)..line.len(), TokenKind::Comment));
+            break;
+        }
+        if WORD_BREAKS.contains(c) {
+            let start = i;
+            i += 1;
+            if let Some(&(_, next)) = chars.get(i) {
+                let double = matches!(
+                    (c, next),
+                    ('|', '|')
+                        | ('&', '&')
+                        | ('>', '>')
+                        | ('<', '<')
+                        | ('>', '&')
+                        | ('&', '>')
+                        | ('>', '|')
+                );
+                if double {
+                    i += 1;
                 }
+            }
+            if c == '<' || c == '>' || (c == '&' && i - start == 2 && chars[i - 1].1 == '>') {
+                redirect_target = true;
+            } else {
+                expect_command = c != ')';
+            }
+            spans.push((offset(start)..offset(i), TokenKind::Operator));
+            continue;
+        }
 
-                // Path or word
-                '/' | '~' | '.' => {
-                    // Likely a path
-                    while pos < chars.len()
-                        && !chars[pos].is_whitespace()
-                        && !"\"'#$|&;><(){}[]".contains(chars[pos])
-                    {
-                        byte_pos += chars[pos].len_utf8();
-                        pos += 1;
+        // A word, made of plain runs, quoted strings and variables
+        let word_start = i;
+        let mut parts: Vec<(Range<usize>, Option<TokenKind>)> = Vec::new();
+        let mut plain_start = i;
+        while i < chars.len() {
+            let c = chars[i].1;
+            if c.is_whitespace() || WORD_BREAKS.contains(c) {
+                break;
+            }
+            match c {
+                '\\' => i = (i + 2).min(chars.len()),
+                '\'' | '"' | '$' => {
+                    if plain_start < i {
+                        parts.push((plain_start..i, None));
                     }
-
-                    let text: String = chars[start_pos..pos].iter().collect();
-                    let token_type = if text.contains('/') || text.starts_with('~') {
-                        TokenType::Path
-                    } else {
-                        TokenType::Word
+                    i = match c {
+                        '\'' => single_quoted(&chars, i, &mut parts),
+                        '"' => double_quoted(&chars, i, &mut parts),
+                        _ => variable(&chars, i, &mut parts),
                     };
-
-                    tokens.push(Token {
-                        text,
-                        range: start_byte..byte_pos,
-                        token_type,
-                    });
+                    plain_start = i;
                 }
+                _ => i += 1,
+            }
+        }
+        if plain_start < i {
+            parts.push((plain_start..i, None));
+        }
 
-                // Regular word
-                _ => {
-                    while pos < chars.len()
-                        && !chars[pos].is_whitespace()
-                        && !"\"'#$|&;><(){}[]".contains(chars[pos])
-                    {
-                        byte_pos += chars[pos].len_utf8();
-                        pos += 1;
-                    }
+        let raw: String = chars[word_start..i].iter().map(|&(_, c)| c).collect();
+        let is_assignment = expect_command
+            && raw.split('=').next().is_some_and(|name| {
+                raw.contains('=') && !name.is_empty() && !name.contains(['"', '\'', '$'])
+            });
+        let kind = if redirect_target {
+            TokenKind::Path
+        } else if expect_command && !is_assignment {
+            TokenKind::Command
+        } else if raw.starts_with('-') {
+            TokenKind::Flag
+        } else if raw.contains('/') || raw.starts_with('~') {
+            TokenKind::Path
+        } else {
+            TokenKind::Argument
+        };
+        for (range, part_kind) in parts {
+            spans.push((
+                offset(range.start)..offset(range.end),
+                part_kind.unwrap_or(kind),
+            ));
+        }
 
-                    let text: String = chars[start_pos..pos].iter().collect();
+        if redirect_target {
+            redirect_target = false;
+        } else if !is_assignment && !COMMAND_PREFIXES.contains(&raw.as_str()) {
+            expect_command = false;
+        }
+    }
+    spans
+}
 
-                    // Determine if this is a path
-                    let token_type = if text.contains('/') {
-                        TokenType::Path
-                    } else {
-                        TokenType::Word
-                    };
+/// Scan a single-quoted string starting at `start`, returning where it ends
+fn single_quoted(
+    chars: &[(usize, char)],
+    start: usize,
+    parts: &mut Vec<(Range<usize>, Option<TokenKind>)>,
+) -> usize {
+    let end = chars[start + 1..]
+        .iter()
+        .position(|&(_, c)| c == '\'')
+        .map_or(chars.len(), |idx| start + 1 + idx + 1);
+    parts.push((start..end, Some(TokenKind::String)));
+    end
+}
 
-                    tokens.push(Token {
-                        text,
-                        range: start_byte..byte_pos,
-                        token_type,
-                    });
+/// Scan a double-quoted string, splitting out the variables inside it
+fn double_quoted(
+    chars: &[(usize, char)],
+    start: usize,
+    parts: &mut Vec<(Range<usize>, Option<TokenKind>)>,
+) -> usize {
+    let mut run_start = start;
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i].1 {
+            '\\' => i = (i + 2).min(chars.len()),
+            '"' => {
+                i += 1;
+                break;
+            }
+            '$' => {
+                if run_start < i {
+                    parts.push((run_start..i, Some(TokenKind::String)));
                 }
+                i = variable(chars, i, parts);
+                run_start = i;
             }
+            _ => i += 1,
         }
-
-        tokens
     }
+    if run_start < i {
+        parts.push((run_start..i, Some(TokenKind::String)));
+    }
+    i
+}
 
-    /// Apply styles to tokens
-    fn style_tokens(&self, tokens: &[Token]) -> Vec<HighlightedSpan> {
-        let mut spans = Vec::new();
-        let mut is_first_word = true;
-        let mut after_operator = false;
-
-        for token in tokens {
-            let style = match &token.token_type {
-                TokenType::Whitespace => HighlightStyle::Default,
-                TokenType::Comment => HighlightStyle::Comment,
-                TokenType::String(_) => HighlightStyle::String,
-                TokenType::Variable => HighlightStyle::Variable,
-                TokenType::Operator => {
-                    after_operator = true;
-                    HighlightStyle::Operator
+/// Scan `$NAME`, `${...}`, `$(...)` or a special parameter like `$?`
+fn variable(
+    chars: &[(usize, char)],
+    start: usize,
+    parts: &mut Vec<(Range<usize>, Option<TokenKind>)>,
+) -> usize {
+    let mut i = start + 1;
+    match chars.get(i).map(|&(_, c)| c) {
+        Some('{') => {
+            i = chars[i..]
+                .iter()
+                .position(|&(_, c)| c == '}')
+                .map_or(chars.len(), |idx| i + idx + 1);
+        }
+        Some('(') => {
+            let mut depth = 0;
+            while i < chars.len() {
+                match chars[i].1 {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
                 }
-                TokenType::Flag => HighlightStyle::Flag,
-                TokenType::Path => HighlightStyle::Path,
-                TokenType::Word => {
-                    let word = &token.text;
-
-                    // Check if it's a keyword
-                    if KEYWORDS.contains(&word.as_str()) {
-                        HighlightStyle::Keyword
-                    } else if is_first_word || after_operator {
-                        // First word or after operator is a command
-                        is_first_word = false;
-                        after_operator = false;
-
-                        // Check if it's a known command
-                        if self.is_command(word) {
-                            HighlightStyle::Command
-                        } else {
-                            HighlightStyle::Command // Assume it's a command anyway
-                        }
-                    } else {
-                        HighlightStyle::Argument
-                    }
+                i += 1;
+                if depth == 0 {
+                    break;
                 }
-            };
-
-            // Update first_word tracking
-            if token.token_type != TokenType::Whitespace && token.token_type != TokenType::Operator
-            {
-                is_first_word = false;
             }
-
-            spans.push(HighlightedSpan {
-                text: token.text.clone(),
-                range: token.range.clone(),
-                style,
-            });
         }
-
-        spans
-    }
-
-    /// Check if a word is a known command
-    fn is_command(&self, word: &str) -> bool {
-        self.cached_commands.iter().any(|cmd| cmd == word)
-    }
-
-    /// Refresh the cached commands from PATH
-    pub fn refresh_commands(&mut self) {
-        // In a full implementation, this would scan PATH directories
-        // For now, we just use the common commands list
-        self.cached_commands = COMMON_COMMANDS.iter().map(|s| s.to_string()).collect();
-    }
-
-    /// Add a command to the cache
-    pub fn add_command(&mut self, cmd: &str) {
-        if !self.cached_commands.contains(&cmd.to_string()) {
-            self.cached_commands.push(cmd.to_string());
+        Some(c) if "?!#$@*-0123456789".contains(c) => i += 1,
+        _ => {
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '_') {
+                i += 1;
+            }
         }
     }
+    // A lone '$' is just a char
+    let kind = if i == start + 1 {
+        None
+    } else {
+        Some(TokenKind::Variable)
+    };
+    parts.push((start..i, kind));
+    i
 }
 
 /// Convert highlight style to RGB color
@@ -608,4 +466,106 @@ mod tests {
 
         assert!(!comment_spans.is_empty());
     }
+
+    #[test]
+    fn test_highlight_follows_line_tokens() {
+        let highlighter = SyntaxHighlighter::new();
+        let text = "if true; then FOO=1 make; fi\necho a\\ b > out.log  ";
+        let spans = highlighter.highlight(text);
+        let joined: String = spans.iter().map(|span| span.text.as_str()).collect();
+        assert_eq!(joined, text);
+        for span in &spans {
+            assert_eq!(&text[span.range.clone()], span.text);
+        }
+
+        let styled: Vec<(&str, HighlightStyle)> = spans
+            .iter()
+            .filter(|span| span.style != HighlightStyle::Default)
+            .map(|span| (span.text.as_str(), span.style))
+            .collect();
+        assert_eq!(
+            styled,
+            vec![
+                ("if", HighlightStyle::Keyword),
+                ("true", HighlightStyle::Command),
+                (";", HighlightStyle::Operator),
+                ("then", HighlightStyle::Keyword),
+                ("FOO=1", HighlightStyle::Argument),
+                ("make", HighlightStyle::Command),
+                (";", HighlightStyle::Operator),
+                ("fi", HighlightStyle::Keyword),
+                ("echo", HighlightStyle::Command),
+                ("a\\ b", HighlightStyle::Argument),
+                (">", HighlightStyle::Operator),
+                ("out.log", HighlightStyle::Path),
+            ]
+        );
+    }
+
+    fn kinds(line: &str) -> Vec<(&str, TokenKind)> {
+        highlight_line(line)
+            .into_iter()
+            .map(|(range, kind)| (&line[range], kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_highlight_line_pipeline() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds(r#"grep -r "foo $BAR" ./src | wc -l # count"#),
+            vec![
+                ("grep", Command),
+                ("-r", Flag),
+                ("\"foo ", String),
+                ("$BAR", Variable),
+                ("\"", String),
+                ("./src", Path),
+                ("|", Operator),
+                ("wc", Command),
+                ("-l", Flag),
+                ("# count", Comment),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_highlight_line_quotes_and_escapes() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds(r"echo a\ b 'it''s' x#y && FOO=1 make > out.log"),
+            vec![
+                ("echo", Command),
+                (r"a\ b", Argument),
+                ("'it'", String),
+                ("'s'", String),
+                ("x#y", Argument),
+                ("&&", Operator),
+                ("FOO=1", Argument),
+                ("make", Command),
+                (">", Operator),
+                ("out.log", Path),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_highlight_line_unterminated_multibyte() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("echo \"héllo ${USÉR} wörld"),
+            vec![
+                ("echo", Command),
+                ("\"héllo ", String),
+                ("${USÉR}", Variable),
+                (" wörld", String),
+            ]
+        );
+        assert_eq!(
+            kinds("ls 'ünterminated"),
+            vec![("ls", Command), ("'ünterminated", String)]
+        );
+        assert_eq!(kinds("echo $"), vec![("echo", Command), ("$", Argument)]);
+        assert_eq!(kinds("x \\"), vec![("x", Command), ("\\", Argument)]);
+    }
 }
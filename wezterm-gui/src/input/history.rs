@@ -0,0 +1,216 @@
+//! Walking through command history from the input editor
+//!
+//! EditorHistoryCursor remembers the half-typed command when browsing
+//! starts and hands it back when the user walks past the newest entry.
+
+use crate::input::editor::Editor;
+
+/// Position in a history list plus the draft it was entered from
+///
+/// History slices are newest first, matching ModernInput's history.
+#[derive(Debug, Clone, Default)]
+pub struct EditorHistoryCursor {
+    /// Text being edited when browsing started
+    draft: Option<String>,
+    /// Index of the entry being shown
+    index: Option<usize>,
+    /// Only visit entries starting with the draft, like zsh's
+    /// history-beginning-search
+    prefix_search: bool,
+}
+
+impl EditorHistoryCursor {
+    /// Create a cursor that visits every entry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only visit entries that start with the text typed before browsing
+    pub fn set_prefix_search(&mut self, prefix_search: bool) {
+        self.prefix_search = prefix_search;
+    }
+
+    /// Check whether prefix filtering is on
+    pub fn prefix_search(&self) -> bool {
+        self.prefix_search
+    }
+
+    /// Check whether a history entry is being shown instead of the draft
+    pub fn is_browsing(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Index of the entry being shown
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Forget the draft and go back to not browsing
+    pub fn reset(&mut self) {
+        self.draft = None;
+        self.index = None;
+    }
+
+    /// Step to the next older entry, returning the text to show
+    ///
+    /// `current` is the editor's text, saved as the draft when browsing
+    /// starts.  Returns None if there is no older matching entry.
+    pub fn previous(&mut self, history: &[String], current: &str) -> Option<String> {
+        let draft = self.draft.as_deref().unwrap_or(current);
+        let start = self.index.map_or(0, |idx| idx + 1);
+        let shown = self
+            .index
+            .and_then(|idx| history.get(idx))
+            .map(String::as_str);
+        let found = (start..history.len()).find(|&idx| {
+            let entry = history[idx].as_str();
+            self.matches(entry, draft) && Some(entry) != shown && entry != current
+        })?;
+
+        if self.draft.is_none() {
+            self.draft = Some(current.to_string());
+        }
+        self.index = Some(found);
+        Some(history[found].clone())
+    }
+
+    /// Step to the next newer entry, returning the text to show
+    ///
+    /// Walking past the newest entry returns the draft and stops browsing.
+    /// Returns None when not browsing.
+    pub fn next(&mut self, history: &[String]) -> Option<String> {
+        let idx = self.index?;
+        let draft = self.draft.clone().unwrap_or_default();
+        let shown = history.get(idx).map(String::as_str);
+        let found = (0..idx.min(history.len())).rev().find(|&i| {
+            let entry = history[i].as_str();
+            self.matches(entry, &draft) && Some(entry) != shown
+        });
+
+        match found {
+            Some(found) => {
+                self.index = Some(found);
+                Some(history[found].clone())
+            }
+            None => {
+                self.reset();
+                Some(draft)
+            }
+        }
+    }
+
+    /// Show the next older entry in `editor`, returning false if there is none
+    ///
+    /// The swap shares one undo entry with any swaps right before it, and
+    /// the cursor goes to the end of the text.
+    pub fn previous_into(&mut self, editor: &mut Editor, history: &[String]) -> bool {
+        let current = editor.full_text();
+        match self.previous(history, &current) {
+            Some(text) => {
+                show(editor, &text);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show the next newer entry, or the draft, in `editor`
+    pub fn next_into(&mut self, editor: &mut Editor, history: &[String]) -> bool {
+        match self.next(history) {
+            Some(text) => {
+                show(editor, &text);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn matches(&self, entry: &str, draft: &str) -> bool {
+        !self.prefix_search || entry.starts_with(draft)
+    }
+}
+
+fn show(editor: &mut Editor, text: &str) {
+    editor.set_text_merging_undo(text);
+    let last = editor.line_count() - 1;
+    editor.set_cursor_coords(last, usize::MAX);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> Vec<String> {
+        [
+            "git push",
+            "ls -la",
+            "git commit -m wip",
+            "git status",
+            "ls -la",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_draft_is_restored() {
+        let history = history();
+        let mut editor = Editor::new();
+        editor.insert_str("echo half");
+        let mut cursor = EditorHistoryCursor::new();
+
+        assert!(cursor.previous_into(&mut editor, &history));
+        assert_eq!(editor.full_text(), "git push");
+        assert!(cursor.previous_into(&mut editor, &history));
+        assert!(cursor.previous_into(&mut editor, &history));
+        assert_eq!(editor.full_text(), "git commit -m wip");
+        assert_eq!(editor.cursor_coords(), (0, 17));
+
+        assert!(cursor.next_into(&mut editor, &history));
+        assert!(cursor.next_into(&mut editor, &history));
+        assert!(cursor.next_into(&mut editor, &history));
+        assert_eq!(editor.full_text(), "echo half");
+        assert!(!cursor.is_browsing());
+        assert!(!cursor.next_into(&mut editor, &history));
+
+        // All that browsing is a single undo step
+        assert!(editor.undo());
+        assert_eq!(editor.full_text(), "echo half");
+        assert!(editor.undo());
+        assert_eq!(editor.full_text(), "");
+    }
+
+    #[test]
+    fn test_prefix_filtering() {
+        let history = history();
+        let mut cursor = EditorHistoryCursor::new();
+        cursor.set_prefix_search(true);
+
+        assert_eq!(cursor.previous(&history, "git"), Some("git push".into()));
+        assert_eq!(
+            cursor.previous(&history, "git push"),
+            Some("git commit -m wip".into())
+        );
+        assert_eq!(
+            cursor.previous(&history, "git commit -m wip"),
+            Some("git status".into())
+        );
+        assert_eq!(cursor.previous(&history, "git status"), None);
+        assert_eq!(cursor.index(), Some(3));
+
+        assert_eq!(cursor.next(&history), Some("git commit -m wip".into()));
+        assert_eq!(cursor.next(&history), Some("git push".into()));
+        assert_eq!(cursor.next(&history), Some("git".into()));
+        assert!(!cursor.is_browsing());
+    }
+
+    #[test]
+    fn test_duplicates_are_skipped() {
+        let history = history();
+        let mut cursor = EditorHistoryCursor::new();
+        cursor.set_prefix_search(true);
+        assert_eq!(cursor.previous(&history, "ls"), Some("ls -la".into()));
+        assert_eq!(cursor.previous(&history, "ls -la"), None);
+    }
+}
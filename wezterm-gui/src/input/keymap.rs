@@ -0,0 +1,883 @@
+//! Rebindable key bindings for the input editor
+//!
+//! A Keymap maps a key and its modifiers to an EditorCommand, which
+//! Editor::execute carries out, or to a nested Keymap for chords such as
+//! Ctrl+X Ctrl+E.  KeyDispatcher tracks a chord while it is being typed.
+//! The defaults follow readline's emacs and vi insert modes; user config
+//! overrides them binding by binding.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use termwiz::input::{KeyCode, Modifiers};
+
+/// How long a chord waits for its next key by default
+const DEFAULT_CHORD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An editor operation that a key can be bound to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EditorCommand {
+    InsertChar(char),
+    /// Insert text as if typed
+    InsertText(String),
+    InsertNewline,
+    Backspace,
+    Delete,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    MoveWordLeft,
+    MoveWordRight,
+    MoveToLineStart,
+    /// First non-whitespace char, then column 0 on a second press
+    MoveToLineStartSmart,
+    MoveToLineEnd,
+    MoveToBufferStart,
+    MoveToBufferEnd,
+    MoveParagraphForward,
+    MoveParagraphBackward,
+    /// Move by soft-wrapped rows, at the width given to
+    /// Editor::set_view_width; by lines until one is given
+    MoveUpVisual,
+    MoveDownVisual,
+    MoveToVisualLineStart,
+    MoveToVisualLineEnd,
+    /// Move to the next occurrence of the text, pushing a mark
+    MoveToNextMatch(String),
+    /// Select the blank-line-delimited block around the cursor
+    SelectParagraph,
+    /// The moves above, extending the selection
+    SelectLeft,
+    SelectRight,
+    SelectUp,
+    SelectDown,
+    SelectWordLeft,
+    SelectWordRight,
+    SelectToLineStart,
+    SelectToLineStartSmart,
+    SelectToLineEnd,
+    SelectToBufferStart,
+    SelectToBufferEnd,
+    SelectUpVisual,
+    SelectDownVisual,
+    KillToLineEnd,
+    KillToLineStart,
+    KillWordBackward,
+    KillWordForward,
+    KillToBufferEnd,
+    KillToBufferStart,
+    KillWholeBuffer,
+    Yank,
+    /// Yank a kill ring entry, indexed as in Editor::kill_ring_entries
+    YankNth(usize),
+    Undo,
+    Redo,
+    SelectAll,
+    StartSelection,
+    /// Start a rectangular selection
+    StartBlockSelection,
+    DeleteSelection,
+    /// Copy, cut and paste use the clipboard given to Editor::set_clipboard
+    Copy,
+    Cut,
+    Paste,
+    DuplicateLine,
+    MoveLineUp,
+    MoveLineDown,
+    DeleteLine,
+    JoinLines,
+    /// Toggle a line comment with the given prefix, such as "#"
+    ToggleComment(String),
+    TrimTrailingWhitespace,
+    /// Re-wrap the paragraph around the cursor to the given width
+    FillParagraph(usize),
+    NormalizeBuffer,
+    ReplaceAll {
+        query: String,
+        replacement: String,
+    },
+    /// Expand the abbreviation before the cursor
+    ExpandAbbreviation,
+    NextPlaceholder,
+    PrevPlaceholder,
+    PushMark,
+    PopMarkAndJump,
+    ExchangePointAndMark,
+    AddCursorAbove,
+    AddCursorBelow,
+    ClearExtraCursors,
+    Clear,
+    StartMacroRecording,
+    StopMacroRecording,
+    /// Play the last recorded macro once
+    PlayMacro,
+    /// Open the input in $VISUAL or $EDITOR; run by the input host, as
+    /// Editor::execute can't start programs
+    EditInExternalEditor,
+}
+
+impl EditorCommand {
+    /// Whether the command can change the text
+    pub fn is_edit(&self) -> bool {
+        matches!(
+            self,
+            Self::InsertChar(_)
+                | Self::InsertText(_)
+                | Self::InsertNewline
+                | Self::Backspace
+                | Self::Delete
+                | Self::KillToLineEnd
+                | Self::KillToLineStart
+                | Self::KillWordBackward
+                | Self::KillWordForward
+                | Self::KillToBufferEnd
+                | Self::KillToBufferStart
+                | Self::KillWholeBuffer
+                | Self::Yank
+                | Self::YankNth(_)
+                | Self::DeleteSelection
+                | Self::Cut
+                | Self::Paste
+                | Self::Undo
+                | Self::Redo
+                | Self::DuplicateLine
+                | Self::MoveLineUp
+                | Self::MoveLineDown
+                | Self::DeleteLine
+                | Self::JoinLines
+                | Self::ToggleComment(_)
+                | Self::TrimTrailingWhitespace
+                | Self::FillParagraph(_)
+                | Self::NormalizeBuffer
+                | Self::ReplaceAll { .. }
+                | Self::ExpandAbbreviation
+                | Self::Clear
+                | Self::PlayMacro
+        )
+    }
+
+    /// Whether the command starts, stops or plays a macro
+    ///
+    /// These are left out of macros being recorded.
+    pub fn is_macro_control(&self) -> bool {
+        matches!(
+            self,
+            Self::StartMacroRecording | Self::StopMacroRecording | Self::PlayMacro
+        )
+    }
+
+    /// Whether doing nothing means the command ran into the buffer's edge
+    pub fn fails_at_boundary(&self) -> bool {
+        matches!(
+            self,
+            Self::MoveLeft
+                | Self::MoveRight
+                | Self::MoveUp
+                | Self::MoveDown
+                | Self::MoveWordLeft
+                | Self::MoveWordRight
+                | Self::MoveUpVisual
+                | Self::MoveDownVisual
+                | Self::SelectLeft
+                | Self::SelectRight
+                | Self::SelectUp
+                | Self::SelectDown
+                | Self::SelectWordLeft
+                | Self::SelectWordRight
+                | Self::SelectUpVisual
+                | Self::SelectDownVisual
+                | Self::Backspace
+                | Self::Delete
+        )
+    }
+}
+
+/// Error from reading key bindings out of user config
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum KeymapError {
+    #[error("Invalid key binding '{0}'")]
+    InvalidKey(String),
+}
+
+/// Key bindings as written in user config
+///
+/// Keys are written like `ctrl+a`, `alt+shift+left` or `cmd+z`, and
+/// chords as keys separated by spaces, like `ctrl+x ctrl+e`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeymapConfig {
+    /// Keys to bind, replacing any existing binding
+    #[serde(default)]
+    pub bindings: BTreeMap<String, EditorCommand>,
+    /// Keys to unbind
+    #[serde(default)]
+    pub unbind: Vec<String>,
+}
+
+/// What a key is bound to
+#[derive(Debug, Clone)]
+pub enum Binding {
+    Command(EditorCommand),
+    /// First key of a chord; the next key is looked up in this map
+    Prefix(Keymap),
+}
+
+/// Map from keys to editor commands
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    bindings: HashMap<(Modifiers, KeyCode), Binding>,
+}
+
+impl Keymap {
+    /// Create a keymap with no bindings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Readline-style emacs bindings
+    pub fn emacs_default() -> Self {
+        use EditorCommand::*;
+        let mut keymap = Self::with_common_bindings();
+        let ctrl = Modifiers::CTRL;
+        let alt = Modifiers::ALT;
+        for (mods, c, command) in [
+            (ctrl, 'a', MoveToLineStart),
+            (ctrl, 'e', MoveToLineEnd),
+            (ctrl, 'b', MoveLeft),
+            (ctrl, 'f', MoveRight),
+            (ctrl, 'p', MoveUp),
+            (ctrl, 'n', MoveDown),
+            (ctrl, 'h', Backspace),
+            (ctrl, 'd', Delete),
+            (ctrl, 'k', KillToLineEnd),
+            (ctrl, 'u', KillToLineStart),
+            (ctrl, 'w', KillWordBackward),
+            (ctrl, 'y', Yank),
+            (ctrl, '_', Undo),
+            (ctrl, 'z', Undo),
+            (ctrl | Modifiers::SHIFT, 'Z', Redo),
+            (ctrl, ' ', PushMark),
+            (alt, 'd', KillWordForward),
+            (alt, 'w', Copy),
+            (alt, 'b', MoveWordLeft),
+            (alt, 'f', MoveWordRight),
+            (alt, '<', MoveToBufferStart),
+            (alt, '>', MoveToBufferEnd),
+            (alt, '{', MoveParagraphBackward),
+            (alt, '}', MoveParagraphForward),
+        ] {
+            keymap.bind(mods, KeyCode::Char(c), command);
+        }
+
+        let mut ctrl_x = Self::new();
+        for (mods, c, command) in [
+            (ctrl, 'e', EditInExternalEditor),
+            (ctrl, 'u', Undo),
+            (Modifiers::NONE, 'u', Undo),
+            (Modifiers::NONE, 'h', SelectAll),
+            (ctrl, 'x', ExchangePointAndMark),
+            (Modifiers::NONE, '(', StartMacroRecording),
+            (Modifiers::NONE, ')', StopMacroRecording),
+            (Modifiers::NONE, 'e', PlayMacro),
+        ] {
+            ctrl_x.bind(mods, KeyCode::Char(c), command);
+        }
+        keymap.bind_prefix(ctrl, KeyCode::Char('x'), ctrl_x);
+        keymap
+    }
+
+    /// Readline-style vi insert mode bindings
+    pub fn vi_insert_default() -> Self {
+        use EditorCommand::*;
+        let mut keymap = Self::with_common_bindings();
+        let ctrl = Modifiers::CTRL;
+        for (c, command) in [
+            ('h', Backspace),
+            ('w', KillWordBackward),
+            ('u', KillToLineStart),
+            ('y', Yank),
+        ] {
+            keymap.bind(ctrl, KeyCode::Char(c), command);
+        }
+        keymap
+    }
+
+    /// Bindings for the arrow and editing keys that every mode shares
+    fn with_common_bindings() -> Self {
+        use EditorCommand::*;
+        let mut keymap = Self::new();
+        let none = Modifiers::NONE;
+        let ctrl = Modifiers::CTRL;
+        let shift = Modifiers::SHIFT;
+        for (mods, key, command) in [
+            (none, KeyCode::Backspace, Backspace),
+            (none, KeyCode::Delete, Delete),
+            (none, KeyCode::LeftArrow, MoveLeft),
+            (none, KeyCode::RightArrow, MoveRight),
+            (ctrl, KeyCode::LeftArrow, MoveWordLeft),
+            (ctrl, KeyCode::RightArrow, MoveWordRight),
+            (none, KeyCode::Home, MoveToLineStartSmart),
+            (none, KeyCode::End, MoveToLineEnd),
+            (ctrl, KeyCode::Home, MoveToBufferStart),
+            (ctrl, KeyCode::End, MoveToBufferEnd),
+            (shift, KeyCode::LeftArrow, SelectLeft),
+            (shift, KeyCode::RightArrow, SelectRight),
+            (shift, KeyCode::UpArrow, SelectUp),
+            (shift, KeyCode::DownArrow, SelectDown),
+            (ctrl | shift, KeyCode::LeftArrow, SelectWordLeft),
+            (ctrl | shift, KeyCode::RightArrow, SelectWordRight),
+            (shift, KeyCode::Home, SelectToLineStartSmart),
+            (shift, KeyCode::End, SelectToLineEnd),
+            (ctrl | shift, KeyCode::Home, SelectToBufferStart),
+            (ctrl | shift, KeyCode::End, SelectToBufferEnd),
+        ] {
+            keymap.bind(mods, key, command);
+        }
+        keymap
+    }
+
+    /// Bind a key, replacing any existing binding
+    pub fn bind(&mut self, mods: Modifiers, key: KeyCode, command: EditorCommand) {
+        self.bindings.insert((mods, key), Binding::Command(command));
+    }
+
+    /// Make a key the start of chords, with `keymap` binding the next key
+    pub fn bind_prefix(&mut self, mods: Modifiers, key: KeyCode, keymap: Keymap) {
+        self.bindings.insert((mods, key), Binding::Prefix(keymap));
+    }
+
+    /// Bind a sequence of keys, adding prefix maps as needed
+    ///
+    /// A command bound to one of the leading keys is replaced.
+    pub fn bind_sequence(&mut self, keys: &[(Modifiers, KeyCode)], command: EditorCommand) {
+        let ((mods, key), leading) = match keys.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+        let mut keymap = self;
+        for (mods, key) in leading {
+            let binding = keymap
+                .bindings
+                .entry((*mods, *key))
+                .or_insert_with(|| Binding::Prefix(Keymap::new()));
+            if let Binding::Command(_) = binding {
+                *binding = Binding::Prefix(Keymap::new());
+            }
+            keymap = match binding {
+                Binding::Prefix(inner) => inner,
+                Binding::Command(_) => unreachable!(),
+            };
+        }
+        keymap.bind(*mods, *key, command);
+    }
+
+    /// Remove a key's binding, returning the command it ran
+    pub fn unbind(&mut self, mods: Modifiers, key: KeyCode) -> Option<EditorCommand> {
+        match self.bindings.remove(&(mods, key))? {
+            Binding::Command(command) => Some(command),
+            Binding::Prefix(_) => None,
+        }
+    }
+
+    /// Remove the binding of a sequence of keys
+    pub fn unbind_sequence(&mut self, keys: &[(Modifiers, KeyCode)]) {
+        let ((mods, key), leading) = match keys.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+        let mut keymap = self;
+        for (mods, key) in leading {
+            keymap = match keymap.bindings.get_mut(&(*mods, *key)) {
+                Some(Binding::Prefix(inner)) => inner,
+                _ => return,
+            };
+        }
+        keymap.bindings.remove(&(*mods, *key));
+    }
+
+    /// Get the command bound to a key
+    pub fn lookup(&self, mods: Modifiers, key: KeyCode) -> Option<&EditorCommand> {
+        match self.binding(mods, key)? {
+            Binding::Command(command) => Some(command),
+            Binding::Prefix(_) => None,
+        }
+    }
+
+    /// Get whatever a key is bound to
+    pub fn binding(&self, mods: Modifiers, key: KeyCode) -> Option<&Binding> {
+        self.bindings.get(&(mods, key))
+    }
+
+    /// Command for a key press
+    ///
+    /// Unbound chars typed without Ctrl, Alt or Super insert themselves.
+    pub fn command_for(&self, mods: Modifiers, key: KeyCode) -> Option<EditorCommand> {
+        if let Some(command) = self.lookup(mods, key) {
+            return Some(command.clone());
+        }
+        match key {
+            KeyCode::Char(c) if (mods - Modifiers::SHIFT).is_empty() => {
+                Some(EditorCommand::InsertChar(c))
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply bindings from user config on top of this keymap
+    ///
+    /// Nothing is changed if any key fails to parse.
+    pub fn apply_config(&mut self, config: &KeymapConfig) -> Result<(), KeymapError> {
+        let unbind = config
+            .unbind
+            .iter()
+            .map(|keys| parse_key_sequence(keys))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bindings = config
+            .bindings
+            .iter()
+            .map(|(keys, command)| parse_key_sequence(keys).map(|keys| (keys, command.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for keys in unbind {
+            self.unbind_sequence(&keys);
+        }
+        for (keys, command) in bindings {
+            self.bind_sequence(&keys, command);
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a key press fed to a KeyDispatcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Run this command
+    Command(EditorCommand),
+    /// The key started or continued a chord
+    Pending,
+    /// A chord was cancelled by Escape, a timeout or a key it doesn't bind
+    Cancelled,
+    /// The key isn't bound
+    Unbound,
+}
+
+/// Looks keys up in a keymap, following chords across key presses
+#[derive(Debug, Clone)]
+pub struct KeyDispatcher {
+    /// Keys of the chord typed so far
+    pending: Vec<(Modifiers, KeyCode)>,
+    /// When the last key of the pending chord was typed
+    pending_since: Option<Instant>,
+    /// How long a chord waits for its next key
+    timeout: Duration,
+    /// Whether a key a chord doesn't bind is looked up again on its own
+    redispatch_unknown: bool,
+}
+
+impl Default for KeyDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyDispatcher {
+    /// Create a dispatcher with no chord pending
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_since: None,
+            timeout: DEFAULT_CHORD_TIMEOUT,
+            redispatch_unknown: false,
+        }
+    }
+
+    /// Set how long a chord waits for its next key
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set whether a key a pending chord doesn't bind is looked up again
+    /// on its own, rather than only cancelling the chord
+    pub fn set_redispatch_unknown(&mut self, redispatch: bool) {
+        self.redispatch_unknown = redispatch;
+    }
+
+    /// Check if a chord is part way typed
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The chord typed so far, as emacs shows it: "C-x -"
+    pub fn pending_prefix(&self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let mut prefix: Vec<String> = self
+            .pending
+            .iter()
+            .map(|(mods, key)| describe_key(*mods, key))
+            .collect();
+        prefix.push("-".to_string());
+        Some(prefix.join(" "))
+    }
+
+    /// Forget the pending chord
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+        self.pending_since = None;
+    }
+
+    /// Look up a key press in `keymap`
+    pub fn dispatch(&mut self, keymap: &Keymap, mods: Modifiers, key: KeyCode) -> Dispatch {
+        self.dispatch_at(keymap, mods, key, Instant::now())
+    }
+
+    fn dispatch_at(
+        &mut self,
+        keymap: &Keymap,
+        mods: Modifiers,
+        key: KeyCode,
+        now: Instant,
+    ) -> Dispatch {
+        let timed_out = self
+            .pending_since
+            .is_some_and(|since| now.duration_since(since) > self.timeout);
+        if timed_out {
+            self.cancel();
+        }
+
+        if self.is_pending() {
+            if key == KeyCode::Escape && mods == Modifiers::NONE {
+                self.cancel();
+                return Dispatch::Cancelled;
+            }
+            let prefix_map = self.pending.iter().try_fold(keymap, |map, (mods, key)| {
+                match map.binding(*mods, *key) {
+                    Some(Binding::Prefix(inner)) => Some(inner),
+                    _ => None,
+                }
+            });
+            match prefix_map.and_then(|map| map.binding(mods, key)) {
+                Some(Binding::Command(command)) => {
+                    self.cancel();
+                    return Dispatch::Command(command.clone());
+                }
+                Some(Binding::Prefix(_)) => {
+                    self.pending.push((mods, key));
+                    self.pending_since = Some(now);
+                    return Dispatch::Pending;
+                }
+                None => {
+                    self.cancel();
+                    if !self.redispatch_unknown {
+                        return Dispatch::Cancelled;
+                    }
+                }
+            }
+        }
+
+        match keymap.binding(mods, key) {
+            Some(Binding::Prefix(_)) => {
+                self.pending.push((mods, key));
+                self.pending_since = Some(now);
+                Dispatch::Pending
+            }
+            _ => keymap
+                .command_for(mods, key)
+                .map_or(Dispatch::Unbound, Dispatch::Command),
+        }
+    }
+}
+
+/// Write a key the way emacs does, like "C-x" or "M-S-<left>"
+fn describe_key(mods: Modifiers, key: &KeyCode) -> String {
+    let mut text = String::new();
+    for (modifier, name) in [
+        (Modifiers::CTRL, "C-"),
+        (Modifiers::ALT, "M-"),
+        (Modifiers::SUPER, "s-"),
+    ] {
+        if mods.contains(modifier) {
+            text.push_str(name);
+        }
+    }
+    match key {
+        // Shift is already in the char
+        KeyCode::Char(' ') => text.push_str("SPC"),
+        KeyCode::Char(c) => text.push(*c),
+        key => {
+            if mods.contains(Modifiers::SHIFT) {
+                text.push_str("S-");
+            }
+            match key {
+                KeyCode::Enter => text.push_str("RET"),
+                KeyCode::Tab => text.push_str("TAB"),
+                KeyCode::Escape => text.push_str("ESC"),
+                KeyCode::Backspace => text.push_str("DEL"),
+                key => text.push_str(&format!("<{:?}>", key).to_lowercase()),
+            }
+        }
+    }
+    text
+}
+
+/// Parse keys separated by spaces, like `ctrl+x ctrl+e`
+pub fn parse_key_sequence(spec: &str) -> Result<Vec<(Modifiers, KeyCode)>, KeymapError> {
+    let keys = spec
+        .split_whitespace()
+        .map(parse_key)
+        .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err(KeymapError::InvalidKey(spec.to_string()));
+    }
+    Ok(keys)
+}
+
+/// Parse a key like `ctrl+shift+z` into its modifiers and key code
+pub fn parse_key(spec: &str) -> Result<(Modifiers, KeyCode), KeymapError> {
+    let invalid = || KeymapError::InvalidKey(spec.to_string());
+    // A trailing "+" is the plus key itself, as in "ctrl++"
+    let (mods_part, key_part) = match spec.strip_suffix("++") {
+        Some(mods) => (Some(mods), "+"),
+        None => match spec.rsplit_once('+') {
+            Some((mods, key)) => (Some(mods), key),
+            None => (None, spec),
+        },
+    };
+
+    let mut mods = Modifiers::NONE;
+    for name in mods_part.into_iter().flat_map(|m| m.split('+')) {
+        mods |= match name.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => Modifiers::CTRL,
+            "alt" | "meta" | "opt" | "option" => Modifiers::ALT,
+            "shift" => Modifiers::SHIFT,
+            "super" | "cmd" | "win" => Modifiers::SUPER,
+            _ => return Err(invalid()),
+        };
+    }
+
+    let mut chars = key_part.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c),
+        _ => match key_part.to_ascii_lowercase().as_str() {
+            "space" => KeyCode::Char(' '),
+            "enter" | "return" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "escape" | "esc" => KeyCode::Escape,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "left" => KeyCode::LeftArrow,
+            "right" => KeyCode::RightArrow,
+            "up" => KeyCode::UpArrow,
+            "down" => KeyCode::DownArrow,
+            "insert" => KeyCode::Insert,
+            _ => return Err(invalid()),
+        },
+    };
+    Ok((mods, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::editor::Editor;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("ctrl+a"),
+            Ok((Modifiers::CTRL, KeyCode::Char('a')))
+        );
+        assert_eq!(
+            parse_key("Alt+Shift+Left"),
+            Ok((Modifiers::ALT | Modifiers::SHIFT, KeyCode::LeftArrow))
+        );
+        assert_eq!(
+            parse_key("cmd++"),
+            Ok((Modifiers::SUPER, KeyCode::Char('+')))
+        );
+        assert_eq!(parse_key("home"), Ok((Modifiers::NONE, KeyCode::Home)));
+        assert!(parse_key("hyper+x").is_err());
+        assert!(parse_key("ctrl+nope").is_err());
+    }
+
+    #[test]
+    fn test_custom_map_swaps_line_start_and_end() {
+        let config: KeymapConfig = serde_json::from_str(
+            r#"{
+                "bindings": {
+                    "ctrl+a": "move_to_line_end",
+                    "ctrl+e": "move_to_line_start",
+                    "cmd+z": "undo"
+                },
+                "unbind": ["ctrl+w"]
+            }"#,
+        )
+        .unwrap();
+        let mut keymap = Keymap::emacs_default();
+        keymap.apply_config(&config).unwrap();
+        assert_eq!(
+            keymap.lookup(Modifiers::SUPER, KeyCode::Char('z')),
+            Some(&EditorCommand::Undo)
+        );
+        assert_eq!(keymap.lookup(Modifiers::CTRL, KeyCode::Char('w')), None);
+
+        let mut editor = Editor::new();
+        editor.set_text("echo hi");
+        editor.set_cursor_coords(0, 2);
+        let command = keymap.command_for(Modifiers::CTRL, KeyCode::Char('a'));
+        assert!(editor.execute(command.unwrap()));
+        assert_eq!(editor.cursor_coords(), (0, 7));
+        let command = keymap.command_for(Modifiers::CTRL, KeyCode::Char('e'));
+        assert!(editor.execute(command.unwrap()));
+        assert_eq!(editor.cursor_coords(), (0, 0));
+
+        let command = keymap.command_for(Modifiers::SHIFT, KeyCode::Char('X'));
+        assert_eq!(command, Some(EditorCommand::InsertChar('X')));
+        assert!(editor.execute(command.unwrap()));
+        assert_eq!(editor.text(), "Xecho hi");
+    }
+
+    #[test]
+    fn test_shift_arrows_select() {
+        let shift = Modifiers::SHIFT;
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        for keymap in [Keymap::emacs_default(), Keymap::vi_insert_default()] {
+            let mut editor = Editor::new();
+            editor.set_text("echo hello world");
+            editor.set_cursor_coords(0, 5);
+            for (mods, key) in [
+                (shift, KeyCode::RightArrow),
+                (shift, KeyCode::RightArrow),
+                (ctrl_shift, KeyCode::RightArrow),
+            ] {
+                let command = keymap.command_for(mods, key).unwrap();
+                assert!(editor.execute(command));
+            }
+            assert_eq!(editor.selected_text().as_deref(), Some("hello "));
+
+            let command = keymap.command_for(shift, KeyCode::Home).unwrap();
+            assert!(editor.execute(command));
+            assert_eq!(editor.selected_text().as_deref(), Some("echo "));
+            let command = keymap.command_for(Modifiers::NONE, KeyCode::End).unwrap();
+            assert!(editor.execute(command));
+            assert_eq!(editor.selection(), None);
+        }
+    }
+
+    #[test]
+    fn test_chords_through_dispatcher() {
+        let keymap = Keymap::emacs_default();
+        let mut keys = KeyDispatcher::new();
+        let ctrl = Modifiers::CTRL;
+        let none = Modifiers::NONE;
+
+        assert_eq!(
+            keys.dispatch(&keymap, ctrl, KeyCode::Char('x')),
+            Dispatch::Pending
+        );
+        assert_eq!(keys.pending_prefix().as_deref(), Some("C-x -"));
+        assert_eq!(
+            keys.dispatch(&keymap, ctrl, KeyCode::Char('e')),
+            Dispatch::Command(EditorCommand::EditInExternalEditor)
+        );
+        assert_eq!(keys.pending_prefix(), None);
+
+        // Ctrl+E on its own is still end of line
+        assert_eq!(
+            keys.dispatch(&keymap, ctrl, KeyCode::Char('e')),
+            Dispatch::Command(EditorCommand::MoveToLineEnd)
+        );
+        keys.dispatch(&keymap, ctrl, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, none, KeyCode::Char('u')),
+            Dispatch::Command(EditorCommand::Undo)
+        );
+    }
+
+    #[test]
+    fn test_chord_cancellation() {
+        let keymap = Keymap::emacs_default();
+        let mut keys = KeyDispatcher::new();
+        let ctrl = Modifiers::CTRL;
+        let none = Modifiers::NONE;
+
+        keys.dispatch(&keymap, ctrl, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, none, KeyCode::Escape),
+            Dispatch::Cancelled
+        );
+        assert!(!keys.is_pending());
+
+        // An unknown second key cancels, and only optionally runs alone
+        keys.dispatch(&keymap, ctrl, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, none, KeyCode::Char('q')),
+            Dispatch::Cancelled
+        );
+        keys.set_redispatch_unknown(true);
+        keys.dispatch(&keymap, ctrl, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, none, KeyCode::Char('q')),
+            Dispatch::Command(EditorCommand::InsertChar('q'))
+        );
+
+        // A chord left hanging times out
+        let start = Instant::now();
+        keys.dispatch_at(&keymap, ctrl, KeyCode::Char('x'), start);
+        let later = start + DEFAULT_CHORD_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(
+            keys.dispatch_at(&keymap, none, KeyCode::Char('u'), later),
+            Dispatch::Command(EditorCommand::InsertChar('u'))
+        );
+    }
+
+    #[test]
+    fn test_chord_from_config() {
+        let config: KeymapConfig = serde_json::from_str(
+            r#"{
+                "bindings": { "ctrl+x ctrl+k": "kill_whole_buffer" },
+                "unbind": ["ctrl+x u"]
+            }"#,
+        )
+        .unwrap();
+        let mut keymap = Keymap::emacs_default();
+        keymap.apply_config(&config).unwrap();
+
+        let mut keys = KeyDispatcher::new();
+        keys.dispatch(&keymap, Modifiers::CTRL, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, Modifiers::CTRL, KeyCode::Char('k')),
+            Dispatch::Command(EditorCommand::KillWholeBuffer)
+        );
+        keys.dispatch(&keymap, Modifiers::CTRL, KeyCode::Char('x'));
+        assert_eq!(
+            keys.dispatch(&keymap, Modifiers::NONE, KeyCode::Char('u')),
+            Dispatch::Cancelled
+        );
+    }
+
+    #[test]
+    fn test_bad_config_changes_nothing() {
+        let mut config = KeymapConfig::default();
+        config
+            .bindings
+            .insert("ctrl+a".to_string(), EditorCommand::Undo);
+        config.unbind.push("ctrl+bogus".to_string());
+
+        let mut keymap = Keymap::emacs_default();
+        assert_eq!(
+            keymap.apply_config(&config),
+            Err(KeymapError::InvalidKey("ctrl+bogus".to_string()))
+        );
+        assert_eq!(
+            keymap.lookup(Modifiers::CTRL, KeyCode::Char('a')),
+            Some(&EditorCommand::MoveToLineStart)
+        );
+    }
+}
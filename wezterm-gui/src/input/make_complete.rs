@@ -0,0 +1,325 @@
+//! Makefile target completion for make
+//!
+//! MakeCompleter reads the Makefile make would use, honouring `-f` and
+//! `-C` earlier on the line, and offers its targets along with those of
+//! the files it includes.  Only plain rules count: pattern rules, suffix
+//! rules and special targets such as .PHONY are left out, though what
+//! .PHONY lists is offered.  Files are parsed again only when they change.
+
+use crate::input::arg_complete::{argument, ArgCompleter, CompletionContext, FileCache};
+use crate::input::complete::{CompletionInfo, CompletionKind};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Makefiles make looks for, in its order
+const MAKEFILE_NAMES: &[&str] = &["GNUmakefile", "makefile", "Makefile"];
+
+/// Targets and includes of one makefile
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Makefile {
+    targets: Vec<String>,
+    includes: Vec<String>,
+}
+
+/// Check if `name` can be offered as a target
+fn is_target_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Find the targets and included files of a makefile
+fn parse_makefile(text: &str) -> Makefile {
+    let mut makefile = Makefile::default();
+    let mut seen = HashSet::new();
+    let mut in_define = false;
+    let mut line = String::new();
+    for raw in text.lines() {
+        // A trailing backslash continues the line
+        if let Some(raw) = raw.strip_suffix('\\') {
+            line.push_str(raw);
+            line.push(' ');
+            continue;
+        }
+        line.push_str(raw);
+        let logical = std::mem::take(&mut line);
+        if logical.starts_with('\t') {
+            continue;
+        }
+        let logical = logical.split('#').next().unwrap_or("").trim();
+        let first = logical.split_whitespace().next().unwrap_or("");
+        if in_define {
+            in_define = first != "endef";
+            continue;
+        }
+        match first {
+            "define" => {
+                in_define = true;
+                continue;
+            }
+            "include" | "-include" | "sinclude" => {
+                let files = logical.split_whitespace().skip(1);
+                makefile.includes.extend(
+                    files
+                        .filter(|file| !file.contains(['$', '*', '?']))
+                        .map(String::from),
+                );
+                continue;
+            }
+            _ => {}
+        }
+
+        let (names, rest) = match logical.split_once(':') {
+            Some(rule) => rule,
+            None => continue,
+        };
+        // Assignments such as `A = x:y`, `A := x` and `A ::= x`
+        if names.contains('=') || rest.trim_start_matches(':').starts_with('=') {
+            continue;
+        }
+        let names: Vec<&str> = names.split_whitespace().collect();
+        let targets = if names == [".PHONY"] {
+            // Only up to a target-specific `;` recipe
+            let prerequisites = rest.split(';').next().unwrap_or("");
+            prerequisites.split_whitespace().collect()
+        } else {
+            names
+        };
+        for target in targets {
+            if is_target_name(target) && seen.insert(target.to_string()) {
+                makefile.targets.push(target.to_string());
+            }
+        }
+    }
+    makefile
+}
+
+/// Completes make targets from the Makefile
+#[derive(Debug, Default)]
+pub struct MakeCompleter {
+    /// Parsed makefiles
+    cache: FileCache<Makefile>,
+}
+
+impl MakeCompleter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse `path`, or reuse what it parsed to if unchanged
+    fn load(&self, path: &Path) -> Option<Arc<Makefile>> {
+        self.cache.get(path, |path| {
+            Some(parse_makefile(&fs::read_to_string(path).ok()?))
+        })
+    }
+
+    /// Targets of the makefile `file` in `dir` and of the files it includes,
+    /// each with the file it came from
+    fn targets(&self, dir: &Path, file: &str) -> Vec<(String, String)> {
+        let makefile = match self.load(&dir.join(file)) {
+            Some(makefile) => makefile,
+            None => return Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        let mut targets = Vec::new();
+        let mut add = |names: &[String], file: &str| {
+            for name in names {
+                if seen.insert(name.clone()) {
+                    targets.push((name.clone(), file.to_string()));
+                }
+            }
+        };
+        add(&makefile.targets, file);
+        // One level deep, which covers the usual shared rules file
+        for include in &makefile.includes {
+            if let Some(included) = self.load(&dir.join(include)) {
+                add(&included.targets, include);
+            }
+        }
+        targets
+    }
+}
+
+/// The directory and makefile chosen by `-C` and `-f` in `args`
+fn makefile_location(cwd: &Path, args: &[&str]) -> (PathBuf, Option<String>) {
+    let mut dir = cwd.to_path_buf();
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (option, value) = match *arg {
+            "-C" | "--directory" | "-f" | "--file" | "--makefile" => (*arg, args.next().copied()),
+            arg if arg.starts_with("--") => match arg.split_once('=') {
+                Some((option, value)) => (option, Some(value)),
+                None => continue,
+            },
+            arg if arg.starts_with("-C") || arg.starts_with("-f") => {
+                let (option, value) = arg.split_at(2);
+                (option, Some(value))
+            }
+            _ => continue,
+        };
+        match (option, value) {
+            ("-C" | "--directory", Some(value)) => dir = dir.join(value),
+            ("-f" | "--file" | "--makefile", Some(value)) => file = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (dir, file)
+}
+
+impl ArgCompleter for MakeCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        let previous = ctx.previous_arg();
+        let wants_path = matches!(
+            previous,
+            Some("-C" | "--directory" | "-f" | "--file" | "--makefile" | "-I" | "-o" | "-W")
+        );
+        // Options, their values and variable assignments aren't targets
+        if wants_path || ctx.word.starts_with('-') || ctx.word.contains('=') {
+            return None;
+        }
+        let (dir, file) = makefile_location(&ctx.cwd, &ctx.args);
+        let file = file.or_else(|| {
+            MAKEFILE_NAMES
+                .iter()
+                .find(|name| dir.join(name).is_file())
+                .map(|name| name.to_string())
+        })?;
+        let items = self
+            .targets(&dir, &file)
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ctx.word))
+            .map(|(name, file)| CompletionInfo {
+                kind: CompletionKind::Command,
+                ..argument(&name, &file, ctx.word)
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    const MAKEFILE: &str = include_str!("testdata/make/Makefile");
+    const COMMON_MK: &str = include_str!("testdata/make/common.mk");
+
+    /// A project directory holding the fixture makefiles
+    fn project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Makefile"), MAKEFILE).unwrap();
+        fs::write(dir.path().join("common.mk"), COMMON_MK).unwrap();
+        dir
+    }
+
+    fn complete(
+        completer: &MakeCompleter,
+        cwd: &Path,
+        args: &[&str],
+        word: &str,
+    ) -> Option<Vec<(String, String)>> {
+        let ctx = CompletionContext {
+            command: "make",
+            args: args.to_vec(),
+            word,
+            cwd: cwd.to_path_buf(),
+            env: HashMap::new(),
+            sudo: false,
+        };
+        let items = completer.complete(&ctx)?;
+        Some(
+            items
+                .into_iter()
+                .map(|item| (item.text, item.description.unwrap_or_default()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_fixture() {
+        let makefile = parse_makefile(MAKEFILE);
+        assert_eq!(
+            makefile.targets,
+            ["all", "clean", "install-docs", "app", "check", "test"]
+        );
+        assert_eq!(makefile.includes, ["common.mk", "missing.mk"]);
+    }
+
+    #[test]
+    fn test_targets_with_includes() {
+        let dir = project();
+        let completer = MakeCompleter::new();
+        let targets = complete(&completer, dir.path(), &[], "").unwrap();
+        let names: Vec<&str> = targets.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "all",
+                "clean",
+                "install-docs",
+                "app",
+                "check",
+                "test",
+                "lint",
+                "release"
+            ]
+        );
+        assert_eq!(targets[3].1, "Makefile");
+        assert_eq!(targets[6].1, "common.mk");
+
+        let targets = complete(&completer, dir.path(), &["-j4"], "c").unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(complete(&completer, dir.path(), &["-f"], "").is_none());
+        assert!(complete(&completer, dir.path(), &[], "--dry").is_none());
+        assert!(complete(&completer, dir.path(), &[], "CC=").is_none());
+
+        let empty = tempfile::tempdir().unwrap();
+        assert!(complete(&completer, empty.path(), &[], "").is_none());
+    }
+
+    #[test]
+    fn test_directory_and_file_options() {
+        let dir = project();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("build.mk"), "docs:\n\tmkdocs\n").unwrap();
+        let completer = MakeCompleter::new();
+        let parent = dir.path().parent().unwrap();
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+
+        let targets = complete(&completer, parent, &["-C", name], "l").unwrap();
+        assert_eq!(targets, [("lint".to_string(), "common.mk".to_string())]);
+        let directory = format!("--directory={}", name);
+        let targets = complete(&completer, parent, &[&directory, "-f", "sub/build.mk"], "");
+        assert_eq!(
+            targets.unwrap(),
+            [("docs".to_string(), "sub/build.mk".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_reparsed_when_changed() {
+        let dir = project();
+        let completer = MakeCompleter::new();
+        assert_eq!(
+            complete(&completer, dir.path(), &[], "dep").unwrap().len(),
+            0
+        );
+
+        let path = dir.path().join("Makefile");
+        fs::write(&path, format!("{}deploy: release\n", MAKEFILE)).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            complete(&completer, dir.path(), &[], "dep").unwrap().len(),
+            1
+        );
+    }
+}
@@ -9,13 +9,44 @@
 
 #![allow(dead_code)] // WIP: Modern input not yet integrated
 
+pub mod arg_complete;
+pub mod bash_bridge;
+pub mod buffer;
+pub mod cargo_complete;
 pub mod complete;
+pub mod dir_cache;
+pub mod draft;
 pub mod editor;
+pub mod environment;
+pub mod external_edit;
+pub mod frecency;
+pub mod glob;
+pub mod help_flags;
 pub mod highlight;
-
-use crate::input::complete::Completer;
-use crate::input::editor::{Editor, EditorAction};
+pub mod history;
+pub mod keymap;
+pub mod make_complete;
+pub mod npm_complete;
+pub mod parser;
+pub mod snippet;
+pub mod spec;
+pub mod ssh_complete;
+pub mod systemd_complete;
+pub mod users;
+pub mod vi;
+pub mod viewport;
+pub mod wrap;
+
+use crate::input::complete::{Completer, CompletionMenu};
+use crate::input::draft::DraftStore;
+use crate::input::editor::{Editor, TextEdit};
+use crate::input::external_edit::ExternalEditSession;
 use crate::input::highlight::{HighlightedSpan, SyntaxHighlighter};
+use crate::input::history::EditorHistoryCursor;
+use crate::input::keymap::{Dispatch, EditorCommand, KeyDispatcher, Keymap};
+use crate::input::parser::CompletionStatus;
+use crate::input::vi::{ViOutcome, ViState};
+use std::borrow::Cow;
 use std::collections::VecDeque;
 
 /// Configuration for the modern input
@@ -95,42 +126,53 @@ pub struct ModernInput {
     pub completer: Completer,
     /// Command history
     pub history: VecDeque<String>,
-    /// Current position in history navigation
-    pub history_pos: Option<usize>,
+    /// Position in history navigation and the draft to return to
+    pub history_cursor: EditorHistoryCursor,
     /// Configuration
     pub config: InputConfig,
-    /// Vi mode (if using vi keybindings)
-    pub vi_mode: ViMode,
+    /// Vi mode and pending command (if using vi keybindings)
+    pub vi: ViState,
+    /// Key bindings for editing commands
+    pub keymap: Keymap,
+    /// Chord typed part way through the keymap
+    pub chords: KeyDispatcher,
+    /// Input handed to an external editor, until it exits
+    external_edit: Option<ExternalEditSession>,
     /// Active history search
     pub history_search: Option<HistorySearch>,
-    /// Current completion suggestions
-    pub completions: Vec<String>,
-    /// Selected completion index
-    pub completion_index: usize,
-    /// Whether completion popup is visible
-    pub completion_visible: bool,
+    /// Completion popup, open while it has suggestions
+    pub completions: CompletionMenu,
+    /// Where the command being composed is autosaved, if anywhere
+    pub drafts: Option<DraftStore>,
 }
 
 impl ModernInput {
     /// Create a new modern input handler
     pub fn new(config: InputConfig) -> Self {
+        let mut completer = Completer::new();
+        completer.refresh_cache_async();
         Self {
             editor: Editor::new(),
             highlighter: SyntaxHighlighter::new(),
-            completer: Completer::new(),
+            completer,
             history: VecDeque::with_capacity(config.max_history),
-            history_pos: None,
+            history_cursor: EditorHistoryCursor::new(),
+            vi: ViState::new(),
+            keymap: match config.keybinding_mode {
+                KeybindingMode::Vi => Keymap::vi_insert_default(),
+                _ => Keymap::emacs_default(),
+            },
+            chords: KeyDispatcher::new(),
+            external_edit: None,
             config,
-            vi_mode: ViMode::Insert,
             history_search: None,
-            completions: Vec::new(),
-            completion_index: 0,
-            completion_visible: false,
+            completions: CompletionMenu::new(),
+            drafts: None,
         }
     }
 
     /// Get the current input text
-    pub fn text(&self) -> &str {
+    pub fn text(&self) -> Cow<'_, str> {
         self.editor.text()
     }
 
@@ -143,17 +185,34 @@ impl ModernInput {
     /// Clear the input
     pub fn clear(&mut self) {
         self.editor.clear();
-        self.history_pos = None;
+        self.history_cursor.reset();
         self.history_search = None;
-        self.hide_completions();
+        self.completions.dismiss();
+        if let Some(drafts) = self.drafts.as_mut() {
+            if let Err(e) = drafts.discard() {
+                log::warn!("Failed to remove input draft: {}", e);
+            }
+        }
+    }
+
+    /// Autosave the input to the draft store, if one is set and a write is due
+    ///
+    /// Runs after every key; also call it from a timer so the last edit
+    /// before a pause is saved.
+    pub fn autosave_draft(&mut self) {
+        if let Some(drafts) = self.drafts.as_mut() {
+            if let Err(e) = drafts.save_if_due(&self.editor) {
+                log::warn!("Failed to save input draft: {}", e);
+            }
+        }
     }
 
     /// Get highlighted spans for rendering
     pub fn highlighted_spans(&self) -> Vec<HighlightedSpan> {
         if self.config.syntax_highlighting {
-            self.highlighter.highlight(self.editor.text())
+            self.highlighter.highlight(&self.editor.text())
         } else {
-            vec![HighlightedSpan::default_text(self.editor.text())]
+            vec![HighlightedSpan::default_text(&self.editor.text())]
         }
     }
 
@@ -162,6 +221,18 @@ impl ModernInput {
         &mut self,
         key: termwiz::input::KeyCode,
         mods: termwiz::input::Modifiers,
+    ) -> InputResult {
+        let result = self.dispatch_key(key, mods);
+        if result == InputResult::Updated {
+            self.autosave_draft();
+        }
+        result
+    }
+
+    fn dispatch_key(
+        &mut self,
+        key: termwiz::input::KeyCode,
+        mods: termwiz::input::Modifiers,
     ) -> InputResult {
         use termwiz::input::{KeyCode, Modifiers};
 
@@ -171,29 +242,52 @@ impl ModernInput {
         }
 
         // Handle completion navigation
-        if self.completion_visible {
+        if self.completions.is_open() {
             match key {
                 KeyCode::Tab if !mods.contains(Modifiers::SHIFT) => {
-                    return self.select_next_completion();
+                    self.completions.next();
+                    return InputResult::Updated;
                 }
                 KeyCode::Tab if mods.contains(Modifiers::SHIFT) => {
-                    return self.select_prev_completion();
+                    self.completions.prev();
+                    return InputResult::Updated;
                 }
                 KeyCode::Enter => {
-                    return self.accept_completion();
+                    self.accept_completion();
+                    return InputResult::Updated;
                 }
                 KeyCode::Escape => {
-                    self.hide_completions();
+                    self.completions.dismiss();
                     return InputResult::Updated;
                 }
+                // Typing narrows the popup instead of closing it
+                KeyCode::Char(_) | KeyCode::Backspace
+                    if !mods.intersects(Modifiers::CTRL | Modifiers::ALT) => {}
                 _ => {
-                    self.hide_completions();
+                    self.completions.dismiss();
                 }
             }
         }
 
+        if self.config.keybinding_mode == KeybindingMode::Vi {
+            let event = termwiz::input::KeyEvent {
+                key,
+                modifiers: mods,
+            };
+            match self.vi.process_key(&mut self.editor, &event) {
+                ViOutcome::Consumed => return InputResult::Updated,
+                ViOutcome::Submit => return self.submit(),
+                ViOutcome::Ignored => {}
+            }
+        }
+
+        // The rest of a chord goes straight to the keymap
+        if self.chords.is_pending() {
+            return self.dispatch_keymap(key, mods);
+        }
+
         // Handle special key combinations
-        match (key.clone(), mods) {
+        match (key, mods) {
             // Ctrl+R - Start history search
             (KeyCode::Char('r'), m) if m.contains(Modifiers::CTRL) => {
                 self.start_history_search();
@@ -204,150 +298,129 @@ impl ModernInput {
             (KeyCode::Enter, m)
                 if m.contains(Modifiers::SHIFT) && self.config.multiline_enabled =>
             {
-                self.editor.insert_char('\n');
+                self.editor.execute(EditorCommand::InsertNewline);
                 return InputResult::Updated;
             }
 
-            // Enter - Submit
+            // Enter - Submit, or start a new line if the command is
+            // left open (unclosed quote, trailing pipe, heredoc...)
             (KeyCode::Enter, m) if !m.contains(Modifiers::SHIFT) => {
-                let text = self.editor.text().to_string();
-                if !text.trim().is_empty() {
-                    self.add_to_history(text.clone());
+                let open = self.editor.is_input_complete() != CompletionStatus::Complete;
+                if open && self.config.multiline_enabled {
+                    self.editor.execute(EditorCommand::InsertNewline);
+                    return InputResult::Updated;
                 }
-                self.clear();
-                return InputResult::Submit(text);
+                return self.submit();
             }
 
-            // Tab - Trigger completion
+            // Tab - Expand an abbreviation, move to the next snippet
+            // placeholder, or trigger completion
             (KeyCode::Tab, m) if !m.contains(Modifiers::SHIFT) => {
-                self.trigger_completion();
-                return InputResult::Updated;
-            }
-
-            // Up arrow - History navigation
-            (KeyCode::UpArrow, m) if !m.contains(Modifiers::ALT) => {
-                self.navigate_history_up();
-                return InputResult::Updated;
-            }
-
-            // Down arrow - History navigation
-            (KeyCode::DownArrow, m) if !m.contains(Modifiers::ALT) => {
-                self.navigate_history_down();
-                return InputResult::Updated;
-            }
-
-            // Ctrl+A - Move to start of line
-            (KeyCode::Char('a'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.move_to_line_start();
-                return InputResult::Updated;
-            }
-
-            // Ctrl+E - Move to end of line
-            (KeyCode::Char('e'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.move_to_line_end();
-                return InputResult::Updated;
-            }
-
-            // Ctrl+K - Kill to end of line
-            (KeyCode::Char('k'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.kill_to_line_end();
-                return InputResult::Updated;
-            }
-
-            // Ctrl+U - Kill to start of line
-            (KeyCode::Char('u'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.kill_to_line_start();
-                return InputResult::Updated;
-            }
-
-            // Ctrl+W - Kill word backward
-            (KeyCode::Char('w'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.kill_word_backward();
+                if !self.editor.expand_at_cursor() && !self.editor.next_placeholder() {
+                    self.trigger_completion();
+                }
                 return InputResult::Updated;
             }
 
-            // Ctrl+Y - Yank (paste from kill ring)
-            (KeyCode::Char('y'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.yank();
+            // Shift+Tab - Previous snippet placeholder
+            (KeyCode::Tab, _) if self.editor.in_snippet() => {
+                self.editor.prev_placeholder();
                 return InputResult::Updated;
             }
 
-            // Ctrl+Z - Undo
-            (KeyCode::Char('z'), m) if m.contains(Modifiers::CTRL) => {
-                self.editor.undo();
+            // Up arrow - History navigation
+            // (moves between lines first when the input spans several)
+            (KeyCode::UpArrow, m) if !m.intersects(Modifiers::ALT | Modifiers::SHIFT) => {
+                if !self.editor.execute(EditorCommand::MoveUp) {
+                    self.navigate_history_up();
+                }
                 return InputResult::Updated;
             }
 
-            // Ctrl+Shift+Z - Redo
-            (KeyCode::Char('Z'), m)
-                if m.contains(Modifiers::CTRL) && m.contains(Modifiers::SHIFT) =>
-            {
-                self.editor.redo();
+            // Down arrow - History navigation
+            (KeyCode::DownArrow, m) if !m.intersects(Modifiers::ALT | Modifiers::SHIFT) => {
+                if !self.editor.execute(EditorCommand::MoveDown) {
+                    self.navigate_history_down();
+                }
                 return InputResult::Updated;
             }
 
             // Escape - Cancel/clear
             (KeyCode::Escape, _) => {
-                if self.config.keybinding_mode == KeybindingMode::Vi {
-                    self.vi_mode = ViMode::Normal;
-                } else {
-                    self.clear();
-                }
+                self.clear();
                 return InputResult::Updated;
             }
 
             _ => {}
         }
 
-        // Handle regular editing
-        let action = match key {
-            KeyCode::Char(c) => {
-                self.editor.insert_char(c);
-                self.update_completions();
-                EditorAction::Insert
-            }
-            KeyCode::Backspace => {
-                self.editor.backspace();
-                self.update_completions();
-                EditorAction::Delete
-            }
-            KeyCode::Delete => {
-                self.editor.delete();
-                self.update_completions();
-                EditorAction::Delete
-            }
-            KeyCode::LeftArrow => {
-                if mods.contains(termwiz::input::Modifiers::CTRL) {
-                    self.editor.move_word_left();
-                } else {
-                    self.editor.move_left();
-                }
-                EditorAction::Move
-            }
-            KeyCode::RightArrow => {
-                if mods.contains(termwiz::input::Modifiers::CTRL) {
-                    self.editor.move_word_right();
-                } else {
-                    self.editor.move_right();
+        // Everything else goes through the keymap
+        self.dispatch_keymap(key, mods)
+    }
+
+    /// Look a key up in the keymap and run what it's bound to
+    fn dispatch_keymap(
+        &mut self,
+        key: termwiz::input::KeyCode,
+        mods: termwiz::input::Modifiers,
+    ) -> InputResult {
+        match self.chords.dispatch(&self.keymap, mods, key) {
+            Dispatch::Command(EditorCommand::EditInExternalEditor) => self.start_external_edit(),
+            Dispatch::Command(command) => {
+                let edit = command.is_edit();
+                self.editor.execute(command);
+                if edit {
+                    self.update_completions();
                 }
-                EditorAction::Move
+                InputResult::Updated
             }
-            KeyCode::Home => {
-                self.editor.move_to_line_start();
-                EditorAction::Move
+            Dispatch::Pending | Dispatch::Cancelled => InputResult::Updated,
+            Dispatch::Unbound => InputResult::Ignored,
+        }
+    }
+
+    /// Chord typed so far, for the status line
+    pub fn pending_prefix(&self) -> Option<String> {
+        self.chords.pending_prefix()
+    }
+
+    /// Write the input out for $VISUAL or $EDITOR
+    fn start_external_edit(&mut self) -> InputResult {
+        match ExternalEditSession::prepare(&self.editor) {
+            Ok(session) => {
+                let command = session.command();
+                self.external_edit = Some(session);
+                InputResult::EditExternally(command)
             }
-            KeyCode::End => {
-                self.editor.move_to_line_end();
-                EditorAction::Move
+            Err(e) => {
+                log::warn!("Failed to start external editor: {}", e);
+                InputResult::Ignored
             }
-            _ => EditorAction::None,
+        }
+    }
+
+    /// Read the input back once the command from EditExternally exits
+    pub fn finish_external_edit(&mut self, succeeded: bool) {
+        let session = match self.external_edit.take() {
+            Some(session) => session,
+            None => return,
         };
+        match session.finish(&mut self.editor, succeeded) {
+            Ok(true) => self.update_completions(),
+            Ok(false) => {}
+            Err(e) => log::warn!("Failed to read back external edit: {}", e),
+        }
+    }
 
-        if action != EditorAction::None {
-            InputResult::Updated
-        } else {
-            InputResult::Ignored
+    /// Submit the current input, adding it to history
+    fn submit(&mut self) -> InputResult {
+        let text = self.editor.full_text();
+        if !text.trim().is_empty() {
+            self.add_to_history(text.clone());
         }
+        self.clear();
+        self.vi.set_mode(ViMode::Insert);
+        InputResult::Submit(text)
     }
 
     /// Start history search mode
@@ -447,45 +520,23 @@ impl ModernInput {
         }
     }
 
-    /// Navigate history up
+    /// Navigate history up, keeping the typed draft to come back to
     fn navigate_history_up(&mut self) {
-        if self.history.is_empty() {
-            return;
-        }
-
-        let new_pos = match self.history_pos {
-            Some(pos) if pos + 1 < self.history.len() => Some(pos + 1),
-            Some(pos) => Some(pos),
-            None => Some(0),
-        };
-
-        if let Some(pos) = new_pos {
-            self.history_pos = Some(pos);
-            if let Some(entry) = self.history.get(pos) {
-                self.editor.set_text(entry);
-            }
-        }
+        let history = self.history.make_contiguous();
+        self.history_cursor.previous_into(&mut self.editor, history);
     }
 
-    /// Navigate history down
+    /// Navigate history down, ending at the draft
     fn navigate_history_down(&mut self) {
-        match self.history_pos {
-            Some(0) => {
-                self.history_pos = None;
-                self.editor.clear();
-            }
-            Some(pos) => {
-                self.history_pos = Some(pos - 1);
-                if let Some(entry) = self.history.get(pos - 1) {
-                    self.editor.set_text(entry);
-                }
-            }
-            None => {}
-        }
+        let history = self.history.make_contiguous();
+        self.history_cursor.next_into(&mut self.editor, history);
     }
 
     /// Add entry to history
     fn add_to_history(&mut self, entry: String) {
+        // Completion counts every use, repeats included
+        self.completer.add_history_entry(entry.clone());
+
         // Don't add duplicates at the front
         if self.history.front() == Some(&entry) {
             return;
@@ -501,96 +552,53 @@ impl ModernInput {
 
     /// Trigger completion
     fn trigger_completion(&mut self) {
-        let text = self.editor.text();
-        let cursor_pos = self.editor.cursor_pos();
-
-        self.completions = self.completer.complete(text, cursor_pos);
-
-        if self.completions.len() == 1 {
-            // Single completion - apply directly
-            self.apply_completion(&self.completions[0].clone());
-        } else if !self.completions.is_empty() {
-            // Multiple completions - show popup
-            self.completion_visible = true;
-            self.completion_index = 0;
+        self.completer.maybe_refresh();
+        let text = self.editor.full_text();
+        let cursor = self.editor.cursor_pos();
+        // A single completion is applied directly
+        if self.completions.open(&self.completer, &text, cursor)
+            && self.completions.items().len() == 1
+        {
+            self.accept_completion();
         }
     }
 
     /// Update completions as user types
     fn update_completions(&mut self) {
-        if !self.config.completion_enabled {
-            return;
-        }
-
         // Only update if popup is visible
-        if self.completion_visible {
-            let text = self.editor.text();
-            let cursor_pos = self.editor.cursor_pos();
-            self.completions = self.completer.complete(text, cursor_pos);
-
-            if self.completions.is_empty() {
-                self.hide_completions();
-            } else {
-                self.completion_index = self.completion_index.min(self.completions.len() - 1);
-            }
-        }
-    }
-
-    /// Select next completion
-    fn select_next_completion(&mut self) -> InputResult {
-        if !self.completions.is_empty() {
-            self.completion_index = (self.completion_index + 1) % self.completions.len();
-        }
-        InputResult::Updated
-    }
-
-    /// Select previous completion
-    fn select_prev_completion(&mut self) -> InputResult {
-        if !self.completions.is_empty() {
-            self.completion_index = if self.completion_index == 0 {
-                self.completions.len() - 1
-            } else {
-                self.completion_index - 1
-            };
+        if !self.config.completion_enabled || !self.completions.is_open() {
+            return;
         }
-        InputResult::Updated
+        let text = self.editor.full_text();
+        self.completions
+            .refine(&self.completer, &text, self.editor.cursor_pos());
     }
 
-    /// Accept current completion
-    fn accept_completion(&mut self) -> InputResult {
-        if let Some(completion) = self.completions.get(self.completion_index).cloned() {
-            self.apply_completion(&completion);
-            self.hide_completions();
+    /// Apply the highlighted completion and close the popup
+    ///
+    /// It replaces what the completer said it would: usually the word at
+    /// the cursor, or the line up to it for a command from history.
+    fn accept_completion(&mut self) {
+        if let Some(item) = self.completions.current() {
+            self.completer.record_accepted(item);
         }
-        InputResult::Updated
-    }
-
-    /// Apply a completion
-    fn apply_completion(&mut self, completion: &str) {
-        // Find the word start
-        let text = self.editor.text();
-        let cursor = self.editor.cursor_pos();
-
-        let word_start = text[..cursor]
-            .rfind(|c: char| c.is_whitespace() || c == '/' || c == '\\')
-            .map(|i| i + 1)
-            .unwrap_or(0);
-
-        // Replace from word start to cursor
-        self.editor.delete_range(word_start, cursor);
-        self.editor.set_cursor(word_start);
-        for c in completion.chars() {
-            self.editor.insert_char(c);
+        let (completion, span) = match self.completions.accept() {
+            Some(accepted) => accepted,
+            None => return,
+        };
+        let text = self.editor.full_text();
+        let end = span.end.min(text.len());
+        let start = span.start.min(end);
+        let range = text[..start].chars().count()..text[..end].chars().count();
+        let edit = TextEdit {
+            range,
+            new_text: completion,
+        };
+        if let Err(err) = self.editor.apply_edits(&[edit]) {
+            log::debug!("Can't apply completion: {}", err);
         }
     }
 
-    /// Hide completion popup
-    fn hide_completions(&mut self) {
-        self.completion_visible = false;
-        self.completions.clear();
-        self.completion_index = 0;
-    }
-
     /// Get cursor position for rendering
     pub fn cursor_position(&self) -> (usize, usize) {
         self.editor.cursor_coords()
@@ -628,6 +636,10 @@ impl ModernInput {
             self.history.pop_back();
         }
 
+        // The completer wants the oldest first
+        let entries: Vec<String> = self.history.iter().rev().cloned().collect();
+        self.completer.add_history(&entries);
+
         Ok(())
     }
 
@@ -660,4 +672,64 @@ pub enum InputResult {
     Submit(String),
     /// Input event was ignored
     Ignored,
+    /// Run this command line in a terminal, then call finish_external_edit
+    EditExternally(Vec<String>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termwiz::input::{KeyCode, Modifiers};
+
+    fn type_text(input: &mut ModernInput, text: &str) {
+        for c in text.chars() {
+            input.handle_key(KeyCode::Char(c), Modifiers::NONE);
+        }
+    }
+
+    #[test]
+    fn test_submit_multiline_heredoc() {
+        let mut input = ModernInput::new(InputConfig::default());
+        type_text(&mut input, "cat <<EOF");
+        assert_eq!(
+            input.handle_key(KeyCode::Enter, Modifiers::NONE),
+            InputResult::Updated
+        );
+        type_text(&mut input, "hello");
+        input.handle_key(KeyCode::Enter, Modifiers::NONE);
+        type_text(&mut input, "EOF");
+        assert_eq!(
+            input.handle_key(KeyCode::Enter, Modifiers::NONE),
+            InputResult::Submit("cat <<EOF\nhello\nEOF".to_string())
+        );
+        assert_eq!(
+            input.history.back().map(String::as_str),
+            Some("cat <<EOF\nhello\nEOF")
+        );
+    }
+    #[test]
+    fn test_completion_popup() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["first.txt", "fixed.txt", "other.txt"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let mut input = ModernInput::new(InputConfig::default());
+        let typed = format!("cat {}/fi", dir.path().display());
+        type_text(&mut input, &typed);
+
+        input.handle_key(KeyCode::Tab, Modifiers::NONE);
+        assert_eq!(input.completions.items().len(), 2);
+        let first = input.completions.current().unwrap().text.clone();
+        input.handle_key(KeyCode::Tab, Modifiers::NONE);
+        input.handle_key(KeyCode::Tab, Modifiers::SHIFT);
+        assert_eq!(input.completions.current().unwrap().text, first);
+
+        // Typing narrows the popup, and Enter applies the highlighted item
+        type_text(&mut input, "x");
+        assert_eq!(input.completions.items().len(), 1);
+        input.handle_key(KeyCode::Enter, Modifiers::NONE);
+        assert!(!input.completions.is_open());
+        assert!(input.text().ends_with("/fixed.txt"), "{}", input.text());
+        assert!(input.text().starts_with("cat /"));
+    }
 }
@@ -0,0 +1,275 @@
+//! Script completion for npm, pnpm and yarn
+//!
+//! NpmCompleter reads the scripts of the nearest package.json, and inside
+//! a workspace package those of the workspace root too.  Each script shows
+//! its command as the description.  A package.json that doesn't parse is
+//! treated as having no scripts.  Files are read again when they change.
+
+use crate::input::arg_complete::{argument, ArgCompleter, CompletionContext, FileCache};
+use crate::input::complete::CompletionInfo;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Longest script command shown as a description, in chars
+const MAX_DESCRIPTION: usize = 40;
+
+/// Subcommands common to npm, pnpm and yarn
+const NPM_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("add", "Add a dependency"),
+    ("audit", "Check dependencies for vulnerabilities"),
+    ("ci", "Clean install from the lockfile"),
+    ("exec", "Run a command from a package"),
+    ("init", "Create a package.json"),
+    ("install", "Install dependencies"),
+    ("link", "Symlink a package folder"),
+    ("ls", "List installed packages"),
+    ("outdated", "Check for outdated packages"),
+    ("pack", "Create a tarball from the package"),
+    ("publish", "Publish the package"),
+    ("remove", "Remove a dependency"),
+    ("restart", "Run the restart script"),
+    ("run", "Run a script"),
+    ("start", "Run the start script"),
+    ("stop", "Run the stop script"),
+    ("test", "Run the test script"),
+    ("uninstall", "Remove a package"),
+    ("update", "Update packages"),
+    ("version", "Bump the package version"),
+];
+
+/// What completion needs from a package.json
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct PackageJson {
+    /// Script names with their commands
+    scripts: Vec<(String, String)>,
+    /// Whether it declares workspaces
+    is_workspace_root: bool,
+}
+
+/// Read the scripts and workspaces of a package.json
+///
+/// Anything that isn't JSON, or scripts that aren't an object of strings,
+/// give nothing rather than an error.
+fn parse_package_json(text: &str) -> PackageJson {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(err) => {
+            log::debug!("Ignoring malformed package.json: {}", err);
+            return PackageJson::default();
+        }
+    };
+    let scripts = value
+        .get("scripts")
+        .and_then(|scripts| scripts.as_object())
+        .map(|scripts| {
+            scripts
+                .iter()
+                .filter_map(|(name, command)| Some((name.clone(), command.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    PackageJson {
+        scripts,
+        is_workspace_root: value.get("workspaces").is_some(),
+    }
+}
+
+/// Shorten a script's command to fit as a description
+fn truncated(command: &str) -> String {
+    if command.chars().count() <= MAX_DESCRIPTION {
+        return command.to_string();
+    }
+    let mut short: String = command.chars().take(MAX_DESCRIPTION - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Completes npm, pnpm and yarn subcommands and scripts
+#[derive(Debug, Default)]
+pub struct NpmCompleter {
+    /// Parsed package.json files
+    cache: FileCache<PackageJson>,
+}
+
+impl NpmCompleter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the package.json in `dir`, or reuse what it parsed to if
+    /// unchanged
+    fn load(&self, dir: &Path) -> Option<Arc<PackageJson>> {
+        self.cache.get(&dir.join("package.json"), |path| {
+            let text = fs::read_to_string(path).unwrap_or_default();
+            Some(parse_package_json(&text))
+        })
+    }
+
+    /// Scripts of the nearest package.json, then of the workspace root
+    /// above it
+    fn scripts(&self, cwd: &Path) -> Vec<(String, String)> {
+        let mut dirs = cwd.ancestors();
+        let package = match dirs.find_map(|dir| self.load(dir)) {
+            Some(package) => package,
+            None => return Vec::new(),
+        };
+        let mut scripts = package.scripts.clone();
+        if !package.is_workspace_root {
+            let root = dirs.find_map(|dir| self.load(dir).filter(|p| p.is_workspace_root));
+            if let Some(root) = root {
+                let own: HashSet<String> = scripts.iter().map(|(name, _)| name.clone()).collect();
+                let inherited = root.scripts.iter().filter(|(name, _)| !own.contains(name));
+                scripts.extend(inherited.cloned());
+            }
+        }
+        scripts
+    }
+}
+
+impl ArgCompleter for NpmCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        if ctx.word.starts_with('-') {
+            return None;
+        }
+        let mut positional = ctx.args.iter().filter(|arg| !arg.starts_with('-'));
+        let (subcommands, scripts) = match positional.next() {
+            // yarn and pnpm run scripts named as subcommands
+            None => (true, ctx.command != "npm"),
+            Some(&"run" | &"run-script") if positional.next().is_none() => (false, true),
+            Some(_) => return None,
+        };
+
+        let mut items = Vec::new();
+        if subcommands {
+            items.extend(
+                NPM_SUBCOMMANDS
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(ctx.word))
+                    .map(|(name, description)| argument(name, description, ctx.word)),
+            );
+        }
+        if scripts {
+            let taken: HashSet<String> = items.iter().map(|item| item.text.clone()).collect();
+            items.extend(
+                self.scripts(&ctx.cwd)
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(ctx.word) && !taken.contains(name))
+                    .map(|(name, command)| argument(name, &truncated(command), ctx.word)),
+            );
+        }
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    const ROOT_JSON: &str = include_str!("testdata/npm/root.json");
+    const APP_JSON: &str = include_str!("testdata/npm/app.json");
+    const BAD_SCRIPTS_JSON: &str = include_str!("testdata/npm/bad-scripts.json");
+
+    fn complete(
+        completer: &NpmCompleter,
+        command: &str,
+        cwd: &Path,
+        args: &[&str],
+        word: &str,
+    ) -> Option<Vec<(String, String)>> {
+        let ctx = CompletionContext {
+            command,
+            args: args.to_vec(),
+            word,
+            cwd: cwd.to_path_buf(),
+            env: HashMap::new(),
+            sudo: false,
+        };
+        let items = completer.complete(&ctx)?;
+        Some(
+            items
+                .into_iter()
+                .map(|item| (item.text, item.description.unwrap_or_default()))
+                .collect(),
+        )
+    }
+
+    fn names(items: Option<Vec<(String, String)>>) -> Vec<String> {
+        items.unwrap().into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_parse_package_json() {
+        let app = parse_package_json(APP_JSON);
+        assert_eq!(app.scripts.len(), 4);
+        assert!(!app.is_workspace_root);
+        assert!(parse_package_json(ROOT_JSON).is_workspace_root);
+        assert_eq!(parse_package_json(BAD_SCRIPTS_JSON), PackageJson::default());
+        assert_eq!(
+            parse_package_json("{ \"scripts\": "),
+            PackageJson::default()
+        );
+    }
+
+    #[test]
+    fn test_scripts_in_a_workspace() {
+        let root = tempfile::tempdir().unwrap();
+        let app = root.path().join("packages").join("app");
+        fs::create_dir_all(app.join("src")).unwrap();
+        fs::write(root.path().join("package.json"), ROOT_JSON).unwrap();
+        fs::write(app.join("package.json"), APP_JSON).unwrap();
+        let completer = NpmCompleter::new();
+
+        let scripts = complete(&completer, "npm", &app.join("src"), &["run"], "").unwrap();
+        assert_eq!(
+            scripts,
+            [
+                ("build", "vite build"),
+                ("dev", "vite --port 3000"),
+                ("test", "vitest run"),
+                ("test:watch", "vitest"),
+                ("lint", "eslint . --ext .ts,.tsx --max-warnings …"),
+            ]
+            .map(|(name, command)| (name.to_string(), command.to_string()))
+        );
+        assert_eq!(
+            names(complete(&completer, "npm", root.path(), &["run"], "")),
+            ["build", "lint"]
+        );
+
+        // npm wants a subcommand first; yarn runs scripts directly
+        assert_eq!(
+            names(complete(&completer, "npm", &app, &[], "te")),
+            ["test"]
+        );
+        assert_eq!(
+            names(complete(&completer, "yarn", &app, &[], "te")),
+            ["test", "test:watch"]
+        );
+        assert!(complete(&completer, "npm", &app, &["install"], "").is_none());
+        assert!(complete(&completer, "npm", &app, &["run", "build"], "").is_none());
+    }
+
+    #[test]
+    fn test_bad_package_json_has_no_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let completer = NpmCompleter::new();
+        assert!(names(complete(&completer, "npm", dir.path(), &["run"], "")).is_empty());
+
+        let path = dir.path().join("package.json");
+        fs::write(&path, BAD_SCRIPTS_JSON).unwrap();
+        assert!(names(complete(&completer, "pnpm", dir.path(), &["run"], "")).is_empty());
+
+        fs::write(&path, "{ not json").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            names(complete(&completer, "pnpm", dir.path(), &[], "in")),
+            ["init", "install"]
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Deciding whether shell input is ready to run
+//!
+//! A small scanner that tracks quotes, brackets, command substitutions,
+//! comments and heredocs closely enough to tell whether Enter should submit
+//! or start a new line.  It doesn't check that the input is valid: `echo )`
+//! is complete, and the shell gets to report the error.
+
+/// Whether input can be submitted as it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompletionStatus {
+    /// Nothing is left open
+    Complete,
+    /// The shell would wait for more input
+    NeedsMore(IncompleteReason),
+}
+
+/// What is left open at the end of the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncompleteReason {
+    /// A `'`, `"` or `` ` `` quote is not closed
+    UnclosedQuote(char),
+    /// A `(`, `{` or `[` is not closed; `$(` and `${` count as well
+    UnclosedBracket(char),
+    /// The last line ends with a backslash
+    TrailingBackslash,
+    /// A heredoc is missing its terminator line
+    OpenHeredoc(String),
+    /// The input ends with `|`, `&&` or `||`
+    TrailingOperator(&'static str),
+}
+
+/// Something the scanner is inside of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frame {
+    /// `(` or `$(`, holding commands
+    Paren,
+    /// `{`, holding commands
+    Brace,
+    /// `[`
+    Bracket,
+    /// `${`
+    Parameter,
+    DoubleQuote,
+    Backtick,
+}
+
+impl Frame {
+    /// Whether commands, comments and operators are recognized inside
+    fn holds_commands(self) -> bool {
+        !matches!(self, Frame::Parameter | Frame::DoubleQuote)
+    }
+
+    fn reason(self) -> IncompleteReason {
+        match self {
+            Frame::Paren => IncompleteReason::UnclosedBracket('('),
+            Frame::Brace | Frame::Parameter => IncompleteReason::UnclosedBracket('{'),
+            Frame::Bracket => IncompleteReason::UnclosedBracket('['),
+            Frame::DoubleQuote => IncompleteReason::UnclosedQuote('"'),
+            Frame::Backtick => IncompleteReason::UnclosedQuote('`'),
+        }
+    }
+}
+
+/// A heredoc whose body starts at the next line
+#[derive(Debug)]
+struct Heredoc {
+    delimiter: String,
+    /// `<<-` strips leading tabs before matching the delimiter
+    strip_tabs: bool,
+}
+
+/// Check whether `input` could be run as it is
+pub fn is_input_complete(input: &str) -> CompletionStatus {
+    // Everything the scanner cares about is ASCII, so scanning bytes never
+    // mistakes part of a multi-byte char for syntax
+    let bytes = input.as_bytes();
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut heredocs: Vec<Heredoc> = Vec::new();
+    let mut trailing_operator: Option<&'static str> = None;
+    let mut word_start = true;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        let top = stack.last().copied();
+        let in_commands = top.is_none_or(Frame::holds_commands);
+
+        if b == b'\\' {
+            match bytes.get(i + 1) {
+                None => return CompletionStatus::NeedsMore(IncompleteReason::TrailingBackslash),
+                Some(b'\n') if i + 2 == bytes.len() && in_commands => {
+                    return CompletionStatus::NeedsMore(IncompleteReason::TrailingBackslash)
+                }
+                _ => {}
+            }
+            i += 2;
+            trailing_operator = None;
+            word_start = false;
+            continue;
+        }
+
+        if !in_commands {
+            match (top, b) {
+                (Some(Frame::DoubleQuote), b'"') | (Some(Frame::Parameter), b'}') => {
+                    stack.pop();
+                }
+                (Some(Frame::Parameter), b'"') => stack.push(Frame::DoubleQuote),
+                (Some(Frame::Parameter), b'\'') => match skip_single_quoted(bytes, i) {
+                    Some(end) => i = end,
+                    None => {
+                        return CompletionStatus::NeedsMore(IncompleteReason::UnclosedQuote('\''))
+                    }
+                },
+                (_, b'`') => stack.push(Frame::Backtick),
+                (_, b'$') => i += push_dollar(bytes, i, &mut stack),
+                _ => {}
+            }
+            i += 1;
+            continue;
+        }
+
+        match b {
+            b'\n' => {
+                i += 1;
+                for heredoc in heredocs.drain(..) {
+                    match skip_heredoc_body(bytes, i, &heredoc) {
+                        Some(end) => i = end,
+                        None => {
+                            return CompletionStatus::NeedsMore(IncompleteReason::OpenHeredoc(
+                                heredoc.delimiter,
+                            ))
+                        }
+                    }
+                }
+                word_start = true;
+                continue;
+            }
+            b' ' | b'\t' => {
+                word_start = true;
+                i += 1;
+                continue;
+            }
+            b'#' if word_start => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut operator = None;
+        match b {
+            b'\'' => match skip_single_quoted(bytes, i) {
+                Some(end) => i = end,
+                None => return CompletionStatus::NeedsMore(IncompleteReason::UnclosedQuote('\'')),
+            },
+            b'"' => stack.push(Frame::DoubleQuote),
+            b'`' if top == Some(Frame::Backtick) => {
+                stack.pop();
+            }
+            b'`' => stack.push(Frame::Backtick),
+            b'$' => i += push_dollar(bytes, i, &mut stack),
+            b'(' => stack.push(Frame::Paren),
+            b'{' => stack.push(Frame::Brace),
+            b'[' => stack.push(Frame::Bracket),
+            b')' | b'}' | b']' => {
+                let closes = match b {
+                    b')' => Frame::Paren,
+                    b'}' => Frame::Brace,
+                    _ => Frame::Bracket,
+                };
+                // A stray closer is the shell's problem, not ours
+                if top == Some(closes) {
+                    stack.pop();
+                }
+            }
+            b'<' if bytes.get(i + 1) == Some(&b'<') => {
+                if bytes.get(i + 2) == Some(&b'<') {
+                    // A here-string, not a heredoc
+                    i += 2;
+                } else {
+                    let (heredoc, end) = parse_heredoc_operator(bytes, i + 2);
+                    heredocs.extend(heredoc);
+                    i = end - 1;
+                }
+            }
+            b'|' | b'&' => {
+                let double = bytes.get(i + 1) == Some(&b);
+                operator = match (b, double) {
+                    (b'|', true) => Some("||"),
+                    (b'|', false) => Some("|"),
+                    (_, true) => Some("&&"),
+                    _ => None,
+                };
+                if double {
+                    i += 1;
+                }
+                word_start = true;
+            }
+            b';' => word_start = true,
+            _ => {}
+        }
+        if !matches!(b, b'|' | b'&' | b';') {
+            word_start = false;
+        }
+        trailing_operator = operator;
+        i += 1;
+    }
+
+    if let Some(heredoc) = heredocs.into_iter().next() {
+        return CompletionStatus::NeedsMore(IncompleteReason::OpenHeredoc(heredoc.delimiter));
+    }
+    if let Some(frame) = stack.last() {
+        return CompletionStatus::NeedsMore(frame.reason());
+    }
+    match trailing_operator {
+        Some(operator) => CompletionStatus::NeedsMore(IncompleteReason::TrailingOperator(operator)),
+        None => CompletionStatus::Complete,
+    }
+}
+
+/// Push a frame for `$(` or `${` at `i`, returning the extra bytes consumed
+fn push_dollar(bytes: &[u8], i: usize, stack: &mut Vec<Frame>) -> usize {
+    match bytes.get(i + 1) {
+        Some(b'(') => {
+            stack.push(Frame::Paren);
+            1
+        }
+        Some(b'{') => {
+            stack.push(Frame::Parameter);
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Index of the closing quote of a single-quoted string starting at `start`
+fn skip_single_quoted(bytes: &[u8], start: usize) -> Option<usize> {
+    bytes[start + 1..]
+        .iter()
+        .position(|&b| b == b'\'')
+        .map(|idx| start + 1 + idx)
+}
+
+/// Parse what follows `<<`, returning the heredoc and where parsing stopped
+fn parse_heredoc_operator(bytes: &[u8], mut i: usize) -> (Option<Heredoc>, usize) {
+    let strip_tabs = bytes.get(i) == Some(&b'-');
+    if strip_tabs {
+        i += 1;
+    }
+    while matches!(bytes.get(i), Some(b' ' | b'\t')) {
+        i += 1;
+    }
+
+    // The delimiter is a word whose quotes are removed
+    let mut delimiter = Vec::new();
+    while let Some(&b) = bytes.get(i) {
+        match b {
+            b'\'' | b'"' => {
+                let close = bytes[i + 1..].iter().position(|&c| c == b);
+                let end = close.map_or(bytes.len(), |idx| i + 1 + idx);
+                delimiter.extend_from_slice(&bytes[i + 1..end]);
+                i = (end + 1).min(bytes.len());
+            }
+            b'\\' => {
+                delimiter.extend(bytes.get(i + 1));
+                i += 2;
+            }
+            b' ' | b'\t' | b'\n' | b';' | b'|' | b'&' | b'<' | b'>' | b'(' | b')' => break,
+            _ => {
+                delimiter.push(b);
+                i += 1;
+            }
+        }
+    }
+    if delimiter.is_empty() {
+        return (None, i);
+    }
+    let heredoc = Heredoc {
+        delimiter: String::from_utf8_lossy(&delimiter).into_owned(),
+        strip_tabs,
+    };
+    (Some(heredoc), i)
+}
+
+/// Skip a heredoc body starting at `start`, returning the index after its
+/// terminator line, or None if the input ends first
+fn skip_heredoc_body(bytes: &[u8], mut start: usize, heredoc: &Heredoc) -> Option<usize> {
+    while start < bytes.len() {
+        let end = bytes[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |idx| start + idx);
+        let mut line = &bytes[start..end];
+        if heredoc.strip_tabs {
+            while let [b'\t', rest @ ..] = line {
+                line = rest;
+            }
+        }
+        if line == heredoc.delimiter.as_bytes() {
+            return Some((end + 1).min(bytes.len()));
+        }
+        start = end + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs_more(input: &str) -> IncompleteReason {
+        match is_input_complete(input) {
+            CompletionStatus::NeedsMore(reason) => reason,
+            CompletionStatus::Complete => panic!("{:?} should need more input", input),
+        }
+    }
+
+    #[test]
+    fn test_heredoc() {
+        assert_eq!(
+            needs_more("cat <<EOF > out.txt\nline one"),
+            IncompleteReason::OpenHeredoc("EOF".into())
+        );
+        assert_eq!(
+            needs_more("cat <<'END'"),
+            IncompleteReason::OpenHeredoc("END".into())
+        );
+        assert_eq!(
+            is_input_complete("cat <<EOF > out.txt\nit's $HOME (\nEOF\necho done"),
+            CompletionStatus::Complete
+        );
+        assert_eq!(
+            is_input_complete("cat <<-EOF\n\tbody\n\tEOF"),
+            CompletionStatus::Complete
+        );
+        assert_eq!(is_input_complete("cat <<< 'x'"), CompletionStatus::Complete);
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(
+            needs_more("echo \"first\nsecond"),
+            IncompleteReason::UnclosedQuote('"')
+        );
+        assert_eq!(
+            is_input_complete("echo \"first\nsecond\""),
+            CompletionStatus::Complete
+        );
+        assert_eq!(
+            needs_more("echo 'it"),
+            IncompleteReason::UnclosedQuote('\'')
+        );
+        assert_eq!(
+            needs_more("echo \"a \\\""),
+            IncompleteReason::UnclosedQuote('"')
+        );
+        assert_eq!(
+            is_input_complete("echo \"| ( {\""),
+            CompletionStatus::Complete
+        );
+        assert_eq!(
+            is_input_complete("echo 'héllo' # it's"),
+            CompletionStatus::Complete
+        );
+    }
+
+    #[test]
+    fn test_substitutions_and_brackets() {
+        assert_eq!(
+            is_input_complete("echo \"$(printf '%s)' \")\")\""),
+            CompletionStatus::Complete
+        );
+        assert_eq!(
+            needs_more("echo $(date"),
+            IncompleteReason::UnclosedBracket('(')
+        );
+        assert_eq!(
+            needs_more("if [ -f x ]; then {"),
+            IncompleteReason::UnclosedBracket('{')
+        );
+        assert_eq!(
+            needs_more("echo ${HOME"),
+            IncompleteReason::UnclosedBracket('{')
+        );
+        assert_eq!(
+            needs_more("echo `date"),
+            IncompleteReason::UnclosedQuote('`')
+        );
+        // Complete, even though the shell will reject it
+        assert_eq!(is_input_complete("echo )"), CompletionStatus::Complete);
+    }
+
+    #[test]
+    fn test_continuations() {
+        assert_eq!(needs_more("make \\"), IncompleteReason::TrailingBackslash);
+        assert_eq!(needs_more("ls |"), IncompleteReason::TrailingOperator("|"));
+        assert_eq!(
+            needs_more("make && # then install"),
+            IncompleteReason::TrailingOperator("&&")
+        );
+        assert_eq!(
+            needs_more("false ||\n"),
+            IncompleteReason::TrailingOperator("||")
+        );
+        assert_eq!(is_input_complete("sleep 10 &"), CompletionStatus::Complete);
+        assert_eq!(is_input_complete("ls | wc -l"), CompletionStatus::Complete);
+        assert_eq!(is_input_complete("echo a\\ b"), CompletionStatus::Complete);
+        assert_eq!(is_input_complete(""), CompletionStatus::Complete);
+    }
+}
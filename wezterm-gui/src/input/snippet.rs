@@ -0,0 +1,117 @@
+//! Abbreviations and snippet templates for the input editor
+//!
+//! A template is plain text with numbered placeholders: `${1}` marks an
+//! empty stop and `${2:default}` one with default text.  Stops are visited
+//! in number order, with `${0}` (if present) last.
+
+use std::ops::Range;
+
+/// A parsed snippet template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    /// Expanded text with placeholder markers replaced by their defaults
+    pub text: String,
+    /// Char ranges of the placeholders within `text`, in visiting order
+    pub placeholders: Vec<Range<usize>>,
+}
+
+impl Snippet {
+    /// Parse a template; anything that isn't a valid placeholder is literal
+    pub fn parse(template: &str) -> Self {
+        let mut text = String::new();
+        let mut len = 0;
+        let mut stops: Vec<(usize, Range<usize>)> = Vec::new();
+        let mut rest = template;
+
+        while let Some(idx) = rest.find("${") {
+            let (before, after) = rest.split_at(idx);
+            text.push_str(before);
+            len += before.chars().count();
+
+            match parse_placeholder(&after[2..]) {
+                Some((number, default, consumed)) => {
+                    let default_len = default.chars().count();
+                    text.push_str(default);
+                    stops.push((number, len..len + default_len));
+                    len += default_len;
+                    rest = &after[2 + consumed..];
+                }
+                None => {
+                    text.push_str("${");
+                    len += 2;
+                    rest = &after[2..];
+                }
+            }
+        }
+        text.push_str(rest);
+
+        // ${0} is where the cursor ends up, so it comes after the others
+        stops.sort_by_key(|(number, range)| (*number == 0, *number, range.start));
+        Self {
+            text,
+            placeholders: stops.into_iter().map(|(_, range)| range).collect(),
+        }
+    }
+}
+
+/// Parse `N}` or `N:default}`, returning the number, default text and the
+/// bytes consumed
+fn parse_placeholder(s: &str) -> Option<(usize, &str, usize)> {
+    let digits = s.chars().take_while(char::is_ascii_digit).count();
+    if digits == 0 {
+        return None;
+    }
+    let number = s[..digits].parse().ok()?;
+    let rest = &s[digits..];
+    if rest.starts_with('}') {
+        return Some((number, "", digits + 1));
+    }
+    let default = rest.strip_prefix(':')?;
+    let end = default.find('}')?;
+    Some((number, &default[..end], digits + 1 + end + 1))
+}
+
+/// A word that expands into a snippet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Abbreviation {
+    /// Word that triggers the expansion
+    pub trigger: String,
+    /// What the trigger expands to
+    pub snippet: Snippet,
+    /// Whether the trigger fires anywhere, not just in command position
+    pub anywhere: bool,
+}
+
+/// Whether the text before a word puts that word in command position
+///
+/// That is the start of the line or right after a pipe, `;`, `&&`, `||`
+/// or an opening paren.
+pub fn is_command_position(before: &str) -> bool {
+    let before = before.trim_end();
+    before.is_empty() || before.ends_with(['|', ';', '&', '('])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_placeholders() {
+        let snippet = Snippet::parse("for ${1:f} in ${2}; do ${0}; done");
+        assert_eq!(snippet.text, "for f in ; do ; done");
+        assert_eq!(snippet.placeholders, vec![4..5, 9..9, 14..14]);
+
+        let snippet = Snippet::parse("echo ${x} ${1:ünï}$");
+        assert_eq!(snippet.text, "echo ${x} ünï$");
+        assert_eq!(snippet.placeholders, vec![10..13]);
+    }
+
+    #[test]
+    fn test_command_position() {
+        assert!(is_command_position(""));
+        assert!(is_command_position("ls | "));
+        assert!(is_command_position("make && "));
+        assert!(is_command_position("echo $("));
+        assert!(!is_command_position("git "));
+    }
+}
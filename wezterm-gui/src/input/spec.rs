@@ -0,0 +1,370 @@
+//! Option completion from fish completion files
+//!
+//! Many tools ship static fish completions, lines such as
+//! `complete -c rg -s i -l ignore-case -d 'Case insensitive'`, which say
+//! what options a command takes far more plainly than bash functions do.
+//! CompletionSpecLoader finds `<command>.fish` in the usual directories the
+//! first time a command's options are completed, and parses it again only
+//! when the file changes.  Lines it doesn't understand are skipped.
+
+use crate::input::arg_complete::{argument, FileCache};
+use crate::input::complete::CompletionInfo;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Where fish completions are installed, searched after the user's own
+const SYSTEM_DIRS: &[&str] = &[
+    "/etc/fish/completions",
+    "/usr/local/share/fish/vendor_completions.d",
+    "/usr/local/share/fish/completions",
+    "/usr/share/fish/vendor_completions.d",
+    "/usr/share/fish/completions",
+];
+
+/// Short flags of `complete` that take no value
+const SWITCHES: &str = "rxfFkAu";
+
+/// An option of a command, from its completion file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OptionSpec {
+    /// Short form, as in `-i`
+    pub short: Option<char>,
+    /// Long form without the dashes, as in `--ignore-case`
+    pub long: Option<String>,
+    /// Old-style long form with one dash, as in `-name`
+    pub old: Option<String>,
+    pub description: Option<String>,
+    /// Whether the option expects an argument (`-r` or `-x`)
+    pub takes_argument: bool,
+}
+
+impl OptionSpec {
+    /// The ways the option can be typed
+    fn spellings(&self) -> Vec<String> {
+        let short = self.short.map(|c| format!("-{}", c));
+        let old = self.old.as_ref().map(|name| format!("-{}", name));
+        let long = self.long.as_ref().map(|name| format!("--{}", name));
+        short.into_iter().chain(old).chain(long).collect()
+    }
+}
+
+/// Split a fish command line into words, removing quotes and escapes
+///
+/// Returns None for an unterminated quote.
+fn fish_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '#' if word.is_none() => break,
+            '\\' => {
+                let escaped = chars.next()?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            '\'' | '"' => {
+                let quote = c;
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        c if c == quote => break,
+                        '\\' => match chars.next()? {
+                            c if c == quote || c == '\\' || (quote == '"' && c == '$') => {
+                                word.push(c)
+                            }
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+/// Parse one `complete` statement, returning the commands it is for and
+/// the option it describes
+///
+/// None for anything else, or a statement with a flag it doesn't know.
+fn parse_complete(words: &[String]) -> Option<(Vec<String>, OptionSpec)> {
+    let (first, rest) = words.split_first()?;
+    if first != "complete" {
+        return None;
+    }
+    let mut commands = Vec::new();
+    let mut spec = OptionSpec::default();
+    let mut rest = rest.iter();
+    while let Some(word) = rest.next() {
+        // Short flags without values may be grouped, as in -rf
+        if let Some(group) = word.strip_prefix('-') {
+            if group.len() > 1 && group.chars().all(|c| SWITCHES.contains(c)) {
+                spec.takes_argument |= group.contains(['r', 'x']);
+                continue;
+            }
+        }
+        // Values follow as the next word, after "=" or run on, as in -si
+        let (flag, attached) = if let Some(long) = word.strip_prefix("--") {
+            match long.split_once('=') {
+                Some((flag, value)) => (format!("--{}", flag), Some(value.to_string())),
+                None => (word.clone(), None),
+            }
+        } else if word.starts_with('-') && word.len() > 2 {
+            let (flag, value) = word.split_at(2);
+            (flag.to_string(), Some(value.to_string()))
+        } else {
+            (word.clone(), None)
+        };
+        let mut value = || attached.clone().or_else(|| rest.next().cloned());
+        match flag.as_str() {
+            "-c" | "--command" => commands.push(value()?),
+            "-s" | "--short-option" => {
+                let value = value()?;
+                let mut chars = value.chars();
+                spec.short = Some(chars.next()?);
+                if chars.next().is_some() {
+                    return None;
+                }
+            }
+            "-l" | "--long-option" => spec.long = Some(value()?),
+            "-o" | "--old-option" => spec.old = Some(value()?),
+            "-d" | "--description" => spec.description = Some(value()?),
+            "-a" | "--arguments" | "-n" | "--condition" | "-w" | "--wraps" => {
+                value()?;
+            }
+            "-r" | "--require-parameter" | "-x" | "--exclusive" => spec.takes_argument = true,
+            "-f" | "--no-files" | "-F" | "--force-files" | "-k" | "--keep-order" | "-A"
+            | "--authoritative" | "-u" | "--unauthoritative" => {}
+            _ => return None,
+        }
+    }
+    Some((commands, spec))
+}
+
+/// Parse the options of `command` from the text of a fish completion file
+///
+/// Statements that only complete arguments, or that are for other
+/// commands, give no options.
+pub fn parse_fish_completions(text: &str, command: &str) -> Vec<OptionSpec> {
+    let mut options = Vec::new();
+    let mut statement = String::new();
+    for line in text.lines() {
+        // A trailing backslash continues the statement on the next line
+        if let Some(line) = line.strip_suffix('\\') {
+            statement.push_str(line);
+            statement.push(' ');
+            continue;
+        }
+        statement.push_str(line);
+        let parsed = fish_words(&statement)
+            .as_deref()
+            .and_then(parse_complete)
+            .filter(|(commands, spec)| {
+                commands.iter().any(|c| c == command)
+                    && (spec.short.is_some() || spec.long.is_some() || spec.old.is_some())
+            });
+        if let Some((_, spec)) = parsed {
+            options.push(spec);
+        }
+        statement.clear();
+    }
+    options
+}
+
+/// Options of commands, read from fish completion files on first use
+#[derive(Debug)]
+pub struct CompletionSpecLoader {
+    /// Directories searched for `<command>.fish`, in order
+    dirs: Vec<PathBuf>,
+    /// Parsed completion files
+    loaded: FileCache<Vec<OptionSpec>>,
+}
+
+impl Default for CompletionSpecLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompletionSpecLoader {
+    /// Create a loader searching the user's and the system's fish
+    /// completion directories
+    pub fn new() -> Self {
+        let user = dirs_next::config_dir().map(|dir| dir.join("fish").join("completions"));
+        let system = SYSTEM_DIRS.iter().map(PathBuf::from);
+        Self::with_dirs(user.into_iter().chain(system).collect())
+    }
+
+    /// Create a loader searching only `dirs`, in order
+    pub fn with_dirs(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            loaded: FileCache::default(),
+        }
+    }
+
+    /// Search `dir` before the other directories
+    pub fn add_dir(&mut self, dir: PathBuf) {
+        self.dirs.insert(0, dir);
+    }
+
+    /// Options of `command`, empty if it has no completion file
+    ///
+    /// The file is parsed again only if it changed since the last call.
+    pub fn options(&self, command: &str) -> Arc<Vec<OptionSpec>> {
+        if command.contains(['/', '\\']) {
+            return Arc::default();
+        }
+        let file_name = format!("{}.fish", command);
+        let found = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.exists());
+        let path = match found {
+            Some(path) => path,
+            None => return Arc::default(),
+        };
+        self.loaded
+            .get(&path, |path| {
+                let text = fs::read_to_string(path).unwrap_or_default();
+                Some(parse_fish_completions(&text, command))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Options of `command` that could be the `-` or `--` word being typed
+    pub fn complete(&self, command: &str, word: &str) -> Vec<CompletionInfo> {
+        if !word.starts_with('-') {
+            return Vec::new();
+        }
+        let options = self.options(command);
+        let mut items = Vec::new();
+        for option in options.iter() {
+            let description = option.description.as_deref().unwrap_or("option");
+            for spelling in option.spellings() {
+                // "-" alone wants everything, "-x" short and old options
+                let wanted = word == "-"
+                    || spelling.starts_with(word)
+                        && (word.starts_with("--") || !spelling.starts_with("--"));
+                if wanted {
+                    items.push(argument(&spelling, description, word));
+                }
+            }
+        }
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    const RG_FISH: &str = include_str!("testdata/rg.fish");
+
+    fn long(name: &str, description: &str, takes_argument: bool) -> OptionSpec {
+        OptionSpec {
+            long: Some(name.to_string()),
+            description: Some(description.to_string()),
+            takes_argument,
+            ..OptionSpec::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_fixture() {
+        let options = parse_fish_completions(RG_FISH, "rg");
+        let names: Vec<String> = options.iter().map(|o| o.spellings().join(" ")).collect();
+        assert_eq!(
+            names,
+            [
+                "-i --ignore-case",
+                "-S --smart-case",
+                "-g --glob",
+                "-t --type",
+                "--max-depth",
+                "--sort",
+                "-hidden-old",
+                "--no-ignore",
+                "-h --help",
+            ]
+        );
+        assert_eq!(options[1].description.as_deref(), Some("Smart case search"));
+        assert!(!options[0].takes_argument);
+        assert!(options[2].takes_argument);
+        assert!(options[3].takes_argument);
+        assert_eq!(
+            options[4],
+            long("max-depth", "Descend at most NUM directories", true)
+        );
+        assert_eq!(options[5], long("sort", "Sort results", true));
+        assert_eq!(
+            options[7].description.as_deref(),
+            Some("Don't respect ignore files")
+        );
+        assert!(parse_fish_completions(RG_FISH, "other")[0].long.as_deref() == Some("not-rg"));
+    }
+
+    #[test]
+    fn test_fish_words() {
+        assert_eq!(
+            fish_words(r#"complete -d "say \"hi\"" -a 'a\'b' x\ y # comment"#),
+            Some(
+                ["complete", "-d", "say \"hi\"", "-a", "a'b", "x y"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(fish_words("complete -d 'open"), None);
+    }
+
+    #[test]
+    fn test_loader_completes_and_reloads() {
+        let user = tempfile::tempdir().unwrap();
+        let system = tempfile::tempdir().unwrap();
+        let path = system.path().join("rg.fish");
+        fs::write(&path, RG_FISH).unwrap();
+        let mut loader = CompletionSpecLoader::with_dirs(vec![system.path().to_path_buf()]);
+        loader.add_dir(user.path().to_path_buf());
+
+        let texts = |word: &str| -> Vec<String> {
+            loader
+                .complete("rg", word)
+                .into_iter()
+                .map(|item| item.text)
+                .collect()
+        };
+        assert_eq!(texts("--s"), ["--smart-case", "--sort"]);
+        assert_eq!(texts("-h"), ["-hidden-old", "-h"]);
+        assert_eq!(texts("-").len(), 14);
+        assert!(texts("s").is_empty());
+        assert!(loader.complete("fd", "--").is_empty());
+        let items = loader.complete("rg", "--ig");
+        assert_eq!(
+            items[0].description.as_deref(),
+            Some("Case insensitive search")
+        );
+
+        // A user's file comes first, and changes are picked up
+        let user_file = user.path().join("rg.fish");
+        fs::write(&user_file, "complete -c rg -l mine\n").unwrap();
+        assert_eq!(texts("--"), ["--mine"]);
+        fs::write(
+            &user_file,
+            "complete -c rg -l mine -d 'Changed'\ncomplete -c rg -l more\n",
+        )
+        .unwrap();
+        let file = fs::File::options().write(true).open(&user_file).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(texts("--"), ["--mine", "--more"]);
+    }
+}
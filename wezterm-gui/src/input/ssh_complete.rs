@@ -0,0 +1,536 @@
+//! Host and remote path completion for ssh, scp, rsync and sftp
+//!
+//! Hosts come from the Host lines of ~/.ssh/config and from known_hosts.
+//! For scp-style targets such as `user@host:path`, the part before the
+//! colon completes to a host and the part after it to a remote path.
+//! Remote paths are listed with `ssh host ls` over an existing
+//! multiplexed connection only, and only when the user opted in: it is
+//! slow, and shows up in the logs of audited servers.
+
+use crate::input::arg_complete::{argument, output_capped, ArgCompleter, CompletionContext};
+use crate::input::complete::{CompletionInfo, CompletionKind, QuotingContext};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Longest a remote listing may take, connection check included
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most output read from a remote listing
+const MAX_REMOTE_OUTPUT: usize = 64 * 1024;
+/// Most remote entries offered
+const MAX_REMOTE_ENTRIES: usize = 200;
+
+/// Options of scp, rsync, sftp and ssh whose value is the next word
+const VALUE_OPTIONS: &[&str] = &[
+    "-b", "-c", "-D", "-e", "-F", "-i", "-J", "-l", "-L", "-o", "-p", "-P", "-R", "-S", "-W",
+];
+
+/// A `[user@]host:path` argument of scp or rsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteTarget<'a> {
+    pub user: Option<&'a str>,
+    pub host: &'a str,
+    pub path: &'a str,
+}
+
+impl RemoteTarget<'_> {
+    /// The target up to and including the colon, as it would be typed
+    pub fn prefix(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.to_string()
+        };
+        match self.user {
+            Some(user) => format!("{}@{}:", user, host),
+            None => format!("{}:", host),
+        }
+    }
+}
+
+/// Split `word` into user, host and path if it names a remote file
+///
+/// Like scp, a word is remote when a colon comes before any slash; IPv6
+/// hosts go in brackets, as in `[::1]:path`.
+pub fn parse_remote_target(word: &str) -> Option<RemoteTarget<'_>> {
+    let colon = word.find(':')?;
+    let slash = word.find('/').unwrap_or(word.len());
+    let at = word.find('@').filter(|&at| at < colon.min(slash));
+    let (user, rest) = match at {
+        Some(at) => (Some(&word[..at]), &word[at + 1..]),
+        None => (None, word),
+    };
+    let (host, path) = match rest.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']')?;
+            (host, after.strip_prefix(':')?)
+        }
+        None => {
+            let (host, path) = rest.split_once(':')?;
+            if host.contains('/') {
+                return None;
+            }
+            (host, path)
+        }
+    };
+    // "C:\..." is a drive, not a host
+    let drive = cfg!(windows) && host.len() == 1 && user.is_none();
+    if host.is_empty() || drive || user == Some("") {
+        return None;
+    }
+    // ssh would take these for options
+    if host.starts_with('-') || user.is_some_and(|user| user.starts_with('-')) {
+        return None;
+    }
+    Some(RemoteTarget { user, host, path })
+}
+
+/// Hosts found in ssh's Host lines, leaving out patterns and negations
+fn parse_ssh_config_hosts(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (keyword, rest) = line.split_once(|c: char| c.is_whitespace() || c == '=')?;
+            keyword.eq_ignore_ascii_case("host").then_some(rest)
+        })
+        .flat_map(|rest| {
+            rest.split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|host| !host.is_empty() && !host.contains(['*', '?', '!']))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Hosts in known_hosts lines, leaving out hashed entries
+fn parse_known_hosts(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut hosts = fields.next()?;
+            if hosts.starts_with('@') {
+                hosts = fields.next()?;
+            }
+            (!hosts.starts_with(['#', '|'])).then_some(hosts)
+        })
+        .flat_map(|hosts| hosts.split(','))
+        .filter_map(|host| {
+            // "[host]:2222" is a host on another port
+            let host = match host.strip_prefix('[') {
+                Some(bracketed) => bracketed.split_once(']')?.0,
+                None => host,
+            };
+            (!host.is_empty() && !host.contains(['*', '?', '!'])).then(|| host.to_string())
+        })
+        .collect()
+}
+
+/// Knows the hosts to offer
+pub trait HostSource: Send + Sync {
+    fn hosts(&self) -> Vec<String>;
+}
+
+/// Hosts from the user's ssh config and the known_hosts files
+#[derive(Debug, Clone)]
+pub struct SshHosts {
+    configs: Vec<PathBuf>,
+    known_hosts: Vec<PathBuf>,
+}
+
+impl Default for SshHosts {
+    fn default() -> Self {
+        let ssh = dirs_next::home_dir().map(|home| home.join(".ssh"));
+        Self {
+            configs: ssh.iter().map(|ssh| ssh.join("config")).collect(),
+            known_hosts: ssh
+                .iter()
+                .map(|ssh| ssh.join("known_hosts"))
+                .chain(Some(PathBuf::from("/etc/ssh/ssh_known_hosts")))
+                .collect(),
+        }
+    }
+}
+
+impl HostSource for SshHosts {
+    fn hosts(&self) -> Vec<String> {
+        let read = |path: &PathBuf| fs::read_to_string(path).unwrap_or_default();
+        let configured = self
+            .configs
+            .iter()
+            .flat_map(|p| parse_ssh_config_hosts(&read(p)));
+        let known = self
+            .known_hosts
+            .iter()
+            .flat_map(|p| parse_known_hosts(&read(p)));
+        let mut seen = HashSet::new();
+        configured
+            .chain(known)
+            .filter(|host| seen.insert(host.clone()))
+            .collect()
+    }
+}
+
+/// Lists directories on remote hosts
+pub trait RemoteLister: Send + Sync {
+    /// `ls -1p` of `dir` on `host`, or None if it can't be had quickly
+    fn list(&self, host: &str, dir: &str) -> Option<String>;
+}
+
+/// Lists remote directories with ssh, over a running master connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemSsh;
+
+impl RemoteLister for SystemSsh {
+    fn list(&self, host: &str, dir: &str) -> Option<String> {
+        let deadline = Instant::now() + REMOTE_TIMEOUT;
+        // Without a master connection ssh would have to log in first
+        let mut check = Command::new("ssh");
+        check.args(["-o", "BatchMode=yes", "-O", "check", "--", host]);
+        output_capped(&mut check, REMOTE_TIMEOUT, 0)?;
+
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return None;
+        }
+        let dir = remote_dir_arg(dir)?;
+        let mut ls = Command::new("ssh");
+        ls.args(["-o", "BatchMode=yes", "--", host, "ls", "-1p", "--", &dir]);
+        output_capped(&mut ls, left, MAX_REMOTE_OUTPUT)
+    }
+}
+
+/// Quote `dir` for the remote shell, leaving a leading `~/` for it to
+/// expand
+fn remote_dir_arg(dir: &str) -> Option<String> {
+    let (home, rest) = match dir.strip_prefix("~/") {
+        Some(rest) => ("~/", rest),
+        None if dir == "~" => return Some(dir.to_string()),
+        None => ("", dir),
+    };
+    if rest.is_empty() {
+        return Some(home.to_string());
+    }
+    Some(format!("{}{}", home, shlex::try_quote(rest).ok()?))
+}
+
+/// Completes hosts for ssh, and hosts and remote paths for scp, rsync
+/// and sftp
+pub struct SshCompleter {
+    hosts: Box<dyn HostSource>,
+    remote: Box<dyn RemoteLister>,
+    /// Whether remote paths are listed; off unless opted in
+    remote_paths: AtomicBool,
+}
+
+impl Default for SshCompleter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SshCompleter {
+    /// Create a completer for the user's hosts, listing with ssh
+    pub fn new() -> Self {
+        Self::with_sources(Box::new(SshHosts::default()), Box::new(SystemSsh))
+    }
+
+    /// Create a completer taking hosts from `hosts` and listing remote
+    /// directories with `remote`
+    pub fn with_sources(hosts: Box<dyn HostSource>, remote: Box<dyn RemoteLister>) -> Self {
+        Self {
+            hosts,
+            remote,
+            remote_paths: AtomicBool::new(false),
+        }
+    }
+
+    /// Allow or forbid listing remote directories
+    pub fn set_remote_paths(&self, enabled: bool) {
+        self.remote_paths.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Hosts starting with the host part of `word`, each followed by
+    /// `suffix`
+    fn complete_hosts(&self, word: &str, suffix: &str) -> Vec<CompletionInfo> {
+        let (user, typed) = match word.split_once('@') {
+            Some((user, host)) => (format!("{}@", user), host),
+            None => (String::new(), word),
+        };
+        self.hosts
+            .hosts()
+            .iter()
+            .filter(|host| host.starts_with(typed))
+            .map(|host| {
+                let text = format!("{}{}{}", user, host, suffix);
+                // No space after "host:", where a path goes next
+                CompletionInfo {
+                    is_directory: !suffix.is_empty(),
+                    ..argument(&text, "host", word)
+                }
+            })
+            .collect()
+    }
+
+    /// Remote entries matching `target`, keeping its `user@host:` prefix
+    fn complete_remote(&self, target: &RemoteTarget) -> Vec<CompletionInfo> {
+        if !self.remote_paths.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+        let (dir, name) = match target.path.rfind('/') {
+            Some(slash) => target.path.split_at(slash + 1),
+            None => ("", target.path),
+        };
+        // The path is typed with the local shell's escapes, and the
+        // entries go back with them
+        let quoting = QuotingContext::None;
+        let (remote_dir, name) = (quoting.unquote(dir), quoting.unquote(name));
+        let host = match target.user {
+            Some(user) => format!("{}@{}", user, target.host),
+            None => target.host.to_string(),
+        };
+        let listing = match self.remote.list(
+            &host,
+            if remote_dir.is_empty() {
+                "."
+            } else {
+                &remote_dir
+            },
+        ) {
+            Some(listing) => listing,
+            None => return Vec::new(),
+        };
+        let prefix = format!("{}{}", target.prefix(), dir);
+        let typed = target.prefix().chars().count() + target.path.chars().count();
+        listing
+            .lines()
+            .filter(|entry| entry.starts_with(&name) && !entry.is_empty())
+            .take(MAX_REMOTE_ENTRIES)
+            .map(|entry| {
+                let is_directory = entry.ends_with('/');
+                CompletionInfo {
+                    text: format!("{}{}", prefix, quoting.quote(entry)),
+                    description: Some("remote".to_string()),
+                    is_directory,
+                    kind: if is_directory {
+                        CompletionKind::Directory
+                    } else {
+                        CompletionKind::File
+                    },
+                    match_indices: (0..typed).collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl ArgCompleter for SshCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        let after_option = ctx
+            .previous_arg()
+            .is_some_and(|arg| VALUE_OPTIONS.contains(&arg));
+        if ctx.word.starts_with('-') || after_option {
+            return None;
+        }
+        if ctx.command == "ssh" {
+            // The host comes first; the rest is the remote command
+            let mut args = ctx.args.iter();
+            while let Some(arg) = args.next() {
+                if VALUE_OPTIONS.contains(arg) {
+                    args.next();
+                } else if !arg.starts_with('-') {
+                    return None;
+                }
+            }
+            return Some(self.complete_hosts(ctx.word, ""));
+        }
+        if let Some(target) = parse_remote_target(ctx.word) {
+            return Some(self.complete_remote(&target));
+        }
+        // Local paths are offered along with the hosts
+        let local = ctx.word.starts_with(['/', '.', '~']) || ctx.word.contains('/');
+        if local {
+            return None;
+        }
+        Some(self.complete_hosts(ctx.word, ":"))
+    }
+
+    fn with_paths(&self, ctx: &CompletionContext) -> bool {
+        ctx.command != "ssh" && parse_remote_target(ctx.word).is_none()
+    }
+}
+
+impl std::fmt::Debug for SshCompleter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshCompleter")
+            .field("remote_paths", &self.remote_paths)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    struct FakeHosts;
+
+    impl HostSource for FakeHosts {
+        fn hosts(&self) -> Vec<String> {
+            ["myhost", "mybox", "build.example.com"]
+                .iter()
+                .map(|host| host.to_string())
+                .collect()
+        }
+    }
+
+    /// Hosts and directories listed so far
+    type Calls = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// A remote home with a src directory, recording what was listed
+    struct FakeSsh(Calls);
+
+    impl RemoteLister for FakeSsh {
+        fn list(&self, host: &str, dir: &str) -> Option<String> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((host.to_string(), dir.to_string()));
+            Some("notes.txt\nsrc/\nsetup.sh\nmy file.txt\n".to_string())
+        }
+    }
+
+    fn completer() -> (SshCompleter, Calls) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let completer =
+            SshCompleter::with_sources(Box::new(FakeHosts), Box::new(FakeSsh(Arc::clone(&calls))));
+        (completer, calls)
+    }
+
+    fn complete(
+        completer: &SshCompleter,
+        command: &str,
+        args: &[&str],
+        word: &str,
+    ) -> Option<Vec<String>> {
+        let ctx = CompletionContext {
+            command,
+            args: args.to_vec(),
+            word,
+            cwd: PathBuf::from("/"),
+            env: HashMap::new(),
+            sudo: false,
+        };
+        let items = completer.complete(&ctx)?;
+        Some(items.into_iter().map(|item| item.text).collect())
+    }
+
+    #[test]
+    fn test_parse_remote_target() {
+        let target = |user, host, path| Some(RemoteTarget { user, host, path });
+        assert_eq!(parse_remote_target("myhost:"), target(None, "myhost", ""));
+        assert_eq!(
+            parse_remote_target("me@myhost:src/a:b"),
+            target(Some("me"), "myhost", "src/a:b")
+        );
+        assert_eq!(
+            parse_remote_target("me@[fe80::1]:/tmp"),
+            target(Some("me"), "fe80::1", "/tmp")
+        );
+        assert_eq!(parse_remote_target("./a:b"), None);
+        assert_eq!(parse_remote_target("dir/a:b"), None);
+        assert_eq!(parse_remote_target("file.txt"), None);
+        assert_eq!(parse_remote_target(":path"), None);
+        assert_eq!(parse_remote_target("@host:path"), None);
+        assert_eq!(parse_remote_target("[-oProxyCommand=sh]:x"), None);
+        assert_eq!(parse_remote_target("me@[-F/tmp/cfg]:x"), None);
+
+        for word in ["myhost:", "me@myhost:", "me@[fe80::1]:"] {
+            assert_eq!(parse_remote_target(word).unwrap().prefix(), word);
+        }
+    }
+
+    #[test]
+    fn test_host_sources() {
+        let config = "Host myhost mybox\n  HostName 10.0.0.2\nHost *.internal !bad\n\
+                      host=build\nMatch host foo\n";
+        assert_eq!(parse_ssh_config_hosts(config), ["myhost", "mybox", "build"]);
+        let known = "github.com,140.82.112.3 ssh-ed25519 AAAA\n\
+                     [git.example.com]:2222 ssh-rsa AAAA\n\
+                     |1|hashed= ssh-rsa AAAA\n\
+                     @cert-authority *.example.com ssh-rsa AAAA\n\
+                     # comment\n";
+        assert_eq!(
+            parse_known_hosts(known),
+            ["github.com", "140.82.112.3", "git.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_hosts_before_the_colon() {
+        let (completer, calls) = completer();
+        assert_eq!(
+            complete(&completer, "scp", &["file"], "my").unwrap(),
+            ["myhost:", "mybox:"]
+        );
+        assert_eq!(
+            complete(&completer, "rsync", &["-av"], "me@b").unwrap(),
+            ["me@build.example.com:"]
+        );
+        assert_eq!(
+            complete(&completer, "ssh", &["-p", "22"], "my").unwrap(),
+            ["myhost", "mybox"]
+        );
+        assert!(complete(&completer, "ssh", &["myhost"], "ls").is_none());
+        assert!(complete(&completer, "scp", &["-i"], "my").is_none());
+        assert!(complete(&completer, "scp", &[], "./my").is_none());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remote_paths_are_opt_in() {
+        let (completer, calls) = completer();
+        assert_eq!(
+            complete(&completer, "scp", &["file"], "me@myhost:s").unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(calls.lock().unwrap().is_empty());
+
+        completer.set_remote_paths(true);
+        assert_eq!(
+            complete(&completer, "scp", &["file"], "me@myhost:s").unwrap(),
+            ["me@myhost:src/", "me@myhost:setup.sh"]
+        );
+        assert_eq!(
+            complete(&completer, "scp", &["file"], "myhost:/srv/n").unwrap(),
+            ["myhost:/srv/notes.txt"]
+        );
+        assert_eq!(
+            complete(&completer, "scp", &["file"], "myhost:~/work\\ dir/my").unwrap(),
+            ["myhost:~/work\\ dir/my\\ file.txt"]
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ("me@myhost".to_string(), ".".to_string()),
+                ("myhost".to_string(), "/srv/".to_string()),
+                ("myhost".to_string(), "~/work dir/".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_dir_arg() {
+        assert_eq!(remote_dir_arg("~").as_deref(), Some("~"));
+        assert_eq!(remote_dir_arg("~/").as_deref(), Some("~/"));
+        assert_eq!(
+            remote_dir_arg("~/work dir/").as_deref(),
+            Some("~/'work dir/'")
+        );
+        assert_eq!(remote_dir_arg("/srv/").as_deref(), Some("/srv/"));
+        assert_eq!(remote_dir_arg("~bob/x").as_deref(), Some("'~bob/x'"));
+    }
+}
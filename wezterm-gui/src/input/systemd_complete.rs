@@ -0,0 +1,498 @@
+//! Unit name completion for systemctl and journalctl
+//!
+//! SystemdCompleter lists the unit files in systemd's unit directories,
+//! taking each one's Description= line as the description.  Where systemctl
+//! can be asked quickly it also knows the loaded units, so stop and restart
+//! offer what is running while enable and disable offer what is installed.
+//! A directory is scanned again only when its modification time changes.
+
+use crate::input::arg_complete::{
+    argument, output_with_timeout, ArgCompleter, CompletionContext, FileCache,
+};
+use crate::input::complete::CompletionInfo;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Unit directories of the system manager, in order of precedence
+const SYSTEM_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/run/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+
+/// Unit directories of the user manager below the config directory, and
+/// shared ones
+const USER_UNIT_DIRS: &[&str] = &["/etc/systemd/user", "/usr/lib/systemd/user"];
+
+/// Kinds of unit offered
+const UNIT_SUFFIXES: &[&str] = &[".service", ".timer", ".socket"];
+
+/// Longest systemctl may take to list the loaded units
+const SYSTEMCTL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long a listing of the loaded units is reused
+const RUNTIME_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// systemctl subcommands offered before a unit
+const SYSTEMCTL_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("cat", "Show the unit files"),
+    ("daemon-reload", "Reload the unit files"),
+    ("disable", "Disable units"),
+    ("edit", "Edit a unit file"),
+    ("enable", "Enable units"),
+    ("is-active", "Check whether units are active"),
+    ("is-enabled", "Check whether units are enabled"),
+    ("is-failed", "Check whether units have failed"),
+    ("kill", "Send a signal to the processes of units"),
+    ("list-timers", "List the timers"),
+    ("list-unit-files", "List the installed unit files"),
+    ("list-units", "List the loaded units"),
+    ("mask", "Mask units"),
+    ("reenable", "Reenable units"),
+    ("reload", "Reload units"),
+    ("restart", "Restart units"),
+    ("show", "Show the properties of units"),
+    ("start", "Start units"),
+    ("status", "Show the status of units"),
+    ("stop", "Stop units"),
+    ("try-restart", "Restart units if running"),
+    ("unmask", "Unmask units"),
+];
+
+/// Subcommands acting on installed unit files
+const FILE_SUBCOMMANDS: &[&str] = &[
+    "cat",
+    "disable",
+    "edit",
+    "enable",
+    "is-enabled",
+    "mask",
+    "reenable",
+    "start",
+    "unmask",
+];
+
+/// Subcommands acting on loaded units
+const RUNTIME_SUBCOMMANDS: &[&str] = &[
+    "is-active",
+    "is-failed",
+    "kill",
+    "reload",
+    "restart",
+    "show",
+    "status",
+    "stop",
+    "try-restart",
+];
+
+/// A unit file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// Loaded units by whether they are the user's, with when they were listed
+type RuntimeCache = HashMap<bool, (Instant, Option<Arc<Vec<UnitFile>>>)>;
+
+/// Lists the units systemd has loaded
+pub trait RuntimeUnits: Send + Sync {
+    /// Names and descriptions of the loaded units, or None if unknown
+    fn loaded_units(&self, user: bool) -> Option<Vec<UnitFile>>;
+}
+
+/// Asks systemctl for the loaded units
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Systemctl;
+
+impl RuntimeUnits for Systemctl {
+    fn loaded_units(&self, user: bool) -> Option<Vec<UnitFile>> {
+        let mut command = Command::new("systemctl");
+        if user {
+            command.arg("--user");
+        }
+        command.args(["list-units", "--plain", "--no-legend"]);
+        let out = output_with_timeout(&mut command, SYSTEMCTL_TIMEOUT)?;
+        Some(parse_list_units(&out))
+    }
+}
+
+/// Parse the lines of `systemctl list-units --plain --no-legend`, which
+/// hold the unit, its load, active and sub states, then its description
+fn parse_list_units(out: &str) -> Vec<UnitFile> {
+    out.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?.to_string();
+            let description: Vec<&str> = fields.skip(3).collect();
+            Some(UnitFile {
+                name,
+                description: (!description.is_empty()).then(|| description.join(" ")),
+            })
+        })
+        .collect()
+}
+
+/// The Description= value of a unit file's text
+fn unit_description(text: &str) -> Option<String> {
+    text.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "Description").then(|| value.trim().to_string())
+    })
+}
+
+/// Check if `name` is a unit that can be offered; templates such as
+/// `getty@.service` need an instance first
+fn is_unit_name(name: &str) -> bool {
+    UNIT_SUFFIXES.iter().any(|suffix| {
+        name.strip_suffix(suffix)
+            .is_some_and(|stem| !stem.is_empty() && !stem.ends_with('@'))
+    })
+}
+
+/// Unit files in `dir`, sorted by name
+fn scan_unit_dir(dir: &Path) -> Vec<UnitFile> {
+    let mut units: Vec<UnitFile> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            if !is_unit_name(&name) {
+                return None;
+            }
+            // Masked units link to /dev/null, which reads as empty
+            let text = fs::read_to_string(entry.path()).unwrap_or_default();
+            Some(UnitFile {
+                description: unit_description(&text),
+                name,
+            })
+        })
+        .collect();
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+    units
+}
+
+/// Completes systemd units for systemctl and journalctl
+pub struct SystemdCompleter {
+    system_dirs: Vec<PathBuf>,
+    user_dirs: Vec<PathBuf>,
+    runtime: Option<Box<dyn RuntimeUnits>>,
+    dir_cache: FileCache<Vec<UnitFile>>,
+    runtime_cache: Mutex<RuntimeCache>,
+}
+
+impl Default for SystemdCompleter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemdCompleter {
+    /// Create a completer for the system's unit directories and systemctl
+    pub fn new() -> Self {
+        let config = dirs_next::config_dir().map(|dir| dir.join("systemd").join("user"));
+        let user_dirs = config
+            .into_iter()
+            .chain(USER_UNIT_DIRS.iter().map(PathBuf::from))
+            .collect();
+        let system_dirs = SYSTEM_UNIT_DIRS.iter().map(PathBuf::from).collect();
+        let mut completer = Self::with_dirs(system_dirs, user_dirs);
+        completer.set_runtime(Some(Box::new(Systemctl)));
+        completer
+    }
+
+    /// Create a completer reading units from the given directories, without
+    /// asking for the loaded units
+    pub fn with_dirs(system_dirs: Vec<PathBuf>, user_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            system_dirs,
+            user_dirs,
+            runtime: None,
+            dir_cache: FileCache::default(),
+            runtime_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask `runtime` for the loaded units, or only read unit files with None
+    pub fn set_runtime(&mut self, runtime: Option<Box<dyn RuntimeUnits>>) {
+        self.runtime = runtime;
+        self.runtime_cache.lock().unwrap().clear();
+    }
+
+    /// Unit files in `dir`, scanned again if it changed
+    fn scan(&self, dir: &Path) -> Arc<Vec<UnitFile>> {
+        self.dir_cache
+            .get(dir, |dir| Some(scan_unit_dir(dir)))
+            .unwrap_or_default()
+    }
+
+    /// Installed units by name, the first directory's file winning
+    fn unit_files(&self, user: bool) -> BTreeMap<String, Option<String>> {
+        let dirs = if user {
+            &self.user_dirs
+        } else {
+            &self.system_dirs
+        };
+        let mut units = BTreeMap::new();
+        for dir in dirs {
+            for unit in self.scan(dir).iter() {
+                units
+                    .entry(unit.name.clone())
+                    .or_insert_with(|| unit.description.clone());
+            }
+        }
+        units
+    }
+
+    /// Loaded units, if the runtime can be asked
+    fn loaded_units(&self, user: bool) -> Option<Arc<Vec<UnitFile>>> {
+        let runtime = self.runtime.as_ref()?;
+        let mut cache = self.runtime_cache.lock().unwrap();
+        if let Some((taken, units)) = cache.get(&user) {
+            if taken.elapsed() < RUNTIME_CACHE_TTL {
+                return units.clone();
+            }
+        }
+        let units = runtime.loaded_units(user).map(Arc::new);
+        cache.insert(user, (Instant::now(), units.clone()));
+        units
+    }
+
+    /// Units for `subcommand`: loaded ones for those acting on running
+    /// units when they are known, else the installed ones, or both
+    fn units(&self, subcommand: Option<&str>, user: bool) -> BTreeMap<String, Option<String>> {
+        let mut units = self.unit_files(user);
+        let prefer_files = subcommand.is_some_and(|name| FILE_SUBCOMMANDS.contains(&name));
+        if prefer_files {
+            return units;
+        }
+        let loaded = match self.loaded_units(user) {
+            Some(loaded) => loaded,
+            None => return units,
+        };
+        let prefer_loaded = subcommand.is_some_and(|name| RUNTIME_SUBCOMMANDS.contains(&name));
+        if prefer_loaded {
+            units = loaded
+                .iter()
+                .filter(|unit| is_unit_name(&unit.name))
+                .map(|unit| {
+                    let description = unit
+                        .description
+                        .clone()
+                        .or_else(|| units.get(&unit.name).cloned().flatten());
+                    (unit.name.clone(), description)
+                })
+                .collect();
+        } else {
+            for unit in loaded.iter().filter(|unit| is_unit_name(&unit.name)) {
+                units
+                    .entry(unit.name.clone())
+                    .or_insert_with(|| unit.description.clone());
+            }
+        }
+        units
+    }
+}
+
+impl ArgCompleter for SystemdCompleter {
+    fn complete(&self, ctx: &CompletionContext) -> Option<Vec<CompletionInfo>> {
+        let user = ctx.args.contains(&"--user");
+        let (subcommand, lead, typed) = match ctx.command {
+            "journalctl" => match ctx.word.strip_prefix("--unit=") {
+                Some(typed) => (None, "--unit=", typed),
+                None if matches!(ctx.previous_arg(), Some("-u" | "--unit")) => (None, "", ctx.word),
+                None => return None,
+            },
+            _ if ctx.word.starts_with('-') => return None,
+            _ => match ctx.args.iter().find(|arg| !arg.starts_with('-')) {
+                Some(subcommand) => (Some(*subcommand), "", ctx.word),
+                None => {
+                    let items = SYSTEMCTL_SUBCOMMANDS
+                        .iter()
+                        .filter(|(name, _)| name.starts_with(ctx.word))
+                        .map(|(name, description)| argument(name, description, ctx.word))
+                        .collect();
+                    return Some(items);
+                }
+            },
+        };
+        let items = self
+            .units(subcommand, user)
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(typed))
+            .map(|(name, description)| {
+                let text = format!("{}{}", lead, name);
+                argument(&text, description.as_deref().unwrap_or("unit"), ctx.word)
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+impl std::fmt::Debug for SystemdCompleter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SystemdCompleter")
+            .field("system_dirs", &self.system_dirs)
+            .field("user_dirs", &self.user_dirs)
+            .field("runtime", &self.runtime.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str)] = &[
+        (
+            "nginx.service",
+            include_str!("testdata/systemd/nginx.service"),
+        ),
+        (
+            "backup.service",
+            include_str!("testdata/systemd/backup.service"),
+        ),
+        (
+            "backup.timer",
+            include_str!("testdata/systemd/backup.timer"),
+        ),
+        ("dbus.socket", include_str!("testdata/systemd/dbus.socket")),
+        (
+            "getty@.service",
+            include_str!("testdata/systemd/getty@.service"),
+        ),
+        ("README", include_str!("testdata/systemd/README")),
+    ];
+
+    /// A unit directory holding the fixture units
+    fn unit_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in FIXTURES {
+            fs::write(dir.path().join(name), text).unwrap();
+        }
+        dir
+    }
+
+    /// Loaded units: nginx, and a transient unit with no file
+    struct FakeRuntime;
+
+    impl RuntimeUnits for FakeRuntime {
+        fn loaded_units(&self, _user: bool) -> Option<Vec<UnitFile>> {
+            Some(parse_list_units(
+                "nginx.service loaded active running\n\
+                 run-r1.service loaded active running /usr/bin/sleep 60\n\
+                 -.mount loaded active mounted Root Mount\n",
+            ))
+        }
+    }
+
+    fn complete(
+        completer: &SystemdCompleter,
+        command: &'static str,
+        args: &[&'static str],
+        word: &'static str,
+    ) -> Option<Vec<(String, String)>> {
+        let ctx = CompletionContext {
+            command,
+            args: args.to_vec(),
+            word,
+            cwd: PathBuf::from("/"),
+            env: HashMap::new(),
+            sudo: false,
+        };
+        let items = completer.complete(&ctx)?;
+        Some(
+            items
+                .into_iter()
+                .map(|item| (item.text, item.description.unwrap_or_default()))
+                .collect(),
+        )
+    }
+
+    fn names(items: Option<Vec<(String, String)>>) -> Vec<String> {
+        items.unwrap().into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_unit_files() {
+        let dir = unit_dir();
+        let completer = SystemdCompleter::with_dirs(vec![dir.path().to_path_buf()], vec![]);
+        let units = complete(&completer, "systemctl", &["restart"], "").unwrap();
+        let expected = [
+            ("backup.service", "Back up /home"),
+            ("backup.timer", "Nightly backup"),
+            ("dbus.socket", "unit"),
+            (
+                "nginx.service",
+                "A high performance web server and a reverse proxy server",
+            ),
+        ];
+        assert_eq!(
+            units,
+            expected.map(|(name, description)| (name.to_string(), description.to_string()))
+        );
+        assert_eq!(
+            names(complete(&completer, "systemctl", &["restart"], "ng")),
+            ["nginx.service"]
+        );
+        assert_eq!(
+            names(complete(&completer, "systemctl", &[], "res")),
+            ["restart"]
+        );
+        assert!(names(complete(&completer, "systemctl", &["--user", "start"], "")).is_empty());
+
+        // A new unit changes the directory, so it is scanned again
+        fs::write(dir.path().join("redis.service"), "[Unit]\n").unwrap();
+        assert_eq!(
+            names(complete(&completer, "systemctl", &["enable"], "r")),
+            ["redis.service"]
+        );
+    }
+
+    #[test]
+    fn test_subcommands_prefer_files_or_loaded_units() {
+        let dir = unit_dir();
+        let mut completer = SystemdCompleter::with_dirs(vec![dir.path().to_path_buf()], vec![]);
+        completer.set_runtime(Some(Box::new(FakeRuntime)));
+
+        // stop offers what runs, with the file's description if needed
+        assert_eq!(
+            complete(&completer, "systemctl", &["stop"], "").unwrap(),
+            [
+                (
+                    "nginx.service".to_string(),
+                    "A high performance web server and a reverse proxy server".to_string()
+                ),
+                (
+                    "run-r1.service".to_string(),
+                    "/usr/bin/sleep 60".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            names(complete(&completer, "systemctl", &["enable"], "")),
+            [
+                "backup.service",
+                "backup.timer",
+                "dbus.socket",
+                "nginx.service"
+            ]
+        );
+        // journalctl takes both
+        assert_eq!(
+            names(complete(&completer, "journalctl", &["-b", "-u"], "")).len(),
+            5
+        );
+        assert_eq!(
+            names(complete(&completer, "journalctl", &[], "--unit=ru")),
+            ["--unit=run-r1.service"]
+        );
+        assert!(complete(&completer, "journalctl", &[], "").is_none());
+    }
+}
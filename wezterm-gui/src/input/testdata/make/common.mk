@@ -0,0 +1,5 @@
+# Shared rules, included by the Makefile
+lint:
+	cargo clippy
+
+release: all
@@ -0,0 +1,15 @@
+# A trimmed copy of ripgrep's fish completions, for the spec parser tests
+
+complete -c rg -s i -l ignore-case -d 'Case insensitive search'
+complete -c rg -s S -l smart-case -d "Smart case search"
+complete -c rg -s g -l glob -d 'Include or exclude files' -r
+complete -c rg -s t -l type -d 'Only search files matching TYPE' -x -a '(rg --type-list | string replace : \t)'
+complete -c rg -l max-depth -d 'Descend at most NUM directories' -r -f
+complete -c rg -l sort -d 'Sort results' \
+    -x -a 'path modified accessed created none'
+complete -c rg -o hidden-old -d 'An old-style option'
+complete -c rg -n '__fish_seen_subcommand_from foo' -l no-ignore -d 'Don\'t respect ignore files'
+complete -c rg -l unknown-thing --frobnicate
+complete -c other -l not-rg -d 'Belongs to another command'
+set -l unrelated statement
+complete -c rg -s h -l help -d 'Show help'
@@ -0,0 +1,105 @@
+//! Users' home directories, for `~user` paths
+//!
+//! On Unix the users come from /etc/passwd.  When it can't be read, the
+//! directories next to the current user's home are taken as the homes of
+//! users with those names, which holds on most machines.  Windows has no
+//! `~user`, so there are no users there.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+/// Where passwd lives
+#[cfg(unix)]
+const PASSWD: &str = "/etc/passwd";
+
+/// Knows the users and their home directories
+pub trait HomeDirs: Debug + Send + Sync {
+    /// Users with their home directories
+    fn users(&self) -> Vec<(String, PathBuf)>;
+
+    /// Home directory of `user`
+    fn home(&self, user: &str) -> Option<PathBuf> {
+        self.users()
+            .into_iter()
+            .find(|(name, _)| name == user)
+            .map(|(_, home)| home)
+    }
+}
+
+/// Parse the `name:password:uid:gid:gecos:home:shell` lines of passwd into
+/// users and homes, skipping comments, NIS markers and malformed lines
+pub fn parse_passwd(text: &str) -> Vec<(String, PathBuf)> {
+    text.lines()
+        .filter(|line| !line.starts_with(['#', '+', '-']))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            match fields.as_slice() {
+                [name, _, _, _, _, home, ..] if !name.is_empty() && !home.is_empty() => {
+                    Some((name.to_string(), PathBuf::from(home)))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The system's users
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemHomeDirs;
+
+impl HomeDirs for SystemHomeDirs {
+    #[cfg(unix)]
+    fn users(&self) -> Vec<(String, PathBuf)> {
+        match std::fs::read_to_string(PASSWD) {
+            Ok(text) => parse_passwd(&text),
+            Err(_) => neighbour_homes(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn users(&self) -> Vec<(String, PathBuf)> {
+        Vec::new()
+    }
+}
+
+/// Guess the users from the directories beside the current user's home
+#[cfg(unix)]
+fn neighbour_homes() -> Vec<(String, PathBuf)> {
+    let parent = match dirs_next::home_dir().and_then(|home| Some(home.parent()?.to_path_buf())) {
+        Some(parent) => parent,
+        None => return Vec::new(),
+    };
+    let mut users: Vec<(String, PathBuf)> = std::fs::read_dir(&parent)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path())))
+        .collect();
+    users.sort();
+    users
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_passwd() {
+        let passwd = "# comment\n\
+                      root:x:0:0:root:/root:/bin/bash\n\
+                      alice:x:1000:1000:Alice,,,:/home/alice:/bin/zsh\n\
+                      broken line\n\
+                      nohome:x:1001:1001::::\n\
+                      +@netgroup::::::\n\
+                      bob:x:1002:1002::/srv/bob:/usr/sbin/nologin\n";
+        assert_eq!(
+            parse_passwd(passwd),
+            [
+                ("root".to_string(), PathBuf::from("/root")),
+                ("alice".to_string(), PathBuf::from("/home/alice")),
+                ("bob".to_string(), PathBuf::from("/srv/bob")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,819 @@
+//! Vi modal editing for the input editor
+//!
+//! ViState turns vi keys into Editor operations.  Insert mode passes
+//! almost everything through to the caller; Normal and Visual mode keys are
+//! handled here.  Commands with a count run inside a single undo
+//! transaction, so `3dw` undoes in one step.
+
+use crate::input::editor::{CursorPosition, Editor, MAX_REPEAT_COUNT};
+use crate::input::ViMode;
+use termwiz::input::{KeyCode, KeyEvent, Modifiers};
+
+/// What the caller should do with a key after the vi layer saw it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViOutcome {
+    /// The key was handled
+    Consumed,
+    /// The key means nothing to vi; handle it as ordinary input
+    Ignored,
+    /// Enter in Normal mode: submit the input
+    Submit,
+}
+
+/// Operator waiting for a motion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+impl Operator {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            'd' => Some(Self::Delete),
+            'c' => Some(Self::Change),
+            'y' => Some(Self::Yank),
+            _ => None,
+        }
+    }
+}
+
+/// How a motion's range is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MotionKind {
+    /// Up to but not including the end position
+    Exclusive,
+    /// Up to and including the char at the end position
+    Inclusive,
+    /// Whole lines between start and end
+    Linewise,
+}
+
+/// Text held by the unnamed register
+#[derive(Debug, Clone, Default)]
+struct Register {
+    text: String,
+    linewise: bool,
+}
+
+/// Vi mode, pending count and operator, and the unnamed register
+#[derive(Debug, Clone, Default)]
+pub struct ViState {
+    mode: ViMode,
+    /// Count typed so far, if any
+    count: Option<usize>,
+    /// Operator waiting for a motion, with the count typed before it
+    operator: Option<(Operator, usize)>,
+    register: Register,
+    /// Where Visual mode started
+    visual_anchor: CursorPosition,
+}
+
+impl ViState {
+    /// Create a vi layer starting in Insert mode
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current mode
+    pub fn mode(&self) -> ViMode {
+        self.mode
+    }
+
+    /// Switch mode, dropping any pending count or operator
+    pub fn set_mode(&mut self, mode: ViMode) {
+        self.mode = mode;
+        self.count = None;
+        self.operator = None;
+    }
+
+    /// Contents of the unnamed register
+    pub fn register(&self) -> &str {
+        &self.register.text
+    }
+
+    /// Handle a key, applying any vi command it completes to `editor`
+    pub fn process_key(&mut self, editor: &mut Editor, event: &KeyEvent) -> ViOutcome {
+        match self.mode {
+            ViMode::Insert => self.insert_key(editor, event),
+            ViMode::Normal => self.normal_key(editor, event),
+            ViMode::Visual | ViMode::VisualLine => self.visual_key(editor, event),
+        }
+    }
+
+    /// Insert mode: only Escape is ours
+    fn insert_key(&mut self, editor: &mut Editor, event: &KeyEvent) -> ViOutcome {
+        if event.key != KeyCode::Escape {
+            return ViOutcome::Ignored;
+        }
+        // Leaving Insert mode steps back onto the last inserted char
+        if cursor(editor).column > 0 {
+            editor.move_left();
+        }
+        self.set_mode(ViMode::Normal);
+        ViOutcome::Consumed
+    }
+
+    fn normal_key(&mut self, editor: &mut Editor, event: &KeyEvent) -> ViOutcome {
+        let ctrl = event.modifiers.contains(Modifiers::CTRL);
+        match event.key {
+            KeyCode::Char('r') if ctrl => {
+                let count = self.take_count();
+                self.operator = None;
+                for _ in 0..count {
+                    editor.redo();
+                }
+                clamp_to_char(editor);
+                ViOutcome::Consumed
+            }
+            KeyCode::Char(c) if !has_command_modifier(event.modifiers) => {
+                self.normal_char(editor, c);
+                ViOutcome::Consumed
+            }
+            KeyCode::Escape => {
+                self.set_mode(ViMode::Normal);
+                ViOutcome::Consumed
+            }
+            KeyCode::Enter => {
+                self.set_mode(ViMode::Normal);
+                ViOutcome::Submit
+            }
+            _ => ViOutcome::Ignored,
+        }
+    }
+
+    fn normal_char(&mut self, editor: &mut Editor, c: char) {
+        if self.push_count_digit(c) {
+            return;
+        }
+        let count = self.take_count();
+
+        if let Some((op, op_count)) = self.operator.take() {
+            let count = count.saturating_mul(op_count).min(MAX_REPEAT_COUNT);
+            if Operator::from_char(c) == Some(op) {
+                let line = cursor(editor).line;
+                let last = line.saturating_add(count - 1).min(editor.line_count() - 1);
+                self.apply_linewise(editor, op, line, last);
+            } else {
+                self.apply_motion_operator(editor, op, c, count);
+            }
+            return;
+        }
+
+        if let Some(op) = Operator::from_char(c) {
+            self.operator = Some((op, count));
+            return;
+        }
+        if motion(editor, c, count, false).is_some() {
+            clamp_to_char(editor);
+            return;
+        }
+
+        match c {
+            'x' => self.delete_chars(editor, count),
+            'p' => self.put(editor, count, true),
+            'P' => self.put(editor, count, false),
+            'u' => {
+                for _ in 0..count {
+                    editor.undo();
+                }
+                clamp_to_char(editor);
+            }
+            'i' => self.set_mode(ViMode::Insert),
+            'a' => {
+                let pos = cursor(editor);
+                if pos.column < line_len(editor, pos.line) {
+                    editor.move_right();
+                }
+                self.set_mode(ViMode::Insert);
+            }
+            'I' => {
+                let line = cursor(editor).line;
+                editor.set_cursor_coords(line, first_non_blank(editor, line));
+                self.set_mode(ViMode::Insert);
+            }
+            'A' => {
+                editor.move_to_line_end();
+                self.set_mode(ViMode::Insert);
+            }
+            'o' => {
+                editor.move_to_line_end();
+                editor.insert_char('\n');
+                self.set_mode(ViMode::Insert);
+            }
+            'O' => {
+                editor.move_to_line_start();
+                if editor.insert_char('\n') {
+                    editor.move_up();
+                }
+                self.set_mode(ViMode::Insert);
+            }
+            'v' => {
+                self.visual_anchor = cursor(editor);
+                self.set_mode(ViMode::Visual);
+                self.update_visual_selection(editor);
+            }
+            _ => {}
+        }
+    }
+
+    fn visual_key(&mut self, editor: &mut Editor, event: &KeyEvent) -> ViOutcome {
+        let c = match event.key {
+            KeyCode::Char(c) if !has_command_modifier(event.modifiers) => c,
+            KeyCode::Escape => 'v',
+            _ => return ViOutcome::Ignored,
+        };
+        if self.push_count_digit(c) {
+            return ViOutcome::Consumed;
+        }
+        let count = self.take_count();
+
+        let op = match c {
+            'v' => {
+                self.leave_visual(editor);
+                return ViOutcome::Consumed;
+            }
+            'd' | 'x' => Operator::Delete,
+            'c' => Operator::Change,
+            'y' => Operator::Yank,
+            _ => {
+                if motion(editor, c, count, false).is_some() {
+                    clamp_to_char(editor);
+                    self.update_visual_selection(editor);
+                }
+                return ViOutcome::Consumed;
+            }
+        };
+
+        let anchor = self.visual_anchor;
+        let pos = cursor(editor);
+        let (start, end) = if anchor <= pos {
+            (anchor, pos)
+        } else {
+            (pos, anchor)
+        };
+        self.set_mode(ViMode::Normal);
+        editor.set_cursor_coords(start.line, start.column);
+        self.apply_charwise(editor, op, start, char_after(editor, end));
+        ViOutcome::Consumed
+    }
+
+    /// Select from the Visual anchor to the cursor, keeping the cursor put
+    fn update_visual_selection(&self, editor: &mut Editor) {
+        let pos = cursor(editor);
+        let anchor = self.visual_anchor;
+        editor.set_cursor_coords(anchor.line, anchor.column);
+        editor.extend_selection_to_coords(pos.line, pos.column);
+    }
+
+    fn leave_visual(&mut self, editor: &mut Editor) {
+        let pos = cursor(editor);
+        editor.set_cursor_coords(pos.line, pos.column);
+        self.set_mode(ViMode::Normal);
+    }
+
+    /// Add a digit to the pending count, returning false if `c` isn't one
+    ///
+    /// A leading 0 is the line-start motion rather than a count.
+    fn push_count_digit(&mut self, c: char) -> bool {
+        let digit = match c.to_digit(10) {
+            Some(0) if self.count.is_none() => return false,
+            Some(digit) => digit as usize,
+            None => return false,
+        };
+        let count = self.count.unwrap_or(0);
+        self.count = Some(count.saturating_mul(10).saturating_add(digit));
+        true
+    }
+
+    fn take_count(&mut self) -> usize {
+        self.count.take().unwrap_or(1).clamp(1, MAX_REPEAT_COUNT)
+    }
+
+    /// Run an operator over the text a motion moves across
+    fn apply_motion_operator(&mut self, editor: &mut Editor, op: Operator, c: char, count: usize) {
+        let start = cursor(editor);
+
+        // cw changes to the end of the word, like ce
+        let on_word = char_at(editor, start).is_some_and(|ch| !ch.is_whitespace());
+        let c = if op == Operator::Change && c == 'w' && on_word {
+            'e'
+        } else {
+            c
+        };
+
+        let kind = match motion(editor, c, count, true) {
+            Some(kind) => kind,
+            None => return,
+        };
+        let pos = cursor(editor);
+        let (from, mut to) = if start <= pos {
+            (start, pos)
+        } else {
+            (pos, start)
+        };
+
+        match kind {
+            MotionKind::Linewise => self.apply_linewise(editor, op, from.line, to.line),
+            MotionKind::Inclusive => {
+                editor.set_cursor_coords(from.line, from.column);
+                self.apply_charwise(editor, op, from, char_after(editor, to));
+            }
+            MotionKind::Exclusive => {
+                // An exclusive motion that lands at the start of a later
+                // line stops at the end of the line before it instead
+                if to.line > from.line && to.column == 0 {
+                    to = CursorPosition {
+                        line: to.line - 1,
+                        column: line_len(editor, to.line - 1),
+                    };
+                }
+                editor.set_cursor_coords(from.line, from.column);
+                self.apply_charwise(editor, op, from, to);
+            }
+        }
+    }
+
+    /// Delete, change or yank the text from `start` up to `end`
+    fn apply_charwise(
+        &mut self,
+        editor: &mut Editor,
+        op: Operator,
+        start: CursorPosition,
+        end: CursorPosition,
+    ) {
+        let range = editor.byte_offset_of(start)..editor.byte_offset_of(end);
+        self.register = Register {
+            text: editor.text_in_range(range.clone()),
+            linewise: false,
+        };
+        match op {
+            Operator::Yank => {
+                editor.set_cursor_coords(start.line, start.column);
+            }
+            Operator::Delete => {
+                editor.delete_range(range.start, range.end);
+                editor.set_cursor_coords(start.line, start.column);
+                clamp_to_char(editor);
+            }
+            Operator::Change => {
+                editor.delete_range(range.start, range.end);
+                editor.set_cursor_coords(start.line, start.column);
+                self.set_mode(ViMode::Insert);
+            }
+        }
+    }
+
+    /// Delete, change or yank whole lines `first..=last`
+    fn apply_linewise(&mut self, editor: &mut Editor, op: Operator, first: usize, last: usize) {
+        let lines: Vec<_> = (first..=last).filter_map(|l| editor.line(l)).collect();
+        let text = lines.join("\n");
+        drop(lines);
+        self.register = Register {
+            text,
+            linewise: true,
+        };
+
+        match op {
+            Operator::Yank => {}
+            Operator::Delete => {
+                editor.begin_transaction();
+                editor.set_cursor_coords(first, 0);
+                for _ in first..=last {
+                    editor.delete_line();
+                }
+                editor.end_transaction();
+                let line = cursor(editor).line;
+                editor.set_cursor_coords(line, first_non_blank(editor, line));
+            }
+            Operator::Change => {
+                // Keep one empty line to type into
+                let start = editor.byte_offset_of(CursorPosition {
+                    line: first,
+                    column: 0,
+                });
+                let end = editor.byte_offset_of(CursorPosition {
+                    line: last,
+                    column: line_len(editor, last),
+                });
+                editor.delete_range(start, end);
+                editor.set_cursor_coords(first, 0);
+                self.set_mode(ViMode::Insert);
+            }
+        }
+    }
+
+    /// x: delete up to `count` chars under and after the cursor
+    fn delete_chars(&mut self, editor: &mut Editor, count: usize) {
+        let pos = cursor(editor);
+        let available = line_len(editor, pos.line) - pos.column;
+        if available == 0 {
+            return;
+        }
+        let end = CursorPosition {
+            line: pos.line,
+            column: pos.column + count.min(available),
+        };
+        let range = editor.byte_offset_of(pos)..editor.byte_offset_of(end);
+        self.register = Register {
+            text: editor.text_in_range(range),
+            linewise: false,
+        };
+
+        editor.begin_transaction();
+        for _ in 0..count.min(available) {
+            editor.delete();
+        }
+        editor.end_transaction();
+        clamp_to_char(editor);
+    }
+
+    /// p/P: put the register after or before the cursor `count` times
+    fn put(&mut self, editor: &mut Editor, count: usize, after: bool) {
+        if self.register.text.is_empty() && !self.register.linewise {
+            return;
+        }
+        editor.begin_transaction();
+        if self.register.linewise {
+            let text = vec![self.register.text.as_str(); count].join("\n");
+            let line = cursor(editor).line;
+            let target = if after {
+                editor.move_to_line_end();
+                editor.insert_str(&format!("\n{}", text));
+                line + 1
+            } else {
+                editor.move_to_line_start();
+                editor.insert_str(&format!("{}\n", text));
+                line
+            };
+            editor.set_cursor_coords(target, first_non_blank(editor, target));
+        } else {
+            let pos = cursor(editor);
+            if after && pos.column < line_len(editor, pos.line) {
+                editor.move_right();
+            }
+            editor.insert_str(&self.register.text.repeat(count));
+            // The cursor rests on the last char put
+            editor.move_left();
+        }
+        editor.end_transaction();
+    }
+}
+
+/// Apply motion `c` `count` times, returning None if `c` isn't a motion
+///
+/// With `pending` set the motion is the target of an operator, so `l` and
+/// `$` may reach the end of the line.
+fn motion(editor: &mut Editor, c: char, count: usize, pending: bool) -> Option<MotionKind> {
+    let kind = match c {
+        'h' => {
+            for _ in 0..count {
+                if cursor(editor).column == 0 {
+                    break;
+                }
+                editor.move_left();
+            }
+            MotionKind::Exclusive
+        }
+        'l' => {
+            for _ in 0..count {
+                let pos = cursor(editor);
+                let len = line_len(editor, pos.line);
+                let limit = if pending { len } else { len.saturating_sub(1) };
+                if pos.column >= limit {
+                    break;
+                }
+                editor.move_right();
+            }
+            MotionKind::Exclusive
+        }
+        'j' => {
+            for _ in 0..count {
+                editor.move_down();
+            }
+            MotionKind::Linewise
+        }
+        'k' => {
+            for _ in 0..count {
+                editor.move_up();
+            }
+            MotionKind::Linewise
+        }
+        'w' => {
+            for _ in 0..count {
+                editor.move_word_right();
+            }
+            MotionKind::Exclusive
+        }
+        'b' => {
+            for _ in 0..count {
+                editor.move_word_left();
+            }
+            MotionKind::Exclusive
+        }
+        'e' => {
+            for _ in 0..count {
+                let end = word_end(editor, cursor(editor));
+                editor.set_cursor_coords(end.line, end.column);
+            }
+            MotionKind::Inclusive
+        }
+        '0' => {
+            editor.move_to_line_start();
+            MotionKind::Exclusive
+        }
+        '^' => {
+            let line = cursor(editor).line;
+            editor.set_cursor_coords(line, first_non_blank(editor, line));
+            MotionKind::Exclusive
+        }
+        '$' => {
+            for _ in 1..count {
+                editor.move_down();
+            }
+            editor.move_to_line_end();
+            MotionKind::Exclusive
+        }
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Whether modifiers turn a char key into something other than a vi command
+fn has_command_modifier(mods: Modifiers) -> bool {
+    mods.intersects(Modifiers::CTRL | Modifiers::ALT | Modifiers::SUPER)
+}
+
+fn cursor(editor: &Editor) -> CursorPosition {
+    let (line, column) = editor.cursor_coords();
+    CursorPosition { line, column }
+}
+
+fn line_len(editor: &Editor, line: usize) -> usize {
+    editor.line(line).map_or(0, |text| text.chars().count())
+}
+
+fn char_at(editor: &Editor, pos: CursorPosition) -> Option<char> {
+    editor.line(pos.line)?.chars().nth(pos.column)
+}
+
+/// Position just past the char at `pos`, staying on its line
+fn char_after(editor: &Editor, pos: CursorPosition) -> CursorPosition {
+    CursorPosition {
+        line: pos.line,
+        column: (pos.column + 1).min(line_len(editor, pos.line)),
+    }
+}
+
+/// Keep the cursor on a char, as Normal mode does, rather than past the end
+fn clamp_to_char(editor: &mut Editor) {
+    let pos = cursor(editor);
+    let len = line_len(editor, pos.line);
+    if len > 0 && pos.column >= len {
+        editor.set_cursor_coords(pos.line, len - 1);
+    }
+}
+
+fn first_non_blank(editor: &Editor, line: usize) -> usize {
+    editor
+        .line(line)
+        .and_then(|text| text.chars().position(|c| !c.is_whitespace()))
+        .unwrap_or(0)
+}
+
+/// Vi's three kinds of char for word motions
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/// End of the word after `pos`, as the `e` motion finds it
+fn word_end(editor: &Editor, pos: CursorPosition) -> CursorPosition {
+    let mut line = pos.line;
+    let mut column = pos.column + 1;
+    loop {
+        let chars: Vec<char> = editor.line(line).unwrap_or_default().chars().collect();
+        while column < chars.len() && chars[column].is_whitespace() {
+            column += 1;
+        }
+        if column < chars.len() {
+            let class = char_class(chars[column]);
+            while column + 1 < chars.len() && char_class(chars[column + 1]) == class {
+                column += 1;
+            }
+            return CursorPosition { line, column };
+        }
+        if line + 1 >= editor.line_count() {
+            return CursorPosition {
+                line,
+                column: chars.len().saturating_sub(1),
+            };
+        }
+        line += 1;
+        column = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent {
+            key: KeyCode::Char(c),
+            modifiers: Modifiers::NONE,
+        }
+    }
+
+    /// Start in Normal mode with the cursor at `column` of `text`
+    fn setup(text: &str, column: usize) -> (ViState, Editor) {
+        let mut editor = Editor::new();
+        editor.set_text(text);
+        editor.set_cursor_coords(0, column);
+        let mut vi = ViState::new();
+        vi.set_mode(ViMode::Normal);
+        (vi, editor)
+    }
+
+    fn keys(vi: &mut ViState, editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            vi.process_key(editor, &key(c));
+        }
+    }
+
+    #[test]
+    fn test_basic_motions() {
+        let (mut vi, mut editor) = setup("git commit -m 'fix'", 0);
+        keys(&mut vi, &mut editor, "w");
+        assert_eq!(editor.cursor_coords(), (0, 4));
+        keys(&mut vi, &mut editor, "e");
+        assert_eq!(editor.cursor_coords(), (0, 9));
+        keys(&mut vi, &mut editor, "$");
+        assert_eq!(editor.cursor_coords(), (0, 18));
+        keys(&mut vi, &mut editor, "0");
+        assert_eq!(editor.cursor_coords(), (0, 0));
+        keys(&mut vi, &mut editor, "3l2h");
+        assert_eq!(editor.cursor_coords(), (0, 1));
+        keys(&mut vi, &mut editor, "99l");
+        assert_eq!(editor.cursor_coords(), (0, 18));
+    }
+
+    #[test]
+    fn test_vertical_motions_and_caret() {
+        let (mut vi, mut editor) = setup("for f in *; do\n    echo $f\ndone", 2);
+        keys(&mut vi, &mut editor, "j^");
+        assert_eq!(editor.cursor_coords(), (1, 4));
+        keys(&mut vi, &mut editor, "jk");
+        assert_eq!(editor.cursor_coords(), (1, 3));
+    }
+
+    #[test]
+    fn test_counted_delete_word_is_one_undo_step() {
+        let (mut vi, mut editor) = setup("rm -rf build dist out", 3);
+        // "-" and "rf" are separate words, as in vi
+        keys(&mut vi, &mut editor, "3dw");
+        assert_eq!(editor.text(), "rm dist out");
+        assert_eq!(editor.cursor_coords(), (0, 3));
+
+        keys(&mut vi, &mut editor, "u");
+        assert_eq!(editor.text(), "rm -rf build dist out");
+    }
+
+    #[test]
+    fn test_dd_and_put_lines() {
+        let (mut vi, mut editor) = setup("one\ntwo\nthree", 0);
+        keys(&mut vi, &mut editor, "dd");
+        assert_eq!(editor.full_text(), "two\nthree");
+        keys(&mut vi, &mut editor, "p");
+        assert_eq!(editor.full_text(), "two\none\nthree");
+        assert_eq!(editor.cursor_coords(), (1, 0));
+        keys(&mut vi, &mut editor, "yyP");
+        assert_eq!(editor.full_text(), "two\none\none\nthree");
+
+        keys(&mut vi, &mut editor, "2dd");
+        assert_eq!(editor.full_text(), "two\nthree");
+        keys(&mut vi, &mut editor, "u");
+        assert_eq!(editor.full_text(), "two\none\none\nthree");
+    }
+
+    #[test]
+    fn test_huge_counts_are_clamped() {
+        let (mut vi, mut editor) = setup("one\ntwo\nthree", 0);
+        keys(&mut vi, &mut editor, "99999999999999999999j");
+        assert_eq!(editor.cursor_coords(), (2, 0));
+        keys(
+            &mut vi,
+            &mut editor,
+            "99999999999999999999k99999999999999999999dd",
+        );
+        assert_eq!(editor.full_text(), "");
+
+        let (mut vi, mut editor) = setup("one\ntwo", 0);
+        keys(
+            &mut vi,
+            &mut editor,
+            "99999999999999999999d99999999999999999999d",
+        );
+        assert_eq!(editor.full_text(), "");
+        keys(&mut vi, &mut editor, "99999999999999999999P");
+        assert_eq!(editor.line_count(), 2 * MAX_REPEAT_COUNT + 1);
+    }
+
+    #[test]
+    fn test_x_and_charwise_put() {
+        let (mut vi, mut editor) = setup("ecoh", 2);
+        keys(&mut vi, &mut editor, "xp");
+        assert_eq!(editor.text(), "echo");
+        assert_eq!(editor.cursor_coords(), (0, 3));
+        keys(&mut vi, &mut editor, "5x");
+        assert_eq!(editor.text(), "ech");
+        assert_eq!(editor.cursor_coords(), (0, 2));
+    }
+
+    #[test]
+    fn test_change_word_enters_insert() {
+        let (mut vi, mut editor) = setup("ls -la /tmp", 3);
+        keys(&mut vi, &mut editor, "cw");
+        assert_eq!(vi.mode(), ViMode::Insert);
+        assert_eq!(editor.text(), "ls  /tmp");
+
+        // Insert mode leaves typing to the caller
+        assert_eq!(vi.process_key(&mut editor, &key('x')), ViOutcome::Ignored);
+        editor.insert_str("-1");
+        let escape = KeyEvent {
+            key: KeyCode::Escape,
+            modifiers: Modifiers::NONE,
+        };
+        assert_eq!(vi.process_key(&mut editor, &escape), ViOutcome::Consumed);
+        assert_eq!(vi.mode(), ViMode::Normal);
+        assert_eq!(editor.cursor_coords(), (0, 4));
+    }
+
+    #[test]
+    fn test_insert_commands() {
+        let (mut vi, mut editor) = setup("  make", 4);
+        keys(&mut vi, &mut editor, "I");
+        assert_eq!(editor.cursor_coords(), (0, 2));
+        vi.set_mode(ViMode::Normal);
+        keys(&mut vi, &mut editor, "A");
+        assert_eq!(editor.cursor_coords(), (0, 6));
+        vi.set_mode(ViMode::Normal);
+        keys(&mut vi, &mut editor, "o");
+        assert_eq!(editor.full_text(), "  make\n");
+        assert_eq!(editor.cursor_coords(), (1, 0));
+        vi.set_mode(ViMode::Normal);
+        keys(&mut vi, &mut editor, "O");
+        assert_eq!(editor.full_text(), "  make\n\n");
+        assert_eq!(editor.cursor_coords(), (1, 0));
+        assert_eq!(vi.mode(), ViMode::Insert);
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let (mut vi, mut editor) = setup("abc", 0);
+        keys(&mut vi, &mut editor, "xx");
+        assert_eq!(editor.text(), "c");
+        keys(&mut vi, &mut editor, "2u");
+        assert_eq!(editor.text(), "abc");
+        let redo = KeyEvent {
+            key: KeyCode::Char('r'),
+            modifiers: Modifiers::CTRL,
+        };
+        vi.process_key(&mut editor, &redo);
+        assert_eq!(editor.text(), "bc");
+    }
+
+    #[test]
+    fn test_visual_delete_and_yank() {
+        let (mut vi, mut editor) = setup("cargo build --release", 6);
+        keys(&mut vi, &mut editor, "ve");
+        assert_eq!(vi.mode(), ViMode::Visual);
+        assert!(editor.selected_text().is_some());
+        keys(&mut vi, &mut editor, "y");
+        assert_eq!(vi.register(), "build");
+        assert_eq!(vi.mode(), ViMode::Normal);
+        assert_eq!(editor.selected_text(), None);
+
+        keys(&mut vi, &mut editor, "vlld");
+        assert_eq!(editor.text(), "cargo ld --release");
+        assert_eq!(vi.register(), "bui");
+    }
+
+    #[test]
+    fn test_enter_submits_only_in_normal_mode() {
+        let enter = KeyEvent {
+            key: KeyCode::Enter,
+            modifiers: Modifiers::NONE,
+        };
+        let (mut vi, mut editor) = setup("ls", 0);
+        assert_eq!(vi.process_key(&mut editor, &enter), ViOutcome::Submit);
+        vi.set_mode(ViMode::Insert);
+        assert_eq!(vi.process_key(&mut editor, &enter), ViOutcome::Ignored);
+    }
+}
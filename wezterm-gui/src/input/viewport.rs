@@ -0,0 +1,184 @@
+//! Scrolling for the multi-line input
+//!
+//! EditorViewport tracks which soft-wrapped visual rows of the editor are on
+//! screen.  It works from the editor's WrapLayout, so a long line that wraps
+//! takes as many rows here as it does when drawn.
+
+use crate::input::editor::Editor;
+use std::ops::Range;
+
+/// Tab width used for layout unless set otherwise
+const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// The visual rows of an editor that are on screen
+#[derive(Debug, Clone)]
+pub struct EditorViewport {
+    /// Visual row shown at the top
+    top_visual_row: usize,
+    /// Number of rows on screen
+    height: usize,
+    /// Tab width to lay the buffer out with
+    tab_width: usize,
+    /// Visual rows in the layout last seen
+    row_count: usize,
+}
+
+impl EditorViewport {
+    /// Create a viewport `height` rows tall, scrolled to the top
+    pub fn new(height: usize) -> Self {
+        Self {
+            top_visual_row: 0,
+            height,
+            tab_width: DEFAULT_TAB_WIDTH,
+            row_count: 1,
+        }
+    }
+
+    /// Visual row shown at the top
+    pub fn top_visual_row(&self) -> usize {
+        self.top_visual_row
+    }
+
+    /// Number of rows on screen
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Change the number of rows on screen
+    ///
+    /// Call ensure_cursor_visible afterwards to bring the cursor back into
+    /// view.
+    pub fn set_height(&mut self, height: usize) {
+        self.height = height;
+        self.clamp();
+    }
+
+    /// Set the tab width to lay the buffer out with
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// Scroll so the cursor's row is on screen, returning whether it moved
+    ///
+    /// Scrolls as little as possible, except that a cursor more than a
+    /// screen away is centered.  Also catches up with a buffer that shrank.
+    pub fn ensure_cursor_visible(&mut self, editor: &Editor, wrap_width: usize) -> bool {
+        let before = self.top_visual_row;
+        let layout = editor.wrap_layout(wrap_width, self.tab_width);
+        self.row_count = layout.visual_row_count();
+        let (line, column) = editor.cursor_coords();
+        let (row, _) = layout.visual_row(line, column);
+
+        let height = self.height.max(1);
+        let bottom = self.top_visual_row + height;
+        if row < self.top_visual_row {
+            self.top_visual_row = if self.top_visual_row - row > height {
+                row.saturating_sub(height / 2)
+            } else {
+                row
+            };
+        } else if row >= bottom {
+            self.top_visual_row = if row - bottom >= height {
+                row.saturating_sub(height / 2)
+            } else {
+                row + 1 - height
+            };
+        }
+        self.clamp();
+        self.top_visual_row != before
+    }
+
+    /// Scroll by `delta` rows, down when positive, returning whether it moved
+    ///
+    /// Stays within the layout last seen by ensure_cursor_visible.
+    pub fn scroll_lines(&mut self, delta: isize) -> bool {
+        let before = self.top_visual_row;
+        self.top_visual_row = if delta < 0 {
+            self.top_visual_row.saturating_sub(delta.unsigned_abs())
+        } else {
+            self.top_visual_row.saturating_add(delta as usize)
+        };
+        self.clamp();
+        self.top_visual_row != before
+    }
+
+    /// Visual rows to draw, top to bottom
+    pub fn visible_rows(&self) -> Range<usize> {
+        let end = (self.top_visual_row + self.height).min(self.row_count);
+        self.top_visual_row..end.max(self.top_visual_row)
+    }
+
+    /// Keep the last screenful of rows from scrolling off the top
+    fn clamp(&mut self) {
+        let max_top = self.row_count.saturating_sub(self.height.max(1));
+        self.top_visual_row = self.top_visual_row.min(max_top);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered_lines(count: usize) -> String {
+        (0..count)
+            .map(|n| format!("echo {}", n))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_jump_from_top_to_bottom() {
+        let mut editor = Editor::new();
+        editor.set_text(&numbered_lines(200));
+        editor.set_cursor_coords(0, 0);
+        let mut viewport = EditorViewport::new(10);
+
+        assert!(!viewport.ensure_cursor_visible(&editor, 40));
+        assert_eq!(viewport.visible_rows(), 0..10);
+
+        // One row past the bottom scrolls by one
+        editor.set_cursor_coords(10, 0);
+        assert!(viewport.ensure_cursor_visible(&editor, 40));
+        assert_eq!(viewport.visible_rows(), 1..11);
+
+        // A long jump centers the cursor
+        editor.set_cursor_coords(100, 0);
+        viewport.ensure_cursor_visible(&editor, 40);
+        assert_eq!(viewport.visible_rows(), 95..105);
+
+        editor.move_to_buffer_end();
+        viewport.ensure_cursor_visible(&editor, 40);
+        assert_eq!(viewport.visible_rows(), 190..200);
+        assert!(!viewport.scroll_lines(3));
+        assert!(viewport.scroll_lines(-5));
+        assert_eq!(viewport.top_visual_row(), 185);
+    }
+
+    #[test]
+    fn test_counts_wrapped_rows() {
+        let mut editor = Editor::new();
+        editor.set_text(&format!("{}\nls", "x".repeat(45)));
+        let mut viewport = EditorViewport::new(2);
+
+        // The first line takes five rows at width 10
+        viewport.ensure_cursor_visible(&editor, 10);
+        assert_eq!(viewport.visible_rows(), 4..6);
+        editor.set_cursor_coords(0, 25);
+        viewport.ensure_cursor_visible(&editor, 10);
+        assert_eq!(viewport.visible_rows(), 2..4);
+    }
+
+    #[test]
+    fn test_buffer_cleared_while_scrolled() {
+        let mut editor = Editor::new();
+        editor.set_text(&numbered_lines(50));
+        let mut viewport = EditorViewport::new(10);
+        viewport.ensure_cursor_visible(&editor, 40);
+        assert_eq!(viewport.top_visual_row(), 40);
+
+        editor.clear();
+        assert!(viewport.ensure_cursor_visible(&editor, 40));
+        assert_eq!(viewport.visible_rows(), 0..1);
+        assert!(!viewport.scroll_lines(5));
+    }
+}
@@ -0,0 +1,233 @@
+//! Soft-wrap layout for the multi-line input
+//!
+//! Maps logical (line, column) positions to visual rows of a fixed display
+//! width and back.  Wide chars take two cells; lines break after whitespace
+//! where possible and mid-word when a word doesn't fit on a row.
+
+use crate::input::editor::{char_width, CursorPosition};
+use std::ops::Range;
+
+/// Visual rows for every line of a buffer at a given width
+#[derive(Debug, Clone)]
+pub struct WrapLayout {
+    width: usize,
+    tab_width: usize,
+    lines: Vec<LineLayout>,
+    /// Visual row at which each logical line starts
+    first_rows: Vec<usize>,
+    row_count: usize,
+}
+
+/// Layout of a single logical line
+#[derive(Debug, Clone, Default)]
+struct LineLayout {
+    /// Char column at which each visual row starts
+    row_starts: Vec<usize>,
+    /// Display column of each char within its row, plus one for the line end
+    xs: Vec<usize>,
+}
+
+impl WrapLayout {
+    /// Lay out `lines` in rows of `width` display cells
+    pub fn new<S: AsRef<str>>(
+        lines: impl Iterator<Item = S>,
+        width: usize,
+        tab_width: usize,
+    ) -> Self {
+        let width = width.max(1);
+        let tab_width = tab_width.max(1);
+        let mut layout = Self {
+            width,
+            tab_width,
+            lines: Vec::new(),
+            first_rows: Vec::new(),
+            row_count: 0,
+        };
+        for line in lines {
+            let line = layout.layout_line(line.as_ref());
+            layout.first_rows.push(layout.row_count);
+            layout.row_count += line.row_starts.len();
+            layout.lines.push(line);
+        }
+        layout
+    }
+
+    /// Width the layout was made for
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Tab width the layout was made for
+    pub fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+
+    /// Total number of visual rows
+    pub fn visual_row_count(&self) -> usize {
+        self.row_count
+    }
+
+    /// Visual row and display column of a logical position
+    pub fn visual_row(&self, line: usize, column: usize) -> (usize, usize) {
+        let line = line.min(self.lines.len().saturating_sub(1));
+        let layout = match self.lines.get(line) {
+            Some(layout) => layout,
+            None => return (0, 0),
+        };
+        let column = column.min(layout.xs.len() - 1);
+        let row = layout
+            .row_starts
+            .iter()
+            .rposition(|&start| start <= column)
+            .unwrap_or(0);
+        (self.first_rows[line] + row, layout.xs[column])
+    }
+
+    /// Logical line and char columns shown on a visual row
+    ///
+    /// The range ends where the next row starts, or at the end of the line.
+    pub fn row_bounds(&self, row: usize) -> (usize, Range<usize>) {
+        if self.lines.is_empty() {
+            return (0, 0..0);
+        }
+        let row = row.min(self.row_count - 1);
+        let line = self
+            .first_rows
+            .iter()
+            .rposition(|&first| first <= row)
+            .unwrap_or(0);
+        let layout = &self.lines[line];
+        let idx = row - self.first_rows[line];
+        let start = layout.row_starts[idx];
+        let end = layout
+            .row_starts
+            .get(idx + 1)
+            .copied()
+            .unwrap_or(layout.xs.len() - 1);
+        (line, start..end)
+    }
+
+    /// Logical position closest to a display column on a visual row
+    pub fn position_of_visual(&self, row: usize, vcol: usize) -> CursorPosition {
+        let (line, columns) = self.row_bounds(row);
+        let layout = match self.lines.get(line) {
+            Some(layout) => layout,
+            None => return CursorPosition::default(),
+        };
+        let last_row = columns.end == layout.xs.len() - 1;
+        // A position at the start of the next row belongs to that row, so
+        // only the line's final row can hold its end
+        let max = if last_row || columns.is_empty() {
+            columns.end
+        } else {
+            columns.end - 1
+        };
+        let column = (columns.start..=max)
+            .take_while(|&col| layout.xs[col] <= vcol)
+            .last()
+            .unwrap_or(columns.start);
+        CursorPosition { line, column }
+    }
+
+    /// Break one line into rows
+    fn layout_line(&self, text: &str) -> LineLayout {
+        let chars: Vec<char> = text.chars().collect();
+        let mut layout = LineLayout {
+            row_starts: vec![0],
+            xs: Vec::with_capacity(chars.len() + 1),
+        };
+        let mut x = 0;
+        let mut last_space: Option<usize> = None;
+        let mut idx = 0;
+
+        while idx < chars.len() {
+            let c = chars[idx];
+            let w = self.advance(c, x);
+            let hangs = c.is_whitespace() && x <= self.width;
+            if x + w > self.width && x > 0 && !hangs {
+                // Break after the last whitespace on the row, or right here
+                let start = match last_space {
+                    Some(space) if space + 1 > *layout.row_starts.last().unwrap() => space + 1,
+                    _ => idx,
+                };
+                layout.row_starts.push(start);
+                layout.xs.truncate(start);
+                last_space = None;
+                x = 0;
+                idx = start;
+                continue;
+            }
+            if c.is_whitespace() {
+                last_space = Some(idx);
+            }
+            layout.xs.push(x);
+            x += w;
+            idx += 1;
+        }
+        layout.xs.push(x);
+        layout
+    }
+
+    /// Display cells `c` takes when it starts at column `x`
+    fn advance(&self, c: char, x: usize) -> usize {
+        if c == '\t' {
+            self.tab_width - x % self.tab_width
+        } else {
+            char_width(c)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(text: &str, width: usize) -> WrapLayout {
+        WrapLayout::new(text.split('\n'), width, 4)
+    }
+
+    #[test]
+    fn test_line_exactly_as_wide_as_the_layout() {
+        let layout = layout("0123456789\nab", 10);
+        assert_eq!(layout.visual_row_count(), 2);
+        assert_eq!(layout.visual_row(0, 10), (0, 10));
+        assert_eq!(layout.visual_row(1, 1), (1, 1));
+        assert_eq!(
+            layout.position_of_visual(0, 99),
+            CursorPosition {
+                line: 0,
+                column: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_cjk_line() {
+        // Each char is two cells wide, so five fit in ten cells
+        let layout = layout("日本語のテキストです", 10);
+        assert_eq!(layout.visual_row_count(), 2);
+        assert_eq!(layout.visual_row(0, 4), (0, 8));
+        assert_eq!(layout.visual_row(0, 5), (1, 0));
+        assert_eq!(layout.visual_row(0, 7), (1, 4));
+        assert_eq!(
+            layout.position_of_visual(1, 5),
+            CursorPosition { line: 0, column: 7 }
+        );
+        assert_eq!(
+            layout.position_of_visual(0, 20),
+            CursorPosition { line: 0, column: 4 }
+        );
+    }
+
+    #[test]
+    fn test_word_longer_than_width() {
+        let layout = layout("ls abcdefghijklmnop x", 8);
+        assert_eq!(layout.row_bounds(0), (0, 0..3));
+        assert_eq!(layout.row_bounds(1), (0, 3..11));
+        assert_eq!(layout.row_bounds(2), (0, 11..20));
+        assert_eq!(layout.row_bounds(3), (0, 20..21));
+        assert_eq!(layout.visual_row_count(), 4);
+        assert_eq!(layout.visual_row(0, 19), (2, 8));
+        assert_eq!(layout.visual_row(0, 21), (3, 1));
+    }
+}